@@ -1,5 +1,6 @@
 //! Provides backoff timing control for Kubernetes pod states
 //! such as ImagePullBackoff and CrashLoopBackoff.
+use rand::Rng;
 use std::time::Duration;
 
 /// Determines how long to back off before performing a retry.
@@ -17,10 +18,17 @@ pub trait BackoffStrategy: Send {
 
 /// A `BackoffStrategy` in which the durations increase exponentially
 /// until hitting a cap.
+///
+/// Optionally the durations are randomly lengthened, so that many pods which failed at the same
+/// time do not retry in lockstep, and the number of retries is limited.
+#[derive(Clone, Debug)]
 pub struct ExponentialBackoffStrategy {
     base_duration: Duration,
     cap: Duration,
     last_duration: Duration,
+    jitter: f64,
+    max_retries: Option<usize>,
+    retries: usize,
 }
 
 impl Default for ExponentialBackoffStrategy {
@@ -30,11 +38,56 @@ impl Default for ExponentialBackoffStrategy {
             base_duration: Duration::from_secs(10),
             cap: Duration::from_secs(300),
             last_duration: Duration::from_secs(0),
+            jitter: 0.0,
+            max_retries: None,
+            retries: 0,
         }
     }
 }
 
 impl ExponentialBackoffStrategy {
+    /// Gets a backoff strategy that starts at `base_duration` and doubles
+    /// on every retry until reaching `cap`.
+    pub fn new(base_duration: Duration, cap: Duration) -> Self {
+        Self {
+            base_duration,
+            cap,
+            last_duration: Duration::from_secs(0),
+            jitter: 0.0,
+            max_retries: None,
+            retries: 0,
+        }
+    }
+
+    /// Lengthens every duration by a random amount of up to the given fraction of it, e.g. by
+    /// up to 10% with a jitter of `0.1`. The durations still double without the jitter, so
+    /// the jitter does not add up.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.max(0.0);
+        self
+    }
+
+    /// Limits how often is backed off before giving up, see
+    /// [`ExponentialBackoffStrategy::retries_exhausted`]. Unlimited by default.
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// The number of retries allowed, if limited.
+    pub fn max_retries(&self) -> Option<usize> {
+        self.max_retries
+    }
+
+    /// Whether all retries have been backed off for since the last reset, so that the caller
+    /// should give up instead of retrying again.
+    pub fn retries_exhausted(&self) -> bool {
+        match self.max_retries {
+            Some(max_retries) => self.retries >= max_retries,
+            None => false,
+        }
+    }
+
     fn capped_next_duration(&self) -> Duration {
         let next_duration = if self.last_duration == Duration::from_secs(0) {
             self.base_duration
@@ -53,12 +106,18 @@ impl ExponentialBackoffStrategy {
 impl BackoffStrategy for ExponentialBackoffStrategy {
     fn reset(&mut self) {
         self.last_duration = Duration::from_secs(0);
+        self.retries = 0;
     }
 
     fn next_duration(&mut self) -> Duration {
         let next_duration = self.capped_next_duration();
         self.last_duration = next_duration;
-        next_duration
+        self.retries += 1;
+        if self.jitter > 0.0 {
+            next_duration.mul_f64(1.0 + rand::thread_rng().gen_range(0.0, self.jitter))
+        } else {
+            next_duration
+        }
     }
 }
 
@@ -103,4 +162,37 @@ mod test {
         assert_eq!(backoff.next_duration(), Duration::from_secs(300));
         assert_eq!(backoff.next_duration(), Duration::from_secs(300));
     }
+
+    #[test]
+    fn custom_backoff_uses_given_base_and_cap() {
+        let mut backoff =
+            ExponentialBackoffStrategy::new(Duration::from_millis(100), Duration::from_millis(300));
+        assert_eq!(backoff.next_duration(), Duration::from_millis(100));
+        assert_eq!(backoff.next_duration(), Duration::from_millis(200));
+        assert_eq!(backoff.next_duration(), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn jitter_lengthens_backoff_by_up_to_the_given_fraction() {
+        let mut backoff = ExponentialBackoffStrategy::default().with_jitter(0.5);
+        for expected in &[10, 20, 40, 80] {
+            let expected = Duration::from_secs(*expected);
+            let duration = backoff.next_duration();
+            assert!(duration >= expected && duration <= expected.mul_f64(1.5));
+        }
+    }
+
+    #[test]
+    fn retries_are_exhausted_until_reset() {
+        let mut backoff = ExponentialBackoffStrategy::default().with_max_retries(2);
+        assert!(!backoff.retries_exhausted());
+        backoff.next_duration();
+        assert!(!backoff.retries_exhausted());
+        backoff.next_duration();
+        assert!(backoff.retries_exhausted());
+        backoff.reset();
+        assert!(!backoff.retries_exhausted());
+
+        assert!(!ExponentialBackoffStrategy::default().retries_exhausted());
+    }
 }
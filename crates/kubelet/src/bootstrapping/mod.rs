@@ -15,6 +15,7 @@ use tokio::fs::{read, write};
 
 use crate::config::Config as KubeletConfig;
 use crate::kubeconfig::exists as kubeconfig_exists;
+use crate::kubeconfig::in_cluster as in_cluster_kubeconfig;
 use crate::kubeconfig::KUBECONFIG;
 
 const APPROVED_TYPE: &str = "Approved";
@@ -35,7 +36,9 @@ async fn bootstrap_auth<K: AsRef<Path>>(
     config: &KubeletConfig,
     bootstrap_file: K,
 ) -> anyhow::Result<Config> {
-    if kubeconfig_exists() {
+    if let Some(kubeconfig) = in_cluster_kubeconfig(config) {
+        Ok(kubeconfig)
+    } else if kubeconfig_exists() {
         debug!("Found existing kubeconfig, loading...");
         Config::infer()
             .await
@@ -13,6 +13,7 @@
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[cfg(any(feature = "cli", feature = "docs"))]
 use std::iter::FromIterator;
@@ -27,6 +28,11 @@ use serde::Deserialize;
 const DEFAULT_PORT: u16 = 3000;
 const DEFAULT_MAX_PODS: u16 = 110;
 const BOOTSTRAP_FILE: &str = "/etc/kubernetes/bootstrap-kubelet.conf";
+const DEFAULT_NODE_STATUS_INTERVAL_SECS: u64 = 10;
+const MIN_NODE_STATUS_INTERVAL_SECS: u64 = 1;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_POD_EVENT_DEBOUNCE_MILLIS: u64 = 100;
+const DEFAULT_POD_CONCURRENCY: u16 = 10;
 
 /// The configuration needed for a kubelet to run properly.
 ///
@@ -40,6 +46,10 @@ const BOOTSTRAP_FILE: &str = "/etc/kubernetes/bootstrap-kubelet.conf";
 pub struct Config {
     /// The ip address the node is exposed on
     pub node_ip: IpAddr,
+    /// Whether `node_ip` was configured explicitly, rather than derived from the hostname, so
+    /// that providers with a better way to find the address of the node only override a
+    /// derived one
+    pub node_ip_configured: bool,
     /// The hostname of the node
     pub hostname: String,
     /// The node's name
@@ -62,6 +72,50 @@ pub struct Config {
     pub insecure_registries: Option<Vec<String>>,
     /// The directory kubelet should watch for new plugin sockets
     pub plugins_dir: PathBuf,
+    /// How often the node lease and status are updated in the API server
+    pub node_status_interval: Duration,
+    /// How long to wait for the node to be drained on shutdown before exiting anyway
+    pub shutdown_timeout: Duration,
+    /// Updates to the same pod arriving within this window are coalesced into one
+    /// notification to the provider. Zero disables debouncing.
+    pub pod_event_debounce: Duration,
+    /// The maximum number of pods whose state machines advance at the same time. A pod stops
+    /// counting towards this limit once it is running, and while it waits for a backoff to elapse.
+    pub pod_concurrency: u16,
+    /// Whether to reach the API server with the service account of the pod the kubelet runs
+    /// in, falling back to the kubeconfig file when it does not run in a cluster
+    pub in_cluster: bool,
+    /// How many pods may run actors on the node at once, unlimited if unset. Only used by the
+    /// wasCC provider.
+    pub max_actors: Option<u16>,
+    /// How many pods the node advertises as its capacity, overriding `max_pods`. Only used by
+    /// the Stackable provider, which runs a process for each pod.
+    pub max_processes: Option<u16>,
+    /// The directory the Stackable provider downloads and unpacks packages to, instead of
+    /// `(data directory)/stackable/parcels`
+    pub parcel_dir: Option<PathBuf>,
+    /// The directory the Stackable provider writes the configuration files of pods to, instead
+    /// of `(data directory)/stackable/config`
+    pub config_dir: Option<PathBuf>,
+    /// Whether the Stackable provider registers the CRDs it needs, rather than refusing to
+    /// start while they are missing
+    pub register_crds: bool,
+    /// The network interface whose address the Stackable provider registers the node with,
+    /// unless `node_ip` is configured
+    pub node_interface: Option<String>,
+    /// The directory the wasCC provider caches actor modules in, e.g. one shared by several
+    /// nodes
+    pub module_cache_dir: Option<PathBuf>,
+    /// The images whose modules the wasCC provider pulls on startup
+    pub prewarm_images: Vec<String>,
+    /// The network, in CIDR notation, the Stackable provider assigns pod IPs from instead of
+    /// the IP of the node
+    pub pod_ip_pool: Option<String>,
+    /// Whether the Stackable provider enforces the resource limits of pods with cgroups
+    pub cgroups: bool,
+    /// The cgroup below which the Stackable provider creates the cgroups of pods, if
+    /// `cgroups` is enabled
+    pub cgroup_parent: Option<PathBuf>,
 }
 /// The configuration for the Kubelet server.
 #[derive(Clone, Debug)]
@@ -74,6 +128,9 @@ pub struct ServerConfig {
     pub cert_file: PathBuf,
     /// Path to kubelet TLS private key.
     pub private_key_file: PathBuf,
+    /// The bearer token which authenticates requests to the debug routes which change pods.
+    /// These routes are disabled if it is not set.
+    pub debug_token: Option<String>,
 }
 
 #[derive(Debug, Default, serde::Deserialize)]
@@ -115,12 +172,70 @@ struct ConfigBuilder {
     pub server_tls_cert_file: Option<PathBuf>,
     #[serde(default, rename = "tlsPrivateKeyFile")]
     pub server_tls_private_key_file: Option<PathBuf>,
+    #[serde(default, rename = "debugToken")]
+    pub server_debug_token: Option<String>,
     #[serde(default, rename = "allowLocalModules")]
     pub allow_local_modules: Option<bool>,
     #[serde(default, rename = "insecureRegistries")]
     pub insecure_registries: Option<Vec<String>>,
     #[serde(default, rename = "pluginsDir")]
     pub plugins_dir: Option<PathBuf>,
+    #[serde(
+        default,
+        rename = "nodeStatusInterval",
+        deserialize_with = "try_deserialize_u64"
+    )]
+    pub node_status_interval: Option<anyhow::Result<u64>>,
+    #[serde(
+        default,
+        rename = "shutdownTimeout",
+        deserialize_with = "try_deserialize_u64"
+    )]
+    pub shutdown_timeout: Option<anyhow::Result<u64>>,
+    #[serde(
+        default,
+        rename = "podEventDebounceMillis",
+        deserialize_with = "try_deserialize_u64"
+    )]
+    pub pod_event_debounce: Option<anyhow::Result<u64>>,
+    #[serde(
+        default,
+        rename = "podConcurrency",
+        deserialize_with = "try_deserialize_u16"
+    )]
+    pub pod_concurrency: Option<anyhow::Result<u16>>,
+    #[serde(default, rename = "inCluster")]
+    pub in_cluster: Option<bool>,
+    #[serde(
+        default,
+        rename = "maxActors",
+        deserialize_with = "try_deserialize_u16"
+    )]
+    pub max_actors: Option<anyhow::Result<u16>>,
+    #[serde(
+        default,
+        rename = "maxProcesses",
+        deserialize_with = "try_deserialize_u16"
+    )]
+    pub max_processes: Option<anyhow::Result<u16>>,
+    #[serde(default, rename = "parcelDir")]
+    pub parcel_dir: Option<PathBuf>,
+    #[serde(default, rename = "configDir")]
+    pub config_dir: Option<PathBuf>,
+    #[serde(default, rename = "registerCrds")]
+    pub register_crds: Option<bool>,
+    #[serde(default, rename = "nodeInterface")]
+    pub node_interface: Option<String>,
+    #[serde(default, rename = "moduleCacheDir")]
+    pub module_cache_dir: Option<PathBuf>,
+    #[serde(default, rename = "prewarmImages")]
+    pub prewarm_images: Option<Vec<String>>,
+    #[serde(default, rename = "podIpPool")]
+    pub pod_ip_pool: Option<String>,
+    #[serde(default, rename = "cgroups")]
+    pub cgroups: Option<bool>,
+    #[serde(default, rename = "cgroupParent")]
+    pub cgroup_parent: Option<PathBuf>,
 }
 
 struct ConfigBuilderFallbacks {
@@ -147,6 +262,7 @@ impl Config {
         let plugins_dir = default_plugins_path(&data_dir);
         Ok(Config {
             node_ip: default_node_ip(&mut hostname.clone(), preferred_ip_family)?,
+            node_ip_configured: false,
             node_name: sanitize_hostname(&hostname),
             node_labels: HashMap::new(),
             hostname,
@@ -156,6 +272,22 @@ impl Config {
             allow_local_modules: false,
             insecure_registries: None,
             plugins_dir,
+            node_status_interval: Duration::from_secs(DEFAULT_NODE_STATUS_INTERVAL_SECS),
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            pod_event_debounce: Duration::from_millis(DEFAULT_POD_EVENT_DEBOUNCE_MILLIS),
+            pod_concurrency: DEFAULT_POD_CONCURRENCY,
+            in_cluster: false,
+            max_actors: None,
+            max_processes: None,
+            parcel_dir: None,
+            config_dir: None,
+            register_crds: false,
+            node_interface: None,
+            module_cache_dir: None,
+            prewarm_images: Vec::new(),
+            pod_ip_pool: None,
+            cgroups: false,
+            cgroup_parent: None,
             server_config: ServerConfig {
                 addr: match preferred_ip_family {
                     IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
@@ -164,6 +296,7 @@ impl Config {
                 port: DEFAULT_PORT,
                 cert_file,
                 private_key_file,
+                debug_token: None,
             },
         })
     }
@@ -278,10 +411,27 @@ impl ConfigBuilder {
             allow_local_modules: opts.allow_local_modules,
             insecure_registries: opts.insecure_registries.map(parse_comma_separated),
             plugins_dir: opts.plugins_dir,
+            node_status_interval: ok_result_of(opts.node_status_interval),
+            shutdown_timeout: ok_result_of(opts.shutdown_timeout),
+            pod_event_debounce: ok_result_of(opts.pod_event_debounce),
+            pod_concurrency: ok_result_of(opts.pod_concurrency),
+            in_cluster: opts.in_cluster,
+            max_actors: ok_result_of(opts.max_actors),
+            max_processes: ok_result_of(opts.max_processes),
+            parcel_dir: opts.parcel_dir,
+            config_dir: opts.config_dir,
+            register_crds: opts.register_crds,
+            node_interface: opts.node_interface,
+            module_cache_dir: opts.module_cache_dir,
+            prewarm_images: opts.prewarm_images.map(parse_comma_separated),
+            pod_ip_pool: opts.pod_ip_pool,
+            cgroups: opts.cgroups,
+            cgroup_parent: opts.cgroup_parent,
             server_addr: ok_result_of(opts.addr),
             server_port: ok_result_of(opts.port),
             server_tls_cert_file: opts.cert_file,
             server_tls_private_key_file: opts.private_key_file,
+            server_debug_token: opts.debug_token,
         }
     }
 
@@ -316,9 +466,26 @@ impl ConfigBuilder {
             allow_local_modules: other.allow_local_modules.or(self.allow_local_modules),
             insecure_registries: other.insecure_registries.or(self.insecure_registries),
             plugins_dir: other.plugins_dir.or(self.plugins_dir),
+            node_status_interval: other.node_status_interval.or(self.node_status_interval),
+            shutdown_timeout: other.shutdown_timeout.or(self.shutdown_timeout),
+            pod_event_debounce: other.pod_event_debounce.or(self.pod_event_debounce),
+            pod_concurrency: other.pod_concurrency.or(self.pod_concurrency),
+            in_cluster: other.in_cluster.or(self.in_cluster),
+            max_actors: other.max_actors.or(self.max_actors),
+            max_processes: other.max_processes.or(self.max_processes),
+            parcel_dir: other.parcel_dir.or(self.parcel_dir),
+            config_dir: other.config_dir.or(self.config_dir),
+            register_crds: other.register_crds.or(self.register_crds),
+            node_interface: other.node_interface.or(self.node_interface),
+            module_cache_dir: other.module_cache_dir.or(self.module_cache_dir),
+            prewarm_images: other.prewarm_images.or(self.prewarm_images),
+            pod_ip_pool: other.pod_ip_pool.or(self.pod_ip_pool),
+            cgroups: other.cgroups.or(self.cgroups),
+            cgroup_parent: other.cgroup_parent.or(self.cgroup_parent),
             server_tls_private_key_file: other
                 .server_tls_private_key_file
                 .or(self.server_tls_private_key_file),
+            server_debug_token: other.server_debug_token.or(self.server_debug_token),
         }
     }
 
@@ -345,6 +512,7 @@ impl ConfigBuilder {
             .server_port
             .unwrap_or(Ok(DEFAULT_PORT))
             .map_err(|e| invalid_config_value_error(e, "server port"))?;
+        let node_ip_configured = self.node_ip.is_some();
         let node_ip = self
             .node_ip
             .unwrap_or_else(|| Ok((fallbacks.node_ip)(&mut hostname.clone(), &server_addr)))
@@ -356,9 +524,54 @@ impl ConfigBuilder {
             .max_pods
             .unwrap_or(Ok(DEFAULT_MAX_PODS))
             .map_err(|e| invalid_config_value_error(e, "maximum pods"))?;
+        let node_status_interval = self
+            .node_status_interval
+            .unwrap_or(Ok(DEFAULT_NODE_STATUS_INTERVAL_SECS))
+            .and_then(|secs| {
+                if secs < MIN_NODE_STATUS_INTERVAL_SECS {
+                    Err(anyhow::anyhow!(
+                        "must be at least {} second(s) but was {}",
+                        MIN_NODE_STATUS_INTERVAL_SECS,
+                        secs
+                    ))
+                } else {
+                    Ok(Duration::from_secs(secs))
+                }
+            })
+            .map_err(|e| invalid_config_value_error(e, "node status interval"))?;
+        let shutdown_timeout = self
+            .shutdown_timeout
+            .unwrap_or(Ok(DEFAULT_SHUTDOWN_TIMEOUT_SECS))
+            .map(Duration::from_secs)
+            .map_err(|e| invalid_config_value_error(e, "shutdown timeout"))?;
+        let pod_event_debounce = self
+            .pod_event_debounce
+            .unwrap_or(Ok(DEFAULT_POD_EVENT_DEBOUNCE_MILLIS))
+            .map(Duration::from_millis)
+            .map_err(|e| invalid_config_value_error(e, "pod event debounce"))?;
+        let pod_concurrency = self
+            .pod_concurrency
+            .unwrap_or(Ok(DEFAULT_POD_CONCURRENCY))
+            .and_then(|concurrency| {
+                if concurrency == 0 {
+                    Err(anyhow::anyhow!("must be at least 1"))
+                } else {
+                    Ok(concurrency)
+                }
+            })
+            .map_err(|e| invalid_config_value_error(e, "pod concurrency"))?;
+        let max_actors = self
+            .max_actors
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "maximum actors"))?;
+        let max_processes = self
+            .max_processes
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "maximum processes"))?;
 
         Ok(Config {
             node_ip,
+            node_ip_configured,
             node_name,
             node_labels: self.node_labels.unwrap_or_else(HashMap::new),
             hostname,
@@ -368,11 +581,33 @@ impl ConfigBuilder {
             allow_local_modules: self.allow_local_modules.unwrap_or(false),
             insecure_registries: self.insecure_registries,
             plugins_dir,
+            node_status_interval,
+            shutdown_timeout,
+            pod_event_debounce,
+            pod_concurrency,
+            in_cluster: self.in_cluster.unwrap_or(false),
+            max_actors,
+            max_processes,
+            parcel_dir: self.parcel_dir,
+            config_dir: self.config_dir,
+            register_crds: self.register_crds.unwrap_or(false),
+            node_interface: self.node_interface,
+            module_cache_dir: self.module_cache_dir,
+            prewarm_images: self
+                .prewarm_images
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|image| !image.is_empty())
+                .collect(),
+            pod_ip_pool: self.pod_ip_pool,
+            cgroups: self.cgroups.unwrap_or(false),
+            cgroup_parent: self.cgroup_parent,
             server_config: ServerConfig {
                 cert_file: server_tls_cert_file,
                 private_key_file: server_tls_private_key_file,
                 addr: server_addr,
                 port: server_port,
+                debug_token: self.server_debug_token,
             },
         })
     }
@@ -395,6 +630,14 @@ where
     Ok(Some(n))
 }
 
+fn try_deserialize_u64<'de, D>(d: D) -> Result<Option<anyhow::Result<u64>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let n = u64::deserialize(d).map_err(|e| anyhow::Error::msg(format!("{}", e)));
+    Ok(Some(n))
+}
+
 /// CLI options that can be configured for Kubelet
 ///
 /// These can be parsed from args using `Opts::into_app()`
@@ -443,6 +686,13 @@ pub struct Opts {
     )]
     private_key_file: Option<PathBuf>,
 
+    #[structopt(
+        long = "debug-token",
+        env = "KRUSTLET_DEBUG_TOKEN",
+        help = "The bearer token which authenticates requests to the debug routes which change pods, e.g. /debug/evict. These routes are disabled unless it is set"
+    )]
+    debug_token: Option<String>,
+
     #[structopt(
         short = "n",
         long = "node-ip",
@@ -463,7 +713,7 @@ pub struct Opts {
         failure-domain.beta.kubernetes.io/region, failure-domain.beta.kubernetes.io/zone,
         failure-domain.kubernetes.io/region, failure-domain.kubernetes.io/zone,
         kubernetes.io/arch, kubernetes.io/hostname, kubernetes.io/instance-type,
-        kubernetes.io/os)"
+        kubernetes.io/os, topology.kubernetes.io/region, topology.kubernetes.io/zone)"
     )]
     node_labels: Vec<String>,
 
@@ -516,6 +766,118 @@ pub struct Opts {
         help = "Registries that should be accessed over HTTP instead of HTTPS (comma separated)"
     )]
     insecure_registries: Option<String>,
+
+    #[structopt(
+        long = "node-status-interval",
+        env = "KRUSTLET_NODE_STATUS_INTERVAL",
+        help = "How often (in seconds) the node lease and status are updated. Must be at least 1. Defaults to 10"
+    )]
+    node_status_interval: Option<u64>,
+
+    #[structopt(
+        long = "shutdown-timeout",
+        env = "KRUSTLET_SHUTDOWN_TIMEOUT",
+        help = "How long (in seconds) to wait for the node to be drained on shutdown before exiting anyway. Defaults to 30"
+    )]
+    shutdown_timeout: Option<u64>,
+
+    #[structopt(
+        long = "pod-event-debounce-millis",
+        env = "KRUSTLET_POD_EVENT_DEBOUNCE_MILLIS",
+        help = "Updates to the same pod arriving within this many milliseconds are handled as one. 0 disables debouncing. Defaults to 100"
+    )]
+    pod_event_debounce: Option<u64>,
+
+    #[structopt(
+        long = "pod-concurrency",
+        env = "KRUSTLET_POD_CONCURRENCY",
+        help = "The maximum number of pods that are started at the same time. Defaults to 10"
+    )]
+    pod_concurrency: Option<u16>,
+
+    #[structopt(
+        long = "in-cluster",
+        env = "KRUSTLET_IN_CLUSTER",
+        help = "Whether to use the service account of the pod the kubelet runs in, falling back to the kubeconfig file outside of a cluster"
+    )]
+    in_cluster: Option<bool>,
+
+    #[structopt(
+        long = "max-actors",
+        env = "KRUSTLET_MAX_ACTORS",
+        help = "(krustlet-wascc) How many pods may run actors on the node at once. Unlimited by default"
+    )]
+    max_actors: Option<u16>,
+
+    #[structopt(
+        long = "max-processes",
+        env = "KRUSTLET_MAX_PROCESSES",
+        help = "(krustlet-stackable) How many pods the node advertises as its capacity. Defaults to --max-pods"
+    )]
+    max_processes: Option<u16>,
+
+    #[structopt(
+        long = "parcel-dir",
+        env = "KRUSTLET_PARCEL_DIR",
+        help = "(krustlet-stackable) The directory packages are downloaded and unpacked to. Defaults to $KRUSTLET_DATA_DIR/stackable/parcels"
+    )]
+    parcel_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "config-dir",
+        env = "KRUSTLET_CONFIG_DIR",
+        help = "(krustlet-stackable) The directory the configuration files of pods are written to. Defaults to $KRUSTLET_DATA_DIR/stackable/config"
+    )]
+    config_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "register-crds",
+        env = "KRUSTLET_REGISTER_CRDS",
+        help = "(krustlet-stackable) Whether to register missing CRDs instead of refusing to start. Defaults to false"
+    )]
+    register_crds: Option<bool>,
+
+    #[structopt(
+        long = "node-interface",
+        env = "KRUSTLET_NODE_INTERFACE",
+        help = "(krustlet-stackable) The network interface whose address the node is registered with, unless the node IP is set. Defaults to the first interface which is up"
+    )]
+    node_interface: Option<String>,
+
+    #[structopt(
+        long = "module-cache-dir",
+        env = "KRUSTLET_MODULE_CACHE_DIR",
+        help = "(krustlet-wascc) The directory actor modules are cached in, e.g. one shared by several nodes"
+    )]
+    module_cache_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "prewarm-images",
+        env = "KRUSTLET_PREWARM_IMAGES",
+        help = "(krustlet-wascc) Images whose modules are pulled on startup (comma separated)"
+    )]
+    prewarm_images: Option<String>,
+
+    #[structopt(
+        long = "pod-ip-pool",
+        env = "KRUSTLET_POD_IP_POOL",
+        help = "(krustlet-stackable, experimental) The network in CIDR notation pod IPs are assigned from. Pods share the IP of the node by default"
+    )]
+    pod_ip_pool: Option<String>,
+
+    #[structopt(
+        long = "cgroups",
+        env = "KRUSTLET_CGROUPS",
+        help = "(krustlet-stackable) Whether to enforce the resource limits of pods with cgroups v2. Requires root. Defaults to false"
+    )]
+    cgroups: Option<bool>,
+
+    #[structopt(
+        long = "cgroup-parent",
+        env = "KRUSTLET_CGROUP_PARENT",
+        help = "(krustlet-stackable) The cgroup below which the cgroups of pods are created"
+    )]
+    cgroup_parent: Option<PathBuf>,
 }
 
 fn default_hostname() -> anyhow::Result<String> {
@@ -657,7 +1019,24 @@ mod test {
                 "local",
                 "dev"
             ],
-            "pluginsDir": "/some/plugins"
+            "pluginsDir": "/some/plugins",
+            "nodeStatusInterval": 30,
+            "shutdownTimeout": 60,
+            "podEventDebounceMillis": 250,
+            "podConcurrency": 4,
+            "inCluster": true,
+            "maxActors": 25,
+            "maxProcesses": 40,
+            "parcelDir": "/stackable/parcels",
+            "configDir": "/stackable/config",
+            "registerCrds": true,
+            "nodeInterface": "eth1",
+            "moduleCacheDir": "/shared/modules",
+            "prewarmImages": ["greet:v1", "uppercase:v1"],
+            "podIpPool": "10.20.0.0/24",
+            "cgroups": true,
+            "cgroupParent": "/sys/fs/cgroup/pods",
+            "debugToken": "s3cr3t"
         }"#,
         );
         let config = config_builder.unwrap().build(fallbacks()).unwrap();
@@ -679,6 +1058,7 @@ mod test {
         assert_eq!(config.hostname, "krusty-host");
         assert_eq!(config.data_dir.to_string_lossy(), "/krusty/data/dir");
         assert_eq!(format!("{}", config.node_ip), "173.183.193.2");
+        assert!(config.node_ip_configured);
         assert_eq!(config.max_pods, 400);
         assert_eq!(config.allow_local_modules, true);
         assert_eq!(config.node_labels.len(), 2);
@@ -687,6 +1067,29 @@ mod test {
         assert_eq!(&config.insecure_registries.clone().unwrap()[0], "local");
         assert_eq!(&config.insecure_registries.unwrap()[1], "dev");
         assert_eq!(&config.plugins_dir.to_string_lossy(), "/some/plugins");
+        assert_eq!(config.node_status_interval, Duration::from_secs(30));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(60));
+        assert_eq!(config.pod_event_debounce, Duration::from_millis(250));
+        assert_eq!(config.pod_concurrency, 4);
+        assert_eq!(config.in_cluster, true);
+        assert_eq!(config.max_actors, Some(25));
+        assert_eq!(config.max_processes, Some(40));
+        assert_eq!(config.parcel_dir, Some(PathBuf::from("/stackable/parcels")));
+        assert_eq!(config.config_dir, Some(PathBuf::from("/stackable/config")));
+        assert_eq!(config.register_crds, true);
+        assert_eq!(config.node_interface.as_deref(), Some("eth1"));
+        assert_eq!(
+            config.module_cache_dir,
+            Some(PathBuf::from("/shared/modules"))
+        );
+        assert_eq!(config.prewarm_images, vec!["greet:v1", "uppercase:v1"]);
+        assert_eq!(config.pod_ip_pool.as_deref(), Some("10.20.0.0/24"));
+        assert_eq!(config.cgroups, true);
+        assert_eq!(
+            config.cgroup_parent,
+            Some(PathBuf::from("/sys/fs/cgroup/pods"))
+        );
+        assert_eq!(config.server_config.debug_token.as_deref(), Some("s3cr3t"));
     }
 
     #[test]
@@ -716,6 +1119,7 @@ mod test {
         assert_eq!(config.hostname, "fallback-hostname");
         assert_eq!(config.data_dir.to_string_lossy(), "/fallback/data/dir");
         assert_eq!(format!("{}", config.node_ip), "4.4.4.4");
+        assert!(!config.node_ip_configured);
         assert_eq!(config.node_labels.get("label"), Some(&("val".to_owned())));
         assert_eq!(
             &config.plugins_dir.to_string_lossy(),
@@ -752,6 +1156,23 @@ mod test {
             &config.plugins_dir.to_string_lossy(),
             "/fallback/plugins/dir"
         );
+        assert_eq!(config.node_status_interval, Duration::from_secs(10));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(30));
+        assert_eq!(config.pod_event_debounce, Duration::from_millis(100));
+        assert_eq!(config.pod_concurrency, 10);
+        assert_eq!(config.in_cluster, false);
+        assert_eq!(config.max_actors, None);
+        assert_eq!(config.max_processes, None);
+        assert_eq!(config.parcel_dir, None);
+        assert_eq!(config.config_dir, None);
+        assert_eq!(config.register_crds, false);
+        assert_eq!(config.node_interface, None);
+        assert_eq!(config.module_cache_dir, None);
+        assert!(config.prewarm_images.is_empty());
+        assert_eq!(config.pod_ip_pool, None);
+        assert_eq!(config.cgroups, false);
+        assert_eq!(config.cgroup_parent, None);
+        assert_eq!(config.server_config.debug_token, None);
     }
 
     #[test]
@@ -959,6 +1380,23 @@ mod test {
         );
     }
 
+    #[test]
+    fn too_short_node_status_interval_is_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "nodeStatusInterval": 0
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("node status interval"),
+            error.to_string()
+        );
+    }
+
     #[test]
     fn if_invalid_config_value_is_overridden_by_valid_one_it_is_not_an_error() {
         let config_builder_1 = builder_from_json_string(
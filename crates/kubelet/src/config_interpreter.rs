@@ -32,13 +32,31 @@ mod test {
             plugins_dir: std::path::PathBuf::from("/nope"),
             max_pods: 0,
             node_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            node_ip_configured: true,
             node_labels: std::collections::HashMap::new(),
             node_name: "nope".to_owned(),
+            node_status_interval: std::time::Duration::from_secs(10),
+            shutdown_timeout: std::time::Duration::from_secs(30),
+            pod_event_debounce: std::time::Duration::from_millis(100),
+            pod_concurrency: 10,
+            in_cluster: false,
+            max_actors: None,
+            max_processes: None,
+            parcel_dir: None,
+            config_dir: None,
+            register_crds: false,
+            node_interface: None,
+            module_cache_dir: None,
+            prewarm_images: Vec::new(),
+            pod_ip_pool: None,
+            cgroups: false,
+            cgroup_parent: None,
             server_config: crate::config::ServerConfig {
                 addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
                 port: 0,
                 cert_file: std::path::PathBuf::from("/nope"),
                 private_key_file: std::path::PathBuf::from("/nope"),
+                debug_token: None,
             },
         }
     }
@@ -4,7 +4,7 @@ use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
 
 use crate::container::ContainerMap;
 use crate::handle::StopHandler;
-use crate::log::{stream, HandleFactory, Sender};
+use crate::log::{seek_to_tail, stream, HandleFactory, Sender};
 
 /// Represents a handle to a running "container" (whatever that might be). This
 /// can be used on its own, however, it is generally better to use it as a part
@@ -31,6 +31,16 @@ impl<H: StopHandler, F> Handle<H, F> {
         }
     }
 
+    /// Returns the handle of the running instance.
+    pub fn handle(&self) -> &H {
+        &self.handle
+    }
+
+    /// Returns the factory for handles to the output of the running instance.
+    pub fn handle_factory(&self) -> &F {
+        &self.handle_factory
+    }
+
     /// Signal the running instance to stop. Use [`Handle::wait`] to wait for the process to
     /// exit. This uses the underlying [`StopHandler`] implementation passed to the constructor
     pub async fn stop(&mut self) -> anyhow::Result<()> {
@@ -45,7 +55,12 @@ impl<H: StopHandler, F> Handle<H, F> {
         F: HandleFactory<R>,
     {
         let mut handle = self.handle_factory.new_handle();
-        handle.seek(SeekFrom::Start(0)).await?;
+        match sender.tail() {
+            Some(n) => seek_to_tail(&mut handle, n).await?,
+            None => {
+                handle.seek(SeekFrom::Start(0)).await?;
+            }
+        }
         tokio::spawn(stream(handle, sender));
         Ok(())
     }
@@ -199,6 +199,15 @@ impl Container {
         self.0.resources.as_ref()
     }
 
+    /// Get the memory limit of the container in bytes, if one is set.
+    pub fn memory_limit(&self) -> anyhow::Result<Option<u64>> {
+        self.resources()
+            .and_then(|resources| resources.limits.as_ref())
+            .and_then(|limits| limits.get("memory"))
+            .map(crate::resources::parse_memory)
+            .transpose()
+    }
+
     /// Get security context of container.
     pub fn security_context(&self) -> Option<&k8s_openapi::api::core::v1::SecurityContext> {
         self.0.security_context.as_ref()
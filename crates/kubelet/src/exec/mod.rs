@@ -0,0 +1,328 @@
+//! `exec` contains the types handed to providers to run commands in their workloads, e.g. for
+//! `kubectl exec`.
+//!
+//! The API server connects to the kubelet with a websocket speaking one of the
+//! `channel.k8s.io` subprotocols, in which the first byte of every message names the stream the
+//! rest of the message belongs to. SPDY connections are not supported.
+use crate::provider::{NotImplementedError, Provider};
+use futures::{SinkExt, StreamExt};
+use log::{debug, error};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use warp::ws::{Message, WebSocket};
+
+/// The subprotocols the kubelet speaks on the exec websocket, preferred first. With
+/// `v4.channel.k8s.io` the result of the command is reported as a JSON status.
+const PROTOCOLS: &[&str] = &["v4.channel.k8s.io", "channel.k8s.io"];
+
+const STDIN_CHANNEL: u8 = 0;
+const STDOUT_CHANNEL: u8 = 1;
+const STDERR_CHANNEL: u8 = 2;
+const ERROR_CHANNEL: u8 = 3;
+const RESIZE_CHANNEL: u8 = 4;
+
+/// How many messages may be queued per stream before the sender has to wait.
+const STREAM_BUFFER: usize = 16;
+
+/// Client options of an exec request.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Options {
+    /// The command to run followed by its arguments.
+    pub command: Vec<String>,
+    /// Whether the client sends input to the command.
+    pub stdin: bool,
+    /// Whether the client wants the standard output of the command.
+    pub stdout: bool,
+    /// Whether the client wants the standard error of the command.
+    pub stderr: bool,
+    /// Whether the command is to be run in a terminal. All output of the command is sent as
+    /// standard output then.
+    pub tty: bool,
+}
+
+impl Options {
+    /// Parses the options from the query of an exec request, in which the API server repeats
+    /// `command` for every argument.
+    pub fn from_query(query: &str) -> anyhow::Result<Self> {
+        let mut options = Options::default();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            let flag = value == "1" || value == "true";
+            match key.as_ref() {
+                "command" => options.command.push(value.into_owned()),
+                "input" | "stdin" => options.stdin = flag,
+                "output" | "stdout" => options.stdout = flag,
+                "error" | "stderr" => options.stderr = flag,
+                "tty" => options.tty = flag,
+                _ => (),
+            }
+        }
+        if options.command.is_empty() {
+            anyhow::bail!("No command given");
+        }
+        Ok(options)
+    }
+}
+
+/// The size of the terminal of the client, which is sent whenever it changes.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct TerminalSize {
+    /// The number of columns.
+    #[serde(rename = "Width")]
+    pub width: u16,
+    /// The number of rows.
+    #[serde(rename = "Height")]
+    pub height: u16,
+}
+
+/// The client of an exec request has disconnected.
+#[derive(Debug, thiserror::Error)]
+#[error("The exec client has disconnected")]
+pub struct Disconnected;
+
+/// Sends the output of a command to the client of an exec request.
+#[derive(Clone)]
+pub struct Output {
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl Output {
+    /// Sends data the command wrote to its standard output, or to its terminal.
+    pub async fn stdout(&mut self, data: &[u8]) -> Result<(), Disconnected> {
+        self.send(STDOUT_CHANNEL, data).await
+    }
+
+    /// Sends data the command wrote to its standard error.
+    pub async fn stderr(&mut self, data: &[u8]) -> Result<(), Disconnected> {
+        self.send(STDERR_CHANNEL, data).await
+    }
+
+    async fn send(&mut self, channel: u8, data: &[u8]) -> Result<(), Disconnected> {
+        let mut message = Vec::with_capacity(data.len() + 1);
+        message.push(channel);
+        message.extend_from_slice(data);
+        self.sender.send(message).await.map_err(|_| Disconnected)
+    }
+}
+
+/// An exec request, consisting of the command to run and the streams connecting it to the
+/// client.
+pub struct Session {
+    options: Options,
+    stdin: Option<mpsc::Receiver<Vec<u8>>>,
+    resize: Option<mpsc::Receiver<TerminalSize>>,
+    output: Output,
+}
+
+impl Session {
+    /// The options of the request, including the command to run.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Takes the input the client sends to the command, which ends once the client
+    /// disconnects. `None` if the client sends no input or it was taken already.
+    pub fn take_stdin(&mut self) -> Option<mpsc::Receiver<Vec<u8>>> {
+        self.stdin.take()
+    }
+
+    /// Takes the changes of the size of the terminal of the client. `None` if the command does
+    /// not run in a terminal or they were taken already.
+    pub fn take_resize(&mut self) -> Option<mpsc::Receiver<TerminalSize>> {
+        self.resize.take()
+    }
+
+    /// Returns a sender for the output of the command.
+    pub fn output(&self) -> Output {
+        self.output.clone()
+    }
+}
+
+/// Picks the subprotocol to speak from the ones the client offers in its
+/// `Sec-WebSocket-Protocol` header. Clients which offer none get `channel.k8s.io`.
+pub(crate) fn negotiate_protocol(offered: Option<&str>) -> Option<&'static str> {
+    let offered: Vec<&str> = match offered {
+        Some(offered) => offered.split(',').map(str::trim).collect(),
+        None => return Some(PROTOCOLS[PROTOCOLS.len() - 1]),
+    };
+    PROTOCOLS
+        .iter()
+        .find(|protocol| offered.contains(protocol))
+        .copied()
+}
+
+/// Runs the exec request on the websocket: input and terminal sizes from the client are passed
+/// to the provider, the output of the command and finally its result are sent back.
+pub(crate) async fn serve<T: 'static + Provider + Send + Sync>(
+    websocket: WebSocket,
+    protocol: &'static str,
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    container: String,
+    options: Options,
+) {
+    let (mut websocket_sender, mut websocket_receiver) = websocket.split();
+    let (mut stdin_sender, stdin) = mpsc::channel(STREAM_BUFFER);
+    let (mut resize_sender, resize) = mpsc::channel(STREAM_BUFFER);
+    let (output_sender, mut output) = mpsc::channel::<Vec<u8>>(STREAM_BUFFER);
+    let (finished, mut finished_receiver) = oneshot::channel::<()>();
+
+    let session = Session {
+        stdin: if options.stdin { Some(stdin) } else { None },
+        resize: if options.tty { Some(resize) } else { None },
+        output: Output {
+            sender: output_sender.clone(),
+        },
+        options,
+    };
+
+    // Input which the provider does not read is dropped
+    tokio::spawn(async move {
+        loop {
+            let message = tokio::select! {
+                message = websocket_receiver.next() => message,
+                _ = &mut finished_receiver => break,
+            };
+            let message = match message {
+                Some(Ok(message)) if !message.is_close() => message,
+                _ => break,
+            };
+            match message.as_bytes().split_first() {
+                Some((&STDIN_CHANNEL, data)) => {
+                    let _ = stdin_sender.send(data.to_vec()).await;
+                }
+                Some((&RESIZE_CHANNEL, data)) => match serde_json::from_slice(data) {
+                    Ok(size) => {
+                        let _ = resize_sender.send(size).await;
+                    }
+                    Err(e) => debug!("Ignoring invalid terminal size: {}", e),
+                },
+                _ => (),
+            }
+        }
+    });
+    let writer = tokio::spawn(async move {
+        while let Some(message) = output.recv().await {
+            if websocket_sender
+                .send(Message::binary(message))
+                .await
+                .is_err()
+            {
+                debug!("Exec client disconnected");
+                break;
+            }
+        }
+        let _ = websocket_sender.close().await;
+    });
+
+    let result = provider.exec(namespace, pod, container, session).await;
+    if let Err(e) = &result {
+        error!("Error executing command: {}", e);
+    }
+    let mut output = Output {
+        sender: output_sender,
+    };
+    if let Some(status) = status_message(protocol, &result) {
+        let _ = output.send(ERROR_CHANNEL, &status).await;
+    }
+    // The writer finishes once all output was sent
+    drop(output);
+    let _ = writer.await;
+    let _ = finished.send(());
+}
+
+/// Returns the message reporting the result of the command on the error channel, if the
+/// protocol reports it at all.
+fn status_message(protocol: &str, result: &anyhow::Result<i32>) -> Option<Vec<u8>> {
+    let message = match result {
+        Ok(0) => None,
+        Ok(code) => Some(format!(
+            "command terminated with non-zero exit code: {}",
+            code
+        )),
+        Err(e) if e.is::<NotImplementedError>() => {
+            Some("Exec not implemented in provider.".to_owned())
+        }
+        Err(e) => Some(e.to_string()),
+    };
+    if protocol != PROTOCOLS[0] {
+        return message.map(String::into_bytes);
+    }
+    let status = match (result, message) {
+        (_, None) => serde_json::json!({ "metadata": {}, "status": "Success" }),
+        (Ok(code), Some(message)) => serde_json::json!({
+            "metadata": {},
+            "status": "Failure",
+            "message": message,
+            "reason": "NonZeroExitCode",
+            "details": {
+                "causes": [{ "reason": "ExitCode", "message": code.to_string() }]
+            }
+        }),
+        (Err(_), Some(message)) => serde_json::json!({
+            "metadata": {},
+            "status": "Failure",
+            "message": message,
+        }),
+    };
+    Some(status.to_string().into_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_options_from_query() {
+        let options =
+            Options::from_query("command=ls&command=-l&command=%2Fopt&input=1&output=1&tty=true")
+                .unwrap();
+        assert_eq!(
+            options,
+            Options {
+                command: vec!["ls".to_owned(), "-l".to_owned(), "/opt".to_owned()],
+                stdin: true,
+                stdout: true,
+                stderr: false,
+                tty: true,
+            }
+        );
+        assert!(Options::from_query("output=1").is_err());
+    }
+
+    #[test]
+    fn test_negotiate_protocol() {
+        assert_eq!(
+            negotiate_protocol(Some("channel.k8s.io, v4.channel.k8s.io")),
+            Some("v4.channel.k8s.io")
+        );
+        assert_eq!(negotiate_protocol(None), Some("channel.k8s.io"));
+        assert_eq!(negotiate_protocol(Some("v5.channel.k8s.io")), None);
+    }
+
+    #[test]
+    fn test_status_message() {
+        let status = |result| {
+            let message = status_message(PROTOCOLS[0], &result).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&message).unwrap()
+        };
+        assert_eq!(status(Ok(0))["status"], "Success");
+        let failure = status(Ok(2));
+        assert_eq!(failure["reason"], "NonZeroExitCode");
+        assert_eq!(failure["details"]["causes"][0]["message"], "2");
+        assert_eq!(
+            status(Err(anyhow::anyhow!("Pod is not running")))["message"],
+            "Pod is not running"
+        );
+
+        assert!(status_message("channel.k8s.io", &Ok(0)).is_none());
+        assert_eq!(
+            status_message(
+                "channel.k8s.io",
+                &Err(anyhow::anyhow!("Pod is not running"))
+            ),
+            Some(b"Pod is not running".to_vec())
+        );
+    }
+}
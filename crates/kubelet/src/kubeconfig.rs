@@ -2,9 +2,50 @@ use std::env;
 use std::path::PathBuf;
 
 use dirs::home_dir;
+use kube::config::KubeConfigOptions;
+use kube::Config;
+use log::{info, warn};
+
+use crate::config::Config as KubeletConfig;
 
 pub const KUBECONFIG: &str = "KUBECONFIG";
 
+/// Loads the configuration to reach the API server. If the kubelet is configured to run in a
+/// cluster, the service account of its pod is used, otherwise the kubeconfig file.
+///
+/// Unlike [`bootstrap`](crate::bootstrap), this does not request a serving certificate for
+/// the kubelet.
+pub async fn load(config: &KubeletConfig) -> anyhow::Result<Config> {
+    if let Some(kubeconfig) = in_cluster(config) {
+        return Ok(kubeconfig);
+    }
+    Config::from_kubeconfig(&KubeConfigOptions::default())
+        .await
+        .map_err(|e| anyhow::anyhow!("Unable to load kubeconfig: {}", e))
+}
+
+/// Loads the configuration of the service account mounted into the pod the kubelet runs in,
+/// if the kubelet is configured to do so. Returns `None` if it is not, or if the kubelet does
+/// not run in a cluster, so that the kubeconfig file is used instead.
+pub(crate) fn in_cluster(config: &KubeletConfig) -> Option<Config> {
+    if !config.in_cluster {
+        return None;
+    }
+    match Config::from_cluster_env() {
+        Ok(kubeconfig) => {
+            info!("Using the service account of the pod to reach the API server");
+            Some(kubeconfig)
+        }
+        Err(e) => {
+            warn!(
+                "Unable to load the in-cluster config, falling back to the kubeconfig file: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
 /// Search the kubeconfig file
 pub(crate) fn exists() -> bool {
     path().unwrap_or_default().exists()
@@ -1,9 +1,10 @@
 ///! This library contains code for running a kubelet. Use this to create a new
 ///! Kubelet with a specific handler (called a `Provider`)
+use crate::backoff::{BackoffStrategy, ExponentialBackoffStrategy};
 use crate::config::Config;
 use crate::node;
 use crate::plugin_watcher::PluginRegistry;
-use crate::pod::Queue;
+use crate::pod::{Pod, PodKey, Queue};
 use crate::provider::Provider;
 use crate::webserver::start as start_webserver;
 
@@ -13,9 +14,19 @@ use k8s_openapi::api::core::v1::Pod as KubePod;
 use kube::{api::ListParams, Api};
 use kube_runtime::watcher;
 use log::{debug, error, info, warn};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal::ctrl_c;
+use tokio::sync::Mutex;
+
+/// How many times handing a pod event to the queue is attempted before it is dropped.
+const MAX_ENQUEUE_ATTEMPTS: u32 = 5;
+/// Initial wait between attempts to enqueue a pod event, doubled on every retry.
+const ENQUEUE_RETRY_BASE: Duration = Duration::from_millis(500);
+/// Maximum wait between attempts to enqueue a pod event.
+const ENQUEUE_RETRY_CAP: Duration = Duration::from_secs(8);
 
 /// A Kubelet server backed by a given `Provider`.
 ///
@@ -70,15 +81,26 @@ impl<P: 'static + Provider + Sync + Send> Kubelet<P> {
 
         let registrar = plugin_registrar.run().fuse().boxed();
 
+        // Set once the node has been created and the pod informer completed its first sync.
+        let ready = Arc::new(AtomicBool::new(false));
+
         // Start the webserver
-        let webserver = start_webserver(self.provider.clone(), &self.config.server_config)
-            .fuse()
-            .boxed();
+        let webserver = start_webserver(
+            self.provider.clone(),
+            &self.config.server_config,
+            Arc::clone(&ready),
+        )
+        .fuse()
+        .boxed();
 
         // Start updating the node lease and status periodically
-        let node_updater = start_node_updater(client.clone(), self.config.node_name.clone())
-            .fuse()
-            .boxed();
+        let node_updater = start_node_updater(
+            client.clone(),
+            self.config.node_name.clone(),
+            self.config.node_status_interval,
+        )
+        .fuse()
+        .boxed();
 
         // If any of these tasks fail, we can initiate graceful shutdown.
         let services = Box::pin(async {
@@ -104,17 +126,25 @@ impl<P: 'static + Provider + Sync + Send> Kubelet<P> {
             Arc::clone(&signal),
             client.clone(),
             self.config.node_name.clone(),
+            self.config.shutdown_timeout,
         )
         .fuse()
         .boxed();
 
         // Create a queue that locks on events per pod
-        let queue = Queue::new(self.provider.clone(), client.clone());
+        let queue = Queue::new(
+            self.provider.clone(),
+            client.clone(),
+            self.config.pod_event_debounce,
+            self.config.pod_concurrency as usize,
+        );
         let pod_informer = start_pod_informer::<P>(
+            self.provider.clone(),
             client.clone(),
             self.config.node_name.clone(),
             queue,
             Arc::clone(&signal),
+            Arc::clone(&ready),
         )
         .fuse()
         .boxed();
@@ -162,12 +192,19 @@ async fn start_signal_task(signal: Arc<AtomicBool>) -> anyhow::Result<()> {
 }
 
 /// Listens for updates to pods on this node and forwards them to queue.
+///
+/// Sets `ready` once the first list of pods has been synced to the queue. The provider is asked to
+/// reconcile its workloads with the pods after every resync.
 async fn start_pod_informer<P: 'static + Provider + Sync + Send>(
+    provider: Arc<P>,
     client: kube::Client,
     node_name: String,
-    mut queue: Queue<P>,
+    queue: Queue<P>,
     signal: Arc<AtomicBool>,
+    ready: Arc<AtomicBool>,
 ) {
+    let queue = Arc::new(Mutex::new(queue));
+    let pending_retries = PendingRetries::default();
     let node_selector = format!("spec.nodeName={}", node_name);
     let params = ListParams {
         field_selector: Some(node_selector),
@@ -187,16 +224,23 @@ async fn start_pod_informer<P: 'static + Provider + Sync + Send>(
                 }
                 if let kube_runtime::watcher::Event::Restarted(pods) = event {
                     info!("Got a pod watch restart. Resyncing queue...");
+                    let current_pods: Vec<Pod> = pods.iter().cloned().map(Pod::from).collect();
                     // If we got a restart, we need to requeue an applied event for all pods
-                    match queue.resync(pods).await {
-                        Ok(()) => info!("Finished resync of pods"),
+                    let resync = queue.lock().await.resync(pods).await;
+                    match resync {
+                        Ok(()) => {
+                            info!("Finished resync of pods");
+                            if !ready.swap(true, Ordering::Relaxed) {
+                                info!("Initial sync of pods completed, kubelet is ready");
+                            }
+                        }
                         Err(e) => warn!("Error resyncing pods: {}", e),
                     };
+                    if let Err(e) = provider.reconcile(&current_pods).await {
+                        warn!("Error reconciling provider with pods: {}", e);
+                    }
                 } else {
-                    match queue.enqueue(event).await {
-                        Ok(()) => debug!("Enqueued event for processing"),
-                        Err(e) => warn!("Error enqueuing pod event: {}", e),
-                    };
+                    enqueue_with_retry(&queue, &pending_retries, event).await;
                 }
             }
             Ok(None) => break,
@@ -205,9 +249,116 @@ async fn start_pod_informer<P: 'static + Provider + Sync + Send>(
     }
 }
 
+/// The pods whose events are retried in the background, with the number of the retried event, so
+/// that a retry can tell that a newer event of its pod superseded it.
+#[derive(Clone, Default)]
+struct PendingRetries {
+    next: Arc<AtomicU64>,
+    pods: Arc<std::sync::Mutex<HashMap<PodKey, u64>>>,
+}
+
+impl PendingRetries {
+    /// Registers a retry of an event of the pod, superseding any earlier one, and returns its
+    /// number.
+    fn start(&self, key: &PodKey) -> u64 {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        self.pods.lock().unwrap().insert(key.clone(), id);
+        id
+    }
+
+    /// Gives up the retries of the events of the pod, as a newer event arrived.
+    fn supersede(&self, key: &PodKey) {
+        self.pods.lock().unwrap().remove(key);
+    }
+
+    fn is_current(&self, key: &PodKey, id: u64) -> bool {
+        self.pods.lock().unwrap().get(key) == Some(&id)
+    }
+
+    fn finish(&self, key: &PodKey, id: u64) {
+        let mut pods = self.pods.lock().unwrap();
+        if pods.get(key) == Some(&id) {
+            pods.remove(key);
+        }
+    }
+}
+
+/// Hands a pod event to the queue. Failures, e.g. because of a transient error in the provider or
+/// while talking to the API server, are retried in the background with exponential backoff up to
+/// `MAX_ENQUEUE_ATTEMPTS` times before the event is dropped, so that the events of other pods are
+/// not held up meanwhile. A retry is given up as soon as a newer event of the same pod arrives, so
+/// that an outdated event is never handed to the queue after a newer one.
+async fn enqueue_with_retry<P: 'static + Provider + Sync + Send>(
+    queue: &Arc<Mutex<Queue<P>>>,
+    pending_retries: &PendingRetries,
+    event: kube_runtime::watcher::Event<KubePod>,
+) {
+    let key = match &event {
+        watcher::Event::Applied(pod) | watcher::Event::Deleted(pod) => PodKey::from(pod),
+        // Restarts are handled by resyncing the queue instead
+        watcher::Event::Restarted(_) => return,
+    };
+    pending_retries.supersede(&key);
+    let enqueued = queue.lock().await.enqueue(event.clone()).await;
+    let mut error = match enqueued {
+        Ok(()) => {
+            debug!("Enqueued event for processing");
+            return;
+        }
+        Err(e) => e,
+    };
+
+    let id = pending_retries.start(&key);
+    let queue = Arc::clone(queue);
+    let pending_retries = pending_retries.clone();
+    tokio::spawn(async move {
+        let mut backoff = ExponentialBackoffStrategy::new(ENQUEUE_RETRY_BASE, ENQUEUE_RETRY_CAP);
+        for attempt in 1..MAX_ENQUEUE_ATTEMPTS {
+            let delay = backoff.next_duration();
+            warn!(
+                "Error enqueuing event of pod {} (attempt {}/{}), retrying in {:?}: {}",
+                key.name(),
+                attempt,
+                MAX_ENQUEUE_ATTEMPTS,
+                delay,
+                error
+            );
+            tokio::time::delay_for(delay).await;
+            // Checked while holding the queue, so that no newer event can be enqueued in between
+            let mut queue = queue.lock().await;
+            if !pending_retries.is_current(&key, id) {
+                debug!(
+                    "Dropping event of pod {}, a newer event superseded it",
+                    key.name()
+                );
+                return;
+            }
+            match queue.enqueue(event.clone()).await {
+                Ok(()) => {
+                    debug!("Enqueued event for processing");
+                    pending_retries.finish(&key, id);
+                    return;
+                }
+                Err(e) => error = e,
+            }
+        }
+        error!(
+            "Error enqueuing event of pod {}, giving up after {} attempts: {}",
+            key.name(),
+            MAX_ENQUEUE_ATTEMPTS,
+            error
+        );
+        pending_retries.finish(&key, id);
+    });
+}
+
 /// Periodically renew node lease and status. Exits if signal is caught.
-async fn start_node_updater(client: kube::Client, node_name: String) -> anyhow::Result<()> {
-    let sleep_interval = std::time::Duration::from_secs(10);
+async fn start_node_updater(
+    client: kube::Client,
+    node_name: String,
+    sleep_interval: std::time::Duration,
+) -> anyhow::Result<()> {
+    info!("Updating node status every {:?}", sleep_interval);
     loop {
         node::update(&client, &node_name).await;
         tokio::time::delay_for(sleep_interval).await;
@@ -215,16 +366,35 @@ async fn start_node_updater(client: kube::Client, node_name: String) -> anyhow::
 }
 
 /// Checks for shutdown signal and cleans up resources gracefully.
+///
+/// Draining the node is given up after `shutdown_timeout`, so that an unreachable API server
+/// cannot keep the kubelet from exiting. A second SIGINT while draining exits immediately.
 async fn start_signal_handler(
     signal: Arc<AtomicBool>,
     client: kube::Client,
     node_name: String,
+    shutdown_timeout: std::time::Duration,
 ) -> anyhow::Result<()> {
     let duration = std::time::Duration::from_millis(100);
     loop {
         if signal.load(Ordering::Relaxed) {
             info!("Signal caught.");
-            node::drain(&client, &node_name).await?;
+            tokio::select! {
+                res = tokio::time::timeout(shutdown_timeout, node::drain(&client, &node_name)) => {
+                    match res {
+                        Ok(drained) => drained?,
+                        Err(_) => warn!(
+                            "Node drain did not complete within {:?}, shutting down anyway.",
+                            shutdown_timeout
+                        ),
+                    }
+                },
+                res = ctrl_c() => {
+                    res?;
+                    warn!("Caught second keyboard interrupt, exiting immediately.");
+                    std::process::exit(130);
+                }
+            };
             break Ok(());
         }
         tokio::time::delay_for(duration).await;
@@ -63,6 +63,7 @@ mod config_interpreter;
 mod kubelet;
 
 pub(crate) mod kubeconfig;
+pub(crate) mod metrics;
 pub(crate) mod webserver;
 pub(crate) mod plugin_registration_api {
     pub(crate) mod v1 {
@@ -81,11 +82,13 @@ pub(crate) mod plugin_watcher;
 pub mod backoff;
 pub mod config;
 pub mod container;
+pub mod exec;
 pub mod handle;
 pub mod log;
 pub mod node;
 pub mod pod;
 pub mod provider;
+pub mod resources;
 pub mod secret;
 pub mod state;
 pub mod store;
@@ -93,6 +96,7 @@ pub mod volume;
 
 pub use self::kubelet::Kubelet;
 pub use bootstrapping::bootstrap;
+pub use kubeconfig::load as load_kubeconfig;
 
 #[cfg(feature = "derive")]
 #[allow(unused_imports)]
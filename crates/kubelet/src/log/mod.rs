@@ -1,8 +1,26 @@
 //! `log` contains convenient wrappers around fetching logs from the Kubernetes API.
 use anyhow::bail;
+use futures::{SinkExt, StreamExt};
+use hyper::body::HttpBody;
 use log::{debug, error};
 use serde::Deserialize;
-use tokio::io::{AsyncBufReadExt, AsyncRead};
+use std::io::SeekFrom;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use warp::ws::{Message, WebSocket};
+
+/// Size of the chunks a log is read backwards in to find the start of its last lines.
+const TAIL_CHUNK_SIZE: u64 = 8 * 1024;
+
+/// Size of the chunks a followed log is read in.
+const FOLLOW_CHUNK_SIZE: usize = 8 * 1024;
+
+/// How often a followed log is checked for new data.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The subprotocols the kubelet speaks on log websockets, preferred first. With
+/// `base64.binary.k8s.io` the log is sent base64 encoded in text messages.
+const WEBSOCKET_PROTOCOLS: &[&str] = &["binary.k8s.io", "base64.binary.k8s.io"];
 
 /// Possible errors sending log data.
 #[derive(Debug)]
@@ -71,18 +89,68 @@ impl Sender {
     }
 
     /// Async send some data to a client.
-    pub async fn send(&mut self, data: String) -> Result<(), SendError> {
-        let b: hyper::body::Bytes = data.into();
-        self.sender.send_data(b).await.map_err(|e| {
-            if e.is_closed() {
-                debug!("channel closed.");
-                SendError::ChannelClosed
-            } else {
-                error!("channel error: {}", e);
-                SendError::Abnormal(anyhow::Error::new(e))
+    ///
+    /// This waits until the client is ready to receive more data, so a slow client only slows
+    /// down the task streaming to it.
+    pub async fn send<B: Into<hyper::body::Bytes>>(&mut self, data: B) -> Result<(), SendError> {
+        self.sender
+            .send_data(data.into())
+            .await
+            .map_err(Sender::send_error)
+    }
+
+    /// Waits until the client is ready to receive more data. Fails with
+    /// [`SendError::ChannelClosed`] if the client has disconnected in the meantime.
+    pub async fn ready(&mut self) -> Result<(), SendError> {
+        let sender = &mut self.sender;
+        futures::future::poll_fn(|cx| sender.poll_ready(cx))
+            .await
+            .map_err(Sender::send_error)
+    }
+
+    fn send_error(e: hyper::Error) -> SendError {
+        if e.is_closed() {
+            debug!("channel closed.");
+            SendError::ChannelClosed
+        } else {
+            error!("channel error: {}", e);
+            SendError::Abnormal(anyhow::Error::new(e))
+        }
+    }
+}
+
+/// Positions `handle` at the start of its last `n` lines, so that tailing a large log does not
+/// require reading all of it.
+pub async fn seek_to_tail<R: AsyncRead + AsyncSeek + std::marker::Unpin>(
+    handle: &mut R,
+    n: usize,
+) -> std::io::Result<()> {
+    let end = handle.seek(SeekFrom::End(0)).await?;
+    if n == 0 {
+        return Ok(());
+    }
+    let mut buf = vec![0; TAIL_CHUNK_SIZE as usize];
+    let mut position = end;
+    let mut line_starts = 0;
+    while position > 0 {
+        let chunk = TAIL_CHUNK_SIZE.min(position);
+        position -= chunk;
+        handle.seek(SeekFrom::Start(position)).await?;
+        handle.read_exact(&mut buf[..chunk as usize]).await?;
+        for (i, byte) in buf[..chunk as usize].iter().enumerate().rev() {
+            let next = position + i as u64 + 1;
+            // A newline at the very end terminates the last line instead of starting a new one
+            if *byte == b'\n' && next != end {
+                line_starts += 1;
+                if line_starts == n {
+                    handle.seek(SeekFrom::Start(next)).await?;
+                    return Ok(());
+                }
             }
-        })
+        }
     }
+    handle.seek(SeekFrom::Start(0)).await?;
+    Ok(())
 }
 
 /// Stream last `n` lines.
@@ -135,7 +203,28 @@ async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
     Ok(())
 }
 
+/// Stream everything appended to the log as soon as it is written, including incomplete
+/// lines, until the client disconnects.
+async fn follow<R: AsyncRead + std::marker::Unpin>(
+    reader: &mut tokio::io::BufReader<R>,
+    sender: &mut Sender,
+) -> Result<(), SendError> {
+    let mut buf = vec![0; FOLLOW_CHUNK_SIZE];
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read > 0 {
+            sender.send(buf[..read].to_vec()).await?;
+        } else {
+            tokio::time::delay_for(FOLLOW_INTERVAL).await;
+            // Without new data nothing is sent, so a disconnected client would go unnoticed
+            sender.ready().await?;
+        }
+    }
+}
+
 /// Future that streams logs from provided `AsyncRead` to provided `Sender`.
+///
+/// If the client follows the log, the future only finishes once the client has disconnected.
 pub async fn stream<R: AsyncRead + std::marker::Unpin>(
     handle: R,
     mut sender: Sender,
@@ -143,33 +232,76 @@ pub async fn stream<R: AsyncRead + std::marker::Unpin>(
     let buf = tokio::io::BufReader::new(handle);
     let mut lines = buf.lines();
 
-    if let Some(n) = sender.tail() {
-        match tail(&mut lines, &mut sender, n).await {
-            Ok(_) => (),
-            Err(SendError::ChannelClosed) => return Ok(()),
-            Err(SendError::Abnormal(e)) => bail!(e),
-        }
-    } else {
-        match stream_to_end(&mut lines, &mut sender).await {
-            Ok(_) => (),
-            Err(SendError::ChannelClosed) => return Ok(()),
-            Err(SendError::Abnormal(e)) => bail!(e),
-        }
+    let result = match (sender.tail(), sender.follow()) {
+        (Some(n), _) => tail(&mut lines, &mut sender, n).await,
+        // The whole log is followed chunk by chunk, so that its last line is not split if it
+        // is still being written
+        (None, true) => Ok(()),
+        (None, false) => stream_to_end(&mut lines, &mut sender).await,
+    };
+    let result = match result {
+        Ok(_) if sender.follow() => follow(&mut lines.into_inner(), &mut sender).await,
+        result => result,
+    };
+
+    match result {
+        Ok(_) | Err(SendError::ChannelClosed) => Ok(()),
+        Err(SendError::Abnormal(e)) => bail!(e),
     }
+}
 
-    if sender.follow() {
-        loop {
-            match stream_to_end(&mut lines, &mut sender).await {
-                Ok(_) => (),
-                Err(SendError::ChannelClosed) => return Ok(()),
-                Err(SendError::Abnormal(e)) => bail!(e),
-            }
+/// Picks the subprotocol to speak on a log websocket from the ones the client offers in its
+/// `Sec-WebSocket-Protocol` header. Clients which offer none get `binary.k8s.io`.
+pub(crate) fn negotiate_protocol(offered: Option<&str>) -> Option<&'static str> {
+    let offered: Vec<&str> = match offered {
+        Some(offered) => offered.split(',').map(str::trim).collect(),
+        None => return Some(WEBSOCKET_PROTOCOLS[0]),
+    };
+    WEBSOCKET_PROTOCOLS
+        .iter()
+        .find(|protocol| offered.contains(protocol))
+        .copied()
+}
 
-            tokio::time::delay_for(std::time::Duration::from_millis(500)).await;
+/// Sends the log streamed into `body` over the websocket until the log ends or the client
+/// disconnects. Dropping the body on disconnect stops the task streaming the log.
+pub(crate) async fn serve_websocket(
+    websocket: WebSocket,
+    protocol: &'static str,
+    mut body: hyper::Body,
+) {
+    let (mut websocket_sender, mut websocket_receiver) = websocket.split();
+    loop {
+        let data = tokio::select! {
+            data = body.data() => data,
+            message = websocket_receiver.next() => match message {
+                // Clients send nothing but control messages on log websockets
+                Some(Ok(message)) if !message.is_close() => continue,
+                _ => {
+                    debug!("Log client disconnected");
+                    break;
+                }
+            },
+        };
+        let data = match data {
+            Some(Ok(data)) => data,
+            Some(Err(e)) => {
+                error!("Error streaming log: {}", e);
+                break;
+            }
+            None => break,
+        };
+        let message = if protocol == WEBSOCKET_PROTOCOLS[1] {
+            Message::text(base64::encode(&data))
+        } else {
+            Message::binary(data.to_vec())
+        };
+        if websocket_sender.send(message).await.is_err() {
+            debug!("Log client disconnected");
+            break;
         }
     }
-
-    Ok(())
+    let _ = websocket_sender.close().await;
 }
 
 // TODO: Both providers make a handle containing a tempfile. If this is a common pattern,
@@ -180,3 +312,72 @@ pub trait HandleFactory<R>: Sync + Send {
     /// Create new log reader.
     fn new_handle(&self) -> R;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn tail_of(content: &str, n: usize) -> String {
+        let mut handle = std::io::Cursor::new(content.as_bytes().to_vec());
+        seek_to_tail(&mut handle, n).await.unwrap();
+        let mut rest = String::new();
+        handle.read_to_string(&mut rest).await.unwrap();
+        rest
+    }
+
+    #[tokio::test]
+    async fn test_seek_to_tail() {
+        assert_eq!(tail_of("a\nb\nc\n", 2).await, "b\nc\n");
+        assert_eq!(tail_of("a\nb\nc", 2).await, "b\nc");
+        assert_eq!(tail_of("a\nb\nc\n", 5).await, "a\nb\nc\n");
+        assert_eq!(tail_of("a\nb\nc\n", 0).await, "");
+        assert_eq!(tail_of("", 3).await, "");
+    }
+
+    #[tokio::test]
+    async fn test_seek_to_tail_across_chunks() {
+        let line = "x".repeat(TAIL_CHUNK_SIZE as usize);
+        let content = format!("{}\n{}\n{}\n", line, line, line);
+
+        assert_eq!(tail_of(&content, 2).await, format!("{}\n{}\n", line, line));
+    }
+
+    #[tokio::test]
+    async fn test_follow_streams_appended_data_until_disconnect() {
+        use std::io::Write;
+
+        let mut log = tempfile::NamedTempFile::new().unwrap();
+        log.write_all(b"first line\n").unwrap();
+        let (body_sender, mut body) = hyper::Body::channel();
+        let sender = Sender::new(
+            body_sender,
+            Options {
+                tail: None,
+                follow: true,
+            },
+        );
+        let handle = tokio::fs::File::open(log.path()).await.unwrap();
+        let streaming = tokio::spawn(stream(handle, sender));
+
+        assert_eq!(&body.data().await.unwrap().unwrap()[..], b"first line\n");
+        // Incomplete lines are sent as soon as they are written
+        log.write_all(b"second").unwrap();
+        assert_eq!(&body.data().await.unwrap().unwrap()[..], b"second");
+
+        drop(body);
+        let result = tokio::time::timeout(Duration::from_secs(5), streaming)
+            .await
+            .expect("streaming did not stop after the client disconnected");
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_negotiate_protocol() {
+        assert_eq!(negotiate_protocol(None), Some("binary.k8s.io"));
+        assert_eq!(
+            negotiate_protocol(Some("base64.binary.k8s.io")),
+            Some("base64.binary.k8s.io")
+        );
+        assert_eq!(negotiate_protocol(Some("v4.channel.k8s.io")), None);
+    }
+}
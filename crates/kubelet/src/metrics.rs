@@ -0,0 +1,218 @@
+//! Metrics about the pods handled by the kubelet, rendered in the Prometheus text format.
+//!
+//! The metrics are recorded by the state machine runner, so they are available for every
+//! provider without any provider specific code.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the buckets of the state duration histogram.
+const STATE_DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0,
+];
+
+lazy_static::lazy_static! {
+    static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Default)]
+struct Metrics {
+    pods_registered: AtomicU64,
+    pods_running: AtomicU64,
+    pods_failed: AtomicU64,
+    pods_terminated: AtomicU64,
+    state_durations: Mutex<BTreeMap<String, Histogram>>,
+}
+
+struct Histogram {
+    // Cumulative counts, one per entry in STATE_DURATION_BUCKETS
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: vec![0; STATE_DURATION_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(STATE_DURATION_BUCKETS) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Counts a pod as registered when it is created and as terminated when it is dropped, so that a
+/// pod is counted as terminated as well if its state machine is abandoned because it was deleted.
+pub(crate) struct PodRegistration(());
+
+impl PodRegistration {
+    /// Records that the kubelet started handling a pod.
+    pub(crate) fn new() -> Self {
+        METRICS.pods_registered.fetch_add(1, Ordering::Relaxed);
+        PodRegistration(())
+    }
+}
+
+impl Drop for PodRegistration {
+    /// Records that the kubelet stopped handling the pod.
+    fn drop(&mut self) {
+        METRICS.pods_terminated.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Records that a pod entered the running phase.
+pub(crate) fn pod_running() {
+    METRICS.pods_running.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records that a pod entered the failed phase.
+pub(crate) fn pod_failed() {
+    METRICS.pods_failed.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records the time a pod spent in the given state.
+pub(crate) fn observe_state_duration(state: &str, duration: Duration) {
+    let mut durations = METRICS.state_durations.lock().unwrap();
+    durations
+        .entry(state.to_owned())
+        .or_insert_with(Histogram::new)
+        .observe(duration.as_secs_f64());
+}
+
+/// Returns the name of a state for use as a metric label, which is the type name without any
+/// fields, as those would make the number of label values unbounded.
+pub(crate) fn state_name(state_debug: &str) -> &str {
+    state_debug
+        .split(|c: char| c == ' ' || c == '{' || c == '(')
+        .next()
+        .unwrap_or(state_debug)
+}
+
+/// Renders all metrics in the Prometheus text exposition format.
+pub(crate) fn gather() -> String {
+    let mut output = String::new();
+    let counters = [
+        (
+            "krustlet_pods_registered_total",
+            "Number of pods the kubelet started handling",
+            &METRICS.pods_registered,
+        ),
+        (
+            "krustlet_pods_running_total",
+            "Number of times a pod entered the running phase",
+            &METRICS.pods_running,
+        ),
+        (
+            "krustlet_pods_failed_total",
+            "Number of times a pod entered the failed phase",
+            &METRICS.pods_failed,
+        ),
+        (
+            "krustlet_pods_terminated_total",
+            "Number of pods the kubelet stopped handling",
+            &METRICS.pods_terminated,
+        ),
+    ];
+    for (name, help, value) in counters.iter() {
+        // Writing to a String cannot fail
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} counter", name);
+        let _ = writeln!(output, "{} {}", name, value.load(Ordering::Relaxed));
+    }
+
+    let name = "krustlet_pod_state_duration_seconds";
+    let _ = writeln!(output, "# HELP {} Time pods spent in each state", name);
+    let _ = writeln!(output, "# TYPE {} histogram", name);
+    let durations = METRICS.state_durations.lock().unwrap();
+    for (state, histogram) in durations.iter() {
+        for (count, bound) in histogram.buckets.iter().zip(STATE_DURATION_BUCKETS) {
+            let _ = writeln!(
+                output,
+                "{}_bucket{{state=\"{}\",le=\"{}\"}} {}",
+                name, state, bound, count
+            );
+        }
+        let _ = writeln!(
+            output,
+            "{}_bucket{{state=\"{}\",le=\"+Inf\"}} {}",
+            name, state, histogram.count
+        );
+        let _ = writeln!(
+            output,
+            "{}_sum{{state=\"{}\"}} {}",
+            name, state, histogram.sum
+        );
+        let _ = writeln!(
+            output,
+            "{}_count{{state=\"{}\"}} {}",
+            name, state, histogram.count
+        );
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_state_name_strips_fields() {
+        assert_eq!(state_name("Running"), "Running");
+        assert_eq!(state_name("Failed { message: \"boom\" }"), "Failed");
+        assert_eq!(state_name("Error(\"boom\")"), "Error");
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::new();
+        histogram.observe(0.2);
+        histogram.observe(20.0);
+        assert_eq!(histogram.buckets[STATE_DURATION_BUCKETS.len() - 1], 2);
+        assert_eq!(histogram.buckets[0], 0);
+        // 0.5 is the first bound that contains 0.2
+        assert_eq!(histogram.buckets[4], 1);
+        assert_eq!(histogram.count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_deleted_pod_is_counted_as_terminated() {
+        let deleted = tokio::sync::Notify::new();
+        deleted.notify();
+        let terminated = METRICS.pods_terminated.load(Ordering::Relaxed);
+
+        // Like the pod queue, which abandons the state machine of a pod once it is deleted
+        let registration = PodRegistration::new();
+        tokio::select! {
+            _ = futures::future::pending::<()>() => (),
+            _ = deleted.notified() => (),
+        }
+        drop(registration);
+
+        // Other tests may count further pods meanwhile
+        assert!(METRICS.pods_terminated.load(Ordering::Relaxed) > terminated);
+    }
+
+    #[test]
+    fn test_gather_renders_state_durations() {
+        observe_state_duration("MetricsTestState", Duration::from_millis(2));
+        let output = gather();
+        assert!(output.contains("# TYPE krustlet_pods_registered_total counter"));
+        assert!(output.contains(
+            "krustlet_pod_state_duration_seconds_bucket{state=\"MetricsTestState\",le=\"0.005\"} 1"
+        ));
+        assert!(output
+            .contains("krustlet_pod_state_duration_seconds_count{state=\"MetricsTestState\"} 1"));
+    }
+}
@@ -10,6 +10,7 @@ use k8s_openapi::api::coordination::v1::Lease;
 use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
 use k8s_openapi::api::core::v1::Node as KubeNode;
 use k8s_openapi::api::core::v1::Pod as KubePod;
+use k8s_openapi::api::policy::v1beta1::Eviction;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use kube::api::{Api, ListParams, ObjectMeta, PatchParams, PostParams};
 use kube::error::ErrorResponse;
@@ -17,6 +18,7 @@ use kube::Error;
 use log::{debug, error, info, warn};
 use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 const KUBELET_VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -186,7 +188,19 @@ pub async fn drain(client: &kube::Client, node_name: &str) -> anyhow::Result<()>
     Ok(())
 }
 
-/// Fetches list of pods on this node and deletes them.
+/// How often an eviction which a PodDisruptionBudget does not allow yet is retried.
+const EVICTION_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait for an evicted pod to be deleted beyond its termination grace period.
+const EVICTION_DELETE_SLACK: Duration = Duration::from_secs(5);
+
+/// Fetches list of pods on this node and evicts them one after the other, the pods with the
+/// lowest priority first.
+///
+/// Pods are evicted through the eviction API, so that PodDisruptionBudgets are respected. If a
+/// budget does not allow the eviction of a pod within its termination grace period, the pod is
+/// deleted anyway. Every pod is waited for up to its grace period before the next one is
+/// evicted.
 pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
     let pod_client: Api<KubePod> = Api::all(client.clone());
     let node_selector = format!("spec.nodeName={}", node_name);
@@ -201,10 +215,13 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
     // The delete call may return a "pending" response, we must watch for the actual delete event.
     let mut stream = pod_client.watch(&lp, "0").await?.boxed();
 
+    let mut pods: Vec<Pod> = pods.into_iter().map(Pod::from).collect();
+    sort_by_eviction_order(&mut pods);
+
     info!("Evicting {} pods.", pods.len());
 
-    for pod in pods {
-        let pod = Pod::from(pod);
+    let total = pods.len();
+    for (index, pod) in pods.into_iter().enumerate() {
         if pod.is_daemonset() {
             info!("Skipping eviction of DaemonSet '{}'", pod.name());
             continue;
@@ -235,7 +252,14 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
             info!("Marked static pod as terminated.");
             continue;
         } else {
-            match evict_pod(&client, pod.name(), pod.namespace(), &mut stream).await {
+            info!(
+                "Evicting pod '{}' ({}/{}) with priority {}.",
+                pod.name(),
+                index + 1,
+                total,
+                pod.priority()
+            );
+            match evict_pod(&client, &pod, &mut stream).await {
                 Ok(_) => (),
                 Err(e) => {
                     // Absorb the error and attempt to delete other pods with best effort.
@@ -244,9 +268,16 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
             }
         }
     }
+    info!("Evicted all pods.");
     Ok(())
 }
 
+/// Sorts the pods so that the ones with the lowest priority are evicted first. Pods with the
+/// same priority keep their order.
+fn sort_by_eviction_order(pods: &mut Vec<Pod>) {
+    pods.sort_by_key(|pod| pod.priority());
+}
+
 type PodStream = std::pin::Pin<
     Box<
         dyn futures::Stream<Item = Result<kube::api::WatchEvent<KubePod>, kube::error::Error>>
@@ -254,35 +285,98 @@ type PodStream = std::pin::Pin<
     >,
 >;
 
-async fn evict_pod(
-    client: &kube::Client,
-    name: &str,
-    namespace: &str,
-    stream: &mut PodStream,
-) -> anyhow::Result<()> {
-    let ns_client: Api<KubePod> = Api::namespaced(client.clone(), namespace);
+async fn evict_pod(client: &kube::Client, pod: &Pod, stream: &mut PodStream) -> anyhow::Result<()> {
+    let name = pod.name();
+    let namespace = pod.namespace();
+    let grace_period = pod.termination_grace_period();
     info!("Evicting namespace '{}' pod '{}'", namespace, name);
-    let params = Default::default();
-    let response = ns_client.delete(name, &params).await?;
 
-    if response.is_left() {
-        // TODO Timeout?
-        info!("Waiting for pod '{}' eviction.", name);
+    let deadline = tokio::time::Instant::now() + grace_period;
+    loop {
+        match request_eviction(client, name, namespace).await {
+            Ok(()) => break,
+            Err(Error::Api(ErrorResponse { code: 404, .. })) => {
+                info!("Pod '{}' evicted.", name);
+                return Ok(());
+            }
+            // The eviction would violate a PodDisruptionBudget
+            Err(Error::Api(ErrorResponse { code: 429, .. }))
+                if tokio::time::Instant::now() + EVICTION_RETRY_INTERVAL < deadline =>
+            {
+                info!(
+                    "Eviction of pod '{}' is not allowed by its disruption budget yet, retrying in {:?}.",
+                    name, EVICTION_RETRY_INTERVAL
+                );
+                tokio::time::delay_for(EVICTION_RETRY_INTERVAL).await;
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to evict pod '{}', deleting it instead: {:?}",
+                    name, e
+                );
+                let ns_client: Api<KubePod> = Api::namespaced(client.clone(), namespace);
+                if ns_client
+                    .delete(name, &Default::default())
+                    .await?
+                    .is_right()
+                {
+                    info!("Pod '{}' evicted.", name);
+                    return Ok(());
+                }
+                break;
+            }
+        }
+    }
+
+    info!(
+        "Waiting up to {:?} for pod '{}' eviction.",
+        grace_period, name
+    );
+    let deleted = async {
         while let Some(event) = stream.try_next().await? {
             if let kube::api::WatchEvent::Deleted(s) = event {
                 let pod = Pod::from(s);
                 if name == pod.name() && namespace == pod.namespace() {
-                    info!("Pod '{}' evicted.", name);
                     break;
                 }
             }
         }
-    } else {
-        info!("Pod '{}' evicted.", name);
+        Ok::<(), Error>(())
+    };
+    match tokio::time::timeout(grace_period + EVICTION_DELETE_SLACK, deleted).await {
+        Ok(result) => {
+            result?;
+            info!("Pod '{}' evicted.", name);
+        }
+        Err(_) => warn!(
+            "Pod '{}' was not deleted within {:?}, continuing with the next pod.",
+            name, grace_period
+        ),
     }
     Ok(())
 }
 
+/// Asks the API server to evict the pod, which it only does if no PodDisruptionBudget is
+/// violated by it.
+async fn request_eviction(client: &kube::Client, name: &str, namespace: &str) -> Result<(), Error> {
+    let eviction = Eviction {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            namespace: Some(namespace.to_string()),
+            ..Default::default()
+        },
+        delete_options: None,
+    };
+    let request = http::Request::post(format!(
+        "/api/v1/namespaces/{}/pods/{}/eviction",
+        namespace, name
+    ))
+    .header(http::header::CONTENT_TYPE, "application/json")
+    .body(serde_json::to_vec(&eviction)?)?;
+    client.request_text(request).await?;
+    Ok(())
+}
+
 /// Update the timestamps on the Node object.
 ///
 /// This is how we report liveness to the upstream.
@@ -468,6 +562,8 @@ fn node_labels_definition(arch: &str, config: &Config, builder: &mut Builder) {
         "failure-domain.kubernetes.io/region",
         "failure-domain.kubernetes.io/zone",
         "kubernetes.io/instance-type",
+        "topology.kubernetes.io/region",
+        "topology.kubernetes.io/zone",
     ];
 
     // Attempt to append node labels from passed arguments.
@@ -536,6 +632,7 @@ pub struct Builder {
     port: i32,
     conditions: Vec<k8s_openapi::api::core::v1::NodeCondition>,
     addresses: Vec<k8s_openapi::api::core::v1::NodeAddress>,
+    images: Vec<k8s_openapi::api::core::v1::ContainerImage>,
 }
 
 impl Builder {
@@ -649,6 +746,12 @@ impl Builder {
             });
     }
 
+    /// Add an image which is present on the node, under all of its names.
+    pub fn add_image(&mut self, names: Vec<String>, size_bytes: Option<i64>) {
+        self.images
+            .push(k8s_openapi::api::core::v1::ContainerImage { names, size_bytes });
+    }
+
     /// Build node definition from builder.
     pub fn build(self) -> Node {
         let mut metadata: k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta =
@@ -677,6 +780,7 @@ impl Builder {
         });
         status.conditions = Some(self.conditions);
         status.addresses = Some(self.addresses);
+        status.images = Some(self.images);
 
         let kube_node = k8s_openapi::api::core::v1::Node {
             metadata,
@@ -705,6 +809,7 @@ impl Default for Builder {
             port: 10250,
             conditions: vec![],
             addresses: vec![],
+            images: vec![],
         }
     }
 }
@@ -739,10 +844,15 @@ mod test {
             "kubernetes.io/instance-type".to_owned(),
             "allowed".to_owned(),
         );
+        node_labels.insert(
+            "topology.kubernetes.io/zone".to_owned(),
+            "zone-a".to_owned(),
+        );
         node_labels.insert("beta.kubernetes.io/os".to_owned(), "managed".to_owned());
 
         let config = Config {
             node_ip: IpAddr::from(Ipv4Addr::LOCALHOST),
+            node_ip_configured: true,
             hostname: String::from("foo"),
             node_name: String::from("bar"),
             server_config: ServerConfig {
@@ -750,6 +860,7 @@ mod test {
                 port: 8080,
                 cert_file: PathBuf::new(),
                 private_key_file: PathBuf::new(),
+                debug_token: None,
             },
             bootstrap_file: "doesnt/matter".into(),
             allow_local_modules: false,
@@ -758,6 +869,22 @@ mod test {
             plugins_dir: PathBuf::new(),
             node_labels,
             max_pods: 110,
+            node_status_interval: std::time::Duration::from_secs(10),
+            shutdown_timeout: std::time::Duration::from_secs(30),
+            pod_event_debounce: std::time::Duration::from_millis(100),
+            pod_concurrency: 10,
+            in_cluster: false,
+            max_actors: None,
+            max_processes: None,
+            parcel_dir: None,
+            config_dir: None,
+            register_crds: false,
+            node_interface: None,
+            module_cache_dir: None,
+            prewarm_images: Vec::new(),
+            pod_ip_pool: None,
+            cgroups: false,
+            cgroup_parent: None,
         };
 
         let mut builder = Node::builder();
@@ -769,7 +896,36 @@ mod test {
         assert!(result.contains_key("kubelet.kubernetes.io/allowed-prefix"));
         assert!(!result.contains_key("not-allowed.kubernetes.io"));
         assert!(result.contains_key("kubernetes.io/instance-type"));
+        assert!(result
+            .get("topology.kubernetes.io/zone")
+            .unwrap()
+            .eq("zone-a"));
         assert!(!result.get("beta.kubernetes.io/os").unwrap().eq("managed"));
         assert!(result.get("beta.kubernetes.io/os").unwrap().eq("linux"));
     }
+
+    #[test]
+    fn test_pods_are_evicted_lowest_priority_first() {
+        let pod = |name: &str, priority: Option<i32>| {
+            let kube_pod: KubePod = serde_json::from_value(serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "metadata": { "name": name },
+                "spec": { "containers": [], "priority": priority },
+            }))
+            .unwrap();
+            Pod::from(kube_pod)
+        };
+        let mut pods = vec![
+            pod("critical", Some(2_000_000_000)),
+            pod("default", None),
+            pod("low", Some(-10)),
+            pod("zero", Some(0)),
+        ];
+
+        sort_by_eviction_order(&mut pods);
+
+        let names: Vec<&str> = pods.iter().map(|pod| pod.name()).collect();
+        assert_eq!(names, vec!["low", "default", "zero", "critical"]);
+    }
 }
@@ -1,3 +1,4 @@
+use crate::backoff::{BackoffStrategy, ExponentialBackoffStrategy};
 use crate::fs_watch::FileSystemWatcher;
 use crate::grpc_sock;
 use crate::plugin_registration_api::v1::{
@@ -6,7 +7,7 @@ use crate::plugin_registration_api::v1::{
 };
 
 use anyhow::Context;
-use log::{debug, error, trace, warn};
+use log::{debug, error, info, trace, warn};
 use notify::Event;
 use tokio::fs::{create_dir_all, read_dir};
 use tokio::stream::StreamExt;
@@ -90,10 +91,39 @@ impl PluginRegistry {
     }
 
     /// Starts the plugin registrar and runs all automatic plugin discovery and registration loops.
-    /// This will block indefinitely or until the underlying watch stops. To stop watching the
-    /// filesystem, simply stop polling the future. Underneath the hood this is creating a watch on
-    /// a directory using OS native APIs and then watching that event stream
+    /// This will block indefinitely. To stop watching the filesystem, simply stop polling the
+    /// future. Underneath the hood this is creating a watch on a directory using OS native APIs
+    /// and then watching that event stream
+    ///
+    /// If the watch cannot be set up or the underlying event stream ends, this is logged and the
+    /// watch is set up again after a backoff, so that plugin discovery doesn't stop for the rest
+    /// of the process lifetime because of a transient error
     pub async fn run(&self) -> anyhow::Result<()> {
+        let mut backoff = ExponentialBackoffStrategy::default();
+        loop {
+            match self.start_watch().await {
+                Ok(event_stream) => {
+                    backoff.reset();
+                    self.handle_events(event_stream).await;
+                    warn!(
+                        "Watch on plugin directory {} ended unexpectedly",
+                        self.plugin_dir.display()
+                    );
+                }
+                Err(e) => error!(
+                    "Unable to watch plugin directory {}: {:?}",
+                    self.plugin_dir.display(),
+                    e
+                ),
+            }
+            let delay = backoff.next_duration();
+            info!("Restarting plugin directory watch in {:?}", delay);
+            tokio::time::delay_for(delay).await;
+        }
+    }
+
+    /// Registers all plugins already present in the plugin directory and returns a watch on it
+    async fn start_watch(&self) -> anyhow::Result<FileSystemWatcher> {
         // Create plugin directory if it doesn't exist
         create_dir_all(&self.plugin_dir).await?;
 
@@ -104,15 +134,28 @@ impl PluginRegistry {
             .collect::<Result<Vec<PathBuf>, _>>()
             .await?;
 
-        // Manually assemble an event and call handle_event to reconfigure sockets properly on restart
-        self.handle_create(Event {
-            paths: dir_entries,
-            ..Default::default()
-        })
-        .await?;
+        // Manually assemble an event per file and call handle_event to reconfigure sockets
+        // properly on restart. A single broken or vanished socket must not keep the others from
+        // being registered
+        for path in dir_entries {
+            if let Err(e) = self
+                .handle_create(Event {
+                    paths: vec![path],
+                    ..Default::default()
+                })
+                .await
+            {
+                error!(
+                    "An error occurred while processing an existing plugin: {:?}",
+                    e
+                );
+            }
+        }
 
-        let mut event_stream = FileSystemWatcher::new(&self.plugin_dir)?;
+        FileSystemWatcher::new(&self.plugin_dir)
+    }
 
+    async fn handle_events(&self, mut event_stream: FileSystemWatcher) {
         while let Some(res) = event_stream.next().await {
             match res {
                 Ok(event) if event.kind.is_create() => {
@@ -126,7 +169,6 @@ impl PluginRegistry {
                 Err(e) => error!("An error occurred while watching the plugin directory. Will continue to retry: {:?}", e),
             }
         }
-        Ok(())
     }
 
     async fn handle_create(&self, event: Event) -> anyhow::Result<()> {
@@ -621,6 +663,38 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn test_registrar_survives_stale_socket() {
+        let (tempdir, registrar) = setup();
+
+        // A socket file whose plugin is gone, so connecting to it fails
+        tokio::fs::write(tempdir.path().join("stale.sock"), b"")
+            .await
+            .expect("Unable to create stale socket file");
+
+        start_registrar(registrar.clone()).await;
+
+        let (tx, rx) = mpsc::channel(1);
+
+        let plugin = TestCSIPlugin {
+            name: "foo".to_string(),
+            registration_response: Mutex::new(tx),
+        };
+
+        setup_server(plugin, tempdir.path().join("foo.sock"));
+
+        let registration_status = get_registration_response(rx).await;
+
+        assert!(
+            registration_status.plugin_registered,
+            "Registrar should still register plugins after failing on a stale socket"
+        );
+        assert!(
+            registrar.get_endpoint("foo").await.is_some(),
+            "Plugin should be registered in memory"
+        );
+    }
+
     #[tokio::test]
     async fn test_unregister() {
         let (tempdir, registrar) = setup();
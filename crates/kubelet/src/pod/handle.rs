@@ -4,7 +4,9 @@ use log::{debug, error, info};
 use tokio::io::{AsyncRead, AsyncSeek};
 use tokio::sync::RwLock;
 
-use crate::container::{ContainerMapByName, HandleMap as ContainerHandleMap};
+use crate::container::{
+    ContainerMapByName, Handle as ContainerHandle, HandleMap as ContainerHandleMap,
+};
 use crate::handle::StopHandler;
 use crate::log::{HandleFactory, Sender};
 use crate::pod::Pod;
@@ -66,6 +68,83 @@ impl<H: StopHandler, F> Handle<H, F> {
         handle.output(sender).await
     }
 
+    /// Returns the pod this handle manages.
+    pub fn pod(&self) -> &Pod {
+        &self.pod
+    }
+
+    /// Applies `f` to the handle of the running instance of the specified container.
+    pub async fn map_container_handle<T>(
+        &self,
+        container_name: &str,
+        f: impl FnOnce(&H) -> T,
+    ) -> anyhow::Result<T> {
+        let mut handles = self.container_handles.write().await;
+        let handle = self.get_container(&mut handles, container_name)?;
+        Ok(f(handle.handle()))
+    }
+
+    /// Applies `f` to the log handle factory of the specified container.
+    pub async fn map_container_handle_factory<T>(
+        &self,
+        container_name: &str,
+        f: impl FnOnce(&F) -> T,
+    ) -> anyhow::Result<T> {
+        let mut handles = self.container_handles.write().await;
+        let handle = self.get_container(&mut handles, container_name)?;
+        Ok(f(handle.handle_factory()))
+    }
+
+    /// Stops the specified container, e.g. to start it again with
+    /// [`Handle::replace_container`].
+    pub async fn stop_container(&self, container_name: &str) -> anyhow::Result<()> {
+        let mut handles = self.container_handles.write().await;
+        self.get_container(&mut handles, container_name)?
+            .stop()
+            .await
+    }
+
+    /// Replaces the handle of the specified container with the handle of a new instance. The
+    /// previous instance has to be stopped before.
+    pub async fn replace_container(
+        &self,
+        container_name: &str,
+        handle: ContainerHandle<H, F>,
+    ) -> anyhow::Result<()> {
+        let mut handles = self.container_handles.write().await;
+        *self.get_container(&mut handles, container_name)? = handle;
+        Ok(())
+    }
+
+    fn get_container<'a>(
+        &self,
+        handles: &'a mut ContainerHandleMap<H, F>,
+        container_name: &str,
+    ) -> anyhow::Result<&'a mut ContainerHandle<H, F>> {
+        Ok(handles
+            .get_mut_by_name(container_name.to_owned())
+            .ok_or_else(|| ProviderError::ContainerNotFound {
+                pod_name: self.pod.name().to_owned(),
+                container_name: container_name.to_owned(),
+            })?)
+    }
+
+    /// Applies `f` to the handle of the running instance of every container in the pod.
+    pub async fn map_container_handles<T>(&self, f: impl Fn(&H) -> T) -> Vec<T> {
+        let handles = self.container_handles.read().await;
+        handles.values().map(|handle| f(handle.handle())).collect()
+    }
+
+    /// Applies `f` to the log handle factory of every container in the pod, e.g. to find out
+    /// which log files are still in use.
+    pub async fn map_handle_factories<T>(&self, f: impl Fn(&F) -> T) -> Vec<T> {
+        let handles = self.container_handles.read().await;
+        handles
+            .values()
+            .map(|handle| f(handle.handle_factory()))
+            .collect()
+    }
+
     /// Signal the pod and all its running containers to stop and wait for them
     /// to complete. As of right now, there is not a way to do this in wasmtime,
     /// so this does nothing
@@ -8,8 +8,8 @@ pub use handle::{key_from_pod, pod_key, Handle};
 pub(crate) use queue::Queue;
 pub(crate) use status::initialize_pod_container_statuses;
 pub use status::{
-    make_registered_status, make_status, make_status_with_containers, patch_status, Phase, Status,
-    StatusMessage,
+    make_registered_status, make_status, make_status_with_containers, patch_status, reject_pod,
+    Phase, Status, StatusMessage,
 };
 
 use crate::container::{Container, ContainerKey};
@@ -19,6 +19,13 @@ use k8s_openapi::api::core::v1::{
 };
 use kube::api::Meta;
 
+/// The node labels which carry the architecture of a node.
+const ARCHITECTURE_LABELS: &[&str] = &["kubernetes.io/arch", "beta.kubernetes.io/arch"];
+
+/// The grace period Kubernetes applies if a pod does not specify
+/// `terminationGracePeriodSeconds`.
+pub const DEFAULT_TERMINATION_GRACE_PERIOD_SECONDS: i64 = 30;
+
 /// A Kubernetes Pod
 ///
 /// This is a new type around the k8s_openapi Pod definition
@@ -54,6 +61,52 @@ impl Pod {
         self.kube_pod.spec.as_ref()?.node_selector.as_ref()
     }
 
+    /// Returns whether the node selector and the required node affinity of the pod allow it to
+    /// run on a node of the given architecture. Pods which do not restrict the architecture run
+    /// on any node.
+    pub fn runs_on_architecture(&self, arch: &str) -> bool {
+        let selected = self.node_selector().map_or(true, |selector| {
+            ARCHITECTURE_LABELS
+                .iter()
+                .filter_map(|label| selector.get(*label))
+                .all(|value| value == arch)
+        });
+        let terms = self
+            .kube_pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.affinity.as_ref())
+            .and_then(|affinity| affinity.node_affinity.as_ref())
+            .and_then(|affinity| {
+                affinity
+                    .required_during_scheduling_ignored_during_execution
+                    .as_ref()
+            })
+            .map(|selector| selector.node_selector_terms.as_slice())
+            .unwrap_or_default();
+        // The terms are ORed, the expressions of a term ANDed
+        let affine = terms.is_empty()
+            || terms.iter().any(|term| {
+                term.match_expressions
+                    .iter()
+                    .flatten()
+                    .filter(|requirement| ARCHITECTURE_LABELS.contains(&requirement.key.as_str()))
+                    .all(|requirement| {
+                        let listed = requirement
+                            .values
+                            .as_ref()
+                            .map_or(false, |values| values.iter().any(|value| value == arch));
+                        match requirement.operator.as_str() {
+                            "In" => listed,
+                            "NotIn" => !listed,
+                            "DoesNotExist" => false,
+                            _ => true,
+                        }
+                    })
+            });
+        selected && affine
+    }
+
     /// Get the pod's service account name
     pub fn service_account_name(&self) -> Option<&str> {
         let spec = self.kube_pod.spec.as_ref()?;
@@ -66,6 +119,28 @@ impl Pod {
         spec.volumes.as_ref()
     }
 
+    /// Get the pod's priority, which the API server resolves from its priority class.
+    /// Pods without one have the priority 0.
+    pub fn priority(&self) -> i32 {
+        self.kube_pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.priority)
+            .unwrap_or(0)
+    }
+
+    /// Get how long the pod's containers are given to terminate gracefully,
+    /// [`DEFAULT_TERMINATION_GRACE_PERIOD_SECONDS`] unless the pod specifies otherwise.
+    pub fn termination_grace_period(&self) -> std::time::Duration {
+        let seconds = self
+            .kube_pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.termination_grace_period_seconds)
+            .unwrap_or(DEFAULT_TERMINATION_GRACE_PERIOD_SECONDS);
+        std::time::Duration::from_secs(seconds.max(0) as u64)
+    }
+
     /// Get the pod's host ip
     pub fn host_ip(&self) -> Option<&str> {
         let status = self.kube_pod.status.as_ref()?;
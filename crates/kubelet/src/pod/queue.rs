@@ -1,6 +1,8 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::Notify;
+use std::time::Duration;
+use tokio::sync::{Notify, Semaphore};
+use tokio::time::Instant;
 
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
@@ -9,9 +11,10 @@ use kube::Client as KubeClient;
 use kube_runtime::watcher::Event;
 use log::{debug, error, warn};
 
+use crate::metrics::PodRegistration;
 use crate::pod::{Pod, PodKey};
 use crate::provider::Provider;
-use crate::state::{run_to_completion, AsyncDrop};
+use crate::state::{run_to_completion, run_with_permit, AsyncDrop};
 use tokio::sync::RwLock;
 
 /// A per-pod queue that takes incoming Kubernetes events and broadcasts them to the correct queue
@@ -21,18 +24,37 @@ use tokio::sync::RwLock;
 /// the main kubelet process). This queue will only handle the latest update. So if a modify comes
 /// in while it is still handling a create and then another modify comes in after, only the second
 /// modify will be handled, which is ok given that each event contains the whole pod object
+///
+/// Updates for a pod that arrive within the debounce window of each other are coalesced, so the
+/// provider is only notified once per burst of updates.
+///
+/// At most `concurrency` pods advance their state machines at the same time, the others wait for
+/// a permit before entering their initial state. Pods which are only waiting, e.g. for a backoff
+/// to elapse, give up their permit until they advance again. Waiting for a permit does not hold
+/// up the per-pod event handling: updates are still written to the pod manifest behind the
+/// per-pod lock, so a pod sees its latest version once it gets a permit, and a deletion ends the
+/// wait immediately. Pods that are being terminated never wait for a permit.
 pub(crate) struct Queue<P> {
     provider: Arc<P>,
     handlers: HashMap<PodKey, tokio::sync::mpsc::Sender<Event<KubePod>>>,
     client: KubeClient,
+    debounce: Duration,
+    concurrency: Arc<Semaphore>,
 }
 
 impl<P: 'static + Provider + Sync + Send> Queue<P> {
-    pub fn new(provider: Arc<P>, client: KubeClient) -> Self {
+    pub fn new(
+        provider: Arc<P>,
+        client: KubeClient,
+        debounce: Duration,
+        concurrency: usize,
+    ) -> Self {
         Queue {
             provider,
             handlers: HashMap::new(),
             client,
+            debounce,
+            concurrency: Arc::new(Semaphore::new(concurrency)),
         }
     }
 
@@ -55,20 +77,46 @@ impl<P: 'static + Provider + Sync + Send> Queue<P> {
                     Arc::clone(&pod_manifest),
                     pod_state,
                     Arc::clone(&pod_deleted),
+                    Arc::clone(&self.concurrency),
                 ));
                 pod_manifest
             }
             _ => return Err(anyhow::anyhow!("Got non-apply event when starting pod")),
         };
 
+        let debounce = self.debounce;
         tokio::spawn(async move {
-            while let Some(event) = receiver.recv().await {
+            // An event that was received while debouncing, but could not be coalesced
+            let mut pending: Option<Event<KubePod>> = None;
+            loop {
+                let event = match pending.take() {
+                    Some(event) => event,
+                    None => match receiver.recv().await {
+                        Some(event) => event,
+                        None => break,
+                    },
+                };
                 // Watch errors are handled before an event ever gets here, so it should always have
                 // a pod
                 debug!("received event: {:?}", event);
                 match event {
-                    Event::Applied(pod) => {
-                        debug!("yay");
+                    Event::Applied(mut pod) => {
+                        // Only keep the latest of all updates arriving within the debounce
+                        // window, each of them contains the whole pod object anyway
+                        let deadline = Instant::now() + debounce;
+                        while debounce > Duration::from_millis(0) {
+                            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                                Ok(Some(Event::Applied(newer))) => {
+                                    debug!("Coalescing update for pod {}", pod.name());
+                                    pod = newer;
+                                }
+                                Ok(Some(other)) => {
+                                    pending = Some(other);
+                                    break;
+                                }
+                                Ok(None) | Err(_) => break,
+                            }
+                        }
                         let pod = Pod::from(pod);
                         debug!("Pod {} applied.", pod.name());
                         if let Some(_timestamp) = pod.deletion_timestamp() {
@@ -174,6 +222,7 @@ async fn start_task<P: Provider>(
     pod: Arc<RwLock<Pod>>,
     mut pod_state: P::PodState,
     pod_deleted: Arc<Notify>,
+    concurrency: Arc<Semaphore>,
 ) {
     let state: P::InitialState = Default::default();
     let (namespace, name) = {
@@ -181,14 +230,25 @@ async fn start_task<P: Provider>(
         (p.namespace().to_string(), p.name().to_string())
     };
 
+    // The pod counts as terminated once its state machine completes or, if the pod is deleted
+    // and its state machine is abandoned, once its terminated state completes
+    let registration = PodRegistration::new();
+    let run = run_with_permit(
+        &task_client,
+        state,
+        &mut pod_state,
+        Arc::clone(&pod),
+        Some(concurrency),
+    );
     tokio::select! {
-        _ = run_to_completion(&task_client, state, &mut pod_state, Arc::clone(&pod)) => (),
+        _ = run => (),
         _ = pod_deleted.notified() => {
             let state: P::TerminatedState = Default::default();
             debug!("Pod {} terminated. Jumping to state {:?}.", name, state);
             run_to_completion(&task_client, state, &mut pod_state, Arc::clone(&pod)).await;
         }
     }
+    drop(registration);
 
     debug!("Pod {} waiting for deregistration.", name);
     pod_deleted.notified().await;
@@ -35,6 +35,25 @@ pub async fn patch_status(api: &Api<KubePod>, name: &str, patch: serde_json::Val
     }
 }
 
+/// Marks the pod as failed before its state machine is started, for pods which the provider
+/// refuses to run at all, e.g. in [`crate::provider::Provider::initialize_pod_state`].
+pub async fn reject_pod(client: &kube::Client, pod: &Pod, reason: &str, message: &str) {
+    let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    let patch = serde_json::json!(
+        {
+            "metadata": {
+                "resourceVersion": "",
+            },
+            "status": {
+                "phase": Phase::Failed,
+                "reason": reason,
+                "message": message,
+            }
+        }
+    );
+    patch_status(&api, pod.name(), patch).await;
+}
+
 const MAX_STATUS_INIT_RETRIES: usize = 5;
 
 /// Initializes Pod container status array and wait for Pod reflection to update.
@@ -1,8 +1,9 @@
 //! Traits and types needed to create backend providers for a Kubelet
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use async_trait::async_trait;
-use k8s_openapi::api::core::v1::{ConfigMap, EnvVarSource, Secret};
+use k8s_openapi::api::core::v1::{ConfigMap, EnvVarSource, Node, ResourceFieldSelector, Secret};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
 use kube::api::Api;
 use log::{error, info};
 use thiserror::Error;
@@ -11,7 +12,8 @@ use tokio::sync::Notify;
 use crate::container::Container;
 use crate::log::Sender;
 use crate::node::Builder;
-use crate::pod::Pod;
+use crate::pod::{Pod, PodKey};
+use crate::resources;
 use crate::state::{AsyncDrop, State};
 use std::sync::Arc;
 
@@ -83,6 +85,13 @@ pub trait Provider: Sized {
     // TODO: Is there a way to provide a default implementation of this if Self::PodState: Default?
     async fn initialize_pod_state(&self, pod: &Pod, pod_changed: Arc<Notify>) -> anyhow::Result<Self::PodState>;
 
+    /// Hook which is called every time the pod watch (re)starts, including its first start,
+    /// once the queue was resynced with the given pods scheduled to the node. Providers can use
+    /// it to clean up workloads of pods which vanished in the meantime.
+    async fn reconcile(&self, _pods: &[Pod]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     /// Given a Pod, get back the logs for the associated workload.
     async fn logs(
         &self,
@@ -92,11 +101,37 @@ pub trait Provider: Sized {
         sender: Sender,
     ) -> anyhow::Result<()>;
 
-    /// Execute a given command on a workload and then return the result.
+    /// Runs the command of the session in a workload of the pod, connected to the streams of
+    /// the session, and returns its exit code once it has finished.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn exec(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _container: String,
+        _session: crate::exec::Session,
+    ) -> anyhow::Result<i32> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Returns the capabilities the workloads of a pod were granted, to help diagnosing why a
+    /// workload cannot reach something.
     ///
     /// The default implementation of this returns a message that this feature is
     /// not available. Override this only when there is an implementation.
-    async fn exec(&self, _pod: Pod, _command: String) -> anyhow::Result<Vec<String>> {
+    async fn capabilities(&self, _namespace: String, _pod: String) -> anyhow::Result<Vec<String>> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Forcibly evicts a pod, to recover from a pod which got stuck: its workloads are stopped
+    /// and its resources released right away, without waiting for its state machine, and the
+    /// pod is reported as terminated. Evicting a pod which is gone already must succeed.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn evict_pod(&self, _pod_key: &PodKey) -> anyhow::Result<()> {
         Err(NotImplementedError.into())
     }
 
@@ -126,7 +161,8 @@ pub trait Provider: Sized {
                     on_missing_env_value(
                         env_var.value_from,
                         client,
-                        pod.namespace(),
+                        pod,
+                        container,
                         &field_map(pod),
                     )
                     .await
@@ -161,7 +197,7 @@ pub async fn env_vars(
         let value = match env_var.value {
             Some(v) => v,
             None => {
-                on_missing_env_value(env_var.value_from, client, pod.namespace(), &field_map(pod))
+                on_missing_env_value(env_var.value_from, client, pod, container, &field_map(pod))
                     .await
             }
         };
@@ -177,9 +213,11 @@ pub async fn env_vars(
 async fn on_missing_env_value(
     env_var_source: Option<EnvVarSource>,
     client: &kube::Client,
-    ns: &str,
+    pod: &Pod,
+    container: &Container,
     fields: &HashMap<String, String>,
 ) -> String {
+    let ns = pod.namespace();
     let env_src = match env_var_source {
         Some(env_src) => env_src,
         None => return String::new(),
@@ -237,11 +275,94 @@ async fn on_missing_env_value(
     if let Some(cfkey) = env_src.field_ref.as_ref() {
         return fields.get(&cfkey.field_path).cloned().unwrap_or_default();
     }
-    // Reource Fields (Not implementable just yet... need more of a model.)
+    // Downward API (Resource Fields)
+    if let Some(selector) = env_src.resource_field_ref.as_ref() {
+        return resource_field(selector, client, pod, container).await;
+    }
 
     String::new()
 }
 
+/// Resolves a resource field of the Downward API for the container, or for the container of the
+/// pod the selector names.
+///
+/// The allocatable resources of the node are only fetched if a limit is not set, as the limit
+/// defaults to them then.
+async fn resource_field(
+    selector: &ResourceFieldSelector,
+    client: &kube::Client,
+    pod: &Pod,
+    container: &Container,
+) -> String {
+    let container = match selector.container_name.as_deref().filter(|n| !n.is_empty()) {
+        Some(name) => match pod.all_containers().into_iter().find(|c| c.name() == name) {
+            Some(container) => container,
+            None => {
+                error!(
+                    "Container {} of resource field {} not found in pod {}",
+                    name,
+                    selector.resource,
+                    pod.name()
+                );
+                return String::new();
+            }
+        },
+        None => container.clone(),
+    };
+    let limit_missing = selector
+        .resource
+        .strip_prefix("limits.")
+        .map_or(false, |name| {
+            container
+                .resources()
+                .and_then(|r| r.limits.as_ref())
+                .map_or(true, |limits| !limits.contains_key(name))
+        });
+    let allocatable = if limit_missing {
+        node_allocatable(client, pod).await
+    } else {
+        BTreeMap::new()
+    };
+    match resources::resource_field_value(
+        &selector.resource,
+        selector.divisor.as_ref(),
+        container.resources(),
+        &allocatable,
+    ) {
+        Ok(value) => value,
+        Err(e) => {
+            error!(
+                "Error resolving resource field {}: {}",
+                selector.resource, e
+            );
+            String::new()
+        }
+    }
+}
+
+/// Fetches the allocatable resources of the node the pod is scheduled on.
+async fn node_allocatable(client: &kube::Client, pod: &Pod) -> BTreeMap<String, Quantity> {
+    let node_name = match pod
+        .as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.node_name.as_deref())
+    {
+        Some(node_name) => node_name,
+        None => return BTreeMap::new(),
+    };
+    match Api::<Node>::all(client.clone()).get(node_name).await {
+        Ok(node) => node
+            .status
+            .and_then(|status| status.allocatable)
+            .unwrap_or_default(),
+        Err(e) => {
+            error!("Error fetching node {}: {}", node_name, e);
+            BTreeMap::new()
+        }
+    }
+}
+
 /// Build the map of allowable field_ref values.
 ///
 /// The Downward API only supports a small selection of fields. This
@@ -0,0 +1,242 @@
+//! `resources` contains helpers for working with Kubernetes resource quantities, e.g. the
+//! requests and limits of a container.
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::core::v1::ResourceRequirements;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+/// Binary suffixes and the power of 1024 they multiply the number in front of them by.
+const BINARY_SUFFIXES: &[(&str, i32)] = &[
+    ("Ki", 1),
+    ("Mi", 2),
+    ("Gi", 3),
+    ("Ti", 4),
+    ("Pi", 5),
+    ("Ei", 6),
+];
+
+/// Decimal suffixes and the power of 1000 they multiply the number in front of them by.
+const DECIMAL_SUFFIXES: &[(&str, i32)] = &[
+    ("m", -1),
+    ("k", 1),
+    ("M", 2),
+    ("G", 3),
+    ("T", 4),
+    ("P", 5),
+    ("E", 6),
+];
+
+/// The resources of a container the Downward API exposes through `resourceFieldRef`.
+const DOWNWARD_API_RESOURCES: &[&str] = &["cpu", "memory", "ephemeral-storage"];
+
+/// Converts a quantity to a number, e.g. `128Mi` to 134217728 or `500m` to 0.5.
+///
+/// Quantities may be plain numbers, numbers in exponent notation (`1e3`) or numbers with a binary
+/// (`Ki`, `Mi`, ...) or decimal (`m`, `k`, `M`, ...) suffix.
+pub fn parse_quantity(quantity: &Quantity) -> anyhow::Result<f64> {
+    let value = quantity.0.trim();
+    let invalid = || anyhow::anyhow!("Invalid quantity: '{}'", value);
+
+    let (number, factor) = if let Some((suffix, power)) = BINARY_SUFFIXES
+        .iter()
+        .find(|(suffix, _)| value.ends_with(suffix))
+    {
+        let number = &value[..value.len() - suffix.len()];
+        (number, 1024f64.powi(*power))
+    } else if let Some((suffix, power)) = DECIMAL_SUFFIXES
+        .iter()
+        .find(|(suffix, _)| value.ends_with(suffix))
+    {
+        let number = &value[..value.len() - suffix.len()];
+        (number, 1000f64.powi(*power))
+    } else {
+        // Plain numbers as well as the exponent notation are understood by the float parser
+        (value, 1.0)
+    };
+
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(invalid());
+    }
+    Ok(number * factor)
+}
+
+/// Converts a memory quantity to bytes, rounding fractions of a byte up.
+pub fn parse_memory(quantity: &Quantity) -> anyhow::Result<u64> {
+    Ok(parse_quantity(quantity)?.ceil() as u64)
+}
+
+/// Returns the value of a resource of a container as the Downward API exposes it through
+/// `resourceFieldRef`, e.g. for `limits.memory` or `requests.cpu`.
+///
+/// The value is divided by the divisor, which defaults to 1, and rounded up, so a CPU limit of
+/// `500m` is exposed as `1` unless the divisor is `1m`. As in Kubernetes, a limit the container
+/// does not set defaults to the allocatable amount of the node and a request it does not set is
+/// 0.
+pub fn resource_field_value(
+    resource: &str,
+    divisor: Option<&Quantity>,
+    requirements: Option<&ResourceRequirements>,
+    allocatable: &BTreeMap<String, Quantity>,
+) -> anyhow::Result<String> {
+    let unsupported = || anyhow::anyhow!("Unsupported resource: '{}'", resource);
+    let mut parts = resource.splitn(2, '.');
+    let (kind, name) = match (parts.next(), parts.next()) {
+        (Some(kind), Some(name)) if DOWNWARD_API_RESOURCES.contains(&name) => (kind, name),
+        _ => return Err(unsupported()),
+    };
+
+    let value = match kind {
+        "limits" => {
+            let limit = requirements
+                .and_then(|requirements| requirements.limits.as_ref())
+                .and_then(|limits| limits.get(name))
+                .or_else(|| allocatable.get(name))
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "No {} limit is set and the allocatable {} of the node is unknown",
+                        name,
+                        name
+                    )
+                })?;
+            parse_quantity(limit)?
+        }
+        "requests" => requirements
+            .and_then(|requirements| requirements.requests.as_ref())
+            .and_then(|requests| requests.get(name))
+            .map(parse_quantity)
+            .transpose()?
+            .unwrap_or(0.0),
+        _ => return Err(unsupported()),
+    };
+    let divisor = match divisor {
+        Some(divisor) => parse_quantity(divisor)?,
+        None => 1.0,
+    };
+    if divisor == 0.0 {
+        return Err(anyhow::anyhow!("The divisor of {} must not be 0", resource));
+    }
+    Ok(round_up(value / divisor).to_string())
+}
+
+/// Rounds up, but ignores the error dividing decimal quantities like `0.7` by `1m` introduces,
+/// which would otherwise round `700.0000000000001` up to 701.
+fn round_up(value: f64) -> u64 {
+    let rounded = value.round();
+    if (value - rounded).abs() <= 1e-9 * rounded.max(1.0) {
+        rounded as u64
+    } else {
+        value.ceil() as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn quantity(value: &str) -> Quantity {
+        Quantity(value.to_owned())
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory(&quantity("1024")).unwrap(), 1024);
+        assert_eq!(parse_memory(&quantity("64Mi")).unwrap(), 64 * 1024 * 1024);
+        assert_eq!(parse_memory(&quantity("1Gi")).unwrap(), 1024 * 1024 * 1024);
+        assert_eq!(parse_memory(&quantity("128M")).unwrap(), 128_000_000);
+        assert_eq!(parse_memory(&quantity("1.5k")).unwrap(), 1500);
+        assert_eq!(parse_memory(&quantity("1e3")).unwrap(), 1000);
+    }
+
+    #[test]
+    fn test_parse_fractional_quantity() {
+        assert_eq!(parse_quantity(&quantity("500m")).unwrap(), 0.5);
+        assert_eq!(parse_quantity(&quantity("2")).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn test_parse_invalid_quantity() {
+        assert!(parse_quantity(&quantity("")).is_err());
+        assert!(parse_quantity(&quantity("Mi")).is_err());
+        assert!(parse_quantity(&quantity("12 apples")).is_err());
+        assert!(parse_quantity(&quantity("-1Gi")).is_err());
+    }
+
+    fn requirements(limits: &[(&str, &str)], requests: &[(&str, &str)]) -> ResourceRequirements {
+        let quantities = |values: &[(&str, &str)]| {
+            values
+                .iter()
+                .map(|(name, value)| (name.to_string(), quantity(value)))
+                .collect()
+        };
+        ResourceRequirements {
+            limits: Some(quantities(limits)),
+            requests: Some(quantities(requests)),
+        }
+    }
+
+    #[test]
+    fn test_resource_field_memory() {
+        let requirements = requirements(&[("memory", "64Mi")], &[("memory", "1500k")]);
+        let value = |resource: &str, divisor: Option<&str>| {
+            resource_field_value(
+                resource,
+                divisor.map(quantity).as_ref(),
+                Some(&requirements),
+                &BTreeMap::new(),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(value("limits.memory", None), "67108864");
+        assert_eq!(value("limits.memory", Some("1Mi")), "64");
+        assert_eq!(value("limits.memory", Some("1Gi")), "1");
+        assert_eq!(value("requests.memory", Some("1k")), "1500");
+        assert_eq!(value("requests.memory", Some("1M")), "2");
+    }
+
+    #[test]
+    fn test_resource_field_cpu() {
+        let requirements = requirements(&[("cpu", "700m")], &[("cpu", "2")]);
+        let value = |resource: &str, divisor: Option<&str>| {
+            resource_field_value(
+                resource,
+                divisor.map(quantity).as_ref(),
+                Some(&requirements),
+                &BTreeMap::new(),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(value("limits.cpu", None), "1");
+        assert_eq!(value("limits.cpu", Some("1m")), "700");
+        assert_eq!(value("limits.cpu", Some("100m")), "7");
+        assert_eq!(value("requests.cpu", None), "2");
+        assert_eq!(value("requests.cpu", Some("500m")), "4");
+    }
+
+    #[test]
+    fn test_resource_field_defaults() {
+        let mut allocatable = BTreeMap::new();
+        allocatable.insert("cpu".to_owned(), quantity("4"));
+        let requirements = requirements(&[], &[]);
+
+        assert_eq!(
+            resource_field_value("limits.cpu", None, Some(&requirements), &allocatable).unwrap(),
+            "4"
+        );
+        assert_eq!(
+            resource_field_value("requests.cpu", None, None, &allocatable).unwrap(),
+            "0"
+        );
+        assert!(resource_field_value("limits.memory", None, None, &allocatable).is_err());
+        assert!(resource_field_value("limits.gpu", None, None, &allocatable).is_err());
+        assert!(resource_field_value(
+            "limits.cpu",
+            Some(&quantity("0")),
+            Some(&requirements),
+            &allocatable
+        )
+        .is_err());
+    }
+}
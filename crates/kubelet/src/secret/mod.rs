@@ -2,6 +2,7 @@
 
 use k8s_openapi::api::core::v1::Secret;
 use kube::api::Api;
+use log::warn;
 use oci_distribution::secrets::RegistryAuth;
 
 /// Resolves registry authentication from image pull secrets
@@ -24,6 +25,9 @@ impl RegistryAuthResolver {
     }
 
     /// Get the registry authentication method appropriate to the given image reference
+    ///
+    /// Falls back to anonymous access if none of the pod's image pull secrets exist or contain
+    /// credentials for the registry of the reference
     pub async fn resolve_registry_auth(
         &self,
         reference: &oci_distribution::Reference,
@@ -40,6 +44,13 @@ impl RegistryAuthResolver {
 
         for secret_result in secret_results {
             match secret_result {
+                // Like the Kubernetes kubelet, don't fail the pull because of a missing secret,
+                // the image may well be pullable without it
+                Err(kube::Error::Api(kube::error::ErrorResponse {
+                    code: 404, message, ..
+                })) => {
+                    warn!("Skipping image pull secret: {}", message);
+                }
                 Err(e) => return Err(e.into()),
                 Ok(secret) => {
                     if let Some(auth) = parse_auth(&secret, reference.registry()) {
@@ -81,6 +92,8 @@ fn parse_auth_from_secret_value(
     //     "reg2": { ... }
     //   }
     // }
+    // (as in `.dockerconfigjson`) or the legacy `.dockercfg` form, which
+    // lacks the "auths" wrapper
     parse_byte_string_json(secret_value)
         .and_then(|value| parse_auth_from_json_value(&value, registry_name))
 }
@@ -95,18 +108,93 @@ fn parse_auth_from_json_value(
 ) -> Option<RegistryAuth> {
     json_value
         .get("auths")
-        .and_then(|auths| auths.get(registry_name))
-        .and_then(|creds| parse_auth_from_json_creds(creds))
+        .unwrap_or(json_value)
+        .as_object()
+        .and_then(|auths| {
+            auths
+                .iter()
+                .find(|(key, _)| registry_host(key) == registry_name)
+        })
+        .and_then(|(_, creds)| parse_auth_from_json_creds(creds))
+}
+
+/// Docker config keys may be full URLs such as `https://index.docker.io/v1/`,
+/// so reduce them to the host to compare them against the image reference.
+fn registry_host(key: &str) -> &str {
+    let without_scheme = key
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    without_scheme.split('/').next().unwrap_or(without_scheme)
 }
 
 fn parse_auth_from_json_creds(json_creds: &serde_json::Value) -> Option<RegistryAuth> {
     let username = json_creds.get("username");
     let password = json_creds.get("password");
-    // TODO: my test creds also included an entry "auth" - should we return this? (e.g. bearer auth?)
     match (username, password) {
         (Some(serde_json::Value::String(u)), Some(serde_json::Value::String(p))) => {
             Some(RegistryAuth::Basic(u.to_owned(), p.to_owned()))
         }
+        // "auth" holds "username:password" in base64, which is all `docker login`
+        // writes into the config
+        _ => match json_creds.get("auth") {
+            Some(serde_json::Value::String(auth)) => parse_auth_field(auth),
+            _ => None,
+        },
+    }
+}
+
+fn parse_auth_field(auth: &str) -> Option<RegistryAuth> {
+    let decoded = base64::decode(auth).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(u), Some(p)) => Some(RegistryAuth::Basic(u.to_owned(), p.to_owned())),
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn basic(auth: Option<RegistryAuth>) -> Option<(String, String)> {
+        match auth {
+            Some(RegistryAuth::Basic(u, p)) => Some((u, p)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_parse_username_and_password() {
+        let config = serde_json::json!({
+            "auths": { "example.com": { "username": "user", "password": "pass" } }
+        });
+        assert_eq!(
+            basic(parse_auth_from_json_value(&config, "example.com")),
+            Some(("user".to_owned(), "pass".to_owned()))
+        );
+        assert!(parse_auth_from_json_value(&config, "other.com").is_none());
+    }
+
+    #[test]
+    fn test_parse_auth_field() {
+        let config = serde_json::json!({
+            "auths": { "example.com": { "auth": base64::encode("user:pa:ss") } }
+        });
+        assert_eq!(
+            basic(parse_auth_from_json_value(&config, "example.com")),
+            Some(("user".to_owned(), "pa:ss".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_parse_legacy_dockercfg_with_url_key() {
+        let config = serde_json::json!({
+            "https://example.com/v1/": { "username": "user", "password": "pass" }
+        });
+        assert_eq!(
+            basic(parse_auth_from_json_value(&config, "example.com")),
+            Some(("user".to_owned(), "pass".to_owned()))
+        );
+    }
+}
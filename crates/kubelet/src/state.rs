@@ -3,12 +3,14 @@ use log::{debug, error, warn};
 
 pub mod prelude;
 
+use crate::metrics;
 use crate::pod::{initialize_pod_container_statuses, patch_status};
 use crate::pod::{Phase, Pod};
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use kube::api::Api;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
 
 #[cfg(feature = "derive")]
 #[doc(hidden)]
@@ -29,6 +31,8 @@ pub use kubelet_derive::*;
 pub struct StateHolder<PodState> {
     // This is private, preventing manual construction of Transition::Next
     state: Box<dyn State<PodState>>,
+    // The type name of the state, see Transition::state_name
+    name: &'static str,
 }
 
 /// Represents result of state execution and which state to transition to next.
@@ -210,7 +214,22 @@ impl<PodState> Transition<PodState> {
     where
         I: TransitionTo<S>,
     {
-        Transition::Next(StateHolder { state: Box::new(s) })
+        Transition::Next(StateHolder {
+            state: Box::new(s),
+            name: std::any::type_name::<S>(),
+        })
+    }
+
+    /// Returns the name of the type of the state to transition to, without its module path, or
+    /// `None` if the state machine completes. This lets tests check the transitions of a state.
+    pub fn state_name(&self) -> Option<&'static str> {
+        match self {
+            Transition::Next(holder) => {
+                let path = holder.name.split('<').next().unwrap_or(holder.name);
+                path.rsplit("::").next()
+            }
+            Transition::Complete(_) => None,
+        }
     }
 }
 
@@ -233,6 +252,63 @@ pub trait State<PodState>: Sync + Send + 'static + std::fmt::Debug {
         pod_state: &mut PodState,
         pod: &Pod,
     ) -> anyhow::Result<serde_json::Value>;
+
+    /// Whether the pod only waits in this state, e.g. for a backoff to elapse before it retries.
+    /// A waiting pod gives up its concurrency permit, so that pods which wait for a long time
+    /// or even forever do not keep other pods from starting.
+    fn is_waiting(&self) -> bool {
+        false
+    }
+}
+
+/// The concurrency permit of a pod which has not reported the `Running` phase yet.
+struct PodPermit {
+    /// The semaphore to take permits from, or `None` if the pod is not limited (anymore)
+    concurrency: Option<Arc<Semaphore>>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl PodPermit {
+    fn new(concurrency: Option<Arc<Semaphore>>) -> Self {
+        PodPermit {
+            concurrency,
+            permit: None,
+        }
+    }
+
+    /// Takes a permit before the pod advances from the state, unless the pod only waits in it, in
+    /// which case the permit is released until the pod advances again.
+    async fn enter<PodState>(&mut self, state: &dyn State<PodState>) {
+        if state.is_waiting() {
+            self.permit = None;
+        } else if self.permit.is_none() {
+            if let Some(concurrency) = &self.concurrency {
+                self.permit = Some(Arc::clone(concurrency).acquire_owned().await);
+            }
+        }
+    }
+
+    /// Releases the permit for good, as the pod is running.
+    fn running(&mut self) -> bool {
+        self.concurrency = None;
+        self.permit.take().is_some()
+    }
+}
+
+/// Remembers the phase the pod reports and counts the pod in the metrics of the phase, unless it
+/// already reported that phase before, so that reporting a phase again does not count twice.
+/// Returns whether the pod entered a new phase.
+fn record_phase(phase: &mut Option<String>, new_phase: &str) -> bool {
+    if phase.as_deref() == Some(new_phase) {
+        return false;
+    }
+    match new_phase {
+        "Running" => metrics::pod_running(),
+        "Failed" => metrics::pod_failed(),
+        _ => (),
+    }
+    *phase = Some(new_phase.to_string());
+    true
 }
 
 /// Iteratively evaluate state machine until it returns Complete.
@@ -241,6 +317,23 @@ pub async fn run_to_completion<PodState: Send + Sync + 'static>(
     state: impl State<PodState>,
     pod_state: &mut PodState,
     pod: Arc<RwLock<Pod>>,
+) {
+    run_with_permit(client, state, pod_state, pod, None).await
+}
+
+/// Like [`run_to_completion`], but holds a permit of the given semaphore until the pod reports
+/// the `Running` phase or the state machine completes, whichever happens first.
+///
+/// The permit is not held for the whole lifetime of the pod, as the state handling a running pod
+/// usually only returns once the pod stops, which would keep other pods from ever starting. For
+/// the same reason, the permit is released while the pod is in a waiting state, see
+/// [`State::is_waiting`], and taken again before the pod advances.
+pub(crate) async fn run_with_permit<PodState: Send + Sync + 'static>(
+    client: &kube::Client,
+    state: impl State<PodState>,
+    pod_state: &mut PodState,
+    pod: Arc<RwLock<Pod>>,
+    concurrency: Option<Arc<Semaphore>>,
 ) {
     let (name, api) = {
         let initial_pod = pod.read().await.clone();
@@ -258,14 +351,23 @@ pub async fn run_to_completion<PodState: Send + Sync + 'static>(
     }
 
     let mut state: Box<dyn State<PodState>> = Box::new(state);
+    let mut phase: Option<String> = None;
+    let mut permit = PodPermit::new(concurrency);
 
     loop {
+        permit.enter(state.as_ref()).await;
         debug!("Pod {} entering state {:?}", &name, state);
 
         let latest_pod = { pod.read().await.clone() };
 
         match state.json_status(pod_state, &latest_pod).await {
             Ok(patch) => {
+                if let Some(new_phase) = patch["status"]["phase"].as_str() {
+                    record_phase(&mut phase, new_phase);
+                }
+                if phase.as_deref() == Some("Running") && permit.running() {
+                    debug!("Pod {} is running, releasing its concurrency permit", &name);
+                }
                 patch_status(&api, &name, patch).await;
             }
             Err(e) => {
@@ -274,7 +376,10 @@ pub async fn run_to_completion<PodState: Send + Sync + 'static>(
         }
 
         debug!("Pod {} executing state handler {:?}", &name, state);
+        let state_name = format!("{:?}", state);
+        let entered = Instant::now();
         let transition = { state.next(pod_state, &latest_pod).await };
+        metrics::observe_state_duration(metrics::state_name(&state_name), entered.elapsed());
 
         state = match transition {
             Transition::Next(s) => {
@@ -288,6 +393,7 @@ pub async fn run_to_completion<PodState: Send + Sync + 'static>(
                 }
                 Err(e) => {
                     error!("Pod {} state machine exited with error: {:?}", &name, e);
+                    record_phase(&mut phase, "Failed");
                     let patch = serde_json::json!(
                         {
                             "metadata": {
@@ -329,7 +435,20 @@ impl<P: 'static + Sync + Send> State<P> for Stub {
 #[cfg(test)]
 mod test {
     use crate::pod::Pod;
-    use crate::state::{State, Transition, TransitionTo};
+    use crate::state::{record_phase, PodPermit, State, Transition, TransitionTo};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+
+    #[test]
+    fn reporting_the_same_phase_again_is_not_a_new_phase() {
+        let mut phase = None;
+        assert!(record_phase(&mut phase, "Pending"));
+        assert!(record_phase(&mut phase, "Failed"));
+        assert!(!record_phase(&mut phase, "Failed"));
+        assert_eq!(phase.as_deref(), Some("Failed"));
+        assert!(record_phase(&mut phase, "Running"));
+    }
 
     #[derive(Debug)]
     struct PodState;
@@ -356,6 +475,71 @@ mod test {
         }
     }
 
+    #[derive(Debug)]
+    struct BackoffState;
+
+    #[async_trait::async_trait]
+    impl State<PodState> for BackoffState {
+        async fn next(
+            self: Box<Self>,
+            _pod_state: &mut PodState,
+            _pod: &Pod,
+        ) -> Transition<PodState> {
+            Transition::Complete(Ok(()))
+        }
+
+        async fn json_status(
+            &self,
+            _pod_state: &mut PodState,
+            _pod: &Pod,
+        ) -> anyhow::Result<serde_json::Value> {
+            Ok(serde_json::json!(null))
+        }
+
+        fn is_waiting(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn pods_in_backoff_do_not_keep_other_pods_from_starting() {
+        let concurrency = Arc::new(Semaphore::new(2));
+        let mut backing_off = vec![];
+        for _ in 0..2 {
+            let mut permit = PodPermit::new(Some(Arc::clone(&concurrency)));
+            permit.enter::<PodState>(&ValidState).await;
+            permit.enter::<PodState>(&BackoffState).await;
+            backing_off.push(permit);
+        }
+
+        let mut starting = PodPermit::new(Some(Arc::clone(&concurrency)));
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            starting.enter::<PodState>(&ValidState),
+        )
+        .await
+        .expect("pod did not start while the other pods were in backoff");
+
+        // A pod takes a permit again once its backoff elapsed
+        backing_off[0].enter::<PodState>(&ValidState).await;
+        assert_eq!(concurrency.available_permits(), 0);
+        assert!(starting.running());
+        assert_eq!(concurrency.available_permits(), 1);
+    }
+
+    #[test]
+    fn transition_names_the_next_state() {
+        #[derive(Debug)]
+        struct TestState;
+
+        impl TransitionTo<ValidState> for TestState {}
+
+        let transition: Transition<PodState> = Transition::next(Box::new(TestState), ValidState);
+        assert_eq!(transition.state_name(), Some("ValidState"));
+        let transition: Transition<PodState> = Transition::Complete(Ok(()));
+        assert_eq!(transition.state_name(), None);
+    }
+
     #[test]
     fn it_can_transition_to_valid_state() {
         #[derive(Debug)]
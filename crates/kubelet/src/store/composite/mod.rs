@@ -3,6 +3,7 @@
 use crate::store::PullPolicy;
 use crate::store::Store;
 use async_trait::async_trait;
+use log::{debug, warn};
 use oci_distribution::secrets::RegistryAuth;
 use oci_distribution::Reference;
 use std::sync::Arc;
@@ -15,42 +16,70 @@ pub trait InterceptingStore: Store {
     fn intercepts(&self, image_ref: &Reference) -> bool;
 }
 
-/// Provides a way to overlay an `InterceptingStore` so that the
-/// interceptor handles the references it can, and the base store
-/// handles all other references.
-pub trait ComposableStore {
-    /// Creates a `Store` identical to the implementer except that
-    /// 'get' requests are offered to the interceptor first.
-    fn with_override(
-        self,
-        interceptor: Arc<dyn InterceptingStore + Send + Sync>,
-    ) -> Arc<dyn Store + Send + Sync>;
+/// A `Store` which modules fetched from other stores can be written to,
+/// so that it can serve them itself the next time they are requested.
+#[async_trait]
+pub trait CachingStore: Store {
+    /// Saves the module with the given image `Reference` in the cache.
+    async fn put(&self, image_ref: &Reference, module: &[u8]) -> anyhow::Result<()>;
 }
 
-impl ComposableStore for Arc<dyn Store + Send + Sync> {
+/// Provides ways to build a `Store` out of several others, e.g. a local
+/// cache which falls back to a remote registry.
+pub trait ComposableStore: Sized {
+    /// Converts the implementer into a `Store`.
+    fn into_store(self) -> Arc<dyn Store + Send + Sync>;
+
+    /// Creates a `Store` identical to the implementer except that
+    /// 'get' requests are offered to the interceptor first.
     fn with_override(
         self,
         interceptor: Arc<dyn InterceptingStore + Send + Sync>,
     ) -> Arc<dyn Store + Send + Sync> {
         Arc::new(CompositeStore {
-            base: self,
+            base: self.into_store(),
             interceptor,
         })
     }
+
+    /// Creates a `Store` which gets modules from the implementer, and from
+    /// the fallback if the implementer fails to provide them. Chaining
+    /// fallbacks gives an ordered list of stores which are tried one after
+    /// another.
+    fn with_fallback(self, fallback: Arc<dyn Store + Send + Sync>) -> Arc<dyn Store + Send + Sync> {
+        Arc::new(FallbackStore {
+            primary: self.into_store(),
+            fallback,
+        })
+    }
+
+    /// Creates a `Store` which looks modules up in the cache before getting
+    /// them from the implementer, and saves every module it got from the
+    /// implementer in the cache. The cache is skipped for the `Always` pull
+    /// policy, but still updated.
+    fn with_cache(
+        self,
+        cache: Arc<dyn CachingStore + Send + Sync>,
+    ) -> Arc<dyn Store + Send + Sync> {
+        Arc::new(CachedStore {
+            cache,
+            base: self.into_store(),
+        })
+    }
+}
+
+impl ComposableStore for Arc<dyn Store + Send + Sync> {
+    fn into_store(self) -> Arc<dyn Store + Send + Sync> {
+        self
+    }
 }
 
 impl<S> ComposableStore for Arc<S>
 where
     S: Store + Send + Sync + 'static,
 {
-    fn with_override(
-        self,
-        interceptor: Arc<dyn InterceptingStore + Send + Sync>,
-    ) -> Arc<dyn Store + Send + Sync> {
-        Arc::new(CompositeStore {
-            base: self,
-            interceptor,
-        })
+    fn into_store(self) -> Arc<dyn Store + Send + Sync> {
+        self
     }
 }
 
@@ -75,14 +104,83 @@ impl Store for CompositeStore {
     }
 }
 
+struct FallbackStore {
+    primary: Arc<dyn Store + Send + Sync>,
+    fallback: Arc<dyn Store + Send + Sync>,
+}
+
+#[async_trait]
+impl Store for FallbackStore {
+    async fn get(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<Vec<u8>> {
+        match self.primary.get(image_ref, pull_policy, auth).await {
+            Ok(module) => Ok(module),
+            Err(e) => {
+                debug!(
+                    "Failed to get image ref '{}', falling back to the next store: {}",
+                    image_ref, e
+                );
+                self.fallback.get(image_ref, pull_policy, auth).await
+            }
+        }
+    }
+}
+
+struct CachedStore {
+    cache: Arc<dyn CachingStore + Send + Sync>,
+    base: Arc<dyn Store + Send + Sync>,
+}
+
+#[async_trait]
+impl Store for CachedStore {
+    async fn get(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<Vec<u8>> {
+        if pull_policy != PullPolicy::Always {
+            match self.cache.get(image_ref, pull_policy, auth).await {
+                Ok(module) => return Ok(module),
+                Err(e) => debug!("Image ref '{}' is not cached: {}", image_ref, e),
+            }
+        }
+        let module = self.base.get(image_ref, pull_policy, auth).await?;
+        // The module can be used even if it could not be cached
+        if let Err(e) = self.cache.put(image_ref, &module).await {
+            warn!("Failed to cache image ref '{}': {}", image_ref, e);
+        }
+        Ok(module)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use oci_distribution::secrets::RegistryAuth;
+    use std::collections::HashMap;
     use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
 
     struct FakeBase {}
     struct FakeInterceptor {}
+    struct FakeUnavailable {}
+
+    /// Counts how often modules are fetched from it.
+    #[derive(Default)]
+    struct FakeRegistry {
+        pulls: AtomicUsize,
+    }
+
+    #[derive(Default)]
+    struct FakeCache {
+        modules: Mutex<HashMap<String, Vec<u8>>>,
+    }
 
     #[async_trait]
     impl Store for FakeBase {
@@ -108,6 +206,59 @@ mod test {
         }
     }
 
+    #[async_trait]
+    impl Store for FakeUnavailable {
+        async fn get(
+            &self,
+            image_ref: &Reference,
+            _pull_policy: PullPolicy,
+            _auth: &RegistryAuth,
+        ) -> anyhow::Result<Vec<u8>> {
+            Err(anyhow::anyhow!("{} is unavailable", image_ref))
+        }
+    }
+
+    #[async_trait]
+    impl Store for FakeRegistry {
+        async fn get(
+            &self,
+            _image_ref: &Reference,
+            _pull_policy: PullPolicy,
+            _auth: &RegistryAuth,
+        ) -> anyhow::Result<Vec<u8>> {
+            self.pulls.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![7, 8, 9])
+        }
+    }
+
+    #[async_trait]
+    impl Store for FakeCache {
+        async fn get(
+            &self,
+            image_ref: &Reference,
+            _pull_policy: PullPolicy,
+            _auth: &RegistryAuth,
+        ) -> anyhow::Result<Vec<u8>> {
+            self.modules
+                .lock()
+                .unwrap()
+                .get(&image_ref.whole())
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("{} is not cached", image_ref))
+        }
+    }
+
+    #[async_trait]
+    impl CachingStore for FakeCache {
+        async fn put(&self, image_ref: &Reference, module: &[u8]) -> anyhow::Result<()> {
+            self.modules
+                .lock()
+                .unwrap()
+                .insert(image_ref.whole(), module.to_vec());
+            Ok(())
+        }
+    }
+
     impl InterceptingStore for FakeInterceptor {
         fn intercepts(&self, image_ref: &Reference) -> bool {
             image_ref.whole().starts_with("int")
@@ -143,4 +294,63 @@ mod test {
         assert_eq!(4, result.len());
         assert_eq!(11, result[0]);
     }
+
+    #[tokio::test]
+    async fn if_store_fails_then_fallback_store_returns_value() {
+        let store = Arc::new(FakeUnavailable {})
+            .with_fallback(Arc::new(FakeUnavailable {}))
+            .with_fallback(Arc::new(FakeBase {}));
+        let result = store
+            .get(
+                &Reference::try_from("foo/bar").unwrap(),
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+            )
+            .await
+            .unwrap();
+        assert_eq!(vec![11, 10, 5, 14], result);
+
+        let store = Arc::new(FakeUnavailable {}).with_fallback(Arc::new(FakeUnavailable {}));
+        assert!(store
+            .get(
+                &Reference::try_from("foo/bar").unwrap(),
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn cache_miss_falls_through_and_populates_cache() {
+        let registry = Arc::new(FakeRegistry::default());
+        let cache = Arc::new(FakeCache::default());
+        let store = Arc::new(FakeUnavailable {})
+            .with_fallback(registry.clone())
+            .with_cache(cache.clone());
+        let reference = Reference::try_from("foo/bar:v1").unwrap();
+
+        for _ in 0..2 {
+            let result = store
+                .get(
+                    &reference,
+                    PullPolicy::IfNotPresent,
+                    &RegistryAuth::Anonymous,
+                )
+                .await
+                .unwrap();
+            assert_eq!(vec![7, 8, 9], result);
+        }
+        assert_eq!(1, registry.pulls.load(Ordering::SeqCst));
+        assert_eq!(
+            Some(&vec![7, 8, 9]),
+            cache.modules.lock().unwrap().get(&reference.whole())
+        );
+
+        store
+            .get(&reference, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await
+            .unwrap();
+        assert_eq!(2, registry.pulls.load(Ordering::SeqCst));
+    }
 }
@@ -1,11 +1,12 @@
 //! `fs` implements fetching modules from the local file system.
 
-use crate::store::composite::InterceptingStore;
+use crate::store::composite::{CachingStore, InterceptingStore};
 use crate::store::{PullPolicy, Store};
 use async_trait::async_trait;
+use log::debug;
 use oci_distribution::secrets::RegistryAuth;
 use oci_distribution::Reference;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /// A `Store` which fetches modules only from the local filesystem,
 /// not a remote registry. References must be of the form
@@ -36,3 +37,62 @@ impl InterceptingStore for FileSystemStore {
         image_ref.registry() == "fs"
     }
 }
+
+/// A `CachingStore` which keeps modules in a directory of the local
+/// filesystem, laid out by registry, repository and tag or digest.
+///
+/// It only serves modules which were put into it, so it is meant to be
+/// composed with a store which fetches modules from elsewhere, see
+/// [`ComposableStore::with_cache`](crate::store::composite::ComposableStore::with_cache).
+pub struct DirectoryCache {
+    root_dir: PathBuf,
+}
+
+impl DirectoryCache {
+    /// Create a new `DirectoryCache` keeping its modules below `root_dir`
+    pub fn new<T: AsRef<Path>>(root_dir: T) -> Self {
+        Self {
+            root_dir: root_dir.as_ref().into(),
+        }
+    }
+
+    fn module_path(&self, r: &Reference) -> PathBuf {
+        let mut path = self.root_dir.join(r.registry());
+        path.push(r.repository());
+        match r.digest() {
+            Some(digest) => path.push(digest.replace(':', "_")),
+            None => path.push(r.tag().unwrap_or("latest")),
+        }
+        path.join("module.wasm")
+    }
+}
+
+#[async_trait]
+impl Store for DirectoryCache {
+    async fn get(
+        &self,
+        image_ref: &Reference,
+        _pull_policy: PullPolicy,
+        _auth: &RegistryAuth,
+    ) -> anyhow::Result<Vec<u8>> {
+        let path = self.module_path(image_ref);
+        debug!("Fetching image ref '{:?}' from cache {:?}", image_ref, path);
+        Ok(tokio::fs::read(&path).await?)
+    }
+}
+
+#[async_trait]
+impl CachingStore for DirectoryCache {
+    async fn put(&self, image_ref: &Reference, module: &[u8]) -> anyhow::Result<()> {
+        let path = self.module_path(image_ref);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // The module is moved into place once it is complete, so that a
+        // failed write never leaves a truncated module in the cache
+        let partial_path = path.with_extension("wasm.partial");
+        tokio::fs::write(&partial_path, module).await?;
+        tokio::fs::rename(&partial_path, &path).await?;
+        Ok(())
+    }
+}
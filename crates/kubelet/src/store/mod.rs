@@ -11,12 +11,13 @@ use tokio::sync::Mutex;
 use tokio::sync::RwLock;
 
 use async_trait::async_trait;
-use log::debug;
+use log::{debug, warn};
 use oci_distribution::Reference;
+use thiserror::Error;
 
 use crate::container::PullPolicy;
 use crate::pod::Pod;
-use crate::store::oci::Client;
+use crate::store::oci::{Client, DigestCache};
 
 /// A store of container modules.
 ///
@@ -104,23 +105,108 @@ pub trait Store: Sync {
     }
 }
 
+/// The digest of a pulled image does not match the digest of the reference it
+/// was pulled by.
+#[derive(Debug, Error)]
+#[error("digest of image {reference} is {actual}, expected {expected}")]
+pub struct DigestMismatchError {
+    /// The reference the image was pulled by
+    pub reference: String,
+    /// The digest given in the reference
+    pub expected: String,
+    /// The digest of the pulled image
+    pub actual: String,
+}
+
+/// Checks that the pulled image has the digest given in the reference, if
+/// the reference contains one. References by tag are always accepted.
+fn verify_digest(image_ref: &Reference, image_data: &ImageData) -> anyhow::Result<()> {
+    let expected = match image_ref.digest() {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+    match &image_data.digest {
+        Some(actual) if actual.eq_ignore_ascii_case(expected) => Ok(()),
+        actual => Err(DigestMismatchError {
+            reference: image_ref.whole(),
+            expected: expected.to_owned(),
+            actual: actual.clone().unwrap_or_else(|| String::from("unknown")),
+        }
+        .into()),
+    }
+}
+
 /// A `Store` implementation which obtains module data from remote registries
 /// but caches it in local storage.
+///
+/// Images referenced by digest are verified against that digest when they are
+/// pulled, and a cached copy is only used if it was stored with that digest.
+///
+/// With a [`DigestCache`], images are only pulled if their digest is not found
+/// in it.
 pub struct LocalStore<S: Storer, C: Client> {
     storer: Arc<RwLock<S>>,
     client: Arc<Mutex<C>>,
+    digest_cache: Option<Arc<DigestCache>>,
 }
 
 impl<S: Storer, C: Client> LocalStore<S, C> {
-    async fn pull(&self, image_ref: &Reference, auth: &RegistryAuth) -> anyhow::Result<()> {
+    /// Looks images up by their digest in the cache before pulling them, and
+    /// caches every pulled image. Looking up images which are referenced by
+    /// tag takes a request for their digest.
+    pub fn with_digest_cache(mut self, digest_cache: DigestCache) -> Self {
+        self.digest_cache = Some(Arc::new(digest_cache));
+        self
+    }
+
+    /// Pulls the image, unless it is found in the digest cache. `digest` is the
+    /// digest of the image, if it is known already.
+    async fn pull(
+        &self,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+        digest: Option<String>,
+    ) -> anyhow::Result<()> {
+        if let Some(digest_cache) = &self.digest_cache {
+            let digest = match digest.or_else(|| image_ref.digest().map(str::to_owned)) {
+                Some(digest) => Some(digest),
+                None => self
+                    .client
+                    .lock()
+                    .await
+                    .fetch_digest(image_ref, auth)
+                    .await
+                    .ok(),
+            };
+            if let Some(digest) = digest {
+                if let Some(module) = digest_cache.get(&digest).await {
+                    let image_data = ImageData {
+                        layers: vec![module],
+                        digest: Some(digest),
+                    };
+                    return self.store(image_ref, image_data).await;
+                }
+            }
+        }
+
         debug!("Pulling image ref '{:?}' from registry", image_ref);
         let image_data = self.client.lock().await.pull(image_ref, auth).await?;
-        self.storer
-            .write()
-            .await
-            .store(image_ref, image_data)
-            .await?;
-        Ok(())
+        verify_digest(image_ref, &image_data)?;
+        if let (Some(digest_cache), Some(digest), Some(module)) = (
+            &self.digest_cache,
+            &image_data.digest,
+            image_data.layers.first(),
+        ) {
+            // The module can be used even if it could not be cached
+            if let Err(e) = digest_cache.put(digest, module).await {
+                warn!("Failed to cache image ref '{:?}': {}", image_ref, e);
+            }
+        }
+        self.store(image_ref, image_data).await
+    }
+
+    async fn store(&self, image_ref: &Reference, image_data: ImageData) -> anyhow::Result<()> {
+        self.storer.write().await.store(image_ref, image_data).await
     }
 }
 
@@ -134,8 +220,18 @@ impl<S: Storer + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C
     ) -> anyhow::Result<Vec<u8>> {
         match pull_policy {
             PullPolicy::IfNotPresent => {
-                if !self.storer.read().await.is_present(image_ref).await {
-                    self.pull(image_ref, auth).await?
+                let storer = self.storer.read().await;
+                let present = match image_ref.digest() {
+                    Some(digest) => {
+                        storer
+                            .is_present_with_digest(image_ref, digest.to_owned())
+                            .await
+                    }
+                    None => storer.is_present(image_ref).await,
+                };
+                drop(storer);
+                if !present {
+                    self.pull(image_ref, auth, None).await?
                 }
             }
             PullPolicy::Always => {
@@ -149,10 +245,10 @@ impl<S: Storer + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C
                     .storer
                     .read()
                     .await
-                    .is_present_with_digest(image_ref, digest)
+                    .is_present_with_digest(image_ref, digest.clone())
                     .await;
                 if !already_got_with_digest {
-                    self.pull(image_ref, auth).await?
+                    self.pull(image_ref, auth, Some(digest)).await?
                 }
             }
             PullPolicy::Never => (),
@@ -0,0 +1,222 @@
+//! A content-addressed cache of modules, keyed by the digest of their image.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+/// The size in bytes the modules in a [`DigestCache`] may take up, unless it is created with
+/// another one.
+pub const DEFAULT_DIGEST_CACHE_SIZE: u64 = 512 * 1024 * 1024;
+
+/// A cache of modules keyed by the digest of their image, so that an image which did not change
+/// is not pulled again, no matter which reference it is pulled by.
+///
+/// Every module is stored with the SHA-256 hash of its content, which it is verified against
+/// when it is read. Once the modules take up more than the maximum size, the least recently used
+/// ones are evicted.
+pub struct DigestCache {
+    root_dir: PathBuf,
+    max_size: u64,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    modules: HashMap<String, Entry>,
+    total_size: u64,
+    /// Counts up whenever a module is used, to order the modules by their last use
+    clock: u64,
+}
+
+struct Entry {
+    size: u64,
+    last_used: u64,
+}
+
+impl Entries {
+    fn insert(&mut self, key: String, size: u64) {
+        self.clock += 1;
+        let entry = Entry {
+            size,
+            last_used: self.clock,
+        };
+        if let Some(replaced) = self.modules.insert(key, entry) {
+            self.total_size -= replaced.size;
+        }
+        self.total_size += size;
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        if let Some(entry) = self.modules.get_mut(key) {
+            entry.last_used = self.clock;
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.modules.remove(key) {
+            self.total_size -= entry.size;
+        }
+    }
+
+    fn least_recently_used(&self) -> Option<String> {
+        self.modules
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone())
+    }
+}
+
+impl DigestCache {
+    /// Creates a cache keeping its modules in `root_dir`, which may take up `max_size` bytes.
+    /// Modules which are already in the directory are taken over, ordered by when they were
+    /// last written.
+    pub fn new<T: AsRef<Path>>(root_dir: T, max_size: u64) -> Self {
+        let root_dir = root_dir.as_ref().to_path_buf();
+        let mut modules: Vec<(SystemTime, String, u64)> = std::fs::read_dir(&root_dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let path = entry.path();
+                if path.extension()? != "wasm" {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                let key = path.file_stem()?.to_str()?.to_owned();
+                Some((metadata.modified().ok()?, key, metadata.len()))
+            })
+            .collect();
+        modules.sort();
+        let mut entries = Entries::default();
+        for (_, key, size) in modules {
+            entries.insert(key, size);
+        }
+        DigestCache {
+            root_dir,
+            max_size,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    fn key(digest: &str) -> String {
+        digest.replace(':', "_")
+    }
+
+    fn module_path(&self, key: &str) -> PathBuf {
+        self.root_dir.join(format!("{}.wasm", key))
+    }
+
+    fn hash_path(&self, key: &str) -> PathBuf {
+        self.root_dir.join(format!("{}.sha256", key))
+    }
+
+    /// Returns the module of the image with the given digest, if it is cached and intact.
+    /// Damaged modules are removed from the cache.
+    pub async fn get(&self, digest: &str) -> Option<Vec<u8>> {
+        let key = DigestCache::key(digest);
+        let mut entries = self.entries.lock().await;
+        if !entries.modules.contains_key(&key) {
+            return None;
+        }
+        let module = tokio::fs::read(self.module_path(&key)).await.ok();
+        let hash = tokio::fs::read_to_string(self.hash_path(&key)).await.ok();
+        match (module, hash) {
+            (Some(module), Some(hash)) if content_hash(&module) == hash.trim() => {
+                debug!("Found module of image {} in the digest cache", digest);
+                entries.touch(&key);
+                Some(module)
+            }
+            _ => {
+                warn!(
+                    "Cached module of image {} is damaged, removing it from the cache",
+                    digest
+                );
+                self.evict(&mut entries, &key).await;
+                None
+            }
+        }
+    }
+
+    /// Caches the module of the image with the given digest and evicts the least recently used
+    /// modules if the cache grew too large. Modules larger than the whole cache are not cached.
+    pub async fn put(&self, digest: &str, module: &[u8]) -> anyhow::Result<()> {
+        let size = module.len() as u64;
+        if size > self.max_size {
+            debug!(
+                "Module of image {} is larger than the digest cache, not caching it",
+                digest
+            );
+            return Ok(());
+        }
+        let key = DigestCache::key(digest);
+        let mut entries = self.entries.lock().await;
+        tokio::fs::create_dir_all(&self.root_dir).await?;
+        // The module is moved into place last, so that only complete modules with their hash
+        // are found in the directory
+        let module_path = self.module_path(&key);
+        let partial_path = module_path.with_extension("wasm.partial");
+        tokio::fs::write(&partial_path, module).await?;
+        tokio::fs::write(self.hash_path(&key), content_hash(module)).await?;
+        tokio::fs::rename(&partial_path, &module_path).await?;
+        entries.insert(key.clone(), size);
+
+        while entries.total_size > self.max_size {
+            match entries.least_recently_used() {
+                Some(evicted) if evicted != key => {
+                    debug!("Evicting {} from the digest cache", evicted);
+                    self.evict(&mut entries, &evicted).await;
+                }
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    async fn evict(&self, entries: &mut Entries, key: &str) {
+        entries.remove(key);
+        for path in &[self.module_path(key), self.hash_path(key)] {
+            if let Err(e) = tokio::fs::remove_file(path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Failed to remove {:?} from the digest cache: {}", path, e);
+                }
+            }
+        }
+    }
+}
+
+fn content_hash(module: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(module))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn modules_are_verified_and_evicted_least_recently_used_first() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = DigestCache::new(directory.path(), 8);
+
+        cache.put("sha256:aaa", &[1, 2, 3]).await.unwrap();
+        cache.put("sha256:bbb", &[4, 5, 6]).await.unwrap();
+        assert_eq!(Some(vec![1, 2, 3]), cache.get("sha256:aaa").await);
+        // Evicts bbb, which was used less recently than aaa
+        cache.put("sha256:ccc", &[7, 8, 9]).await.unwrap();
+        assert_eq!(None, cache.get("sha256:bbb").await);
+        assert_eq!(Some(vec![1, 2, 3]), cache.get("sha256:aaa").await);
+        assert_eq!(Some(vec![7, 8, 9]), cache.get("sha256:ccc").await);
+
+        // Modules which do not match their hash are not served
+        std::fs::write(directory.path().join("sha256_ccc.wasm"), &[7, 8, 0]).unwrap();
+        assert_eq!(None, cache.get("sha256:ccc").await);
+        assert!(!directory.path().join("sha256_ccc.wasm").exists());
+
+        // Modules cached by an earlier run are taken over
+        let cache = DigestCache::new(directory.path(), 8);
+        assert_eq!(Some(vec![1, 2, 3]), cache.get("sha256:aaa").await);
+    }
+}
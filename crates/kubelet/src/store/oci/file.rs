@@ -27,6 +27,7 @@ impl<C: Client + Send> FileStore<C> {
                 root_dir: root_dir.as_ref().into(),
             })),
             client: Arc::new(Mutex::new(client)),
+            digest_cache: None,
         }
     }
 }
@@ -46,7 +47,12 @@ impl FileStorer {
     fn pull_path(&self, r: &Reference) -> PathBuf {
         let mut path = self.root_dir.join(r.registry());
         path.push(r.repository());
-        path.push(r.tag().unwrap_or("latest"));
+        // Images referenced by digest get their own directory, so they never
+        // share a cache entry with a tag
+        match r.digest() {
+            Some(digest) => path.push(digest.replace(':', "_")),
+            None => path.push(r.tag().unwrap_or("latest")),
+        }
         path
     }
 
@@ -112,6 +118,7 @@ impl<C: Client + Send> Clone for FileStore<C> {
         Self {
             storer: self.storer.clone(),
             client: self.client.clone(),
+            digest_cache: self.digest_cache.clone(),
         }
     }
 }
@@ -130,6 +137,7 @@ async fn file_content_is(path: PathBuf, text: String) -> bool {
 mod test {
     use super::*;
     use crate::container::PullPolicy;
+    use crate::store::oci::{DigestCache, DEFAULT_DIGEST_CACHE_SIZE};
     use crate::store::Store;
     use oci_distribution::client::ImageData;
     use oci_distribution::secrets::RegistryAuth;
@@ -266,6 +274,42 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn file_module_store_reuses_modules_from_digest_cache() -> anyhow::Result<()> {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let digest_dir = create_temp_dir();
+        let mut fake_client = FakeImageClient::new(vec![]);
+        fake_client.update("foo/bar:1.0", vec![1, 2, 3], &digest);
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client, &scratch_dir.path).with_digest_cache(
+            DigestCache::new(&digest_dir.path, DEFAULT_DIGEST_CACHE_SIZE),
+        );
+        store
+            .get(
+                &Reference::try_from("foo/bar:1.0")?,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+            )
+            .await?;
+
+        // The image cannot be pulled anymore, but it is found by its digest
+        let other_scratch_dir = create_temp_dir();
+        let store = FileStore::new(FakeImageClient::new(vec![]), &other_scratch_dir.path)
+            .with_digest_cache(DigestCache::new(
+                &digest_dir.path,
+                DEFAULT_DIGEST_CACHE_SIZE,
+            ));
+        let module_bytes = store
+            .get(
+                &Reference::try_from(format!("foo/bar@{}", digest))?,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+            )
+            .await?;
+        assert_eq!(vec![1, 2, 3], module_bytes);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn file_module_store_does_not_pull_if_policy_never() -> anyhow::Result<()> {
         let fake_client = FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
@@ -354,6 +398,56 @@ mod test {
         Ok(())
     }
 
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[tokio::test]
+    async fn file_module_store_accepts_matching_digest() -> anyhow::Result<()> {
+        let reference =
+            "foo/bar@sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let fake_client = FakeImageClient::new(vec![(reference, vec![1, 2], DIGEST)]);
+        let fake_ref = Reference::try_from(reference)?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client, &scratch_dir.path);
+        let module_bytes = store
+            .get(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+            )
+            .await?;
+        assert_eq!(vec![1, 2], module_bytes);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_rejects_digest_mismatch() -> anyhow::Result<()> {
+        let reference =
+            "foo/bar@sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let fake_client = FakeImageClient::new(vec![(reference, vec![1, 2], "sha256:12")]);
+        let fake_ref = Reference::try_from(reference)?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client, &scratch_dir.path);
+        let error = store
+            .get(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+            )
+            .await
+            .expect_err("expected a digest mismatch");
+        assert!(error
+            .downcast_ref::<crate::store::DigestMismatchError>()
+            .is_some());
+        let module_bytes = store
+            .get(&fake_ref, PullPolicy::Never, &RegistryAuth::Anonymous)
+            .await;
+        assert!(
+            module_bytes.is_err(),
+            "a module with the wrong digest must not be cached"
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn file_module_store_copes_with_no_tag() -> anyhow::Result<()> {
         let fake_client = FakeImageClient::new(vec![("foo/bar", vec![2, 3], "sha256:23")]);
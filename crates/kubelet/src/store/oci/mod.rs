@@ -1,6 +1,8 @@
 //! `oci` implements different storage methods for fetching modules from an OCI registry.
+mod cache;
 mod client;
 mod file;
 
+pub use cache::{DigestCache, DEFAULT_DIGEST_CACHE_SIZE};
 pub use client::Client;
 pub use file::FileStore;
@@ -1,14 +1,18 @@
 use crate::config::ServerConfig;
+use crate::exec;
 use crate::log::{Options, Sender};
-use crate::provider::{NotImplementedError, Provider};
+use crate::metrics;
+use crate::pod::PodKey;
+use crate::provider::{NotImplementedError, Provider, ProviderError};
 use http::status::StatusCode;
 use http::Response;
 use hyper::Body;
 /// Server is an HTTP(S) server for answering Kubelet callbacks.
 ///
 /// Logs and exec calls are the main things that a server should handle.
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use warp::Filter;
 
@@ -17,13 +21,41 @@ const PING: &str = "this is the Krustlet HTTP server";
 /// Start the Krustlet HTTP(S) server
 ///
 /// This is a primitive implementation of an HTTP provider for the internal API.
+///
+/// `/healthz` answers as soon as the server is up, `/readyz` only once `ready` has been set.
+///
+/// Debug routes which change pods, e.g. `/debug/evict`, require the debug token of the
+/// configuration as a bearer token, and are disabled without one.
 pub(crate) async fn start<T: 'static + Provider + Send + Sync>(
     provider: Arc<T>,
     config: &ServerConfig,
+    ready: Arc<AtomicBool>,
 ) -> anyhow::Result<()> {
     let health = warp::get().and(warp::path("healthz")).map(|| PING);
+    let readiness = warp::get()
+        .and(warp::path("readyz"))
+        .and_then(move || get_readiness(ready.clone()));
     let ping = warp::get().and(warp::path::end()).map(|| PING);
+    let prometheus = warp::get().and(warp::path("metrics")).map(|| {
+        warp::reply::with_header(
+            metrics::gather(),
+            "content-type",
+            "text/plain; version=0.0.4",
+        )
+    });
 
+    let logs_provider = provider.clone();
+    let logs_over_websocket = warp::get()
+        .and(warp::path!("containerLogs" / String / String / String))
+        .and(warp::query::<Options>())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .and_then(move |namespace, pod, container, opts, protocols, ws| {
+            let provider = logs_provider.clone();
+            get_container_logs_over_websocket(
+                provider, namespace, pod, container, opts, protocols, ws,
+            )
+        });
     let logs_provider = provider.clone();
     let logs = warp::get()
         .and(warp::path!("containerLogs" / String / String / String))
@@ -34,14 +66,50 @@ pub(crate) async fn start<T: 'static + Provider + Send + Sync>(
         });
 
     let exec_provider = provider.clone();
-    let exec = warp::post()
+    let exec = warp::get()
+        .or(warp::post())
+        .unify()
         .and(warp::path!("exec" / String / String / String))
-        .and_then(move |namespace, pod, container| {
+        .and(warp::query::raw())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .and_then(move |namespace, pod, container, query, protocols, ws| {
             let provider = exec_provider.clone();
-            post_exec(provider, namespace, pod, container)
+            exec_over_websocket(provider, namespace, pod, container, query, protocols, ws)
+        });
+    let exec_without_websocket = warp::post()
+        .and(warp::path!("exec" / String / String / String))
+        .and_then(|namespace, pod, container| post_exec(namespace, pod, container));
+
+    let capabilities_provider = provider.clone();
+    let capabilities = warp::get()
+        .and(warp::path!("debug" / "capabilities" / String / String))
+        .and_then(move |namespace, pod| {
+            let provider = capabilities_provider.clone();
+            get_capabilities(provider, namespace, pod)
+        });
+
+    let evict_provider = provider.clone();
+    let debug_token = config.debug_token.clone();
+    let evict = warp::post()
+        .and(warp::path!("debug" / "evict" / String / String))
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |namespace, pod, authorization| {
+            let provider = evict_provider.clone();
+            let debug_token = debug_token.clone();
+            evict_pod(provider, debug_token, authorization, namespace, pod)
         });
 
-    let routes = ping.or(health).or(logs).or(exec);
+    let routes = ping
+        .or(health)
+        .or(readiness)
+        .or(prometheus)
+        .or(logs_over_websocket)
+        .or(logs)
+        .or(exec)
+        .or(exec_without_websocket)
+        .or(capabilities)
+        .or(evict);
 
     warp::serve(routes)
         .tls()
@@ -52,6 +120,17 @@ pub(crate) async fn start<T: 'static + Provider + Send + Sync>(
     Ok(())
 }
 
+/// Report whether the kubelet is ready.
+///
+/// Implements the path /readyz
+async fn get_readiness(ready: Arc<AtomicBool>) -> Result<Response<Body>, Infallible> {
+    if ready.load(Ordering::Relaxed) {
+        return_with_code(StatusCode::OK, "ready".to_owned())
+    } else {
+        return_with_code(StatusCode::SERVICE_UNAVAILABLE, "not ready".to_owned())
+    }
+}
+
 /// Get the logs from the running container.
 ///
 /// Implements the kubelet path /containerLogs/{namespace}/{pod}/{container}
@@ -71,13 +150,93 @@ async fn get_container_logs<T: 'static + Provider + Send + Sync>(
 
     match provider.logs(namespace, pod, container, log_sender).await {
         Ok(()) => Ok(Response::new(log_body)),
+        Err(e) => logs_error(e),
+    }
+}
+
+/// Get the logs from the running container over a websocket, which stays open while the log is
+/// followed.
+///
+/// Implements the kubelet path /containerLogs/{namespace}/{pod}/{container} for websocket
+/// upgrade requests
+async fn get_container_logs_over_websocket<T: 'static + Provider + Send + Sync>(
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    container: String,
+    opts: Options,
+    protocols: Option<String>,
+    ws: warp::ws::Ws,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    debug!(
+        "Got websocket log request for container {} in pod {} in namespace {}. Options: {:?}.",
+        container, pod, namespace, opts
+    );
+    let protocol = match crate::log::negotiate_protocol(protocols.as_deref()) {
+        Some(protocol) => protocol,
+        None => {
+            return Ok(Box::new(return_with_code(
+                StatusCode::BAD_REQUEST,
+                format!("Unsupported log protocols {:?}", protocols),
+            )?))
+        }
+    };
+    let (sender, log_body) = Body::channel();
+    let log_sender = Sender::new(sender, opts);
+
+    if let Err(e) = provider.logs(namespace, pod, container, log_sender).await {
+        return Ok(Box::new(logs_error(e)?));
+    }
+    let reply =
+        ws.on_upgrade(move |websocket| crate::log::serve_websocket(websocket, protocol, log_body));
+    Ok(Box::new(warp::reply::with_header(
+        reply,
+        "sec-websocket-protocol",
+        protocol,
+    )))
+}
+
+/// Answers a log request the provider failed to serve.
+fn logs_error(e: anyhow::Error) -> Result<Response<Body>, Infallible> {
+    error!("Error fetching logs: {}", e);
+    if e.is::<NotImplementedError>() {
+        return_with_code(
+            StatusCode::NOT_IMPLEMENTED,
+            "Logs not implemented in provider.".to_owned(),
+        )
+    } else {
+        return_with_code(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Server error: {}", e),
+        )
+    }
+}
+
+/// Get the capabilities the workloads of a pod were granted, as a JSON list.
+///
+/// Implements the debug path /debug/capabilities/{namespace}/{pod}
+async fn get_capabilities<T: 'static + Provider + Send + Sync>(
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+) -> Result<Response<Body>, Infallible> {
+    match provider.capabilities(namespace, pod).await {
+        Ok(capabilities) => match serde_json::to_string(&capabilities) {
+            Ok(body) => return_with_code(StatusCode::OK, body),
+            Err(e) => return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            ),
+        },
         Err(e) => {
-            error!("Error fetching logs: {}", e);
+            error!("Error fetching capabilities: {}", e);
             if e.is::<NotImplementedError>() {
                 return_with_code(
                     StatusCode::NOT_IMPLEMENTED,
-                    "Logs not implemented in provider.".to_owned(),
+                    "Capabilities not implemented in provider.".to_owned(),
                 )
+            } else if let Some(ProviderError::PodNotFound { .. }) = e.downcast_ref() {
+                return_with_code(StatusCode::NOT_FOUND, e.to_string())
             } else {
                 return_with_code(
                     StatusCode::INTERNAL_SERVER_ERROR,
@@ -88,18 +247,130 @@ async fn get_container_logs<T: 'static + Provider + Send + Sync>(
     }
 }
 
-/// Run a pod exec command and get the output
+/// Forcibly evict a pod, e.g. one which got stuck.
+///
+/// Implements the debug path /debug/evict/{namespace}/{pod}
+async fn evict_pod<T: 'static + Provider + Send + Sync>(
+    provider: Arc<T>,
+    debug_token: Option<String>,
+    authorization: Option<String>,
+    namespace: String,
+    pod: String,
+) -> Result<Response<Body>, Infallible> {
+    let debug_token = match debug_token {
+        Some(debug_token) => debug_token,
+        None => {
+            return return_with_code(
+                StatusCode::FORBIDDEN,
+                "Debug routes which change pods are disabled, as no debug token is configured."
+                    .to_owned(),
+            )
+        }
+    };
+    if !is_authorized(&debug_token, authorization.as_deref()) {
+        warn!(
+            "Rejected unauthorized request to evict pod {} in namespace {}",
+            pod, namespace
+        );
+        return return_with_code(StatusCode::UNAUTHORIZED, "Unauthorized".to_owned());
+    }
+    info!(
+        "Got request to forcibly evict pod {} in namespace {}",
+        pod, namespace
+    );
+    match provider.evict_pod(&PodKey::new(&namespace, &pod)).await {
+        Ok(()) => return_with_code(StatusCode::OK, format!("Evicted pod {}", pod)),
+        Err(e) => {
+            error!("Error evicting pod {}: {:?}", pod, e);
+            if e.is::<NotImplementedError>() {
+                return_with_code(
+                    StatusCode::NOT_IMPLEMENTED,
+                    "Eviction not implemented in provider.".to_owned(),
+                )
+            } else {
+                return_with_code(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Server error: {}", e),
+                )
+            }
+        }
+    }
+}
+
+/// Returns whether the `Authorization` header carries the debug token as a bearer token. The
+/// tokens are compared in constant time, so that the time to answer does not reveal how much of
+/// the debug token was guessed right.
+fn is_authorized(debug_token: &str, authorization: Option<&str>) -> bool {
+    let token = match authorization.and_then(|header| header.strip_prefix("Bearer ")) {
+        Some(token) => token.trim().as_bytes(),
+        None => return false,
+    };
+    let expected = debug_token.as_bytes();
+    token.len() == expected.len()
+        && token
+            .iter()
+            .zip(expected)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Run a pod exec command, streaming its input and output over the websocket.
 ///
 /// Implements the kubelet path /exec/{namespace}/{pod}/{container}
-async fn post_exec<T: 'static + Provider + Send + Sync>(
-    _provider: Arc<T>,
+async fn exec_over_websocket<T: 'static + Provider + Send + Sync>(
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    container: String,
+    query: String,
+    protocols: Option<String>,
+    ws: warp::ws::Ws,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    debug!(
+        "Got exec request for container {} in pod {} in namespace {}. Query: {}.",
+        container, pod, namespace, query
+    );
+    let options = match exec::Options::from_query(&query) {
+        Ok(options) => options,
+        Err(e) => {
+            return Ok(Box::new(return_with_code(
+                StatusCode::BAD_REQUEST,
+                e.to_string(),
+            )?))
+        }
+    };
+    let protocol = match exec::negotiate_protocol(protocols.as_deref()) {
+        Some(protocol) => protocol,
+        None => {
+            return Ok(Box::new(return_with_code(
+                StatusCode::BAD_REQUEST,
+                format!("Unsupported exec protocols {:?}", protocols),
+            )?))
+        }
+    };
+    let reply = ws.on_upgrade(move |websocket| {
+        exec::serve(
+            websocket, protocol, provider, namespace, pod, container, options,
+        )
+    });
+    Ok(Box::new(warp::reply::with_header(
+        reply,
+        "sec-websocket-protocol",
+        protocol,
+    )))
+}
+
+/// Answer exec requests which are not made over a websocket, e.g. with SPDY.
+///
+/// Implements the kubelet path /exec/{namespace}/{pod}/{container}
+async fn post_exec(
     _namespace: String,
     _pod: String,
     _container: String,
 ) -> Result<Response<Body>, Infallible> {
     return_with_code(
         StatusCode::NOT_IMPLEMENTED,
-        "Exec not implemented.".to_string(),
+        "Exec is only supported over websockets.".to_string(),
     )
 }
 
@@ -108,3 +379,17 @@ fn return_with_code(code: StatusCode, body: String) -> Result<Response<Body>, In
     *response.status_mut() = code;
     Ok(response)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_only_the_debug_token_is_authorized() {
+        assert!(is_authorized("s3cr3t", Some("Bearer s3cr3t")));
+        assert!(!is_authorized("s3cr3t", Some("Bearer s3cr3")));
+        assert!(!is_authorized("s3cr3t", Some("Bearer s3cr3T")));
+        assert!(!is_authorized("s3cr3t", Some("Basic s3cr3t")));
+        assert!(!is_authorized("s3cr3t", None));
+    }
+}
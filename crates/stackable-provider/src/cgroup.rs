@@ -0,0 +1,346 @@
+//! Resource constraints of processes via cgroups v2.
+//!
+//! Enforcing constraints is opt-in, see [`crate::StackableProvider::with_cgroups`], as creating
+//! cgroups requires privileges the krustlet may lack. Once enabled, every pod whose container
+//! requests or limits CPU or limits memory gets a cgroup of its own, which its process joins
+//! between fork and exec, so that it never runs unconstrained. The CPU limit becomes the
+//! `cpu.max` quota and the CPU request the `cpu.weight` of the cgroup, converted as the
+//! Kubernetes kubelet converts them, and the memory limit becomes `memory.max`. If the node does
+//! not use cgroups v2 or the cgroup cannot be created, the process is started without
+//! constraints and a warning is logged.
+//!
+//! A process which exceeds its memory limit is killed by the kernel, together with everything
+//! else in its cgroup, which shows in the `oom_kill` counter of the `memory.events` of the cgroup.
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use kubelet::container::Container;
+use kubelet::pod::PodKey;
+use kubelet::resources::parse_quantity;
+use log::debug;
+use nix::libc;
+
+use crate::error::StackableError;
+use crate::error::StackableError::PodValidationError;
+
+/// Where the unified cgroup hierarchy is mounted.
+const CGROUP_MOUNT: &str = "/sys/fs/cgroup";
+
+/// The cgroup the cgroups of the pods are created in, unless configured otherwise via
+/// [`crate::StackableProvider::with_cgroups`].
+pub const DEFAULT_CGROUP_PARENT: &str = "/sys/fs/cgroup/krustlet";
+
+/// The period of the CPU quota in microseconds, which Kubernetes uses as well.
+const CPU_PERIOD: u64 = 100_000;
+
+/// The smallest CPU quota the kernel accepts, in microseconds.
+const MIN_CPU_QUOTA: u64 = 1_000;
+
+/// The bounds of the CPU shares of cgroups v1, which Kubernetes converts CPU requests to.
+const MIN_CPU_SHARES: u64 = 2;
+const MAX_CPU_SHARES: u64 = 262_144;
+
+/// The controllers the cgroups of pods use.
+const CONTROLLERS: &str = "+cpu +memory";
+
+/// The `cpu.weight` of cgroups without a CPU request.
+const DEFAULT_CPU_WEIGHT: u64 = 100;
+
+/// The resource constraints of a process.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ResourceLimits {
+    cpu: Option<CpuLimits>,
+    /// The memory the process may use in bytes, unlimited if unset
+    memory: Option<u64>,
+}
+
+/// The CPU constraints of a process.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct CpuLimits {
+    /// The CPU time the process may use per [`CPU_PERIOD`], unlimited if unset
+    quota: Option<u64>,
+    /// The share of the CPU the process gets relative to others when the CPU is contended
+    weight: u64,
+}
+
+impl ResourceLimits {
+    /// Returns the constraints of the container, if it requests or limits CPU or limits memory.
+    /// As in Kubernetes, a container which only limits CPU requests as much as it may use.
+    pub(crate) fn from_container(container: &Container) -> Result<Option<Self>, StackableError> {
+        let resources = match container.resources() {
+            Some(resources) => resources,
+            None => return Ok(None),
+        };
+        let resource = |quantities: &Option<BTreeMap<String, Quantity>>, name: &str| {
+            quantities
+                .as_ref()
+                .and_then(|quantities| quantities.get(name))
+                .map(|quantity| {
+                    parse_quantity(quantity).map_err(|e| PodValidationError {
+                        msg: format!(
+                            "Invalid {} resource of container {}: {}",
+                            name,
+                            container.name(),
+                            e
+                        ),
+                    })
+                })
+                .transpose()
+        };
+        let cpu_limit = resource(&resources.limits, "cpu")?;
+        let cpu = resource(&resources.requests, "cpu")?
+            .or(cpu_limit)
+            .map(|request| CpuLimits {
+                quota: cpu_limit.map(cpu_quota),
+                weight: cpu_weight(request),
+            });
+        let memory = resource(&resources.limits, "memory")?.map(|bytes| bytes.ceil() as u64);
+        if cpu.is_none() && memory.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(ResourceLimits { cpu, memory }))
+    }
+}
+
+impl CpuLimits {
+    /// Returns the content of `cpu.max`.
+    fn max(&self) -> String {
+        match self.quota {
+            Some(quota) => format!("{} {}", quota, CPU_PERIOD),
+            None => CpuLimits::unlimited(),
+        }
+    }
+
+    /// Returns the content of `cpu.max` without a quota.
+    fn unlimited() -> String {
+        format!("max {}", CPU_PERIOD)
+    }
+}
+
+/// Converts a CPU limit in cores to a quota per [`CPU_PERIOD`].
+fn cpu_quota(cores: f64) -> u64 {
+    ((cores * CPU_PERIOD as f64).ceil() as u64).max(MIN_CPU_QUOTA)
+}
+
+/// Converts a CPU request in cores to a `cpu.weight`, by way of the CPU shares of cgroups v1, as
+/// Kubernetes does.
+fn cpu_weight(cores: f64) -> u64 {
+    let shares = ((cores * 1024.0) as u64)
+        .max(MIN_CPU_SHARES)
+        .min(MAX_CPU_SHARES);
+    1 + (shares - MIN_CPU_SHARES) * 9_999 / (MAX_CPU_SHARES - MIN_CPU_SHARES)
+}
+
+/// The cgroups of the pods on the node, if enforcing resource constraints is enabled.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Cgroups {
+    parent: Option<PathBuf>,
+}
+
+impl Cgroups {
+    /// Enables resource constraints, with the cgroups of the pods below the given one.
+    pub(crate) fn enable(&mut self, parent: PathBuf) {
+        self.parent = Some(parent);
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.parent.is_some()
+    }
+
+    /// Returns the cgroup of the pod, if resource constraints are enabled.
+    fn path(&self, key: &PodKey) -> Option<PathBuf> {
+        let parent = self.parent.as_ref()?;
+        Some(parent.join(format!("{}_{}", key.namespace(), key.name())))
+    }
+
+    /// Creates the cgroup of the pod with the given constraints, or updates them if it exists,
+    /// and returns its path.
+    pub(crate) fn create(&self, key: &PodKey, limits: &ResourceLimits) -> io::Result<PathBuf> {
+        let (parent, path) = match (&self.parent, self.path(key)) {
+            (Some(parent), Some(path)) => (parent, path),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "resource constraints are disabled",
+                ))
+            }
+        };
+        let mount = Path::new(CGROUP_MOUNT);
+        if !mount.join("cgroup.controllers").exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("cgroups v2 are not mounted at {}", CGROUP_MOUNT),
+            ));
+        }
+        fs::create_dir_all(parent)?;
+        // The controllers have to be enabled for the children of every ancestor of the cgroup,
+        // from the top down
+        let ancestors: Vec<&Path> = parent
+            .ancestors()
+            .filter(|ancestor| ancestor.starts_with(mount))
+            .collect();
+        for ancestor in ancestors.into_iter().rev() {
+            fs::write(ancestor.join("cgroup.subtree_control"), CONTROLLERS)?;
+        }
+
+        match fs::create_dir(&path) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+        // Everything is written, so that constraints which were dropped are lifted again
+        let cpu_max = limits
+            .cpu
+            .map_or_else(CpuLimits::unlimited, |cpu| cpu.max());
+        let cpu_weight = limits.cpu.map_or(DEFAULT_CPU_WEIGHT, |cpu| cpu.weight);
+        let memory_max = limits
+            .memory
+            .map_or_else(|| String::from("max"), |bytes| bytes.to_string());
+        fs::write(path.join("cpu.max"), &cpu_max)?;
+        fs::write(path.join("cpu.weight"), cpu_weight.to_string())?;
+        fs::write(path.join("memory.max"), &memory_max)?;
+        // An OOM kill takes down everything in the cgroup, e.g. also commands run next to the
+        // process, instead of leaving parts of the pod behind
+        fs::write(path.join("memory.oom.group"), "1")?;
+        debug!(
+            "Created cgroup {:?} with cpu.max {}, cpu.weight {} and memory.max {}",
+            path, cpu_max, cpu_weight, memory_max
+        );
+        Ok(path)
+    }
+
+    /// Returns how often processes in the cgroup of the pod were killed for exceeding its memory
+    /// limit, if the pod has a cgroup.
+    pub(crate) fn oom_kills(&self, key: &PodKey) -> Option<u64> {
+        let events = fs::read_to_string(self.path(key)?.join("memory.events")).ok()?;
+        parse_oom_kills(&events)
+    }
+
+    /// Removes the cgroup of the pod, once its process is gone.
+    pub(crate) fn remove(&self, key: &PodKey) -> io::Result<()> {
+        let path = match self.path(key) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        match fs::remove_dir(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Returns the `oom_kill` counter of the content of a `memory.events` file.
+fn parse_oom_kills(events: &str) -> Option<u64> {
+    events.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some("oom_kill"), Some(count)) => count.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// Returns the file which lists the processes of the cgroup.
+pub(crate) fn procs_file(cgroup: &Path) -> PathBuf {
+    cgroup.join("cgroup.procs")
+}
+
+/// Moves the calling process into the cgroup whose `cgroup.procs` file is given. Meant to be
+/// called between fork and exec, while still running as root.
+pub(crate) fn join(procs_file: &CStr) -> io::Result<()> {
+    // Safety: open, write and close are async-signal-safe and do not allocate, and the path is
+    // nul-terminated
+    unsafe {
+        let fd = libc::open(procs_file.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Writing 0 moves the writing process
+        let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+        let error = io::Error::last_os_error();
+        libc::close(fd);
+        if written == -1 {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Container as KubeContainer;
+
+    fn container(resources: serde_json::Value) -> Container {
+        let container: KubeContainer = serde_json::from_value(serde_json::json!({
+            "name": "zookeeper",
+            "resources": resources,
+        }))
+        .unwrap();
+        Container::new(&container)
+    }
+
+    fn limits(resources: serde_json::Value) -> Option<ResourceLimits> {
+        ResourceLimits::from_container(&container(resources)).unwrap()
+    }
+
+    #[test]
+    fn test_cpu_resources_are_converted_like_kubernetes_does() {
+        let cpu = limits(serde_json::json!({
+            "requests": { "cpu": "250m" },
+            "limits": { "cpu": "1500m" },
+        }))
+        .unwrap()
+        .cpu
+        .unwrap();
+        assert_eq!(cpu.max(), "150000 100000");
+        assert_eq!(cpu.weight, 10);
+
+        let cpu = limits(serde_json::json!({ "limits": { "cpu": "2" } }))
+            .unwrap()
+            .cpu
+            .unwrap();
+        assert_eq!(cpu.max(), "200000 100000");
+        assert_eq!(cpu.weight, 79);
+
+        let cpu = limits(serde_json::json!({ "requests": { "cpu": "1m" } }))
+            .unwrap()
+            .cpu
+            .unwrap();
+        assert_eq!(cpu.max(), "max 100000");
+        assert_eq!(cpu.weight, 1);
+
+        assert!(
+            ResourceLimits::from_container(&container(serde_json::json!({
+                "limits": { "cpu": "lots" },
+            })))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_memory_limit_is_enforced_without_cpu_resources() {
+        assert_eq!(
+            limits(serde_json::json!({ "limits": { "memory": "512Mi" } })),
+            Some(ResourceLimits {
+                cpu: None,
+                memory: Some(512 * 1024 * 1024),
+            })
+        );
+        // Memory requests are not enforced
+        assert_eq!(
+            limits(serde_json::json!({ "requests": { "memory": "1Gi" } })),
+            None
+        );
+    }
+
+    #[test]
+    fn test_oom_kills_are_read_from_memory_events() {
+        let events = "low 0\nhigh 0\nmax 12\noom 3\noom_kill 2\noom_group_kill 0\n";
+        assert_eq!(parse_oom_kills(events), Some(2));
+        assert_eq!(parse_oom_kills("low 0\n"), None);
+    }
+}
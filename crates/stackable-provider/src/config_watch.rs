@@ -0,0 +1,115 @@
+//! Watching the config maps a pod refers to, so that its process can be restarted with the new
+//! configuration when they change.
+use std::time::Duration;
+
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::{ListParams, Meta, WatchEvent};
+use kube::{Api, Client};
+use kubelet::pod::Pod;
+use log::{debug, info, warn};
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+/// Annotation which enables restarting the process of a pod when one of the config maps it
+/// refers to changes.
+pub const RESTART_ON_CONFIG_CHANGE_ANNOTATION: &str = "stackable.de/restart-on-config-change";
+
+/// How long no further change has to be seen after a change before it is reported, so that a
+/// burst of changes results in a single restart.
+const DEBOUNCE_PERIOD: Duration = Duration::from_secs(5);
+
+/// How long to wait before watching again after the API server could not be reached.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Returns whether the pod asks for its process to be restarted when its config maps change.
+pub(crate) fn restart_on_config_change(pod: &Pod) -> bool {
+    pod.get_annotation(RESTART_ON_CONFIG_CHANGE_ANNOTATION) == Some("true")
+}
+
+/// Completes once one of the given config maps changed and no further change was seen for the
+/// debounce period. Never completes if `names` is empty.
+pub(crate) async fn changed(client: Client, namespace: &str, names: Vec<String>) {
+    if names.is_empty() {
+        futures::future::pending::<()>().await;
+    }
+    let api: Api<ConfigMap> = Api::namespaced(client, namespace);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    let watches = futures::future::join_all(
+        names
+            .iter()
+            .map(|name| watch_config_map(&api, name, sender.clone())),
+    );
+    let debounced = async {
+        receiver.recv().await;
+        // Once a change was seen, further changes only postpone reporting it
+        while let Ok(Some(())) = tokio::time::timeout(DEBOUNCE_PERIOD, receiver.recv()).await {}
+    };
+    futures::pin_mut!(watches, debounced);
+    futures::future::select(watches, debounced).await;
+    info!("Config maps {:?} changed", names);
+}
+
+/// Watches the config map with the given name and sends on `changes` whenever it is added,
+/// modified, or deleted. Only this config map is watched, so that changes of the other config
+/// maps in the namespace neither cause traffic nor require the permission to list them.
+async fn watch_config_map(api: &Api<ConfigMap>, name: &str, changes: UnboundedSender<()>) {
+    let list_params = ListParams::default().fields(&format!("metadata.name={}", name));
+
+    let mut resource_version = current_resource_version(api, &list_params).await;
+    loop {
+        let mut events = match api.watch(&list_params, &resource_version).await {
+            Ok(events) => events.boxed(),
+            Err(e) => {
+                warn!("Unable to watch config map {}: {}", name, e);
+                tokio::time::delay_for(RETRY_DELAY).await;
+                resource_version = current_resource_version(api, &list_params).await;
+                continue;
+            }
+        };
+        loop {
+            let config_map = match events.try_next().await {
+                Ok(Some(WatchEvent::Added(config_map)))
+                | Ok(Some(WatchEvent::Modified(config_map)))
+                | Ok(Some(WatchEvent::Deleted(config_map))) => config_map,
+                Ok(Some(WatchEvent::Error(e))) => {
+                    // Usually the resource version is too old, so start over from the current one
+                    debug!("Watching config map {} failed: {:?}", name, e);
+                    resource_version = current_resource_version(api, &list_params).await;
+                    break;
+                }
+                Ok(Some(_)) => continue,
+                // The API server ends watches after a while, so they have to be renewed
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Watching config map {} failed: {}", name, e);
+                    tokio::time::delay_for(RETRY_DELAY).await;
+                    resource_version = current_resource_version(api, &list_params).await;
+                    break;
+                }
+            };
+            if let Some(version) = Meta::resource_ver(&config_map) {
+                resource_version = version;
+            }
+            debug!("Config map {} changed", name);
+            if changes.send(()).is_err() {
+                // Nobody is interested in changes anymore
+                return;
+            }
+        }
+    }
+}
+
+/// Returns the resource version the config maps selected by `list_params` are at right now, so
+/// that only changes after this point are watched for.
+async fn current_resource_version(api: &Api<ConfigMap>, list_params: &ListParams) -> String {
+    loop {
+        match api.list(list_params).await {
+            Ok(list) => return list.metadata.resource_version.unwrap_or_default(),
+            Err(e) => {
+                warn!("Unable to list config maps: {}", e);
+                tokio::time::delay_for(RETRY_DELAY).await;
+            }
+        }
+    }
+}
@@ -11,22 +11,50 @@ pub enum StackableError {
     Reqwest(#[from] reqwest::Error),
     #[error(transparent)]
     IO(#[from] std::io::Error),
+    #[error(transparent)]
+    Nix(#[from] nix::Error),
     #[error("unable to create repository from received repo object")]
     RepositoryConversionError,
     #[error("error parsing package from containerimage string, has to be in the form of: \"repositoryname/package:version\"")]
     PackageParseError,
+    #[error("Invalid package archive: {msg}")]
+    PackageArchiveError{msg: String},
     #[error("Invalid content in pod object: {msg}")]
     PodValidationError{msg: String},
     #[error(transparent)]
     Kube(#[from] kube::Error),
     #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
     TemplateRenderError(#[from] RenderError),
     #[error(transparent)]
     TemplateError(#[from] TemplateError),
     #[error("A required CRD has not been registered: {missing_crds:?}")]
     CrdMissing{missing_crds: Vec<String>},
+    #[error("No bundled definition exists for CRD {crd}")]
+    CrdDefinitionMissing{crd: String},
+    #[error("Download of package failed: {msg}")]
+    PackageDownloadError{msg: String},
+    #[error("Download of package {package} was cancelled")]
+    PackageDownloadCancelled{package: Package},
+    #[error("Unable to retrieve repository metadata from {url}: {msg}")]
+    RepositoryMetadataError{url: String, msg: String},
+    #[error("Unable to connect to repository {repository}: {msg}")]
+    RepositoryConnectionError{repository: String, msg: String},
+    #[error("Invalid client certificate in secret {secret}: {msg}")]
+    ClientCertificateError{secret: String, msg: String},
     #[error("Package {package} not found in repository")]
     PackageNotFound{package: Package},
+    #[error("Unsupported hash algorithm {algorithm}, supported algorithms are: {supported}")]
+    UnsupportedHashAlgorithm{algorithm: String, supported: String},
+    #[error("Invalid pod IP pool {pool}: {msg}")]
+    InvalidPodIpPool{pool: String, msg: String},
+    #[error("No IP address of the pod IP pool {pool} is free")]
+    PodIpPoolExhausted{pool: String},
+    #[error("Pod {pod} is not running")]
+    PodNotRunning{pod: String},
     #[error("{msg}")]
     RuntimeError{msg: String}
 }
\ No newline at end of file
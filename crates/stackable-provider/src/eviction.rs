@@ -0,0 +1,34 @@
+//! Forcible eviction of pods, to recover from pods which got stuck.
+//!
+//! The process of a pod is owned by its state machine, which may be busy with anything, so an
+//! evicted pod has its process killed through its process record and is marked as evicted. Its
+//! state machine notices the mark instead of restarting the process, and terminates the pod.
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use kubelet::pod::PodKey;
+
+/// The reason reported for pods which were evicted forcibly, as the Kubernetes kubelet does.
+pub const EVICTION_REASON: &str = "Evicted";
+
+/// The pods which were evicted while their state machine was still running.
+#[derive(Clone, Default)]
+pub struct Evictions {
+    pods: Arc<Mutex<HashSet<PodKey>>>,
+}
+
+impl Evictions {
+    pub fn insert(&self, key: PodKey) {
+        self.pods.lock().unwrap().insert(key);
+    }
+
+    pub fn contains(&self, key: &PodKey) -> bool {
+        self.pods.lock().unwrap().contains(key)
+    }
+
+    /// Forgets the pod once its state machine is done, so that a later pod with the same name
+    /// is not taken for evicted.
+    pub fn remove(&self, key: &PodKey) {
+        self.pods.lock().unwrap().remove(key);
+    }
+}
@@ -0,0 +1,303 @@
+//! Runs commands next to the process of a pod, e.g. for `kubectl exec`.
+//!
+//! Commands are run like the process of the pod: with its environment, in its working directory,
+//! as its user and group and with its umask. Processes run directly on the node, so there are no
+//! namespaces to enter, and everybody who may exec into a pod can run any command on the node
+//! with the privileges of its process.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::PathBuf;
+use std::process::{Child, Stdio};
+use std::sync::{Arc, Mutex};
+
+use futures::executor::block_on;
+use kubelet::exec::{Output, Session, TerminalSize};
+use kubelet::pod::PodKey;
+use log::{debug, warn};
+use nix::pty::{openpty, Winsize};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use tokio::sync::mpsc::Receiver;
+use tokio::task::JoinHandle;
+
+use crate::error::StackableError;
+use crate::error::StackableError::{PodNotRunning, PodValidationError, RuntimeError};
+use crate::states::starting::{ProcessSpec, Starting};
+
+/// The size of the chunks the output of a command is read in.
+const OUTPUT_CHUNK_SIZE: usize = 8 * 1024;
+
+/// The pods whose processes are running, next to which commands can be run.
+#[derive(Clone, Default)]
+pub(crate) struct ExecTargets {
+    targets: Arc<Mutex<HashMap<PodKey, ExecTarget>>>,
+}
+
+struct ExecTarget {
+    container: String,
+    spec: ProcessSpec,
+}
+
+/// Keeps a pod available for exec until it is dropped.
+pub(crate) struct Registration {
+    targets: Arc<Mutex<HashMap<PodKey, ExecTarget>>>,
+    key: PodKey,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.targets.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl ExecTargets {
+    /// Makes commands run in the container of the pod run like the given process, as long as
+    /// the returned registration is kept.
+    pub(crate) fn register(
+        &self,
+        key: PodKey,
+        container: &str,
+        spec: &ProcessSpec,
+    ) -> Registration {
+        self.targets.lock().unwrap().insert(
+            key.clone(),
+            ExecTarget {
+                container: container.to_string(),
+                spec: spec.clone(),
+            },
+        );
+        Registration {
+            targets: Arc::clone(&self.targets),
+            key,
+        }
+    }
+
+    /// Stops commands from being run next to the process of the pod, e.g. because the pod was
+    /// evicted.
+    pub(crate) fn remove(&self, key: &PodKey) {
+        self.targets.lock().unwrap().remove(key);
+    }
+
+    /// Runs the command of the session in the container of the pod and returns its exit code.
+    /// Fails if the process of the pod is not running.
+    pub(crate) async fn exec(
+        &self,
+        key: &PodKey,
+        container: &str,
+        mut session: Session,
+    ) -> Result<i32, StackableError> {
+        let target = self
+            .targets
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|target| (target.container.clone(), target.spec.clone()));
+        let (target_container, spec) = target.ok_or_else(|| PodNotRunning { pod: key.name() })?;
+        if target_container != container {
+            return Err(PodValidationError {
+                msg: format!("Pod {} has no container {}", key.name(), container),
+            });
+        }
+
+        let mut command_line = session.options().command.clone();
+        let spec = ProcessSpec {
+            binary: PathBuf::from(command_line.remove(0)),
+            args: command_line,
+            ..spec
+        };
+        debug!(
+            "Executing command {:?} with arguments {:?} in pod {}",
+            spec.binary,
+            spec.args,
+            key.name()
+        );
+        let (child, copies) = if session.options().tty {
+            ExecTargets::spawn_in_terminal(&spec, &mut session)?
+        } else {
+            ExecTargets::spawn_with_pipes(&spec, &mut session)?
+        };
+
+        let status = tokio::task::spawn_blocking(move || {
+            let mut child = child;
+            child.wait()
+        })
+        .await
+        .map_err(|e| RuntimeError { msg: e.to_string() })??;
+        // All output is sent before the result is reported
+        for copy in copies {
+            let _ = copy.await;
+        }
+        Ok(status
+            .code()
+            .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)))
+    }
+
+    /// Starts the command with its standard streams connected to the client by pipes.
+    fn spawn_with_pipes(
+        spec: &ProcessSpec,
+        session: &mut Session,
+    ) -> Result<(Child, Vec<JoinHandle<()>>), StackableError> {
+        let options = session.options().clone();
+        let input = session.take_stdin();
+        let piped = |wanted| {
+            if wanted {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            }
+        };
+        let mut child = Starting::build_command(spec)
+            .stdin(piped(input.is_some()))
+            .stdout(piped(options.stdout))
+            .stderr(piped(options.stderr))
+            .spawn()?;
+
+        if let (Some(input), Some(stdin)) = (input, child.stdin.take()) {
+            forward_input(input, stdin);
+        }
+        let mut copies = vec![];
+        if let Some(stdout) = child.stdout.take() {
+            copies.push(forward_output(stdout, session.output(), false, child.id()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            copies.push(forward_output(stderr, session.output(), true, child.id()));
+        }
+        Ok((child, copies))
+    }
+
+    /// Starts the command in a new terminal, which becomes the controlling terminal of the
+    /// command, so that shells can do job control.
+    fn spawn_in_terminal(
+        spec: &ProcessSpec,
+        session: &mut Session,
+    ) -> Result<(Child, Vec<JoinHandle<()>>), StackableError> {
+        let terminal = openpty(None, None)?;
+        // Safety: openpty returned two new file descriptors, which nothing else owns
+        let (master, slave) = unsafe {
+            (
+                File::from_raw_fd(terminal.master),
+                File::from_raw_fd(terminal.slave),
+            )
+        };
+        let mut command = Starting::build_command(spec);
+        command
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave));
+        // Safety: setsid and ioctl are async-signal-safe and do not allocate, so they may be
+        // called between fork and exec
+        unsafe {
+            command.pre_exec(|| {
+                if nix::libc::setsid() == -1 || nix::libc::ioctl(0, nix::libc::TIOCSCTTY, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+        // The terminal reports the end of the output only once the command holds its last end
+        drop(command);
+
+        if let Some(input) = session.take_stdin() {
+            forward_input(input, master.try_clone()?);
+        }
+        if let Some(resize) = session.take_resize() {
+            tokio::spawn(forward_resize(resize, master.try_clone()?));
+        }
+        let copy = forward_output(master, session.output(), false, child.id());
+        Ok((child, vec![copy]))
+    }
+}
+
+/// Writes the input of the client to the command until the client disconnects.
+fn forward_input<W: Write + Send + 'static>(mut input: Receiver<Vec<u8>>, mut writer: W) {
+    tokio::task::spawn_blocking(move || {
+        while let Some(data) = block_on(input.recv()) {
+            if writer.write_all(&data).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Sends everything the command writes to the client. The command is killed if the client
+/// disconnects, as nobody could see its output anymore.
+fn forward_output<R: Read + Send + 'static>(
+    mut reader: R,
+    mut output: Output,
+    stderr: bool,
+    pid: u32,
+) -> JoinHandle<()> {
+    tokio::task::spawn_blocking(move || {
+        let mut buffer = [0u8; OUTPUT_CHUNK_SIZE];
+        loop {
+            // Reading from a terminal fails once the command has closed it
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            let data = &buffer[..read];
+            let sent = if stderr {
+                block_on(output.stderr(data))
+            } else {
+                block_on(output.stdout(data))
+            };
+            if sent.is_err() {
+                debug!("Exec client disconnected, killing process {}", pid);
+                let _ = kill(Pid::from_raw(pid as i32), Signal::SIGKILL);
+                break;
+            }
+        }
+    })
+}
+
+/// Applies the size of the terminal of the client to the terminal of the command.
+async fn forward_resize(mut resize: Receiver<TerminalSize>, master: File) {
+    while let Some(size) = resize.recv().await {
+        let size = Winsize {
+            ws_row: size.height,
+            ws_col: size.width,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // Safety: the file descriptor is open as long as `master` lives and the size is only read
+        if unsafe { nix::libc::ioctl(master.as_raw_fd(), nix::libc::TIOCSWINSZ, &size) } == -1 {
+            warn!(
+                "Unable to resize terminal: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pod_is_exec_target_while_registered() {
+        let targets = ExecTargets::default();
+        let key = PodKey::new("default", "zookeeper");
+        let spec = ProcessSpec {
+            binary: PathBuf::from("/bin/sh"),
+            args: vec![],
+            env: HashMap::new(),
+            working_directory: std::env::temp_dir(),
+            uid: None,
+            gid: None,
+            umask: crate::DEFAULT_UMASK,
+            hosts_file: None,
+            cgroup: None,
+        };
+
+        let registration = targets.register(key.clone(), "zookeeper", &spec);
+        assert!(targets.targets.lock().unwrap().contains_key(&key));
+        drop(registration);
+        assert!(targets.targets.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,127 @@
+//! Entries for `/etc/hosts` from the `hostAliases` of pods.
+//!
+//! Processes run directly on the node and share its `/etc/hosts`, so the entries of a pod cannot
+//! be added to it. Instead every pod with host aliases gets a hosts file of its own, made of the
+//! hosts file of the node followed by the aliases, and its process is started in a mount
+//! namespace of its own, in which that file is bind mounted over `/etc/hosts`.
+//!
+//! This has some limitations:
+//! - Creating the namespace requires the krustlet to run as root, pods with host aliases fail
+//!   otherwise.
+//! - The hosts file of the pod is written when its process is started, so changes to the hosts
+//!   file of the node only reach the process when it is restarted.
+//! - Only name resolution which reads `/etc/hosts` sees the aliases, e.g. a process which queries
+//!   a DNS server directly does not.
+//!
+//! The `HOSTALIASES` environment variable is not used, as it can only map names to other names
+//! and not to addresses, and is only honoured by glibc.
+use std::ffi::CStr;
+use std::io;
+use std::path::Path;
+use std::ptr;
+
+use k8s_openapi::api::core::v1::HostAlias;
+use kubelet::pod::Pod;
+use nix::libc;
+
+use crate::error::StackableError;
+
+const NODE_HOSTS_FILE: &str = "/etc/hosts";
+
+/// Returns the host aliases of the pod.
+pub(crate) fn host_aliases(pod: &Pod) -> Vec<HostAlias> {
+    pod.as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.host_aliases.clone())
+        .unwrap_or_default()
+}
+
+/// Appends the aliases to the hosts file of the node, in the format the Kubernetes kubelet uses.
+fn render(node_hosts: &str, aliases: &[HostAlias]) -> String {
+    let mut hosts = String::from(node_hosts);
+    if !hosts.is_empty() && !hosts.ends_with('\n') {
+        hosts.push('\n');
+    }
+    hosts.push_str("\n# Entries added by HostAliases.\n");
+    for alias in aliases {
+        let hostnames = alias.hostnames.as_deref().unwrap_or_default();
+        if let (Some(ip), false) = (&alias.ip, hostnames.is_empty()) {
+            hosts.push_str(&format!("{}\t{}\n", ip, hostnames.join("\t")));
+        }
+    }
+    hosts
+}
+
+/// Writes the hosts file of a pod with the given aliases.
+pub(crate) fn write_hosts_file(path: &Path, aliases: &[HostAlias]) -> Result<(), StackableError> {
+    let node_hosts = match std::fs::read_to_string(NODE_HOSTS_FILE) {
+        Ok(node_hosts) => node_hosts,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, render(&node_hosts, aliases))?;
+    Ok(())
+}
+
+/// Moves the calling process into a new mount namespace and bind mounts the hosts file over
+/// `/etc/hosts` in it. Meant to be called between fork and exec, while still running as root.
+pub(crate) fn enter_namespace(hosts_file: &CStr) -> io::Result<()> {
+    let root = b"/\0".as_ptr() as *const libc::c_char;
+    let node_hosts_file = b"/etc/hosts\0".as_ptr() as *const libc::c_char;
+    // Safety: unshare and mount are async-signal-safe and do not allocate, and all strings are
+    // nul-terminated
+    unsafe {
+        if libc::unshare(libc::CLONE_NEWNS) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // The bind mount must not propagate back to the mount namespace of the node
+        if libc::mount(
+            ptr::null(),
+            root,
+            ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            ptr::null(),
+        ) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::mount(
+            hosts_file.as_ptr(),
+            node_hosts_file,
+            ptr::null(),
+            libc::MS_BIND,
+            ptr::null(),
+        ) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_aliases_are_appended_to_node_hosts() {
+        let aliases = vec![
+            HostAlias {
+                ip: Some(String::from("10.1.2.3")),
+                hostnames: Some(vec![String::from("foo.local"), String::from("bar.local")]),
+            },
+            HostAlias {
+                ip: Some(String::from("10.1.2.4")),
+                hostnames: None,
+            },
+        ];
+        assert_eq!(
+            render("127.0.0.1\tlocalhost", &aliases),
+            "127.0.0.1\tlocalhost\n\n# Entries added by HostAliases.\n10.1.2.3\tfoo.local\tbar.local\n"
+        );
+    }
+}
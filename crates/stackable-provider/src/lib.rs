@@ -1,6 +1,6 @@
-use kubelet::provider::Provider;
+use kubelet::provider::{Provider, ProviderError};
 use kubelet::log::Sender;
-use kubelet::pod::{Pod, PodKey};
+use kubelet::pod::{make_status, patch_status, Phase, Pod, PodKey};
 
 use crate::states::failed::Failed;
 use kubelet::backoff::ExponentialBackoffStrategy;
@@ -8,44 +8,137 @@ use kubelet::node::Builder;
 use crate::states::terminated::Terminated;
 use crate::states::download_package::Downloading;
 use kube::{Client, Api};
+use kube::api::{ListParams, PostParams};
+use kube::error::ErrorResponse;
+use k8s_openapi::api::core::v1::Pod as KubePod;
 use crate::error::StackableError;
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
-use crate::error::StackableError::{CrdMissing, PodValidationError};
-use log::{debug, info, error};
+use crate::error::StackableError::{CrdDefinitionMissing, CrdMissing, PodValidationError};
+use log::{debug, info, error, warn};
 use std::path::PathBuf;
+use std::net::IpAddr;
 use std::fs;
 use crate::repository::package::Package;
 use std::convert::TryFrom;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::parcel_gc::{PackageUsage, DEFAULT_PARCEL_GC_GRACE_PERIOD};
 use tokio::sync::Notify;
-use std::process::Child;
+use tokio::sync::Mutex as TokioMutex;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::process::{ProcessHandle, ProcessRecords};
+use crate::exec::ExecTargets;
+use crate::states::starting::ProcessSpec;
+use kubelet::exec::Session;
+use crate::repository::{KubeRepositories, RepositoryLookup, REPOSITORY_CRD};
+use crate::eviction::{Evictions, EVICTION_REASON};
+use crate::pod_ip::{make_ip_status, PodIps};
+use crate::cgroup::Cgroups;
+use nix::sys::signal::Signal;
 
 pub struct StackableProvider {
     client: Client,
     parcel_directory: PathBuf,
     config_directory: PathBuf,
+    log_directory: PathBuf,
+    max_package_size: u64,
+    umask: u32,
+    max_restarts: usize,
+    max_pods: Option<usize>,
+    download_backoff: ExponentialBackoffStrategy,
+    package_usage: Arc<Mutex<PackageUsage>>,
+    port_map: Arc<TokioMutex<BTreeMap<u16, PodKey>>>,
+    exec_targets: ExecTargets,
+    repositories: Arc<dyn RepositoryLookup>,
+    process_records: ProcessRecords,
+    evictions: Evictions,
+    pod_ips: PodIps,
+    cgroups: Cgroups,
 }
 
-pub const CRDS: &'static [&'static str] = &["repositories.stable.stackable.de"];
+pub const CRDS: &'static [&'static str] = &[REPOSITORY_CRD];
+
+/// The definitions of the CRDs in [`CRDS`], which are registered with the API server if they
+/// are missing and the provider is created with `register_crds` enabled.
+const CRD_DEFINITIONS: &'static [(&'static str, &'static str)] = &[(
+    REPOSITORY_CRD,
+    include_str!("../crds/repository.yaml"),
+)];
+
+/// The maximum size in bytes the content of a package archive may have once unpacked, unless
+/// configured otherwise via [`StackableProvider::with_max_package_size`].
+pub const DEFAULT_MAX_PACKAGE_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// The umask processes are started with, unless configured otherwise via
+/// [`StackableProvider::with_umask`].
+pub const DEFAULT_UMASK: u32 = 0o022;
+
+/// How often a failed process is restarted before its pod is given up on, unless configured
+/// otherwise via [`StackableProvider::with_max_restarts`].
+pub const DEFAULT_MAX_RESTARTS: usize = 5;
+
+/// By how much the delays between download attempts are randomly lengthened, so that pods
+/// which failed at the same time do not all retry at once, unless configured otherwise via
+/// [`StackableProvider::with_download_backoff`].
+pub const DEFAULT_DOWNLOAD_BACKOFF_JITTER: f64 = 0.2;
 
 
 mod states;
 mod repository;
 mod error;
+mod config_watch;
+mod parcel_gc;
+mod probe;
+mod exec;
+mod process;
+mod host_aliases;
+mod eviction;
+mod pod_ip;
+mod lifecycle;
+mod cgroup;
+
+pub use crate::repository::package::Package;
+pub use crate::pod_ip::PodIpPool;
+pub use crate::cgroup::DEFAULT_CGROUP_PARENT;
+pub use crate::config_watch::RESTART_ON_CONFIG_CHANGE_ANNOTATION;
+pub use crate::states::create_service::CREATE_SERVICE_ANNOTATION;
 
 pub struct PodState {
     client: Client,
     parcel_directory: PathBuf,
     download_directory: PathBuf,
     config_directory: PathBuf,
+    log_file: PathBuf,
     package_download_backoff_strategy: ExponentialBackoffStrategy,
     package: Package,
     pod_changed: Arc<Notify>,
-    process_handle: Option<Child>,
+    process_handle: Option<ProcessHandle>,
+    process_spec: Option<ProcessSpec>,
+    max_package_size: u64,
+    umask: u32,
+    errors: usize,
+    /// How often the process was restarted after failing, as reported in the container status
+    restart_count: i32,
+    max_restarts: usize,
+    restart_backoff_strategy: ExponentialBackoffStrategy,
+    pod_key: PodKey,
+    package_usage: Arc<Mutex<PackageUsage>>,
+    port_map: Arc<TokioMutex<BTreeMap<u16, PodKey>>>,
+    exec_targets: ExecTargets,
+    repositories: Arc<dyn RepositoryLookup>,
+    process_records: ProcessRecords,
+    evictions: Evictions,
+    pod_ips: PodIps,
+    cgroups: Cgroups,
+    /// How often processes in the cgroup of the pod were killed for exceeding its memory limit
+    /// before the current process was started
+    oom_kills: Option<u64>,
+    /// Whether the last process of the pod was killed for exceeding its memory limit
+    oom_killed: bool,
 }
 
 impl PodState {
-    pub fn take_handle(mut self) -> Option<Child> {
+    pub fn take_handle(mut self) -> Option<ProcessHandle> {
         let result = self.process_handle;
         self.process_handle = None;
         result
@@ -53,22 +146,152 @@ impl PodState {
 }
 
 impl StackableProvider {
-    pub async fn new(client: Client, parcel_directory: PathBuf, config_directory: PathBuf) -> Result<Self, StackableError> {
+    /// Creates the provider after checking that all required CRDs are registered.
+    ///
+    /// Missing CRDs are an error, unless `register_crds` is set, in which case they are created
+    /// from the definitions bundled with the provider.
+    ///
+    /// The processes of pods which are still running from before a restart of the krustlet keep
+    /// their ports, and are re-adopted once their pods are started again. Processes of pods which
+    /// were deleted from the node `node_name` meanwhile are killed instead.
+    pub async fn new(
+        client: Client,
+        node_name: &str,
+        parcel_directory: PathBuf,
+        config_directory: PathBuf,
+        register_crds: bool,
+    ) -> Result<Self, StackableError> {
+        let log_directory = parcel_directory.join("_logs");
+        let package_usage = Arc::new(Mutex::new(PackageUsage::new(DEFAULT_PARCEL_GC_GRACE_PERIOD)));
+        tokio::spawn(parcel_gc::run(
+            Arc::clone(&package_usage),
+            parcel_directory.clone(),
+            parcel_directory.join("_download"),
+        ));
+        let process_records = ProcessRecords::new(parcel_directory.join("_processes"));
+        let mut port_map = BTreeMap::new();
+        let pods_on_node = pods_on_node(&client, node_name).await;
+        for record in process_records.load_all() {
+            let pod_exists = pods_on_node.as_ref().map_or(true, |pods| pods.contains(&record.pod_key()));
+            if !record.is_running() {
+                debug!("Process {} of pod {} has exited since the last run", record.pid, record.name);
+                process_records.remove(&record.pod_key());
+            } else if !pod_exists {
+                info!("Process {} of pod {} is still running, but the pod was deleted meanwhile, killing it", record.pid, record.name);
+                if let Err(e) = record.adopt().signal(Signal::SIGKILL) {
+                    warn!("Failed to kill process {} of deleted pod {}: {}", record.pid, record.name, e);
+                }
+                process_records.remove(&record.pod_key());
+            } else {
+                info!("Process {} of pod {} is still running, reserving its ports {:?}", record.pid, record.name, record.ports);
+                for port in &record.ports {
+                    port_map.insert(*port, record.pod_key());
+                }
+            }
+        }
         let provider = StackableProvider {
+            repositories: Arc::new(KubeRepositories::new(client.clone())),
             client,
             parcel_directory,
             config_directory,
+            log_directory,
+            max_package_size: DEFAULT_MAX_PACKAGE_SIZE,
+            umask: DEFAULT_UMASK,
+            max_restarts: DEFAULT_MAX_RESTARTS,
+            max_pods: None,
+            download_backoff: ExponentialBackoffStrategy::default().with_jitter(DEFAULT_DOWNLOAD_BACKOFF_JITTER),
+            package_usage,
+            port_map: Arc::new(TokioMutex::new(port_map)),
+            exec_targets: Default::default(),
+            process_records,
+            evictions: Default::default(),
+            pod_ips: Default::default(),
+            cgroups: Default::default(),
         };
         let missing_crds = provider.check_crds().await;
         if missing_crds.is_empty() {
             debug!("All required CRDS present!");
             return Ok(provider);
+        } else if register_crds {
+            provider.register_crds(&missing_crds).await?;
+            return Ok(provider);
         } else {
             debug!("Missing required CDRS");
             return Err(CrdMissing { missing_crds });
         }
     }
 
+    /// Sets the maximum size in bytes the content of a package archive may have once unpacked.
+    /// Larger packages are refused during installation.
+    pub fn with_max_package_size(mut self, max_package_size: u64) -> Self {
+        self.max_package_size = max_package_size;
+        self
+    }
+
+    /// Sets the umask the processes of all pods are started with.
+    pub fn with_umask(mut self, umask: u32) -> Self {
+        self.umask = umask;
+        self
+    }
+
+    /// Sets how often the process of a pod is restarted after failing before the pod is
+    /// considered failed for good. Earlier failures are forgotten once the process has been
+    /// running for a while.
+    pub fn with_max_restarts(mut self, max_restarts: usize) -> Self {
+        self.max_restarts = max_restarts;
+        self
+    }
+
+    /// Sets how long to wait between attempts to download the package of a pod. Once the
+    /// retries of the strategy are exhausted, the pod is failed instead of trying again.
+    /// Defaults to the Kubernetes backoff with a jitter of [`DEFAULT_DOWNLOAD_BACKOFF_JITTER`]
+    /// and unlimited retries.
+    pub fn with_download_backoff(mut self, backoff: ExponentialBackoffStrategy) -> Self {
+        self.download_backoff = backoff;
+        self
+    }
+
+    /// Sets how many pods the node advertises as its `pods` capacity and allocatable, so that the
+    /// scheduler does not place more processes on the node than it can run. Without a limit the
+    /// node advertises the `max_pods` of the kubelet configuration.
+    pub fn with_max_pods(mut self, max_pods: usize) -> Self {
+        self.max_pods = Some(max_pods);
+        self
+    }
+
+    /// Sets the IP of the node, which pods report as their host IP, and as their pod IP unless
+    /// they get one from a pool.
+    pub fn with_node_ip(mut self, node_ip: IpAddr) -> Self {
+        self.pod_ips.set_node_ip(node_ip);
+        self
+    }
+
+    /// Gives every pod an address of its own from the pool instead of the IP of the node, except
+    /// for pods with `hostNetwork` enabled. This is experimental: the addresses are reported in
+    /// the status of the pods, but are not configured on the node, so processes still listen on
+    /// the addresses of the node. The pool has to be routed to the node and its addresses have to
+    /// be local to it, e.g. by adding the pool to the loopback interface, for the pods to be
+    /// reachable under their IPs.
+    pub fn with_pod_ip_pool(mut self, pool: PodIpPool) -> Self {
+        self.pod_ips.set_pool(pool);
+        self
+    }
+
+    /// Enforces the CPU requests and limits and the memory limits of containers with cgroups v2,
+    /// creating the cgroups of the pods below the given one, e.g. [`DEFAULT_CGROUP_PARENT`].
+    /// This requires the krustlet to run as root on a node which uses cgroups v2, otherwise
+    /// processes run unconstrained.
+    pub fn with_cgroups(mut self, parent: PathBuf) -> Self {
+        self.cgroups.enable(parent);
+        self
+    }
+
+    /// Sets how long an installed parcel has to be unused by any pod before it is removed.
+    pub fn with_parcel_gc_grace_period(self, grace_period: Duration) -> Self {
+        self.package_usage.lock().unwrap().set_grace_period(grace_period);
+        self
+    }
+
     fn get_package(&self, pod: &Pod) -> Result<Package, StackableError> {
         let containers = pod.containers();
         if (containers.len().ne(&1)) {
@@ -77,7 +300,21 @@ impl StackableProvider {
         } else {
             // List has exactly one value, try to parse this
             if let Ok(Some(reference)) = containers[0].image() {
-                return Package::try_from(reference);
+                let package = Package::try_from(reference)?;
+                // Init containers run commands of the package of the pod, as it is the only one
+                // which gets installed
+                for init_container in pod.init_containers() {
+                    let init_package = match init_container.image() {
+                        Ok(Some(reference)) => Package::try_from(reference)?,
+                        _ => {
+                            return Err(PodValidationError { msg: format!("Unable to get package reference of init container {}", init_container.name()) });
+                        }
+                    };
+                    if init_package != package {
+                        return Err(PodValidationError { msg: format!("Init container {} uses package {}, but only package {} of the container is installed", init_container.name(), init_package, package) });
+                    }
+                }
+                return Ok(package);
             } else {
                 let e = PodValidationError { msg: String::from("Unable to get package reference from pod") };
                 return Err(e);
@@ -104,12 +341,86 @@ impl StackableProvider {
         }
         missing_crds
     }
+
+    /// Creates the given CRDs from their bundled definitions.
+    async fn register_crds(&self, missing_crds: &[String]) -> Result<(), StackableError> {
+        let crds: Api<CustomResourceDefinition> = Api::all(self.client.clone());
+        for crd in missing_crds {
+            info!("Registering missing CRD \"{}\"", crd);
+            crds.create(&PostParams::default(), &bundled_crd(crd)?).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns all packages that are installed in the parcel directory.
+    pub fn installed_packages(&self) -> Vec<Package> {
+        parcel_gc::installed_packages(&self.parcel_directory)
+    }
+
+    /// Returns the path of the file that stdout and stderr of the process started for the
+    /// given pod are written to.
+    fn get_log_file(&self, namespace: &str, pod: &str) -> PathBuf {
+        self.log_directory.join(format!("{}-{}.log", namespace, pod))
+    }
+}
+
+/// Parses the bundled definition of the given CRD.
+fn bundled_crd(name: &str) -> Result<CustomResourceDefinition, StackableError> {
+    let (_, definition) = CRD_DEFINITIONS
+        .iter()
+        .find(|(crd, _)| *crd == name)
+        .ok_or_else(|| CrdDefinitionMissing { crd: String::from(name) })?;
+    Ok(serde_yaml::from_str(definition)?)
 }
 
 // No cleanup state needed, we clean up when dropping PodState.
 #[async_trait::async_trait]
 impl kubelet::state::AsyncDrop for PodState {
-    async fn async_drop(self) {}
+    async fn async_drop(self) {
+        // The log file is kept after the process terminated, so that the logs of failed pods can
+        // still be read, and only removed once the pod is gone
+        match tokio::fs::remove_file(&self.log_file).await {
+            Ok(()) => debug!("Removed log file {:?}", self.log_file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove log file {:?}: {}", self.log_file, e),
+        }
+        self.package_usage.lock().unwrap().release(&self.package, &self.pod_key);
+        self.evictions.remove(&self.pod_key);
+        self.pod_ips.release(&self.pod_key);
+        release_ports(&self.port_map, &self.pod_key).await;
+    }
+}
+
+/// Returns the pods bound to the node, or `None` if they cannot be listed, in which case all
+/// processes which are still running have to be assumed to belong to existing pods.
+async fn pods_on_node(client: &Client, node_name: &str) -> Option<BTreeSet<PodKey>> {
+    let api: Api<KubePod> = Api::all(client.clone());
+    let list_params = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
+    match api.list(&list_params).await {
+        Ok(pods) => Some(pods.iter().map(|pod| PodKey::from(Pod::from(pod.clone()))).collect()),
+        Err(e) => {
+            warn!("Unable to list the pods of node {}, keeping all processes which are still running: {}", node_name, e);
+            None
+        }
+    }
+}
+
+/// Releases the host ports reserved for the pod.
+async fn release_ports(port_map: &TokioMutex<BTreeMap<u16, PodKey>>, pod_key: &PodKey) {
+    let mut lock = port_map.lock().await;
+    let ports_to_remove: Vec<u16> = lock
+        .iter()
+        .filter_map(|(k, v)| if v == pod_key { Some(*k) } else { None })
+        .collect();
+    debug!(
+        "Pod {} in namespace {} releasing ports {:?}.",
+        pod_key.name(),
+        pod_key.namespace(),
+        &ports_to_remove
+    );
+    for port in ports_to_remove {
+        lock.remove(&port);
+    }
 }
 
 #[async_trait::async_trait]
@@ -125,13 +436,23 @@ impl Provider for StackableProvider {
         builder.set_architecture(Self::ARCH);
         builder.add_taint("NoSchedule", "kubernetes.io/arch", Self::ARCH);
         builder.add_taint("NoExecute", "kubernetes.io/arch", Self::ARCH);
+        if let Some(max_pods) = self.max_pods {
+            builder.add_capacity("pods", &max_pods.to_string());
+            builder.add_allocatable("pods", &max_pods.to_string());
+        }
         Ok(())
     }
 
     async fn initialize_pod_state(&self, pod: &Pod, pod_changed: Arc<Notify>) -> anyhow::Result<Self::PodState> {
+        if !pod.runs_on_architecture(Self::ARCH) {
+            let message = format!("Pod {} selects an architecture other than {}, which this node runs", pod.name(), Self::ARCH);
+            kubelet::pod::reject_pod(&self.client, pod, "UnsupportedArchitecture", &message).await;
+            return Err(anyhow::anyhow!(message));
+        }
         let parcel_directory = self.parcel_directory.clone();
         let download_directory = parcel_directory.join("_download");
         let config_directory = self.config_directory.clone();
+        let log_directory = self.log_directory.clone();
 
         let package = self.get_package(pod)?;
         if !(&download_directory.is_dir()) {
@@ -140,20 +461,289 @@ impl Provider for StackableProvider {
         if !(&config_directory.is_dir()) {
             fs::create_dir_all(&config_directory)?;
         }
+        if !(&log_directory.is_dir()) {
+            fs::create_dir_all(&log_directory)?;
+        }
+
+        let pod_ip = match self.pod_ips.assign(pod) {
+            Ok(pod_ip) => pod_ip,
+            Err(e) => {
+                kubelet::pod::reject_pod(&self.client, pod, "PodIpPoolExhausted", &e.to_string()).await;
+                return Err(e.into());
+            }
+        };
+        if let Some(pod_ip) = pod_ip {
+            let api: Api<KubePod> = Api::namespaced(self.client.clone(), pod.namespace());
+            patch_status(&api, pod.name(), make_ip_status(self.pod_ips.node_ip(), pod_ip)).await;
+        }
 
+        // Only mark the package as used once nothing can fail anymore, as it is released when the
+        // pod state is dropped
+        let pod_key = PodKey::from(pod);
+        self.package_usage.lock().unwrap().acquire(&package, pod_key.clone());
         Ok(PodState {
             client: self.client.clone(),
             parcel_directory,
             download_directory,
             config_directory: self.config_directory.clone(),
-            package_download_backoff_strategy: ExponentialBackoffStrategy::default(),
+            log_file: self.get_log_file(pod.namespace(), pod.name()),
+            package_download_backoff_strategy: self.download_backoff.clone(),
             package,
             pod_changed,
             process_handle: None,
+            process_spec: None,
+            max_package_size: self.max_package_size,
+            umask: self.umask,
+            errors: 0,
+            restart_count: 0,
+            max_restarts: self.max_restarts,
+            restart_backoff_strategy: ExponentialBackoffStrategy::default(),
+            pod_key,
+            package_usage: Arc::clone(&self.package_usage),
+            port_map: Arc::clone(&self.port_map),
+            exec_targets: self.exec_targets.clone(),
+            repositories: Arc::clone(&self.repositories),
+            process_records: self.process_records.clone(),
+            evictions: self.evictions.clone(),
+            pod_ips: self.pod_ips.clone(),
+            cgroups: self.cgroups.clone(),
+            oom_kills: None,
+            oom_killed: false,
         })
     }
 
     async fn logs(&self, namespace: String, pod: String, container: String, sender: Sender) -> anyhow::Result<()> {
+        let log_file = self.get_log_file(&namespace, &pod);
+        debug!("Streaming logs for container {} of pod {} from {:?}", container, pod, log_file);
+        if !log_file.is_file() {
+            return Err(ProviderError::PodNotFound { pod_name: pod }.into());
+        }
+        let mut handle = tokio::fs::File::open(&log_file).await?;
+        if let Some(n) = sender.tail() {
+            kubelet::log::seek_to_tail(&mut handle, n).await?;
+        }
+        // Streaming runs in its own task, so a slow client does not hold up the provider
+        tokio::spawn(kubelet::log::stream(handle, sender));
         Ok(())
     }
+
+    async fn exec(&self, namespace: String, pod: String, container: String, session: Session) -> anyhow::Result<i32> {
+        let key = PodKey::new(&namespace, &pod);
+        Ok(self.exec_targets.exec(&key, &container, session).await?)
+    }
+
+    /// Kills the process of the pod and releases its ports right away, and reports the pod as
+    /// failed with the reason `Evicted`. The state machine of the pod then terminates it instead
+    /// of restarting the process. A pod which has not started its process yet, e.g. because its
+    /// package is still being downloaded, is terminated once it would start it.
+    async fn evict_pod(&self, pod_key: &PodKey) -> anyhow::Result<()> {
+        info!("Forcibly evicting pod {} in namespace {}", pod_key.name(), pod_key.namespace());
+        let api: Api<KubePod> = Api::namespaced(self.client.clone(), &pod_key.namespace());
+        let pod_exists = match api.get(&pod_key.name()).await {
+            Ok(_) => true,
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => false,
+            Err(e) => return Err(e.into()),
+        };
+        // Without the pod there is no state machine which would ever forget the mark
+        if pod_exists {
+            self.evictions.insert(pod_key.clone());
+        }
+        self.exec_targets.remove(pod_key);
+        if let Some(record) = self.process_records.load(pod_key) {
+            if record.is_running() {
+                info!("Killing process {} of evicted pod {}", record.pid, pod_key.name());
+                if let Err(e) = record.adopt().signal(Signal::SIGKILL) {
+                    warn!("Failed to kill process {} of evicted pod {}: {}", record.pid, pod_key.name(), e);
+                }
+            }
+            if !pod_exists {
+                self.process_records.remove(pod_key);
+            }
+        }
+        release_ports(&self.port_map, pod_key).await;
+
+        if pod_exists {
+            patch_status(&api, &pod_key.name(), make_status(Phase::Failed, EVICTION_REASON)?).await;
+        } else {
+            info!("Evicted pod {} is gone already", pod_key.name());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repository::mock::MockRepository;
+    use crate::states::download_package::Downloading;
+    use crate::states::install_package::Installing;
+    use crate::states::running::Running;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+    use kubelet::state::State;
+    use serde_json::json;
+    use std::path::Path;
+
+    #[test]
+    fn test_all_required_crds_are_bundled() {
+        for crd in CRDS {
+            let definition = bundled_crd(crd).unwrap();
+            assert_eq!(definition.metadata.name.as_deref(), Some(*crd));
+        }
+        assert!(bundled_crd("unknown.stable.stackable.de").is_err());
+    }
+
+    fn test_package() -> Package {
+        Package {
+            product: String::from("zookeeper"),
+            version: String::from("3.4.14"),
+        }
+    }
+
+    fn test_pod() -> Pod {
+        let pod: KubePod = serde_json::from_value(json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "zookeeper", "namespace": "default" },
+            "spec": {
+                "containers": [{ "name": "zookeeper", "image": "zookeeper:3.4.14" }]
+            }
+        }))
+        .unwrap();
+        Pod::from(pod)
+    }
+
+    /// Returns the state of a pod which runs the test package, with all its directories in
+    /// `directory`. The API server the client points to does not exist.
+    fn pod_state(directory: &Path, repositories: Arc<dyn RepositoryLookup>) -> PodState {
+        let parcel_directory = directory.join("parcels");
+        let download_directory = parcel_directory.join("_download");
+        fs::create_dir_all(&download_directory).unwrap();
+        PodState {
+            client: Client::new(kube::Config::new("http://127.0.0.1:1".parse().unwrap())),
+            parcel_directory,
+            download_directory,
+            config_directory: directory.join("config"),
+            log_file: directory.join("zookeeper.log"),
+            package_download_backoff_strategy: ExponentialBackoffStrategy::default(),
+            package: test_package(),
+            pod_changed: Arc::new(Notify::new()),
+            process_handle: None,
+            process_spec: None,
+            max_package_size: DEFAULT_MAX_PACKAGE_SIZE,
+            umask: DEFAULT_UMASK,
+            errors: 0,
+            restart_count: 0,
+            max_restarts: DEFAULT_MAX_RESTARTS,
+            restart_backoff_strategy: ExponentialBackoffStrategy::default(),
+            pod_key: PodKey::new("default", "zookeeper"),
+            package_usage: Arc::new(Mutex::new(PackageUsage::new(DEFAULT_PARCEL_GC_GRACE_PERIOD))),
+            port_map: Default::default(),
+            exec_targets: Default::default(),
+            repositories,
+            process_records: ProcessRecords::new(directory.join("processes")),
+            evictions: Default::default(),
+            pod_ips: Default::default(),
+            cgroups: Default::default(),
+            oom_kills: None,
+            oom_killed: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_package_is_downloaded_and_installed() {
+        let directory = tempfile::tempdir().unwrap();
+        let archive = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/valid-package.tar.gz")).unwrap();
+        let repository = MockRepository::default().with_package(test_package(), archive);
+        let mut pod_state = pod_state(directory.path(), Arc::new(repository.clone()));
+        let pod = test_pod();
+
+        let transition = Box::new(Downloading).next(&mut pod_state, &pod).await;
+        assert_eq!(transition.state_name(), Some("Installing"));
+        assert!(pod_state.download_directory.join(test_package().get_file_name()).is_file());
+
+        let installing = Installing {
+            download_directory: pod_state.download_directory.clone(),
+            parcel_directory: pod_state.parcel_directory.clone(),
+            package: test_package(),
+        };
+        let transition = Box::new(installing).next(&mut pod_state, &pod).await;
+        assert_eq!(transition.state_name(), Some("CreatingConfig"));
+        assert!(pod_state.parcel_directory.join(test_package().get_directory_name()).is_dir());
+
+        // A package which was downloaded already is not fetched again
+        let transition = Box::new(Downloading).next(&mut pod_state, &pod).await;
+        assert_eq!(transition.state_name(), Some("Installing"));
+        assert_eq!(repository.downloads(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_download_of_unknown_package_is_retried() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut pod_state = pod_state(directory.path(), Arc::new(MockRepository::default()));
+
+        let transition = Box::new(Downloading).next(&mut pod_state, &test_pod()).await;
+        assert_eq!(transition.state_name(), Some("DownloadingBackoff"));
+    }
+
+    #[tokio::test]
+    async fn test_download_fails_setup_without_repository_crd() {
+        let directory = tempfile::tempdir().unwrap();
+        let repository = MockRepository::default().with_package(test_package(), vec![]).without_crd();
+        let mut pod_state = pod_state(directory.path(), Arc::new(repository.clone()));
+
+        let transition = Box::new(Downloading).next(&mut pod_state, &test_pod()).await;
+        assert_eq!(transition.state_name(), Some("SetupFailed"));
+        assert_eq!(repository.downloads(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_restarts_are_counted_in_container_status() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut pod_state = pod_state(directory.path(), Arc::new(MockRepository::default()));
+        pod_state.restart_backoff_strategy = ExponentialBackoffStrategy::new(Duration::from_millis(1), Duration::from_millis(1));
+        let pod: KubePod = serde_json::from_value(json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "zookeeper", "namespace": "default" },
+            "spec": {
+                "containers": [{ "name": "zookeeper", "image": "zookeeper:3.4.14" }],
+                "restartPolicy": "Always"
+            }
+        }))
+        .unwrap();
+        let pod = Pod::from(pod);
+
+        for restarts in 1..=2 {
+            let failed = Box::new(Failed { message: String::from("process died") });
+            let transition = failed.next(&mut pod_state, &pod).await;
+            assert_eq!(transition.state_name(), Some("Starting"));
+
+            let status = Running.json_status(&mut pod_state, &pod).await.unwrap();
+            let container_status = &status["status"]["containerStatuses"][0];
+            assert_eq!(container_status["name"], "zookeeper");
+            assert_eq!(container_status["restartCount"], restarts);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_evicted_pod_is_not_restarted() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut pod_state = pod_state(directory.path(), Arc::new(MockRepository::default()));
+        let pod: KubePod = serde_json::from_value(json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "zookeeper", "namespace": "default" },
+            "spec": {
+                "containers": [{ "name": "zookeeper", "image": "zookeeper:3.4.14" }],
+                "restartPolicy": "Always"
+            }
+        }))
+        .unwrap();
+        let pod = Pod::from(pod);
+        pod_state.evictions.insert(pod_state.pod_key.clone());
+
+        let failed = Box::new(Failed { message: String::from("process died") });
+        let transition = failed.next(&mut pod_state, &pod).await;
+        assert_eq!(transition.state_name(), Some("Terminated"));
+    }
 }
@@ -0,0 +1,252 @@
+//! Execution of the lifecycle hooks of containers, i.e. `postStart` hooks after their process
+//! started and `preStop` hooks before it is stopped.
+//!
+//! `exec` hooks are run like the process of the pod, i.e. with its environment, in its working
+//! directory and as its user. `httpGet` hooks are sent like `httpGet` probes, to the loopback
+//! interface unless they name another host.
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::{Handler, Lifecycle};
+use kubelet::container::Container;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+use crate::error::StackableError;
+use crate::error::StackableError::PodValidationError;
+use crate::probe::{http_get_action, ProbeAction};
+use crate::states::starting::{ProcessSpec, Starting};
+
+/// How long a `postStart` hook may run. Kubernetes does not limit it, but the state machine of
+/// the pod waits for the hook, so that a hook which hangs would keep the pod from being stopped.
+pub(crate) const POST_START_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// What a lifecycle hook of a container does.
+#[derive(Debug, PartialEq)]
+pub(crate) enum LifecycleHook {
+    /// Runs the command next to the process.
+    Exec { command: Vec<String> },
+    /// Sends a GET request, which has to return a status below 400.
+    HttpGet(ProbeAction),
+}
+
+impl LifecycleHook {
+    /// Returns the `postStart` hook of the container, if it has one.
+    pub(crate) fn post_start(container: &Container) -> Result<Option<Self>, StackableError> {
+        LifecycleHook::from_container(container, |lifecycle| &lifecycle.post_start, "postStart")
+    }
+
+    /// Returns the `preStop` hook of the container, if it has one.
+    pub(crate) fn pre_stop(container: &Container) -> Result<Option<Self>, StackableError> {
+        LifecycleHook::from_container(container, |lifecycle| &lifecycle.pre_stop, "preStop")
+    }
+
+    /// Returns the hook of the container which `handler` selects. Only `exec` and `httpGet`
+    /// hooks are supported.
+    fn from_container(
+        container: &Container,
+        handler: impl Fn(&Lifecycle) -> &Option<Handler>,
+        name: &str,
+    ) -> Result<Option<Self>, StackableError> {
+        let handler = match container
+            .lifecycle()
+            .and_then(|lifecycle| handler(lifecycle).as_ref())
+        {
+            Some(handler) => handler,
+            None => return Ok(None),
+        };
+        let command = handler
+            .exec
+            .as_ref()
+            .and_then(|exec| exec.command.clone())
+            .filter(|command| !command.is_empty());
+        match (command, &handler.http_get) {
+            (Some(command), _) => Ok(Some(LifecycleHook::Exec { command })),
+            (None, Some(http_get)) => {
+                let ports = container.ports().as_deref().unwrap_or_default();
+                Ok(Some(LifecycleHook::HttpGet(http_get_action(
+                    http_get,
+                    ports,
+                    &format!("{} hook", name),
+                )?)))
+            }
+            (None, None) => Err(PodValidationError {
+                msg: format!(
+                    "{} hook of container {} is not supported, only exec and httpGet hooks are",
+                    name,
+                    container.name()
+                ),
+            }),
+        }
+    }
+
+    /// Runs the hook, which has to finish within the timeout. `exec` hooks need the spec of the
+    /// process they are run next to.
+    pub(crate) async fn run(
+        &self,
+        spec: Option<&ProcessSpec>,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        match self {
+            LifecycleHook::Exec { command } => {
+                let spec = spec.ok_or_else(|| {
+                    String::from("the process is not known, so the command cannot be run like it")
+                })?;
+                run_command(command, spec, timeout).await
+            }
+            LifecycleHook::HttpGet(action) => {
+                match tokio::time::timeout(timeout, action.run()).await {
+                    Ok(result) => result,
+                    Err(_) => Err(format!("timed out after {:?}", timeout)),
+                }
+            }
+        }
+    }
+}
+
+/// Runs the command like the process of the spec and kills it if it takes longer than the
+/// timeout.
+async fn run_command(
+    command: &[String],
+    spec: &ProcessSpec,
+    timeout: Duration,
+) -> Result<(), String> {
+    let spec = ProcessSpec {
+        binary: PathBuf::from(&command[0]),
+        args: command[1..].to_vec(),
+        ..spec.clone()
+    };
+    let mut child = Starting::build_command(&spec)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("starting {:?} failed: {}", spec.binary, e))?;
+    let pid = child.id();
+    let mut wait = tokio::task::spawn_blocking(move || child.wait());
+    let status = match tokio::time::timeout(timeout, &mut wait).await {
+        Ok(status) => status,
+        Err(_) => {
+            let _ = kill(Pid::from_raw(pid as i32), Signal::SIGKILL);
+            // Reaps the killed command
+            let _ = wait.await;
+            return Err(format!("{:?} timed out after {:?}", spec.binary, timeout));
+        }
+    };
+    match status {
+        Ok(Ok(status)) if status.success() => Ok(()),
+        Ok(Ok(status)) => Err(format!("{:?} exited with {}", spec.binary, status)),
+        Ok(Err(e)) => Err(format!("waiting for {:?} failed: {}", spec.binary, e)),
+        Err(e) => Err(format!("waiting for {:?} failed: {}", spec.binary, e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn spec() -> ProcessSpec {
+        ProcessSpec {
+            binary: PathBuf::from("/bin/false"),
+            args: vec![],
+            env: HashMap::new(),
+            working_directory: std::env::temp_dir(),
+            uid: None,
+            gid: None,
+            umask: crate::DEFAULT_UMASK,
+            hosts_file: None,
+            cgroup: None,
+        }
+    }
+
+    fn exec(command: &[&str]) -> LifecycleHook {
+        LifecycleHook::Exec {
+            command: command.iter().map(|part| part.to_string()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exec_hook_has_to_succeed_in_time() {
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            exec(&["/bin/sh", "-c", "exit 0"])
+                .run(Some(&spec()), timeout)
+                .await,
+            Ok(())
+        );
+        assert!(exec(&["/bin/sh", "-c", "exit 1"])
+            .run(Some(&spec()), timeout)
+            .await
+            .is_err());
+        assert!(exec(&["/bin/sh", "-c", "exit 0"])
+            .run(None, timeout)
+            .await
+            .is_err());
+
+        let started = std::time::Instant::now();
+        let result = exec(&["/bin/sleep", "10"])
+            .run(Some(&spec()), Duration::from_millis(100))
+            .await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < timeout);
+    }
+
+    #[tokio::test]
+    async fn test_http_get_hook_has_to_succeed() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/started", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            for status in &["200 OK", "500 Internal Server Error"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 1024];
+                stream.read(&mut request).unwrap();
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        let hook = LifecycleHook::HttpGet(ProbeAction::HttpGet {
+            url: url.parse().unwrap(),
+            headers: vec![],
+        });
+        let timeout = Duration::from_secs(5);
+
+        assert_eq!(hook.run(None, timeout).await, Ok(()));
+        assert!(hook.run(None, timeout).await.is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_hooks_are_read_from_the_container() {
+        let container = Container::new(
+            &serde_json::from_value(serde_json::json!({
+                "name": "zookeeper",
+                "ports": [{ "name": "admin", "containerPort": 8080 }],
+                "lifecycle": {
+                    "postStart": { "exec": { "command": ["bin/init.sh", "--wait"] } },
+                    "preStop": { "httpGet": { "path": "/drain", "port": "admin" } },
+                },
+            }))
+            .unwrap(),
+        );
+
+        assert_eq!(
+            LifecycleHook::post_start(&container).unwrap(),
+            Some(exec(&["bin/init.sh", "--wait"]))
+        );
+        assert_eq!(
+            LifecycleHook::pre_stop(&container).unwrap(),
+            Some(LifecycleHook::HttpGet(ProbeAction::HttpGet {
+                url: "http://127.0.0.1:8080/drain".parse().unwrap(),
+                headers: vec![],
+            }))
+        );
+    }
+}
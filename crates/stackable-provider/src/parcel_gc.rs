@@ -0,0 +1,230 @@
+//! Removal of installed parcels that are no longer used by any pod.
+//!
+//! A package is in use from the moment a pod referencing it is handed to the provider until the
+//! pod is dropped again, so parcels of pods that are still downloading or installing their
+//! package are never removed. Unused parcels are only removed after a grace period, so that a
+//! pod which is recreated shortly after being deleted does not have to download its package
+//! again.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use kubelet::pod::PodKey;
+use log::{debug, info, warn};
+
+use crate::repository::package::Package;
+
+/// How long an installed parcel has to be unused before it is removed, unless configured
+/// otherwise via [`crate::StackableProvider::with_parcel_gc_grace_period`].
+pub const DEFAULT_PARCEL_GC_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// How often installed parcels are checked for removal.
+const PARCEL_GC_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Keeps track of which pods use which packages and since when unused packages are unused.
+pub(crate) struct PackageUsage {
+    grace_period: Duration,
+    users: HashMap<Package, HashSet<PodKey>>,
+    unused_since: HashMap<Package, Instant>,
+}
+
+impl PackageUsage {
+    pub(crate) fn new(grace_period: Duration) -> Self {
+        PackageUsage {
+            grace_period,
+            users: HashMap::new(),
+            unused_since: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn set_grace_period(&mut self, grace_period: Duration) {
+        self.grace_period = grace_period;
+    }
+
+    /// Records that the given pod uses the package.
+    pub(crate) fn acquire(&mut self, package: &Package, pod: PodKey) {
+        self.unused_since.remove(package);
+        self.users.entry(package.clone()).or_default().insert(pod);
+    }
+
+    /// Records that the given pod no longer uses the package.
+    pub(crate) fn release(&mut self, package: &Package, pod: &PodKey) {
+        if let Some(users) = self.users.get_mut(package) {
+            users.remove(pod);
+            if users.is_empty() {
+                self.users.remove(package);
+                self.unused_since.insert(package.clone(), Instant::now());
+            }
+        }
+    }
+
+    /// Returns the installed packages which have been unused for longer than the grace period.
+    ///
+    /// Installed packages that were never used since the provider started, e.g. because their
+    /// pods were deleted while the krustlet was down, are considered unused from now on.
+    fn expired(&mut self, installed: Vec<Package>, now: Instant) -> Vec<Package> {
+        let mut expired = vec![];
+        for package in installed {
+            if self.users.contains_key(&package) {
+                continue;
+            }
+            let unused_since = *self.unused_since.entry(package.clone()).or_insert(now);
+            if now.duration_since(unused_since) >= self.grace_period {
+                expired.push(package);
+            }
+        }
+        expired
+    }
+}
+
+/// Returns all packages that are installed in the parcel directory.
+///
+/// Entries whose name does not follow the `product-version` convention are skipped.
+pub(crate) fn installed_packages(parcel_directory: &Path) -> Vec<Package> {
+    let entries = match fs::read_dir(parcel_directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!(
+                "Unable to read parcel directory {:?}: {}",
+                parcel_directory, e
+            );
+            return vec![];
+        }
+    };
+    let mut packages = vec![];
+    for entry in entries.filter_map(Result::ok) {
+        if !entry.path().is_dir() {
+            continue;
+        }
+        let name = entry.file_name();
+        match name.to_str().and_then(Package::from_directory_name) {
+            Some(package) => packages.push(package),
+            None => debug!(
+                "Skipping {:?} in parcel directory, it is not a package",
+                name
+            ),
+        }
+    }
+    packages
+}
+
+/// Removes the parcels, and their downloaded archives, of all packages that have been unused for
+/// longer than the grace period.
+///
+/// The usage is locked for the whole run, so no pod can start using a package while it is being
+/// removed.
+fn collect(usage: &Mutex<PackageUsage>, parcel_directory: &Path, download_directory: &Path) {
+    let mut usage = usage.lock().unwrap();
+    let expired = usage.expired(installed_packages(parcel_directory), Instant::now());
+    for package in expired {
+        let parcel = parcel_directory.join(package.get_directory_name());
+        info!(
+            "Removing parcel {:?} of package {}, which is no longer used by any pod",
+            parcel, package
+        );
+        if let Err(e) = fs::remove_dir_all(&parcel) {
+            warn!("Failed to remove parcel {:?}: {}", parcel, e);
+            continue;
+        }
+        usage.unused_since.remove(&package);
+
+        let archive = download_directory.join(package.get_file_name());
+        if archive.exists() {
+            if let Err(e) = fs::remove_file(&archive) {
+                warn!("Failed to remove package archive {:?}: {}", archive, e);
+            }
+        }
+    }
+}
+
+/// Removes the parcels of all packages that have been unused for longer than the grace period
+/// without blocking the async worker threads, as removing large parcels can take a while.
+pub(crate) async fn collect_in_background(
+    usage: Arc<Mutex<PackageUsage>>,
+    parcel_directory: PathBuf,
+    download_directory: PathBuf,
+) {
+    let result = tokio::task::spawn_blocking(move || {
+        collect(&usage, &parcel_directory, &download_directory)
+    })
+    .await;
+    if let Err(e) = result {
+        warn!("Parcel garbage collection failed: {}", e);
+    }
+}
+
+/// Periodically removes unused parcels for as long as the provider lives.
+pub(crate) async fn run(
+    usage: Arc<Mutex<PackageUsage>>,
+    parcel_directory: PathBuf,
+    download_directory: PathBuf,
+) {
+    loop {
+        tokio::time::delay_for(PARCEL_GC_INTERVAL).await;
+        collect_in_background(
+            Arc::clone(&usage),
+            parcel_directory.clone(),
+            download_directory.clone(),
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn package(version: &str) -> Package {
+        Package {
+            product: String::from("kafka"),
+            version: String::from(version),
+        }
+    }
+
+    #[test]
+    fn test_used_packages_never_expire() {
+        let mut usage = PackageUsage::new(Duration::from_secs(0));
+        usage.acquire(&package("1.0"), PodKey::new("default", "kafka"));
+
+        let expired = usage.expired(vec![package("1.0"), package("2.0")], Instant::now());
+
+        assert_eq!(expired, vec![package("2.0")]);
+    }
+
+    #[test]
+    fn test_released_packages_expire_after_grace_period() {
+        let mut usage = PackageUsage::new(Duration::from_secs(60));
+        let pod = PodKey::new("default", "kafka");
+        usage.acquire(&package("1.0"), pod.clone());
+        usage.release(&package("1.0"), &pod);
+        let now = Instant::now();
+
+        assert!(usage.expired(vec![package("1.0")], now).is_empty());
+        assert_eq!(
+            usage.expired(vec![package("1.0")], now + Duration::from_secs(61)),
+            vec![package("1.0")]
+        );
+    }
+
+    #[test]
+    fn test_collect_removes_expired_parcels() {
+        let parcel_directory = tempfile::tempdir().unwrap();
+        let download_directory = tempfile::tempdir().unwrap();
+        fs::create_dir(parcel_directory.path().join("kafka-1.0")).unwrap();
+        fs::create_dir(parcel_directory.path().join("kafka-2.0")).unwrap();
+        fs::write(download_directory.path().join("kafka-2.0.tar.gz"), "").unwrap();
+        let usage = Mutex::new(PackageUsage::new(Duration::from_secs(0)));
+        usage
+            .lock()
+            .unwrap()
+            .acquire(&package("1.0"), PodKey::new("default", "kafka"));
+
+        collect(&usage, parcel_directory.path(), download_directory.path());
+
+        assert!(parcel_directory.path().join("kafka-1.0").exists());
+        assert!(!parcel_directory.path().join("kafka-2.0").exists());
+        assert!(!download_directory.path().join("kafka-2.0.tar.gz").exists());
+    }
+}
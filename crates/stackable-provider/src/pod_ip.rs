@@ -0,0 +1,247 @@
+//! Assignment of the IP addresses reported in the status of pods.
+//!
+//! Processes run directly on the node and listen on its addresses, so by default every pod
+//! reports the IP of the node as its pod IP, like pods with `hostNetwork` enabled do on other
+//! nodes. This makes Services and DNS records of the pods work and gives the `status.podIP`
+//! field of the downward API a value.
+//!
+//! Alternatively, every pod can get an address of its own from a pool. This is a preparation for
+//! running pods in network namespaces of their own: the addresses are only assigned and reported
+//! so far, and are not configured on the node, so the processes still listen on the addresses of
+//! the node. Traffic to the pod IPs only reaches the processes if the pool is routed to the node
+//! and its addresses are local to the node, e.g. because the whole pool was added to the
+//! loopback interface, and the processes listen on all addresses. Pods with `hostNetwork`
+//! enabled always get the IP of the node.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use kubelet::pod::{Pod, PodKey};
+
+use crate::error::StackableError;
+
+/// A range of IPv4 addresses in CIDR notation, e.g. `10.244.1.0/24`, which pods get addresses
+/// from. The network and the broadcast address are never assigned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PodIpPool {
+    network: Ipv4Addr,
+    prefix_length: u8,
+}
+
+impl PodIpPool {
+    /// Returns the range of the addresses which can be assigned to pods.
+    fn range(&self) -> std::ops::Range<u64> {
+        let first = u64::from(u32::from(self.network));
+        let size = 1u64 << (32 - u32::from(self.prefix_length));
+        if size <= 2 {
+            // Networks without room for a network and a broadcast address use all of them
+            first..first + size
+        } else {
+            first + 1..first + size - 1
+        }
+    }
+
+    /// Returns the addresses which can be assigned to pods, in ascending order.
+    fn addresses(&self) -> impl Iterator<Item = Ipv4Addr> {
+        self.range().map(|address| Ipv4Addr::from(address as u32))
+    }
+
+    fn contains(&self, address: Ipv4Addr) -> bool {
+        self.range().contains(&u64::from(u32::from(address)))
+    }
+}
+
+impl FromStr for PodIpPool {
+    type Err = StackableError;
+
+    fn from_str(pool: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| StackableError::InvalidPodIpPool {
+            pool: String::from(pool),
+            msg: String::from(msg),
+        };
+        let mut parts = pool.trim().splitn(2, '/');
+        let address: Ipv4Addr = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| invalid("expected an IPv4 address"))?;
+        let prefix_length: u8 = parts
+            .next()
+            .ok_or_else(|| invalid("expected a prefix length, e.g. /24"))?
+            .parse()
+            .map_err(|_| invalid("the prefix length is not a number"))?;
+        if prefix_length > 32 {
+            return Err(invalid("the prefix length must not exceed 32"));
+        }
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(prefix_length))
+            .unwrap_or(0);
+        if u32::from(address) & !mask != 0 {
+            return Err(invalid("the address has host bits set"));
+        }
+        Ok(PodIpPool {
+            network: address,
+            prefix_length,
+        })
+    }
+}
+
+impl fmt::Display for PodIpPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_length)
+    }
+}
+
+/// The IP addresses assigned to pods.
+#[derive(Clone, Default)]
+pub(crate) struct PodIps {
+    node_ip: Option<IpAddr>,
+    pool: Option<PodIpPool>,
+    assigned: Arc<Mutex<BTreeMap<Ipv4Addr, PodKey>>>,
+}
+
+impl PodIps {
+    pub(crate) fn set_node_ip(&mut self, node_ip: IpAddr) {
+        self.node_ip = Some(node_ip);
+    }
+
+    pub(crate) fn set_pool(&mut self, pool: PodIpPool) {
+        self.pool = Some(pool);
+    }
+
+    pub(crate) fn node_ip(&self) -> Option<IpAddr> {
+        self.node_ip
+    }
+
+    /// Returns the IP address of the pod, if it gets one. Pods which already have an address of
+    /// the pool in their status, e.g. because their process survived a restart of the krustlet,
+    /// keep it if no other pod got it in the meantime.
+    pub(crate) fn assign(&self, pod: &Pod) -> Result<Option<IpAddr>, StackableError> {
+        let pool = match self.pool {
+            Some(pool) if !uses_host_network(pod) => pool,
+            _ => return Ok(self.node_ip),
+        };
+        let key = PodKey::from(pod);
+        let mut assigned = self.assigned.lock().unwrap();
+        if let Some((address, _)) = assigned.iter().find(|(_, owner)| **owner == key) {
+            return Ok(Some(IpAddr::V4(*address)));
+        }
+        let previous = pod.pod_ip().and_then(|ip| ip.parse().ok());
+        let address = previous
+            .filter(|address| pool.contains(*address) && !assigned.contains_key(address))
+            .or_else(|| {
+                pool.addresses()
+                    .find(|address| !assigned.contains_key(address))
+            })
+            .ok_or_else(|| StackableError::PodIpPoolExhausted {
+                pool: pool.to_string(),
+            })?;
+        assigned.insert(address, key);
+        Ok(Some(IpAddr::V4(address)))
+    }
+
+    /// Releases the address of the pod, so that it can be assigned to another one.
+    pub(crate) fn release(&self, key: &PodKey) {
+        self.assigned
+            .lock()
+            .unwrap()
+            .retain(|_, owner| owner != key);
+    }
+}
+
+fn uses_host_network(pod: &Pod) -> bool {
+    pod.as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.host_network)
+        .unwrap_or(false)
+}
+
+/// Returns the status patch which reports the IP of the node and the pod.
+pub(crate) fn make_ip_status(node_ip: Option<IpAddr>, pod_ip: IpAddr) -> serde_json::Value {
+    let mut status = serde_json::json!({
+        "podIP": pod_ip.to_string(),
+        "podIPs": [{ "ip": pod_ip.to_string() }],
+    });
+    if let Some(node_ip) = node_ip {
+        status["hostIP"] = serde_json::json!(node_ip.to_string());
+    }
+    serde_json::json!({
+        "metadata": {
+            "resourceVersion": "",
+        },
+        "status": status,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    fn pod(name: &str, pod_ip: Option<&str>) -> Pod {
+        let pod: KubePod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": name, "namespace": "default" },
+            "spec": { "containers": [] },
+            "status": { "podIP": pod_ip },
+        }))
+        .unwrap();
+        Pod::from(pod)
+    }
+
+    #[test]
+    fn test_pool_is_parsed_from_cidr_notation() {
+        let pool: PodIpPool = "10.244.1.0/30".parse().unwrap();
+        assert_eq!(pool.to_string(), "10.244.1.0/30");
+        assert_eq!(
+            pool.addresses().collect::<Vec<_>>(),
+            vec![Ipv4Addr::new(10, 244, 1, 1), Ipv4Addr::new(10, 244, 1, 2)]
+        );
+        assert!("10.244.1.1/24".parse::<PodIpPool>().is_err());
+        assert!("10.244.1.0".parse::<PodIpPool>().is_err());
+        assert!("10.244.1.0/33".parse::<PodIpPool>().is_err());
+    }
+
+    #[test]
+    fn test_pods_get_free_addresses_of_the_pool() {
+        let mut pod_ips = PodIps::default();
+        pod_ips.set_node_ip("192.168.0.10".parse().unwrap());
+        pod_ips.set_pool("10.244.1.0/30".parse().unwrap());
+
+        let first = pod("first", None);
+        let previous = pod("previous", Some("10.244.1.2"));
+        assert_eq!(
+            pod_ips.assign(&previous).unwrap(),
+            Some("10.244.1.2".parse().unwrap())
+        );
+        assert_eq!(
+            pod_ips.assign(&first).unwrap(),
+            Some("10.244.1.1".parse().unwrap())
+        );
+        assert_eq!(
+            pod_ips.assign(&first).unwrap(),
+            Some("10.244.1.1".parse().unwrap())
+        );
+        assert!(pod_ips.assign(&pod("third", None)).is_err());
+
+        pod_ips.release(&PodKey::from(&first));
+        assert_eq!(
+            pod_ips.assign(&pod("third", None)).unwrap(),
+            Some("10.244.1.1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_pods_get_the_node_ip_without_pool() {
+        let mut pod_ips = PodIps::default();
+        assert_eq!(pod_ips.assign(&pod("first", None)).unwrap(), None);
+
+        pod_ips.set_node_ip("192.168.0.10".parse().unwrap());
+        assert_eq!(
+            pod_ips.assign(&pod("first", None)).unwrap(),
+            Some("192.168.0.10".parse().unwrap())
+        );
+    }
+}
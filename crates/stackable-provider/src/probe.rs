@@ -0,0 +1,433 @@
+//! Execution of the liveness, readiness and startup probes of the processes started for pods.
+//!
+//! The processes run directly on the node, so probes without an explicit host are run against
+//! the loopback interface.
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::{
+    ContainerPort, ContainerStatus as KubeContainerStatus, HTTPGetAction, Probe, TCPSocketAction,
+};
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use kubelet::container::Container;
+use log::debug;
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Instant};
+use url::Url;
+
+use crate::error::StackableError;
+use crate::error::StackableError::PodValidationError;
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PERIOD_SECONDS: i32 = 10;
+const DEFAULT_TIMEOUT_SECONDS: i32 = 1;
+const DEFAULT_SUCCESS_THRESHOLD: i32 = 1;
+const DEFAULT_FAILURE_THRESHOLD: i32 = 3;
+
+/// What a probe checks to decide whether the process is alive.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ProbeAction {
+    /// The process is alive if a GET request to the URL returns a status below 400.
+    HttpGet {
+        url: Url,
+        headers: Vec<(String, String)>,
+    },
+    /// The process is alive if a TCP connection to the address can be opened.
+    TcpSocket { host: String, port: u16 },
+}
+
+/// The kinds of probes a container can define.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ProbeKind {
+    Liveness,
+    Readiness,
+    Startup,
+}
+
+impl std::fmt::Display for ProbeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeKind::Liveness => write!(f, "Liveness"),
+            ProbeKind::Readiness => write!(f, "Readiness"),
+            ProbeKind::Startup => write!(f, "Startup"),
+        }
+    }
+}
+
+/// A probe of a container together with the outcome of its recent runs.
+#[derive(Debug)]
+pub(crate) struct ContainerProbe {
+    kind: ProbeKind,
+    action: ProbeAction,
+    period: Duration,
+    timeout: Duration,
+    success_threshold: u32,
+    failure_threshold: u32,
+    next_run: Instant,
+    successes: u32,
+    failures: u32,
+    healthy: Option<bool>,
+}
+
+/// Checks that the container only has supported probes, so that a pod with an unsupported probe
+/// is rejected once before its process is started, rather than every time it is running.
+pub(crate) fn validate_probes(container: &Container) -> Result<(), StackableError> {
+    for kind in &[ProbeKind::Startup, ProbeKind::Liveness, ProbeKind::Readiness] {
+        ContainerProbe::from_container(container, *kind)?;
+    }
+    Ok(())
+}
+
+impl ContainerProbe {
+    /// Returns the probe of the given kind of the container, if it has one.
+    ///
+    /// Only `httpGet` and `tcpSocket` probes are supported.
+    pub(crate) fn from_container(
+        container: &Container,
+        kind: ProbeKind,
+    ) -> Result<Option<Self>, StackableError> {
+        let probe = match kind {
+            ProbeKind::Liveness => container.liveness_probe(),
+            ProbeKind::Readiness => container.readiness_probe(),
+            ProbeKind::Startup => container.startup_probe(),
+        };
+        let probe = match probe {
+            Some(probe) => probe,
+            None => return Ok(None),
+        };
+        let ports = container.ports().as_deref().unwrap_or_default();
+        let what = format!("{} probe", kind);
+        let action = match (&probe.http_get, &probe.tcp_socket) {
+            (Some(http_get), _) => http_get_action(http_get, ports, &what)?,
+            (None, Some(tcp_socket)) => tcp_socket_action(tcp_socket, ports, &what)?,
+            (None, None) => {
+                return Err(PodValidationError {
+                    msg: format!(
+                        "{} probe of container {} is not supported, only httpGet and tcpSocket probes are",
+                        kind,
+                        container.name()
+                    ),
+                })
+            }
+        };
+        Ok(Some(ContainerProbe::new(
+            kind,
+            action,
+            probe,
+            Instant::now(),
+        )))
+    }
+
+    fn new(kind: ProbeKind, action: ProbeAction, probe: &Probe, now: Instant) -> Self {
+        let seconds = |value: Option<i32>, default: i32| {
+            Duration::from_secs(value.unwrap_or(default).max(0) as u64)
+        };
+        let threshold = |value: Option<i32>, default: i32| value.unwrap_or(default).max(1) as u32;
+        // Kubernetes requires the success threshold of liveness and startup probes to be 1
+        let success_threshold = match kind {
+            ProbeKind::Liveness | ProbeKind::Startup => 1,
+            ProbeKind::Readiness => threshold(probe.success_threshold, DEFAULT_SUCCESS_THRESHOLD),
+        };
+        ContainerProbe {
+            kind,
+            action,
+            period: seconds(probe.period_seconds, DEFAULT_PERIOD_SECONDS),
+            timeout: seconds(probe.timeout_seconds, DEFAULT_TIMEOUT_SECONDS),
+            success_threshold,
+            failure_threshold: threshold(probe.failure_threshold, DEFAULT_FAILURE_THRESHOLD),
+            next_run: now + seconds(probe.initial_delay_seconds, 0),
+            successes: 0,
+            failures: 0,
+            healthy: None,
+        }
+    }
+
+    /// Runs the probe if it is due and returns its outcome if that changed.
+    ///
+    /// The outcome is initially unknown. It becomes a success once the probe succeeded
+    /// `successThreshold` times in a row and a failure, with a message describing the last
+    /// failure, once the probe failed `failureThreshold` times in a row.
+    pub(crate) async fn run_if_due(&mut self) -> Option<Result<(), String>> {
+        let now = Instant::now();
+        if now < self.next_run {
+            return None;
+        }
+        self.next_run = now + self.period;
+        let result = match timeout(self.timeout, self.action.run()).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("timed out after {:?}", self.timeout)),
+        };
+        match result {
+            Ok(()) => self.record_success(),
+            Err(message) => self.record_failure(message),
+        }
+    }
+
+    fn record_success(&mut self) -> Option<Result<(), String>> {
+        self.failures = 0;
+        self.successes += 1;
+        if self.successes >= self.success_threshold && self.healthy != Some(true) {
+            self.healthy = Some(true);
+            Some(Ok(()))
+        } else {
+            None
+        }
+    }
+
+    fn record_failure(&mut self, message: String) -> Option<Result<(), String>> {
+        self.successes = 0;
+        self.failures += 1;
+        debug!(
+            "{} probe failed ({} of {}): {}",
+            self.kind, self.failures, self.failure_threshold, message
+        );
+        if self.failures >= self.failure_threshold && self.healthy != Some(false) {
+            self.healthy = Some(false);
+            Some(Err(format!(
+                "{} probe failed {} times: {}",
+                self.kind, self.failures, message
+            )))
+        } else {
+            None
+        }
+    }
+}
+
+/// Creates a status patch which sets the `Ready` and `ContainersReady` conditions of a pod,
+/// along with the statuses of its containers, which tell whether they are ready as well.
+pub(crate) fn make_ready_status(
+    ready: bool,
+    message: &str,
+    container_statuses: Vec<KubeContainerStatus>,
+) -> serde_json::Value {
+    let status = if ready { "True" } else { "False" };
+    let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    let condition = |condition_type: &str| {
+        serde_json::json!({
+            "type": condition_type,
+            "status": status,
+            "lastTransitionTime": now,
+            "message": message,
+        })
+    };
+    serde_json::json!({
+        "metadata": {
+            "resourceVersion": "",
+        },
+        "status": {
+            "conditions": [condition("Ready"), condition("ContainersReady")],
+            "containerStatuses": container_statuses,
+        }
+    })
+}
+
+impl ProbeAction {
+    pub(crate) async fn run(&self) -> Result<(), String> {
+        match self {
+            ProbeAction::HttpGet { url, headers } => {
+                // Like the Kubernetes kubelet, certificates are not verified
+                let client = reqwest::Client::builder()
+                    .danger_accept_invalid_certs(true)
+                    .build()
+                    .map_err(|e| e.to_string())?;
+                let mut request = client.get(url.clone());
+                for (name, value) in headers {
+                    request = request.header(name.as_str(), value.as_str());
+                }
+                let response = request
+                    .send()
+                    .await
+                    .map_err(|e| format!("GET {} failed: {}", url, e))?;
+                let status = response.status();
+                if status.is_success() || status.is_redirection() {
+                    Ok(())
+                } else {
+                    Err(format!("GET {} returned {}", url, status))
+                }
+            }
+            ProbeAction::TcpSocket { host, port } => TcpStream::connect((host.as_str(), *port))
+                .await
+                .map(|_| ())
+                .map_err(|e| format!("connecting to {}:{} failed: {}", host, port, e)),
+        }
+    }
+}
+
+/// Returns the action of an `httpGet` handler, e.g. of a probe. `what` names the handler in
+/// error messages, e.g. `Liveness probe`.
+pub(crate) fn http_get_action(
+    http_get: &HTTPGetAction,
+    ports: &[ContainerPort],
+    what: &str,
+) -> Result<ProbeAction, StackableError> {
+    let scheme = http_get
+        .scheme
+        .as_deref()
+        .unwrap_or("HTTP")
+        .to_ascii_lowercase();
+    let host = http_get.host.as_deref().unwrap_or(DEFAULT_HOST);
+    let port = resolve_port(&http_get.port, ports, what)?;
+    let path = http_get.path.as_deref().unwrap_or("/");
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let url = Url::parse(&format!("{}://{}:{}/{}", scheme, host, port, path)).map_err(|e| {
+        PodValidationError {
+            msg: format!("Invalid httpGet {}: {}", what, e),
+        }
+    })?;
+    let headers = http_get
+        .http_headers
+        .iter()
+        .flatten()
+        .map(|header| (header.name.clone(), header.value.clone()))
+        .collect();
+    Ok(ProbeAction::HttpGet { url, headers })
+}
+
+fn tcp_socket_action(
+    tcp_socket: &TCPSocketAction,
+    ports: &[ContainerPort],
+    what: &str,
+) -> Result<ProbeAction, StackableError> {
+    Ok(ProbeAction::TcpSocket {
+        host: tcp_socket
+            .host
+            .clone()
+            .unwrap_or_else(|| String::from(DEFAULT_HOST)),
+        port: resolve_port(&tcp_socket.port, ports, what)?,
+    })
+}
+
+/// Resolves a probe port, which is either a number or the name of one of the container's ports.
+fn resolve_port(
+    port: &IntOrString,
+    ports: &[ContainerPort],
+    what: &str,
+) -> Result<u16, StackableError> {
+    let number = match port {
+        IntOrString::Int(number) => *number,
+        IntOrString::String(name) => ports
+            .iter()
+            .find(|port| port.name.as_ref() == Some(name))
+            .map(|port| port.container_port)
+            .ok_or_else(|| PodValidationError {
+                msg: format!("{} refers to unknown port {}", what, name),
+            })?,
+    };
+    if number < 1 || number > u16::MAX as i32 {
+        return Err(PodValidationError {
+            msg: format!("{} port {} is out of range", what, number),
+        });
+    }
+    Ok(number as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn named_port(name: &str, number: i32) -> ContainerPort {
+        ContainerPort {
+            name: Some(String::from(name)),
+            container_port: number,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve_port() {
+        let ports = vec![named_port("http", 8080)];
+
+        let kind = "Liveness probe";
+
+        assert_eq!(
+            resolve_port(&IntOrString::Int(9000), &ports, kind).unwrap(),
+            9000
+        );
+        assert_eq!(
+            resolve_port(&IntOrString::String(String::from("http")), &ports, kind).unwrap(),
+            8080
+        );
+        assert!(resolve_port(&IntOrString::String(String::from("admin")), &ports, kind).is_err());
+        assert!(resolve_port(&IntOrString::Int(0), &ports, kind).is_err());
+    }
+
+    #[test]
+    fn test_http_get_action_defaults() {
+        let http_get = HTTPGetAction {
+            path: Some(String::from("/healthz")),
+            port: IntOrString::String(String::from("http")),
+            ..Default::default()
+        };
+
+        let action =
+            http_get_action(&http_get, &[named_port("http", 8080)], "Liveness probe").unwrap();
+
+        assert_eq!(
+            action,
+            ProbeAction::HttpGet {
+                url: Url::parse("http://127.0.0.1:8080/healthz").unwrap(),
+                headers: vec![],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failure_threshold() {
+        let probe = Probe {
+            failure_threshold: Some(2),
+            period_seconds: Some(0),
+            ..Default::default()
+        };
+        // Nothing listens on the port, as it was released again right after binding it
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let action = ProbeAction::TcpSocket {
+            host: String::from(DEFAULT_HOST),
+            port,
+        };
+        let mut probe = ContainerProbe::new(ProbeKind::Liveness, action, &probe, Instant::now());
+
+        assert_eq!(probe.run_if_due().await, None);
+        assert!(matches!(probe.run_if_due().await, Some(Err(_))));
+        assert_eq!(probe.run_if_due().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_success_threshold() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let action = ProbeAction::TcpSocket {
+            host: String::from(DEFAULT_HOST),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let probe = Probe {
+            success_threshold: Some(2),
+            period_seconds: Some(0),
+            ..Default::default()
+        };
+        let mut probe = ContainerProbe::new(ProbeKind::Readiness, action, &probe, Instant::now());
+
+        assert_eq!(probe.run_if_due().await, None);
+        assert_eq!(probe.run_if_due().await, Some(Ok(())));
+        assert_eq!(probe.run_if_due().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_socket_probe_succeeds() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let action = ProbeAction::TcpSocket {
+            host: String::from(DEFAULT_HOST),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let mut probe = ContainerProbe::new(
+            ProbeKind::Liveness,
+            action,
+            &Probe::default(),
+            Instant::now(),
+        );
+
+        assert_eq!(probe.run_if_due().await, Some(Ok(())));
+        assert_eq!(probe.failures, 0);
+    }
+}
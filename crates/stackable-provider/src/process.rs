@@ -0,0 +1,246 @@
+//! The processes of pods, and the records which let them outlive restarts of the krustlet.
+//!
+//! Whenever the process of a pod was started, a record of it is written to disk. After a restart
+//! of the krustlet the pod passes through its states again, and its process is re-adopted when
+//! it would be started, if it is still running. Processes only survive a restart if whatever
+//! stops the krustlet leaves its child processes alone, e.g. systemd needs `KillMode=process`.
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Child;
+use std::time::Duration;
+
+use kubelet::pod::PodKey;
+use log::{debug, warn};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+
+use crate::error::StackableError;
+use crate::repository::package::Package;
+
+/// How often a killed process is polled while waiting to reap it.
+const REAP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The process of a pod.
+#[derive(Debug)]
+pub enum ProcessHandle {
+    /// A process started by this krustlet.
+    Started(Child),
+    /// A process started before the krustlet was restarted. It is no child of this krustlet, so
+    /// its exit status cannot be collected.
+    Adopted { pid: u32, start_time: u64 },
+}
+
+impl ProcessHandle {
+    pub fn id(&self) -> u32 {
+        match self {
+            ProcessHandle::Started(child) => child.id(),
+            ProcessHandle::Adopted { pid, .. } => *pid,
+        }
+    }
+
+    /// Returns whether the process has exited. Processes started by this krustlet are reaped
+    /// then.
+    pub fn has_exited(&mut self) -> io::Result<bool> {
+        match self {
+            ProcessHandle::Started(child) => Ok(child.try_wait()?.is_some()),
+            ProcessHandle::Adopted { pid, start_time } => Ok(start_time_of(*pid) != Some(*start_time)),
+        }
+    }
+
+    pub fn signal(&self, signal: Signal) -> nix::Result<()> {
+        kill(Pid::from_raw(self.id() as i32), signal)
+    }
+
+    /// Waits for a process started by this krustlet to exit and reaps it. Adopted processes are
+    /// reaped by the process which inherited them from the previous krustlet.
+    ///
+    /// The process is polled instead of waited for, as a process in uninterruptible sleep may
+    /// not exit for a long time even after SIGKILL, which must not stall the runtime.
+    pub async fn reap(&mut self) -> io::Result<()> {
+        if let ProcessHandle::Started(child) = self {
+            while child.try_wait()?.is_none() {
+                tokio::time::delay_for(REAP_POLL_INTERVAL).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns when the process with the given PID started, in clock ticks since boot, which tells
+/// it apart from later processes that get the same PID. `None` if there is no such process or
+/// it has exited and only waits to be reaped.
+pub fn start_time_of(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The name of the command in parentheses may contain spaces, the fields after it do not
+    let fields: Vec<&str> = stat.rsplit(')').next()?.split_whitespace().collect();
+    if fields.first() == Some(&"Z") {
+        return None;
+    }
+    // These are the fields from the state (3rd) on, the start time is the 22nd
+    fields.get(19)?.parse().ok()
+}
+
+/// What is remembered about the process of a pod.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProcessRecord {
+    pub namespace: String,
+    pub name: String,
+    pub pid: u32,
+    pub start_time: u64,
+    pub package: Package,
+    /// The host ports of the pod, which are kept reserved while the process is running
+    pub ports: Vec<u16>,
+}
+
+impl ProcessRecord {
+    pub fn pod_key(&self) -> PodKey {
+        PodKey::new(&self.namespace, &self.name)
+    }
+
+    /// Returns whether the recorded process is still running.
+    pub fn is_running(&self) -> bool {
+        start_time_of(self.pid) == Some(self.start_time)
+    }
+
+    pub fn adopt(&self) -> ProcessHandle {
+        ProcessHandle::Adopted { pid: self.pid, start_time: self.start_time }
+    }
+}
+
+/// The records of the processes of all pods, stored in one JSON file per pod.
+#[derive(Clone, Debug)]
+pub struct ProcessRecords {
+    directory: PathBuf,
+}
+
+impl ProcessRecords {
+    pub fn new(directory: PathBuf) -> Self {
+        ProcessRecords { directory }
+    }
+
+    fn path(&self, key: &PodKey) -> PathBuf {
+        // Namespaces and names of pods cannot contain underscores
+        self.directory.join(format!("{}_{}.json", key.namespace(), key.name()))
+    }
+
+    /// Records the process, replacing any earlier record of its pod.
+    pub fn save(&self, record: &ProcessRecord) -> Result<(), StackableError> {
+        fs::create_dir_all(&self.directory)?;
+        let path = self.path(&record.pod_key());
+        // Written to a temporary file first, so that a crash never leaves a truncated record
+        let temporary_path = path.with_extension("json.tmp");
+        fs::write(&temporary_path, serde_json::to_vec(record)?)?;
+        fs::rename(&temporary_path, &path)?;
+        Ok(())
+    }
+
+    pub fn load(&self, key: &PodKey) -> Option<ProcessRecord> {
+        let path = self.path(key);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Unable to read process record {:?}: {}", path, e);
+                return None;
+            }
+        };
+        match serde_json::from_slice(&content) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                warn!("Ignoring invalid process record {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Returns the records of all pods.
+    pub fn load_all(&self) -> Vec<ProcessRecord> {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let file_name = entry.file_name().into_string().ok()?;
+                let stem = file_name.strip_suffix(".json")?;
+                let (namespace, name) = stem.split_at(stem.find('_')?);
+                self.load(&PodKey::new(namespace, &name[1..]))
+            })
+            .collect()
+    }
+
+    pub fn remove(&self, key: &PodKey) {
+        let path = self.path(key);
+        match fs::remove_file(&path) {
+            Ok(()) => debug!("Removed process record {:?}", path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove process record {:?}: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_start_time_of_process() {
+        assert!(start_time_of(std::process::id()).is_some());
+
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        assert_eq!(start_time_of(pid), None);
+    }
+
+    #[test]
+    fn test_adopted_process_exits() {
+        let mut child = Command::new("sleep").arg("60").spawn().unwrap();
+        let record = ProcessRecord {
+            namespace: String::from("default"),
+            name: String::from("zookeeper"),
+            pid: child.id(),
+            start_time: start_time_of(child.id()).unwrap(),
+            package: Package { product: String::from("zookeeper"), version: String::from("3.4.14") },
+            ports: vec![2181],
+        };
+        assert!(record.is_running());
+
+        let mut adopted = record.adopt();
+        assert!(!adopted.has_exited().unwrap());
+        adopted.signal(Signal::SIGKILL).unwrap();
+        // The test is the parent here, which reaps the process in place of init
+        child.wait().unwrap();
+        assert!(adopted.has_exited().unwrap());
+
+        // A later process with the same PID is not taken for the recorded one
+        let reused = ProcessRecord { start_time: record.start_time + 1, ..record };
+        assert!(!reused.is_running());
+    }
+
+    #[test]
+    fn test_records_are_stored_per_pod() {
+        let directory = tempfile::tempdir().unwrap();
+        let records = ProcessRecords::new(directory.path().join("processes"));
+        let record = ProcessRecord {
+            namespace: String::from("default"),
+            name: String::from("kafka-broker-1"),
+            pid: 42,
+            start_time: 4711,
+            package: Package { product: String::from("kafka"), version: String::from("2.6.0") },
+            ports: vec![9092],
+        };
+
+        records.save(&record).unwrap();
+        assert_eq!(records.load(&record.pod_key()), Some(record.clone()));
+        assert_eq!(records.load_all(), vec![record.clone()]);
+
+        records.remove(&record.pod_key());
+        assert_eq!(records.load(&record.pod_key()), None);
+        assert!(records.load_all().is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+//! The hash algorithms downloaded packages can be verified with.
+//!
+//! Repositories list the hashes of a package by the name of their algorithm. Every algorithm is
+//! registered under that name in a [`HashRegistry`], so supporting another algorithm only needs
+//! another entry in [`HashRegistry::default`].
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::error::StackableError;
+use crate::error::StackableError::UnsupportedHashAlgorithm;
+
+/// Computes the hash of everything the reader returns, as a hexadecimal string.
+pub type HashFunction = fn(&mut dyn Read) -> std::io::Result<String>;
+
+/// The hash algorithms known by their name. Names are matched case-insensitively, as
+/// repositories list them as e.g. `SHA256` or `sha256`.
+#[derive(Clone, Debug)]
+pub struct HashRegistry {
+    algorithms: BTreeMap<String, HashFunction>,
+}
+
+impl Default for HashRegistry {
+    fn default() -> Self {
+        HashRegistry::empty()
+            .with_algorithm("sha256", hex_digest::<Sha256>)
+            .with_algorithm("sha512", hex_digest::<Sha512>)
+    }
+}
+
+impl HashRegistry {
+    /// Returns a registry which knows no algorithm at all.
+    pub fn empty() -> Self {
+        HashRegistry {
+            algorithms: BTreeMap::new(),
+        }
+    }
+
+    /// Registers the algorithm under the given name, replacing an algorithm of the same name.
+    pub fn with_algorithm(mut self, name: &str, hash: HashFunction) -> Self {
+        self.algorithms.insert(name.to_ascii_lowercase(), hash);
+        self
+    }
+
+    /// Returns the names of the registered algorithms in alphabetical order.
+    pub fn supported_algorithms(&self) -> Vec<&str> {
+        self.algorithms.keys().map(String::as_str).collect()
+    }
+
+    /// Computes the hash of the file with the given algorithm.
+    pub fn hash_file(&self, algorithm: &str, file: &Path) -> Result<String, StackableError> {
+        let hash = self
+            .algorithms
+            .get(&algorithm.to_ascii_lowercase())
+            .ok_or_else(|| UnsupportedHashAlgorithm {
+                algorithm: algorithm.to_string(),
+                supported: self.supported_algorithms().join(", "),
+            })?;
+        Ok(hash(&mut File::open(file)?)?)
+    }
+}
+
+/// Hashes the input with the digest `D`, reading it in chunks.
+fn hex_digest<D: Digest>(input: &mut dyn Read) -> std::io::Result<String> {
+    let mut hasher = D::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = input.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.input(&buffer[..read]);
+    }
+    Ok(hasher
+        .result()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_algorithms() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("package.tar.gz");
+        std::fs::write(&file, "content").unwrap();
+        let registry = HashRegistry::default();
+
+        assert_eq!(
+            registry.hash_file("SHA256", &file).unwrap(),
+            "ed7002b439e9ac845f22357d822bac1444730fbdb6016d3ec9432297b9ec9f73"
+        );
+        assert_eq!(
+            registry.hash_file("sha512", &file).unwrap(),
+            "b2d1d285b5199c85f988d03649c37e44fd3dde01e5d69c50fef90651962f48110e9340b60d49a479c4c0b53f5f07d690686dd87d2481937a512e8b85ee7c617f"
+        );
+    }
+
+    #[test]
+    fn test_unknown_algorithm_lists_supported_algorithms() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("package.tar.gz");
+        std::fs::write(&file, "content").unwrap();
+
+        match HashRegistry::default().hash_file("md5", &file) {
+            Err(UnsupportedHashAlgorithm {
+                algorithm,
+                supported,
+            }) => {
+                assert_eq!(algorithm, "md5");
+                assert_eq!(supported, "sha256, sha512");
+            }
+            other => panic!("expected an unsupported algorithm, got {:?}", other),
+        }
+    }
+}
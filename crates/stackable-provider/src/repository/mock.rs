@@ -0,0 +1,79 @@
+//! An in-memory repository, which lets tests drive the download and installation of packages
+//! without a repository server.
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+
+use crate::error::StackableError;
+use crate::error::StackableError::{CrdMissing, PackageNotFound};
+use crate::repository::package::Package;
+use crate::repository::{RepositoryLookup, RepositoryProvider, REPOSITORY_CRD};
+
+/// Serves the archives of its packages from memory and counts how often they are downloaded.
+#[derive(Clone, Default)]
+pub struct MockRepository {
+    packages: HashMap<Package, Arc<Vec<u8>>>,
+    downloads: Arc<AtomicUsize>,
+    crd_missing: bool,
+}
+
+impl MockRepository {
+    /// Adds a package with the given archive to the repository.
+    pub fn with_package(mut self, package: Package, archive: Vec<u8>) -> Self {
+        self.packages.insert(package, Arc::new(archive));
+        self
+    }
+
+    /// Makes the repository behave as if the repository CRD was deleted.
+    pub fn without_crd(mut self) -> Self {
+        self.crd_missing = true;
+        self
+    }
+
+    /// How many packages were downloaded from the repository.
+    pub fn downloads(&self) -> usize {
+        self.downloads.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Display for MockRepository {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "mock")
+    }
+}
+
+#[async_trait::async_trait]
+impl RepositoryProvider for MockRepository {
+    async fn provides_package(&mut self, package: &Package) -> Result<bool, StackableError> {
+        Ok(self.packages.contains_key(package))
+    }
+
+    async fn download_package(&mut self, package: &Package, target_path: PathBuf, _cancelled: BoxFuture<'static, ()>) -> Result<(), StackableError> {
+        let archive = self.packages.get(package).ok_or_else(|| PackageNotFound { package: package.clone() })?;
+        tokio::fs::write(target_path.join(package.get_file_name()), archive.as_slice()).await?;
+        self.downloads.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl RepositoryLookup for MockRepository {
+    async fn find_repositories(&self, package: &Package) -> Result<Vec<Box<dyn RepositoryProvider>>, StackableError> {
+        if self.packages.contains_key(package) {
+            Ok(vec![Box::new(self.clone())])
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    async fn check_available(&self) -> Result<(), StackableError> {
+        if self.crd_missing {
+            return Err(CrdMissing { missing_crds: vec![String::from(REPOSITORY_CRD)] });
+        }
+        Ok(())
+    }
+}
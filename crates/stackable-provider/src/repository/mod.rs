@@ -2,44 +2,229 @@ use crate::repository::package::Package;
 use crate::repository::stackablerepository::StackableRepoProvider;
 use kube::{Client, Api};
 use crate::error::StackableError;
+use crate::error::StackableError::CrdMissing;
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::api::ListParams;
+use kube::error::ErrorResponse;
 use std::convert::TryFrom;
-use log::{trace, debug, info, error};
+use log::{trace, debug, info, error, warn};
+use rand::Rng;
 use crate::repository::repository::Repository;
+use futures::future::BoxFuture;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+pub mod hashes;
+#[cfg(test)]
+pub mod mock;
 pub mod package;
 pub mod repository;
 pub mod stackablerepository;
 
-pub async fn find_repository(client: Client, package: &Package, repository_reference: Option<String>) -> Result<Option<StackableRepoProvider>, StackableError> {
+/// The name of the CRD repositories are defined by.
+pub const REPOSITORY_CRD: &str = "repositories.stable.stackable.de";
+
+/// How long the repository CRD is assumed to be registered after it was found, so that not every
+/// pod looks it up.
+const CRD_CHECK_CACHE_DURATION: Duration = Duration::from_secs(30);
+
+/// The weight of repositories which do not set one.
+pub const DEFAULT_REPOSITORY_WEIGHT: u32 = 1;
+
+/// A repository packages are downloaded from.
+#[async_trait::async_trait]
+pub trait RepositoryProvider: fmt::Display + Send + Sync {
+    /// Returns whether the repository provides the package.
+    async fn provides_package(&mut self, package: &Package) -> Result<bool, StackableError>;
+
+    /// Downloads the package into `target_path`. The download is aborted as soon as `cancelled`
+    /// completes.
+    async fn download_package(&mut self, package: &Package, target_path: PathBuf, cancelled: BoxFuture<'static, ()>) -> Result<(), StackableError>;
+}
+
+/// Looks up the repositories which provide a package.
+#[async_trait::async_trait]
+pub trait RepositoryLookup: Send + Sync {
+    /// Returns all repositories which provide the package, in the order they should be tried.
+    async fn find_repositories(&self, package: &Package) -> Result<Vec<Box<dyn RepositoryProvider>>, StackableError>;
+
+    /// Checks that repositories can be looked up at all, e.g. that the CRD they are defined by
+    /// is still registered.
+    async fn check_available(&self) -> Result<(), StackableError> {
+        Ok(())
+    }
+}
+
+/// Looks up packages in the repositories registered with the API server.
+pub struct KubeRepositories {
+    client: Client,
+    /// When the repository CRD was last found to be registered
+    crd_found: Mutex<Option<Instant>>,
+}
+
+impl KubeRepositories {
+    pub fn new(client: Client) -> Self {
+        KubeRepositories { client, crd_found: Mutex::new(None) }
+    }
+}
+
+#[async_trait::async_trait]
+impl RepositoryLookup for KubeRepositories {
+    /// The repositories are ordered by drawing them one after the other, each with a probability
+    /// proportional to its weight, so that downloads are spread across mirrors. Repositories with
+    /// a weight of 0 are only tried after all others.
+    async fn find_repositories(&self, package: &Package) -> Result<Vec<Box<dyn RepositoryProvider>>, StackableError> {
+        let repos = find_repositories(self.client.clone(), package).await?;
+        let ordered = weighted_order(repos, &mut rand::thread_rng());
+        Ok(ordered.into_iter().map(|repo| Box::new(repo) as Box<dyn RepositoryProvider>).collect())
+    }
+
+    /// Fails if the repository CRD was deleted since the provider started. Other errors while
+    /// looking it up are left to the lookup of the repositories to report.
+    async fn check_available(&self) -> Result<(), StackableError> {
+        if let Some(found) = *self.crd_found.lock().unwrap() {
+            if found.elapsed() < CRD_CHECK_CACHE_DURATION {
+                return Ok(());
+            }
+        }
+        let crds: Api<CustomResourceDefinition> = Api::all(self.client.clone());
+        match crds.get(REPOSITORY_CRD).await {
+            Ok(_) => {
+                *self.crd_found.lock().unwrap() = Some(Instant::now());
+                Ok(())
+            }
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {
+                *self.crd_found.lock().unwrap() = None;
+                Err(CrdMissing { missing_crds: vec![String::from(REPOSITORY_CRD)] })
+            }
+            Err(e) => {
+                debug!("Unable to check whether CRD {} is registered: {}", REPOSITORY_CRD, e);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Returns all repositories registered with the API server which provide the package, with
+/// their weights.
+///
+/// Repositories which cannot be asked for the package, e.g. because they are unreachable, are
+/// logged and skipped, so that a broken mirror does not keep packages from being downloaded from
+/// the others. Only if none of the repositories could be asked, the last error is returned.
+pub async fn find_repositories(client: Client, package: &Package) -> Result<Vec<(u32, StackableRepoProvider)>, StackableError> {
     let repositories: Api<Repository> = Api::namespaced(client.clone(), "default");
-    if let Some(repository_name) = repository_reference {
-        // A repository name was provided, just check that exact repository for the package
-        let repo = repositories.get(&repository_name).await?;
-        let mut repo = StackableRepoProvider::try_from(&repo)?;
-        if repo.provides_package(package.clone()).await? {
-            return Ok(Some(repo));
-        } else {
-            return Ok(None);
+    let list_params = ListParams::default();
+    let repos = repositories.list(&list_params).await?;
+    let mut providing = vec![];
+    let mut asked = 0;
+    let mut last_error = None;
+    for repository in repos.iter() {
+        let repo: &Repository = repository;
+        debug!("got repo definition: {:?}", repository);
+        let weight = repo.spec.weight.unwrap_or(DEFAULT_REPOSITORY_WEIGHT);
+        match repository_providing(&client, repo, package).await {
+            Ok(Some(repo_provider)) => {
+                debug!("Found package {} in repository {}", &package, repo_provider);
+                providing.push((weight, repo_provider));
+                asked += 1;
+            }
+            Ok(None) => asked += 1,
+            Err(error) => {
+                warn!("Skipping repository {} while looking for package {}: {}", repo.metadata.name.as_deref().unwrap_or_default(), &package, error);
+                last_error = Some(error);
+            }
+        }
+    }
+    match last_error {
+        Some(error) if asked == 0 => Err(error),
+        _ => Ok(providing),
+    }
+}
+
+/// Returns the first repository registered with the API server which provides the package or,
+/// if `repository_reference` names a repository, that one if it provides the package.
+pub async fn find_repository(client: Client, package: &Package, repository_reference: Option<String>) -> Result<Option<StackableRepoProvider>, StackableError> {
+    match repository_reference {
+        Some(repository_name) => {
+            let repositories: Api<Repository> = Api::namespaced(client.clone(), "default");
+            let repo = repositories.get(&repository_name).await?;
+            repository_providing(&client, &repo, package).await
         }
+        None => {
+            let repos = find_repositories(client, package).await?;
+            Ok(repos.into_iter().next().map(|(_, repo)| repo))
+        }
+    }
+}
+
+/// Converts the repository definition and returns it if the repository provides the package.
+async fn repository_providing(client: &Client, repo: &Repository, package: &Package) -> Result<Option<StackableRepoProvider>, StackableError> {
+    // Convert repository to object implementing our trait
+    // TODO: add generic implementation here to support different types of repository
+    let mut repo_provider = StackableRepoProvider::try_from(repo)?;
+    trace!("converted to stackable repo: {:?}", repo);
+    if let Some(secret_name) = &repo.spec.client_cert_secret {
+        let namespace = repo.metadata.namespace.as_deref().unwrap_or("default");
+        repo_provider = repo_provider.with_client_certificate(client, namespace, secret_name).await?;
+    }
+    if repo_provider.provides_package(package.clone()).await? {
+        Ok(Some(repo_provider))
     } else {
-        // No repository name was provided, retrieve all repositories from the orchestrator/apiserver
-        // and check which one provides the package
-        let list_params = ListParams::default();
-        let repos = repositories.list(&list_params).await?;
-        for repository in repos.iter() {
-            let repo: &Repository = repository;
-            debug!("got repo definition: {:?}", repository);
-            // Convert repository to object implementing our trait
-            // TODO: add generic implementation here to support different types of repository
-            let mut repo = StackableRepoProvider::try_from(repository)?;
-            trace!("converted to stackable repo: {:?}", repository);
-            if repo.provides_package(package.clone()).await? {
-                debug!("Found package {} in repository {}", &package, repo);
-                return Ok(Some(repo));
-            } else {
-                debug!("Package {} not provided by repository {}", &package, repo);
-            }
+        debug!("Package {} not provided by repository {}", &package, repo_provider);
+        Ok(None)
+    }
+}
+
+/// Orders the weighted items by drawing them one after the other, each with a probability
+/// proportional to its weight. Items with a weight of 0 keep their order after all others, so a
+/// single item, or items which all have a weight of 0, are never reordered.
+fn weighted_order<T, R: Rng>(mut items: Vec<(u32, T)>, rng: &mut R) -> Vec<T> {
+    let mut ordered = Vec::with_capacity(items.len());
+    loop {
+        let total: u64 = items.iter().map(|(weight, _)| u64::from(*weight)).sum();
+        if total == 0 || items.len() == 1 {
+            break;
         }
+        let mut drawn = rng.gen_range(0, total);
+        let index = items
+            .iter()
+            .position(|(weight, _)| {
+                let weight = u64::from(*weight);
+                if drawn < weight {
+                    true
+                } else {
+                    drawn -= weight;
+                    false
+                }
+            })
+            .expect("a number below the total weight falls into one of the weights");
+        ordered.push(items.remove(index).1);
+    }
+    ordered.extend(items.into_iter().map(|(_, item)| item));
+    ordered
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::StdRng;
+    use std::collections::HashMap;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_repositories_are_drawn_by_weight() {
+        let mut rng = StdRng::seed_from_u64(4711);
+        let mut first = HashMap::new();
+        for _ in 0..1000 {
+            let ordered = weighted_order(vec![(1, "a"), (3, "b"), (0, "c")], &mut rng);
+            assert_eq!(ordered.len(), 3);
+            assert_eq!(ordered[2], "c");
+            *first.entry(ordered[0]).or_insert(0) += 1;
+        }
+        assert!((650..850).contains(&first["b"]), "{:?}", first);
+
+        assert_eq!(weighted_order(vec![(0, "a")], &mut rng), vec!["a"]);
+        assert_eq!(weighted_order(vec![(0, "a"), (0, "b")], &mut rng), vec!["a", "b"]);
     }
-    Ok(None)
-}
\ No newline at end of file
+}
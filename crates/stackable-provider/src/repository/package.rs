@@ -8,7 +8,7 @@ use crate::error::StackableError;
 use std::fmt;
 
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Package {
     pub product: String,
     pub version: String,
@@ -22,15 +22,59 @@ impl Package {
     pub fn get_directory_name(&self) -> String {
         format!("{}-{}", self.product, self.version)
     }
+
+    /// Parses the name of a directory a package was installed to back into the package, which
+    /// is the inverse of [`Package::get_directory_name`].
+    ///
+    /// As product names may contain dashes themselves, the version is taken to start after the
+    /// first dash that is followed by a digit, e.g. `kafka-connect-2.6.0` is version `2.6.0` of
+    /// `kafka-connect`. Hidden directories and those starting with an underscore, which the
+    /// provider uses for its own purposes, are never packages.
+    pub fn from_directory_name(name: &str) -> Option<Package> {
+        if name.starts_with('.') || name.starts_with('_') {
+            return None;
+        }
+        let (index, _) = name.match_indices('-').find(|(index, _)| {
+            name[index + 1..]
+                .chars()
+                .next()
+                .map_or(false, |c| c.is_ascii_digit())
+        })?;
+        if index == 0 {
+            return None;
+        }
+        Some(Package {
+            product: String::from(&name[..index]),
+            version: String::from(&name[index + 1..]),
+        })
+    }
+
+    /// The binary that is started when a pod does not specify a command, relative to the
+    /// directory the package was installed to.
+    pub fn get_default_entrypoint(&self) -> String {
+        format!("bin/{}", self.product)
+    }
 }
 
 impl TryFrom<Reference> for Package {
     type Error = StackableError;
 
+    /// Creates a package from an image reference of the form
+    /// `[registry/][repository/path/]product:version`.
+    ///
+    /// Only the last segment of the repository path is used as the product name, the tag is
+    /// used as version. References without a tag are rejected.
     fn try_from(value: Reference) -> Result<Self, Self::Error> {
+        let product = value
+            .repository()
+            .rsplit('/')
+            .next()
+            .filter(|product| !product.is_empty())
+            .ok_or(PackageParseError)?;
+        let version = value.tag().ok_or(PackageParseError)?;
         Ok(Package {
-            product: String::from(value.repository()),
-            version: String::from(value.tag().unwrap()),
+            product: String::from(product),
+            version: String::from(version),
         })
     }
 }
@@ -41,3 +85,49 @@ impl fmt::Display for Package {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(reference: &str) -> Result<Package, StackableError> {
+        Package::try_from(Reference::try_from(reference).unwrap())
+    }
+
+    #[test]
+    fn test_parse_simple_reference() {
+        let package = parse("kafka:2.6.0").unwrap();
+        assert_eq!(package.product, "kafka");
+        assert_eq!(package.version, "2.6.0");
+    }
+
+    #[test]
+    fn test_parse_multi_segment_reference() {
+        let package = parse("stackable/kafka:2.6.0").unwrap();
+        assert_eq!(package.product, "kafka");
+        assert_eq!(package.version, "2.6.0");
+
+        let package = parse("repo.stackable.de:5000/stackable/products/kafka:2.6.0").unwrap();
+        assert_eq!(package.product, "kafka");
+        assert_eq!(package.version, "2.6.0");
+    }
+
+    #[test]
+    fn test_from_directory_name() {
+        let package = Package::from_directory_name("kafka-connect-2.6.0-rc1").unwrap();
+        assert_eq!(package.product, "kafka-connect");
+        assert_eq!(package.version, "2.6.0-rc1");
+        assert_eq!(package.get_directory_name(), "kafka-connect-2.6.0-rc1");
+
+        assert!(Package::from_directory_name("kafka").is_none());
+        assert!(Package::from_directory_name("-2.6.0").is_none());
+        assert!(Package::from_directory_name("_download").is_none());
+        assert!(Package::from_directory_name(".kafka-2.6.0.installing").is_none());
+    }
+
+    #[test]
+    fn test_parse_reference_without_tag() {
+        assert!(matches!(parse("kafka"), Err(PackageParseError)));
+        assert!(matches!(parse("stackable/kafka"), Err(PackageParseError)));
+    }
+}
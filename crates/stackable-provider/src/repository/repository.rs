@@ -13,6 +13,15 @@ namespaced
 pub struct RepositorySpec {
     pub repo_type: RepoType,
     pub properties: HashMap<String, String>,
+    /// How often the repository is picked relative to other repositories providing the same
+    /// package, 1 if not set
+    #[serde(default)]
+    pub weight: Option<u32>,
+    /// The name of a secret in the namespace of the repository with the client certificate
+    /// (`tls.crt`), its key (`tls.key`) and optionally the CA (`ca.crt`) to access the
+    /// repository with mutual TLS
+    #[serde(default)]
+    pub client_cert_secret: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -1,20 +1,50 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
 use std::hash::{Hash, Hasher};
 
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::ByteString;
 use kube::api::Meta;
+use kube::{Api, Client};
 use serde::{Deserialize, Serialize};
 use url::{ParseError, Url};
 
-use std::path::PathBuf;
-use std::fs::File;
-use std::io::{Cursor, copy};
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, copy, Seek, SeekFrom, Write};
+use crate::repository::hashes::HashRegistry;
 use crate::repository::package::Package;
 use crate::repository::repository::Repository;
+use crate::repository::RepositoryProvider;
+use futures::future::BoxFuture;
 use crate::error::StackableError;
-use log::{trace, debug, info, error};
+use log::{trace, debug, info, error, warn};
+use std::error::Error;
 use std::fmt;
-use crate::error::StackableError::PackageNotFound;
+use std::future::Future;
+use crate::error::StackableError::{ClientCertificateError, PackageDownloadCancelled, PackageDownloadError, PackageNotFound, RepositoryConnectionError, RepositoryMetadataError, Reqwest, RuntimeError};
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use reqwest::header::{ACCEPT_RANGES, CONTENT_LENGTH, RANGE};
+use reqwest::StatusCode;
+use kubelet::backoff::{BackoffStrategy, ExponentialBackoffStrategy};
+use std::time::Duration;
+
+/// The size of the chunks packages are downloaded in, if the repository supports range requests.
+const DOWNLOAD_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The number of chunks of a package that are downloaded at the same time.
+const MAX_PARALLEL_CHUNKS: usize = 4;
+
+/// How often fetching the repository metadata is attempted before giving up, unless the
+/// repository sets the `max_metadata_attempts` property.
+pub const DEFAULT_MAX_METADATA_ATTEMPTS: u32 = 5;
+
+/// The delay before the first retry of a failed metadata fetch, which doubles on every further
+/// retry up to `METADATA_RETRY_CAP`.
+const METADATA_RETRY_BASE: Duration = Duration::from_secs(1);
+const METADATA_RETRY_CAP: Duration = Duration::from_secs(30);
 
 
 #[derive(Debug, Clone)]
@@ -22,6 +52,10 @@ pub struct StackableRepoProvider {
     base_url: Url,
     pub name: String,
     content: Option<RepositoryContent>,
+    max_metadata_attempts: u32,
+    http_client: reqwest::Client,
+    /// The secret the client certificate is from, if the repository requires one
+    client_cert_secret: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -51,11 +85,95 @@ struct StackablePackage {
     pub hashes: HashMap<String, String>,
 }
 
+/// The `.part` file of a download, which is removed when the download is cancelled.
+struct PartFile {
+    path: PathBuf,
+    /// Set once the download finished, whether it succeeded or not
+    keep: bool,
+}
+
+impl PartFile {
+    fn new(path: PathBuf) -> Self {
+        PartFile { path, keep: false }
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        match fs::remove_file(&self.path) {
+            Ok(()) => debug!("Removed partial download {:?}", self.path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove partial download {:?}: {}", self.path, e),
+        }
+    }
+}
+
 impl StackableRepoProvider {
     pub fn new(name: String, base_url: String) -> Result<StackableRepoProvider, StackableError> {
         let base_url = Url::parse(&base_url)?;
 
-        Ok(StackableRepoProvider { base_url, name, content: None })
+        Ok(StackableRepoProvider { base_url, name, content: None, max_metadata_attempts: DEFAULT_MAX_METADATA_ATTEMPTS, http_client: reqwest::Client::new(), client_cert_secret: None })
+    }
+
+    /// Accesses the repository with the client certificate from the given secret, for
+    /// repositories which require mutual TLS. Without one, the default client is used.
+    pub async fn with_client_certificate(mut self, client: &Client, namespace: &str, secret_name: &str) -> Result<Self, StackableError> {
+        let secrets: Api<Secret> = Api::namespaced(client.clone(), namespace);
+        let data = secrets.get(secret_name).await?.data.unwrap_or_default();
+        self.http_client = StackableRepoProvider::client_with_identity(secret_name, &data)?;
+        self.client_cert_secret = Some(String::from(secret_name));
+        Ok(self)
+    }
+
+    /// Builds an HTTP client which authenticates with the certificate (`tls.crt`) and key
+    /// (`tls.key`) from the secret data, and trusts the CA (`ca.crt`) from it if there is one.
+    fn client_with_identity(secret_name: &str, data: &BTreeMap<String, ByteString>) -> Result<reqwest::Client, StackableError> {
+        let invalid = |msg: String| ClientCertificateError { secret: String::from(secret_name), msg };
+        let entry = |key: &str| data.get(key).map(|value| value.0.as_slice()).ok_or_else(|| invalid(format!("{} is missing", key)));
+        // The identity is read from a single PEM buffer with the key and the certificate chain
+        let mut pem = entry("tls.key")?.to_vec();
+        pem.push(b'\n');
+        pem.extend_from_slice(entry("tls.crt")?);
+        let identity = reqwest::Identity::from_pem(&pem).map_err(|e| invalid(e.to_string()))?;
+        let mut builder = reqwest::Client::builder().use_rustls_tls().identity(identity);
+        if let Ok(ca) = entry("ca.crt") {
+            let ca = reqwest::Certificate::from_pem(ca).map_err(|e| invalid(format!("ca.crt: {}", e)))?;
+            builder = builder.add_root_certificate(ca);
+        }
+        builder.build().map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Replaces the error of a request which could not connect to the repository, e.g. because
+    /// the TLS handshake failed, by one which names all of its causes, as the error of the
+    /// request itself does not tell why the connection failed.
+    fn explain_connection_error(&self, error: StackableError) -> StackableError {
+        match error {
+            Reqwest(e) if e.is_connect() => {
+                let mut msg = e.to_string();
+                let mut source = e.source();
+                while let Some(cause) = source {
+                    let cause_msg = cause.to_string();
+                    if !msg.contains(&cause_msg) {
+                        msg.push_str(&format!(": {}", cause_msg));
+                    }
+                    source = cause.source();
+                }
+                if let Some(secret) = &self.client_cert_secret {
+                    msg.push_str(&format!(" (using the client certificate from secret {})", secret));
+                }
+                RepositoryConnectionError { repository: self.name.clone(), msg }
+            }
+            e => e,
+        }
+    }
+
+    /// Sets how often fetching the repository metadata is attempted before giving up.
+    pub fn with_max_metadata_attempts(mut self, max_metadata_attempts: u32) -> Self {
+        self.max_metadata_attempts = max_metadata_attempts;
+        self
     }
 
     pub async fn provides_package<T: Into<Package>>(&mut self, package: T) -> Result<bool, StackableError> {
@@ -86,22 +204,196 @@ impl StackableRepoProvider {
         Err(PackageNotFound {package})
     }
 
-    pub async fn download_package(&mut self, package: &Package, target_path: PathBuf) -> Result<(), StackableError> {
+    /// Downloads the package into `target_path`.
+    ///
+    /// The data is first written to a `.part` file, which is only renamed to its final name
+    /// after the hash has been verified. If the server supports range requests, the package
+    /// is downloaded in chunks, several of them in parallel, and a `.part` file left over from
+    /// an earlier, interrupted attempt is resumed instead of starting from scratch.
+    ///
+    /// Packages with a `file://` link are copied from the local filesystem instead.
+    ///
+    /// The download is aborted as soon as `cancelled` completes, or when the returned future is
+    /// dropped before it finished, and the `.part` file is removed then, as nobody is going to
+    /// resume it.
+    pub async fn download_package(&mut self, package: &Package, target_path: PathBuf, cancelled: impl Future<Output = ()>) -> Result<(), StackableError> {
         if self.content.is_none() {
             let _content = self.get_repo_metadata();
         }
 
         let stackable_package = self.get_package(package.clone()).await?;
         let download_link = Url::parse(&stackable_package.link)?;
-        let mut response = reqwest::get(download_link).await?;
+        let target_file = target_path.join(package.get_file_name());
+        let mut part_file = PartFile::new(target_path.join(format!("{}.part", package.get_file_name())));
+
+        let fetched = tokio::select! {
+            result = StackableRepoProvider::fetch(&self.http_client, &download_link, &part_file.path) => Some(result.map_err(|e| self.explain_connection_error(e))),
+            _ = cancelled => None,
+        };
+        match fetched {
+            Some(result) => {
+                // A failed download is kept to be resumed by the next attempt
+                part_file.keep = true;
+                result?
+            }
+            None => {
+                info!("Download of package {} was cancelled", package);
+                return Err(PackageDownloadCancelled { package: package.clone() });
+            }
+        }
 
-        let mut content =  Cursor::new(response.bytes().await?);
+        if let Err(e) = StackableRepoProvider::verify_hash(&part_file.path, &stackable_package.hashes) {
+            // The partial file is complete but broken, there is no point in resuming it later
+            fs::remove_file(&part_file.path)?;
+            return Err(e);
+        }
+        fs::rename(&part_file.path, &target_file)?;
+        Ok(())
+    }
 
-        let mut out = File::create(target_path.join(package.get_file_name()))?;
+    /// Fetches the file behind `download_link` into `part_file`.
+    async fn fetch(client: &reqwest::Client, download_link: &Url, part_file: &Path) -> Result<(), StackableError> {
+        if download_link.scheme() == "file" {
+            let source = StackableRepoProvider::to_file_path(download_link)?;
+            debug!("Copying {:?} to {:?}", source, part_file);
+            tokio::fs::copy(&source, part_file).await?;
+        } else {
+            match StackableRepoProvider::get_ranged_content_length(client, download_link).await? {
+                Some(content_length) => {
+                    StackableRepoProvider::download_ranged(client, download_link, part_file, content_length).await?
+                }
+                None => StackableRepoProvider::download_whole(client, download_link, part_file).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the file behind `url`, if the server announces it and supports
+    /// range requests for it.
+    async fn get_ranged_content_length(client: &reqwest::Client, url: &Url) -> Result<Option<u64>, StackableError> {
+        let response = match client.head(url.clone()).send().await.and_then(|r| r.error_for_status()) {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("HEAD request for {} failed, not using range requests: {}", url, e);
+                return Ok(None);
+            }
+        };
+        let headers = response.headers();
+
+        let supports_ranges = headers
+            .get(ACCEPT_RANGES)
+            .and_then(|value| value.to_str().ok())
+            .map_or(false, |value| value.eq_ignore_ascii_case("bytes"));
+        if !supports_ranges {
+            debug!("Server for {} does not support range requests", url);
+            return Ok(None);
+        }
+
+        Ok(headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok()))
+    }
+
+    /// Downloads the file in one request, overwriting whatever is already present in `part_file`.
+    async fn download_whole(client: &reqwest::Client, url: &Url, part_file: &Path) -> Result<(), StackableError> {
+        debug!("Downloading {} to {:?} in one request", url, part_file);
+        let response = client.get(url.clone()).send().await?.error_for_status()?;
+        let mut content = Cursor::new(response.bytes().await?);
+
+        let mut out = File::create(part_file)?;
         copy(&mut content, &mut out)?;
         Ok(())
     }
 
+    /// Downloads the missing part of the file in chunks of `DOWNLOAD_CHUNK_SIZE` bytes, with up
+    /// to `MAX_PARALLEL_CHUNKS` requests in flight at the same time.
+    ///
+    /// Chunks are written to the file in order, so the `.part` file always contains a
+    /// contiguous prefix of the package and its size is the offset to resume from.
+    async fn download_ranged(
+        client: &reqwest::Client,
+        url: &Url,
+        part_file: &Path,
+        content_length: u64,
+    ) -> Result<(), StackableError> {
+        let mut out = OpenOptions::new().create(true).write(true).open(part_file)?;
+        let mut offset = out.metadata()?.len();
+        if offset > content_length {
+            warn!("Partial download {:?} is larger than the package, starting over", part_file);
+            offset = 0;
+        } else if offset > 0 {
+            info!("Resuming download of {} at byte {} of {}", url, offset, content_length);
+        }
+        out.set_len(offset)?;
+        out.seek(SeekFrom::Start(offset))?;
+
+        let ranges = (offset..content_length)
+            .step_by(DOWNLOAD_CHUNK_SIZE as usize)
+            .map(|start| (start, std::cmp::min(start + DOWNLOAD_CHUNK_SIZE, content_length)));
+        let mut chunks = stream::iter(ranges)
+            .map(|(start, end)| StackableRepoProvider::download_range(client, url, start, end))
+            .buffered(MAX_PARALLEL_CHUNKS);
+
+        while let Some(chunk) = chunks.next().await {
+            let (start, data) = chunk?;
+            trace!("Writing {} bytes at offset {} to {:?}", data.len(), start, part_file);
+            out.seek(SeekFrom::Start(start))?;
+            out.write_all(&data)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Downloads the bytes from `start` (inclusive) to `end` (exclusive).
+    async fn download_range(
+        client: &reqwest::Client,
+        url: &Url,
+        start: u64,
+        end: u64,
+    ) -> Result<(u64, Bytes), StackableError> {
+        let response = client
+            .get(url.clone())
+            .header(RANGE, format!("bytes={}-{}", start, end - 1))
+            .send()
+            .await?
+            .error_for_status()?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(PackageDownloadError {
+                msg: format!("server did not honor range request for {}: {}", url, response.status()),
+            });
+        }
+        let data = response.bytes().await?;
+        if data.len() as u64 != end - start {
+            return Err(PackageDownloadError {
+                msg: format!("expected {} bytes at offset {} from {}, got {}", end - start, start, url, data.len()),
+            });
+        }
+        Ok((start, data))
+    }
+
+    /// Checks the downloaded file against every hash the repository announced for it. Packages
+    /// without any hash are accepted with a warning, hashes of algorithms which are not in the
+    /// [`HashRegistry`] are rejected.
+    fn verify_hash(file: &Path, hashes: &HashMap<String, String>) -> Result<(), StackableError> {
+        if hashes.is_empty() {
+            warn!("No hash provided for {:?}, skipping verification", file);
+            return Ok(());
+        }
+
+        let registry = HashRegistry::default();
+        for (algorithm, expected) in hashes {
+            let actual = registry.hash_file(algorithm, file)?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(PackageDownloadError {
+                    msg: format!("{} of {:?} is {}, expected {}", algorithm, file, actual, expected),
+                });
+            }
+            debug!("Verified {} hash of {:?}", algorithm, file);
+        }
+        Ok(())
+    }
+
     // TODO: implement caching based on version of metadata
     async fn get_repo_metadata(&mut self) -> Result<RepositoryContent, StackableError> {
         trace!("entering get_repo_metadata");
@@ -112,11 +404,29 @@ impl StackableRepoProvider {
         metadata_url
             .path_segments_mut()
             .expect("")
+            .pop_if_empty()
             .push("metadata.json");
 
-        debug!("Retrieving repository metadata from {}", metadata_url);
-
-        let repo_data = reqwest::get(metadata_url).await?.json::<RepoData>().await?;
+        let mut backoff = ExponentialBackoffStrategy::new(METADATA_RETRY_BASE, METADATA_RETRY_CAP);
+        let mut attempt = 1;
+        let repo_data = loop {
+            debug!("Retrieving repository metadata from {} (attempt {})", metadata_url, attempt);
+            match self.fetch_repo_data(&metadata_url).await.map_err(|e| self.explain_connection_error(e)) {
+                Ok(repo_data) => break repo_data,
+                Err(e) if attempt >= self.max_metadata_attempts => {
+                    return Err(RepositoryMetadataError {
+                        url: metadata_url.to_string(),
+                        msg: format!("giving up after {} attempts: {}", attempt, e),
+                    });
+                }
+                Err(e) => {
+                    let delay = backoff.next_duration();
+                    warn!("Failed to retrieve repository metadata from {}, retrying in {:?}: {}", metadata_url, delay, e);
+                    tokio::time::delay_for(delay).await;
+                    attempt += 1;
+                }
+            }
+        };
 
         debug!("Got repository metadata: {:?}", repo_data);
 
@@ -144,13 +454,51 @@ impl StackableRepoProvider {
         Ok(repo_content)
     }
 
+    /// Reads the metadata from the repository once, from disk for `file://` repositories and via
+    /// HTTP otherwise.
+    async fn fetch_repo_data(&self, metadata_url: &Url) -> Result<RepoData, StackableError> {
+        if metadata_url.scheme() == "file" {
+            let metadata_file = StackableRepoProvider::to_file_path(metadata_url)?;
+            Ok(serde_json::from_slice(&tokio::fs::read(metadata_file).await?)?)
+        } else {
+            Ok(self.http_client.get(metadata_url.clone()).send().await?.error_for_status()?.json::<RepoData>().await?)
+        }
+    }
+
+    /// Resolves a link from the repository metadata. Relative links are resolved against the
+    /// directory of the repository, which also contains `metadata.json`, so
+    /// `parcels/product.tar.gz` in a repository at `file:///srv/repo` becomes
+    /// `file:///srv/repo/parcels/product.tar.gz`.
     fn resolve_url(&self, path: String) -> Result<String, StackableError> {
         if let Result::Ok(absolute_link) = Url::parse(&path) {
             return Ok(path);
         }
-        let resolved_path = self.base_url.join(&path)?;
+        let mut base_url = self.base_url.clone();
+        if !base_url.path().ends_with('/') {
+            let directory = format!("{}/", base_url.path());
+            base_url.set_path(&directory);
+        }
+        let resolved_path = base_url.join(&path)?;
         Ok(resolved_path.as_str().to_string())
     }
+
+    /// Converts a `file://` URL to the path it points to.
+    fn to_file_path(url: &Url) -> Result<PathBuf, StackableError> {
+        url.to_file_path().map_err(|_| RuntimeError {
+            msg: format!("{} does not point to a local file", url),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl RepositoryProvider for StackableRepoProvider {
+    async fn provides_package(&mut self, package: &Package) -> Result<bool, StackableError> {
+        StackableRepoProvider::provides_package(self, package.clone()).await
+    }
+
+    async fn download_package(&mut self, package: &Package, target_path: PathBuf, cancelled: BoxFuture<'static, ()>) -> Result<(), StackableError> {
+        StackableRepoProvider::download_package(self, package, target_path, cancelled).await
+    }
 }
 
 impl fmt::Display for StackableRepoProvider {
@@ -164,9 +512,13 @@ impl TryFrom<&Repository> for StackableRepoProvider {
 
     fn try_from(value: &Repository) -> Result<Self, Self::Error> {
         let properties: HashMap<String, String> = value.clone().spec.properties;
+        let max_metadata_attempts = match properties.get("max_metadata_attempts") {
+            Some(attempts) => attempts.parse().map_err(|_| StackableError::RepositoryConversionError)?,
+            None => DEFAULT_MAX_METADATA_ATTEMPTS,
+        };
         let path = properties.get("url");
         match path {
-            Some(gna) => return Ok(StackableRepoProvider { name: Meta::name(value), base_url: Url::parse(gna)?, content: None }),
+            Some(gna) => return Ok(StackableRepoProvider { name: Meta::name(value), base_url: Url::parse(gna)?, content: None, max_metadata_attempts, http_client: reqwest::Client::new(), client_cert_secret: None }),
             None => return Err(StackableError::RepositoryConversionError)
         }
     }
@@ -188,11 +540,192 @@ impl Hash for StackableRepoProvider {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::path::PathBuf;
     use url::Url;
 
     #[test]
     fn test_url_functions() {
         assert!(true);
     }
-}
 
+    fn client_cert_secret_data(keys: &[&str]) -> BTreeMap<String, ByteString> {
+        let testdata = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let mut data = BTreeMap::new();
+        for key in keys {
+            let file = match *key {
+                "tls.key" => "client.key",
+                // The self-signed client certificate is its own CA
+                _ => "client.crt",
+            };
+            data.insert(String::from(*key), ByteString(std::fs::read(testdata.join(file)).unwrap()));
+        }
+        data
+    }
+
+    #[test]
+    fn test_client_with_identity_from_secret() {
+        assert!(StackableRepoProvider::client_with_identity("repo-cert", &client_cert_secret_data(&["tls.crt", "tls.key"])).is_ok());
+        assert!(StackableRepoProvider::client_with_identity("repo-cert", &client_cert_secret_data(&["tls.crt", "tls.key", "ca.crt"])).is_ok());
+
+        match StackableRepoProvider::client_with_identity("repo-cert", &client_cert_secret_data(&["tls.crt"])) {
+            Err(ClientCertificateError { secret, msg }) => {
+                assert_eq!(secret, "repo-cert");
+                assert_eq!(msg, "tls.key is missing");
+            }
+            other => panic!("Expected a ClientCertificateError, got {:?}", other.map(|_| ())),
+        }
+
+        let mut data = client_cert_secret_data(&["tls.crt"]);
+        data.insert(String::from("tls.key"), ByteString(b"not a key".to_vec()));
+        assert!(matches!(StackableRepoProvider::client_with_identity("repo-cert", &data), Err(ClientCertificateError { .. })));
+    }
+
+    #[test]
+    fn test_resolve_url_relative_to_repository_directory() {
+        let repo = StackableRepoProvider::new(String::from("local"), String::from("file:///srv/repo")).unwrap();
+
+        assert_eq!(
+            repo.resolve_url(String::from("parcels/product.tar.gz")).unwrap(),
+            "file:///srv/repo/parcels/product.tar.gz"
+        );
+        assert_eq!(
+            repo.resolve_url(String::from("/mnt/product.tar.gz")).unwrap(),
+            "file:///mnt/product.tar.gz"
+        );
+        assert_eq!(
+            repo.resolve_url(String::from("https://example.com/product.tar.gz")).unwrap(),
+            "https://example.com/product.tar.gz"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_repository() {
+        let repo_directory = tempfile::tempdir().unwrap();
+        let target_directory = tempfile::tempdir().unwrap();
+        fs::create_dir(repo_directory.path().join("parcels")).unwrap();
+        fs::write(repo_directory.path().join("parcels/product-1.0.tar.gz"), "content").unwrap();
+        let metadata = serde_json::json!({
+            "version": "1",
+            "parcels": {
+                "product": [{
+                    "version": "1.0",
+                    "path": "parcels/product-1.0.tar.gz",
+                    "hashes": {
+                        "SHA256": "ed7002b439e9ac845f22357d822bac1444730fbdb6016d3ec9432297b9ec9f73"
+                    }
+                }]
+            }
+        });
+        fs::write(repo_directory.path().join("metadata.json"), metadata.to_string()).unwrap();
+
+        let base_url = Url::from_directory_path(repo_directory.path()).unwrap();
+        let mut repo = StackableRepoProvider::new(String::from("local"), base_url.to_string()).unwrap();
+        let package = Package {
+            product: String::from("product"),
+            version: String::from("1.0"),
+        };
+
+        assert!(repo.provides_package(package.clone()).await.unwrap());
+        repo.download_package(&package, target_directory.path().to_path_buf(), futures::future::pending()).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(target_directory.path().join(package.get_file_name())).unwrap(),
+            "content"
+        );
+    }
+
+    /// Serves a package of `content_length` bytes with range requests, but never sends more
+    /// than the first few bytes of a chunk, and returns its address.
+    async fn serve_stalling_package(content_length: u64) -> std::net::SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = vec![];
+                    let mut buffer = [0u8; 1024];
+                    loop {
+                        let read = socket.read(&mut buffer).await.unwrap_or(0);
+                        if read == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buffer[..read]);
+                        if !request.ends_with(b"\r\n\r\n") {
+                            continue;
+                        }
+                        if request.starts_with(b"HEAD") {
+                            let response = format!("HTTP/1.1 200 OK\r\nAccept-Ranges: bytes\r\nContent-Length: {}\r\n\r\n", content_length);
+                            socket.write_all(response.as_bytes()).await.unwrap();
+                            request.clear();
+                        } else {
+                            let response = format!("HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\npartial", content_length);
+                            socket.write_all(response.as_bytes()).await.unwrap();
+                            futures::future::pending::<()>().await;
+                        }
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_download_removes_partial_file() {
+        let repo_directory = tempfile::tempdir().unwrap();
+        let target_directory = tempfile::tempdir().unwrap();
+        let address = serve_stalling_package(1024).await;
+        let metadata = serde_json::json!({
+            "version": "1",
+            "parcels": {
+                "product": [{
+                    "version": "1.0",
+                    "path": format!("http://{}/product-1.0.tar.gz", address),
+                    "hashes": {}
+                }]
+            }
+        });
+        fs::write(repo_directory.path().join("metadata.json"), metadata.to_string()).unwrap();
+
+        let base_url = Url::from_directory_path(repo_directory.path()).unwrap();
+        let mut repo = StackableRepoProvider::new(String::from("local"), base_url.to_string()).unwrap();
+        let package = Package {
+            product: String::from("product"),
+            version: String::from("1.0"),
+        };
+        assert!(repo.provides_package(package.clone()).await.unwrap());
+
+        let part_file = target_directory.path().join(format!("{}.part", package.get_file_name()));
+        let cancelled = async {
+            // Give the download time to create the partial file before it is cancelled
+            while !part_file.exists() {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        };
+        match repo.download_package(&package, target_directory.path().to_path_buf(), cancelled).await {
+            Err(PackageDownloadCancelled { .. }) => {}
+            other => panic!("expected the download to be cancelled, got {:?}", other),
+        }
+        assert!(!part_file.exists());
+        assert!(!target_directory.path().join(package.get_file_name()).exists());
+    }
+
+    #[tokio::test]
+    async fn test_metadata_error_names_repository() {
+        let repo_directory = tempfile::tempdir().unwrap();
+        let base_url = Url::from_directory_path(repo_directory.path()).unwrap();
+        let mut repo = StackableRepoProvider::new(String::from("local"), base_url.to_string())
+            .unwrap()
+            .with_max_metadata_attempts(1);
+
+        match repo.get_repo_metadata().await {
+            Err(RepositoryMetadataError { url, .. }) => {
+                assert_eq!(url, base_url.join("metadata.json").unwrap().to_string())
+            }
+            other => panic!("expected a metadata error, got {:?}", other),
+        }
+    }
+}
@@ -8,16 +8,19 @@ use crate::states::setup_failed::SetupFailed;
 use crate::states::waiting_config::WaitingConfigMap;
 use crate::PodState;
 use handlebars::{Handlebars, RenderError};
-use k8s_openapi::api::core::v1::{ConfigMap, Volume, VolumeMount};
+use k8s_openapi::api::core::v1::{ConfigMap, Secret, Volume, VolumeMount};
 use kube::api::ListParams;
 use kube::{Api, Client};
+use kubelet::container::Container;
 use kubelet::pod::Pod;
 use kubelet::state::prelude::*;
 use kubelet::state::{State, Transition};
 use log::{debug, error, info, trace, warn};
 use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::fs::read_to_string;
+use std::fs::{read_to_string, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 
 #[derive(Default, Debug, TransitionTo)]
@@ -72,6 +75,61 @@ impl CreatingConfig {
         render_data
     }
 
+    /// Collects the values that can be referenced as `${VAR}` placeholders in config files:
+    /// the environment variables of the container plus `NODE_NAME`, `POD_NAME` and `POD_IP`,
+    /// as far as these are known. Environment variables from the pod take precedence.
+    async fn create_substitution_variables(
+        pod: &Pod,
+        container: &Container,
+        client: &Client,
+    ) -> HashMap<String, String> {
+        let kube_pod = pod.as_kube_pod();
+        let mut variables = HashMap::new();
+        if let Some(node_name) = kube_pod.spec.as_ref().and_then(|s| s.node_name.as_ref()) {
+            variables.insert(String::from("NODE_NAME"), node_name.clone());
+        }
+        variables.insert(String::from("POD_NAME"), pod.name().to_string());
+        if let Some(pod_ip) = kube_pod.status.as_ref().and_then(|s| s.pod_ip.as_ref()) {
+            variables.insert(String::from("POD_IP"), pod_ip.clone());
+        }
+        variables.extend(kubelet::provider::env_vars(container, pod, client).await);
+        variables
+    }
+
+    /// Replaces all `${VAR}` placeholders in `content` with the value of `VAR` from
+    /// `variables`. Placeholders for unknown variables are left untouched and a warning is
+    /// logged, so that misconfigurations are visible.
+    fn substitute_variables(content: &str, variables: &HashMap<String, String>) -> String {
+        let mut result = String::with_capacity(content.len());
+        let mut remainder = content;
+        while let Some(start) = remainder.find("${") {
+            result.push_str(&remainder[..start]);
+            let placeholder = &remainder[start..];
+            match placeholder.find('}') {
+                Some(end) => {
+                    let name = &placeholder[2..end];
+                    match variables.get(name) {
+                        Some(value) => result.push_str(value),
+                        None => {
+                            warn!(
+                                "No value found for placeholder ${{{}}}, leaving it in place",
+                                name
+                            );
+                            result.push_str(&placeholder[..=end]);
+                        }
+                    }
+                    remainder = &placeholder[end + 1..];
+                }
+                None => {
+                    result.push_str(placeholder);
+                    remainder = "";
+                }
+            }
+        }
+        result.push_str(remainder);
+        result
+    }
+
     async fn missing_config_maps(&self, client: Client, configmaps: Vec<String>) -> Vec<String> {
         // TODO: distinguish between an actually missing configmap and an error when talking to
         // the apiserver
@@ -90,7 +148,8 @@ impl CreatingConfig {
         missing_configmaps
     }
 
-    async fn get_config_maps(&self, pod: &Pod) -> Vec<String> {
+    /// Returns the names of all config maps the volumes of the pod refer to.
+    pub fn get_config_maps(pod: &Pod) -> Vec<String> {
         let mut get_config_maps = vec![];
 
         if let Some(volumes) = pod.volumes() {
@@ -125,6 +184,7 @@ impl CreatingConfig {
         map: ConfigMap,
         target_directory: PathBuf,
         template_data: &BTreeMap<String, String>,
+        variables: &HashMap<String, String>,
     ) -> Result<(), StackableError> {
         let config_map_name = map.metadata.name.unwrap_or(String::from("undefined"));
         debug!(
@@ -146,6 +206,8 @@ impl CreatingConfig {
                         template_data.clone(),
                         content.clone(),
                     )?;
+                    let rendered_content =
+                        CreatingConfig::substitute_variables(&rendered_content, variables);
                     debug!("done rendering");
                     let target_file = target_directory.join(&key);
 
@@ -155,11 +217,8 @@ impl CreatingConfig {
                             "writing content of map entry {} to file {:?}",
                             key, target_file
                         );
-                        let write_result = fs::write(target_directory.join(&key), rendered_content);
-                        match write_result {
-                            Ok(()) => debug!("write of file {:?} successful!", target_file),
-                            Err(e) => error!("write of file {:?} failed: {}", target_file, e),
-                        }
+                        fs::write(&target_file, rendered_content)?;
+                        debug!("write of file {:?} successful!", target_file);
                     } else {
                         debug!("No update needed for {:?}", target_file);
                     }
@@ -173,6 +232,56 @@ impl CreatingConfig {
         Ok(())
     }
 
+    async fn retrieve_secret(
+        &self,
+        client: Client,
+        namespace: &str,
+        name: String,
+    ) -> Result<Secret, StackableError> {
+        let secrets: Api<Secret> = Api::namespaced(client, namespace);
+
+        Ok(secrets.get(&name).await?)
+    }
+
+    /// Writes every entry of the secret to a file named after its key in the target directory.
+    /// The files are only readable by the owner, as they usually contain keys or passwords.
+    fn apply_secret(
+        &self,
+        secret: Secret,
+        target_directory: PathBuf,
+    ) -> Result<(), StackableError> {
+        let secret_name = secret.metadata.name.unwrap_or(String::from("undefined"));
+        debug!(
+            "applying secret {} to directory {:?}",
+            &secret_name, target_directory
+        );
+        if !(&target_directory.is_dir()) {
+            info!("creating secret directory {:?}", target_directory);
+            fs::create_dir_all(&target_directory)?;
+        }
+        if let Some(data) = secret.data {
+            for (key, value) in data {
+                let target_file = target_directory.join(&key);
+                debug!(
+                    "writing content of secret entry {} to file {:?}",
+                    key, target_file
+                );
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&target_file)?;
+                // The mode above only applies to newly created files
+                file.set_permissions(fs::Permissions::from_mode(0o600))?;
+                file.write_all(&value.0)?;
+            }
+        } else {
+            debug!("No data found in Secret..");
+        }
+        Ok(())
+    }
+
     fn needs_update(target_file: &PathBuf, content: &str) -> Result<bool, StackableError> {
         if target_file.is_file() {
             let current_content = read_to_string(target_file)?;
@@ -202,7 +311,7 @@ impl State<PodState> for CreatingConfig {
         let target_directory = self.target_directory.clone().unwrap();
 
         // Check if all required config maps have been created in the api-server
-        let referenced_config_maps = self.get_config_maps(_pod).await;
+        let referenced_config_maps = CreatingConfig::get_config_maps(_pod);
         let missing_config_maps = self
             .missing_config_maps(client.clone(), referenced_config_maps)
             .await;
@@ -227,6 +336,8 @@ impl State<PodState> for CreatingConfig {
             fail_fatal!(e);
         }
         let container = containers[0].clone();
+        let variables =
+            CreatingConfig::create_substitution_variables(_pod, &container, &client).await;
 
         if let Some(volumes) = _pod.volumes() {
             debug!("Found {} volumes in pod {}", volumes.len(), _pod.name());
@@ -239,20 +350,80 @@ impl State<PodState> for CreatingConfig {
                             let target_dir = target_directory.join(&mount.mount_path);
                             if let Some(config_map) = &volume.config_map {
                                 if let Some(map_name) = &config_map.name {
-                                    if let Ok(map) = self
+                                    let map = match self
                                         .retrieve_config_map(client.clone(), map_name.to_string())
                                         .await
                                     {
-                                        debug!("found config map: {:?} - applying", config_map);
-                                        self.apply_config_map(
-                                            map,
-                                            target_dir,
-                                            &CreatingConfig::create_render_data(pod_state),
+                                        Ok(map) => map,
+                                        Err(e) => {
+                                            let message = format!(
+                                                "Failed to retrieve config map {}: {}",
+                                                map_name, e
+                                            );
+                                            error!("{}", message);
+                                            return Transition::next(self, SetupFailed { message });
+                                        }
+                                    };
+                                    debug!("found config map: {:?} - applying", config_map);
+                                    if let Err(e) = self.apply_config_map(
+                                        map,
+                                        target_dir,
+                                        &CreatingConfig::create_render_data(pod_state),
+                                        &variables,
+                                    ) {
+                                        let message = format!(
+                                            "Failed to apply config map {}: {}",
+                                            map_name, e
                                         );
+                                        error!("{}", message);
+                                        return Transition::next(self, SetupFailed { message });
                                     }
                                 }
+                            } else if let Some(secret) = &volume.secret {
+                                if let Some(secret_name) = &secret.secret_name {
+                                    let secret = match self
+                                        .retrieve_secret(
+                                            client.clone(),
+                                            _pod.namespace(),
+                                            secret_name.to_string(),
+                                        )
+                                        .await
+                                    {
+                                        Ok(secret) => secret,
+                                        Err(e) => {
+                                            let message = format!(
+                                                "Failed to retrieve secret {}: {}",
+                                                secret_name, e
+                                            );
+                                            error!("{}", message);
+                                            return Transition::next(self, SetupFailed { message });
+                                        }
+                                    };
+                                    debug!("found secret {} - applying", secret_name);
+                                    if let Err(e) = self.apply_secret(secret, target_dir) {
+                                        let message = format!(
+                                            "Failed to apply secret {}: {}",
+                                            secret_name, e
+                                        );
+                                        error!("{}", message);
+                                        return Transition::next(self, SetupFailed { message });
+                                    }
+                                }
+                            } else if volume.empty_dir.is_some() {
+                                debug!("Creating empty directory {:?}", target_dir);
+                                if let Err(e) = fs::create_dir_all(&target_dir) {
+                                    let message = format!(
+                                        "Failed to create directory {:?} for volume {}: {}",
+                                        target_dir, volume.name, e
+                                    );
+                                    error!("{}", message);
+                                    return Transition::next(self, SetupFailed { message });
+                                }
                             } else {
-                                warn!("Skipping volume {} - it is not a config map", volume.name);
+                                warn!(
+                                    "Skipping volume {} - volume type is not supported",
+                                    volume.name
+                                );
                             }
                         }
                     }
@@ -271,3 +442,27 @@ impl State<PodState> for CreatingConfig {
         make_status(Phase::Pending, &"status:initializing")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_substitute_variables() {
+        let mut variables = HashMap::new();
+        variables.insert(String::from("POD_NAME"), String::from("zookeeper-1"));
+        variables.insert(String::from("PORT"), String::from("2181"));
+
+        assert_eq!(
+            CreatingConfig::substitute_variables(
+                "name=${POD_NAME}\nport=${PORT}\nhost=${NODE_NAME}",
+                &variables
+            ),
+            "name=zookeeper-1\nport=2181\nhost=${NODE_NAME}"
+        );
+        assert_eq!(
+            CreatingConfig::substitute_variables("price=$5, ${unterminated", &variables),
+            "price=$5, ${unterminated"
+        );
+    }
+}
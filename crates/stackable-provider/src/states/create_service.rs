@@ -1,21 +1,291 @@
-use kubelet::state::{State, Transition};
+use crate::error::StackableError;
+use crate::error::StackableError::RuntimeError;
+use crate::states::initializing::Initializing;
+use crate::states::setup_failed::SetupFailed;
+use crate::PodState;
+use k8s_openapi::api::core::v1::{
+    EndpointAddress, EndpointPort, EndpointSubset, Endpoints, Pod as KubePod, Service, ServicePort,
+    ServiceSpec,
+};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use kube::api::{DeleteParams, Meta, PostParams};
+use kube::error::ErrorResponse;
+use kube::{Api, Client};
 use kubelet::pod::Pod;
 use kubelet::state::prelude::*;
-use crate::PodState;
-use crate::states::running::Running;
-use crate::states::failed::Failed;
-use crate::states::starting::Starting;
-use crate::states::setup_failed::SetupFailed;
+use kubelet::state::{State, Transition};
+use log::{debug, info, warn};
+use serde::de::DeserializeOwned;
+
+/// Annotation which makes the provider create a service named after the pod for the ports of
+/// its container, so that other workloads can reach it by name. The service is removed again
+/// when the pod terminates. Services and endpoints of the same name which the pod does not own
+/// are neither updated nor removed.
+pub const CREATE_SERVICE_ANNOTATION: &str = "stackable.de/create-service";
 
 #[derive(Default, Debug, TransitionTo)]
-#[transition_to(Starting, SetupFailed)]
+#[transition_to(Initializing, SetupFailed)]
 pub struct CreatingService;
 
+/// A port of the container, as it is exposed by the service.
+#[derive(Debug, PartialEq)]
+struct ExposedPort {
+    name: String,
+    port: i32,
+    protocol: Option<String>,
+}
+
+impl CreatingService {
+    /// Returns whether a service should be created for the pod.
+    pub fn service_requested(pod: &Pod) -> bool {
+        pod.get_annotation(CREATE_SERVICE_ANNOTATION) == Some("true")
+    }
+
+    /// Returns the ports of the containers of the pod. Services with more than one port require
+    /// every port to be named, so unnamed ports are named after their number.
+    fn exposed_ports(pod: &Pod) -> Vec<ExposedPort> {
+        pod.containers()
+            .iter()
+            .flat_map(|container| container.ports().clone().unwrap_or_default())
+            .map(|port| ExposedPort {
+                name: port
+                    .name
+                    .unwrap_or_else(|| format!("port-{}", port.container_port)),
+                port: port.container_port,
+                protocol: port.protocol,
+            })
+            .collect()
+    }
+
+    /// Makes the pod own the objects, so that they are removed by Kubernetes even if the
+    /// provider is not around when the pod is deleted.
+    fn metadata(pod: &Pod) -> ObjectMeta {
+        let kube_pod = pod.as_kube_pod();
+        ObjectMeta {
+            name: Some(pod.name().to_string()),
+            namespace: Some(pod.namespace().to_string()),
+            labels: kube_pod.metadata.labels.clone(),
+            owner_references: kube_pod.metadata.uid.clone().map(|uid| {
+                vec![OwnerReference {
+                    api_version: String::from("v1"),
+                    kind: String::from("Pod"),
+                    name: pod.name().to_string(),
+                    uid,
+                    ..Default::default()
+                }]
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// The service has no selector, as the process does not run in a network namespace of its
+    /// own. Instead, it is backed by endpoints which point at the node the process runs on.
+    fn service(pod: &Pod, ports: &[ExposedPort]) -> Service {
+        Service {
+            metadata: CreatingService::metadata(pod),
+            spec: Some(ServiceSpec {
+                ports: Some(
+                    ports
+                        .iter()
+                        .map(|port| ServicePort {
+                            name: Some(port.name.clone()),
+                            port: port.port,
+                            target_port: Some(IntOrString::Int(port.port)),
+                            protocol: port.protocol.clone(),
+                            ..Default::default()
+                        })
+                        .collect(),
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn endpoints(pod: &Pod, ports: &[ExposedPort], ip: &str) -> Endpoints {
+        Endpoints {
+            metadata: CreatingService::metadata(pod),
+            subsets: Some(vec![EndpointSubset {
+                addresses: Some(vec![EndpointAddress {
+                    ip: ip.to_string(),
+                    ..Default::default()
+                }]),
+                ports: Some(
+                    ports
+                        .iter()
+                        .map(|port| EndpointPort {
+                            name: Some(port.name.clone()),
+                            port: port.port,
+                            protocol: port.protocol.clone(),
+                            ..Default::default()
+                        })
+                        .collect(),
+                ),
+                ..Default::default()
+            }]),
+        }
+    }
+
+    /// Returns the IP address the process of the pod can be reached at. The status of the pod
+    /// handed to the state machine may predate the address being set, so it is fetched again if
+    /// necessary.
+    async fn pod_address(client: &Client, pod: &Pod) -> Result<String, StackableError> {
+        if let Some(ip) = pod.host_ip().or_else(|| pod.pod_ip()) {
+            return Ok(ip.to_string());
+        }
+        let pods: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+        let current = Pod::from(pods.get(pod.name()).await?);
+        current
+            .host_ip()
+            .or_else(|| current.pod_ip())
+            .map(String::from)
+            .ok_or_else(|| RuntimeError {
+                msg: format!("No IP address is known yet for pod {}", pod.name()),
+            })
+    }
+
+    /// Returns whether the object was created for the pod, i.e. whether it is owned by this
+    /// very pod and not by another pod of the same name, or by nobody at all.
+    fn owned_by(metadata: &ObjectMeta, pod: &Pod) -> bool {
+        let uid = match pod.as_kube_pod().metadata.uid.as_deref() {
+            Some(uid) => uid,
+            None => return false,
+        };
+        metadata
+            .owner_references
+            .iter()
+            .flatten()
+            .any(|owner| owner.kind == "Pod" && owner.uid == uid)
+    }
+
+    /// Fails if the object exists already, but was not created for the pod, as it must not be
+    /// overwritten then.
+    fn check_owned_by(kind: &str, metadata: &ObjectMeta, pod: &Pod) -> Result<(), StackableError> {
+        if CreatingService::owned_by(metadata, pod) {
+            Ok(())
+        } else {
+            Err(RuntimeError {
+                msg: format!(
+                    "{} {} already exists and does not belong to pod {}",
+                    kind,
+                    pod.name(),
+                    pod.name()
+                ),
+            })
+        }
+    }
+
+    /// Creates the service and its endpoints, or updates them if they already exist and were
+    /// created for the pod.
+    async fn apply_service(client: &Client, pod: &Pod) -> Result<(), StackableError> {
+        let ports = CreatingService::exposed_ports(pod);
+        let ip = CreatingService::pod_address(client, pod).await?;
+
+        let services: Api<Service> = Api::namespaced(client.clone(), pod.namespace());
+        let mut service = CreatingService::service(pod, &ports);
+        match services.get(pod.name()).await {
+            Ok(existing) => {
+                CreatingService::check_owned_by("Service", &existing.metadata, pod)?;
+                debug!("Updating service {}", pod.name());
+                // The cluster IP of a service cannot be changed
+                service.metadata.resource_version = existing.metadata.resource_version;
+                if let (Some(spec), Some(existing_spec)) = (service.spec.as_mut(), existing.spec) {
+                    spec.cluster_ip = existing_spec.cluster_ip;
+                }
+                services
+                    .replace(pod.name(), &PostParams::default(), &service)
+                    .await?;
+            }
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {
+                debug!("Creating service {}", pod.name());
+                services.create(&PostParams::default(), &service).await?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let endpoints_api: Api<Endpoints> = Api::namespaced(client.clone(), pod.namespace());
+        let mut endpoints = CreatingService::endpoints(pod, &ports, &ip);
+        match endpoints_api.get(pod.name()).await {
+            Ok(existing) => {
+                CreatingService::check_owned_by("Endpoints", &existing.metadata, pod)?;
+                endpoints.metadata.resource_version = existing.metadata.resource_version;
+                endpoints_api
+                    .replace(pod.name(), &PostParams::default(), &endpoints)
+                    .await?;
+            }
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {
+                endpoints_api
+                    .create(&PostParams::default(), &endpoints)
+                    .await?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        info!(
+            "Service {} points to {} for ports {:?}",
+            pod.name(),
+            ip,
+            ports
+        );
+        Ok(())
+    }
+
+    /// Removes the object named after the pod, unless it was not created for the pod.
+    async fn delete_owned<K>(api: &Api<K>, kind: &str, pod: &Pod) -> Result<(), kube::Error>
+    where
+        K: Clone + DeserializeOwned + Meta,
+    {
+        let existing = match api.get(pod.name()).await {
+            Ok(existing) => existing,
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if !CreatingService::owned_by(existing.meta(), pod) {
+            info!(
+                "Not removing {} {}, as it does not belong to pod {}",
+                kind,
+                pod.name(),
+                pod.name()
+            );
+            return Ok(());
+        }
+        api.delete(pod.name(), &DeleteParams::default())
+            .await
+            .map(|_| ())
+    }
+
+    /// Removes the service created for the pod, if the pod asked for one.
+    pub async fn delete_service(client: &Client, pod: &Pod) {
+        if !CreatingService::service_requested(pod) {
+            return;
+        }
+        let services: Api<Service> = Api::namespaced(client.clone(), pod.namespace());
+        let endpoints: Api<Endpoints> = Api::namespaced(client.clone(), pod.namespace());
+        let results = vec![
+            CreatingService::delete_owned(&services, "Service", pod).await,
+            CreatingService::delete_owned(&endpoints, "Endpoints", pod).await,
+        ];
+        for result in results {
+            match result {
+                Ok(()) | Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {}
+                Err(e) => warn!("Failed to remove service {}: {}", pod.name(), e),
+            }
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl State<PodState> for CreatingService {
     async fn next(self: Box<Self>, pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
-        println!("creating service");
-        Transition::next(self, Starting)
+        if !CreatingService::service_requested(_pod) {
+            debug!("No service requested for pod {}", _pod.name());
+            return Transition::next(self, Initializing);
+        }
+        if let Err(e) = CreatingService::apply_service(&pod_state.client, _pod).await {
+            let message = format!("Failed to create service {}: {}", _pod.name(), e);
+            return Transition::next(self, SetupFailed { message });
+        }
+        Transition::next(self, Initializing)
     }
 
     async fn json_status(
@@ -25,4 +295,66 @@ impl State<PodState> for CreatingService {
     ) -> anyhow::Result<serde_json::Value> {
         make_status(Phase::Pending, &"status:initializing")
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_only_objects_of_the_pod_are_owned_by_it() {
+        let pod: KubePod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "kafka", "namespace": "default", "uid": "1234" },
+            "spec": { "containers": [{ "name": "kafka" }] }
+        }))
+        .unwrap();
+        let pod = Pod::from(pod);
+
+        let metadata = CreatingService::metadata(&pod);
+        assert!(CreatingService::owned_by(&metadata, &pod));
+
+        let mut of_other_pod = metadata.clone();
+        of_other_pod.owner_references.as_mut().unwrap()[0].uid = String::from("5678");
+        assert!(!CreatingService::owned_by(&of_other_pod, &pod));
+        assert!(CreatingService::check_owned_by("Service", &of_other_pod, &pod).is_err());
+
+        let unowned = ObjectMeta {
+            name: Some(String::from("kafka")),
+            ..Default::default()
+        };
+        assert!(!CreatingService::owned_by(&unowned, &pod));
+    }
+
+    #[test]
+    fn test_exposed_ports_are_named() {
+        let pod: KubePod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "kafka", "namespace": "default" },
+            "spec": {
+                "containers": [{
+                    "name": "kafka",
+                    "ports": [
+                        { "name": "client", "containerPort": 9092 },
+                        { "containerPort": 9999, "protocol": "TCP" }
+                    ]
+                }]
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(
+            CreatingService::exposed_ports(&Pod::from(pod)),
+            vec![
+                ExposedPort {
+                    name: String::from("client"),
+                    port: 9092,
+                    protocol: None,
+                },
+                ExposedPort {
+                    name: String::from("port-9999"),
+                    port: 9999,
+                    protocol: Some(String::from("TCP")),
+                },
+            ]
+        );
+    }
+}
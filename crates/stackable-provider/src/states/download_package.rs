@@ -6,7 +6,7 @@ use crate::states::running::Running;
 use crate::states::failed::Failed;
 use crate::states::install_package::Installing;
 use crate::states::setup_failed::SetupFailed;
-use crate::error::StackableError::PodValidationError;
+use crate::error::StackableError::{PackageDownloadCancelled, PodValidationError};
 use crate::fail_fatal;
 use kube::api::Meta;
 use k8s_openapi::api::core::v1::PodSpec;
@@ -15,15 +15,35 @@ use crate::error::StackableError;
 use kubelet::container::Container;
 use std::convert::TryFrom;
 use log::{debug, info, warn, error};
-use crate::repository::find_repository;
 use crate::states::download_package_backoff::DownloadingBackoff;
+use kubelet::backoff::BackoffStrategy;
+use crate::states::terminated::Terminated;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::{Api, Client};
+use kube::error::ErrorResponse;
+use tokio::sync::Notify;
 
 #[derive(Default, Debug, TransitionTo)]
-#[transition_to(Installing, DownloadingBackoff)]
+#[transition_to(Installing, DownloadingBackoff, SetupFailed, Terminated)]
 pub struct Downloading;
 
 impl Downloading {
+    /// Completes once the pod is being deleted, which is checked whenever the pod changes, so
+    /// that a download nobody needs anymore can be aborted.
+    async fn pod_deleted(client: Client, pod_changed: Arc<Notify>, namespace: String, name: String) {
+        let api: Api<KubePod> = Api::namespaced(client, &namespace);
+        loop {
+            pod_changed.notified().await;
+            match api.get(&name).await {
+                Ok(pod) if pod.metadata.deletion_timestamp.is_none() => {}
+                Ok(_) | Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => return,
+                Err(e) => debug!("Unable to check whether pod {} is being deleted: {}", name, e),
+            }
+        }
+    }
+
     fn package_downloaded<T: Into<Package>>(&self, package: T, download_directory: PathBuf) -> bool {
         let package = package.into();
         let package_file_name = download_directory.join(package.get_file_name());
@@ -47,43 +67,63 @@ impl State<PodState> for Downloading {
                 package: package.clone(),
             });
         }
-        let repo = find_repository(pod_state.client.clone(), &package, None).await;
-        match repo {
-            Ok(Some(mut repo)) => {
-                // We found a repository providing the package, proceed with download
-                // The repository has already downloaded its metadata it this time, as that
-                // was used to check whether it provides the package
-                info!("Starting download of package {} from repository {}", &package, &repo);
-                let download_directory = pod_state.download_directory.clone();
-                let download_result = repo.download_package(&package, download_directory.clone()).await;
-                match download_result {
-                    Ok(()) => {
-                        info!("Successfully downloaded package {} to {:?}", package, download_directory.clone());
-                        return Transition::next(self, Installing {
-                            download_directory: pod_state.download_directory.clone(),
-                            parcel_directory: pod_state.parcel_directory.clone(),
-                            package: package.clone(),
-                        });
-                    }
-                    Err(e) => {
-                        warn!("Download of package {} failed: {}", package, e);
-                        return Transition::next(self, DownloadingBackoff { package: package.clone() });
-                    }
-                }
-            }
-            Ok(None) => {
-                // No repository was found that provides this package
-                let message = format!("Cannot find package {} in any repository, aborting ..", &package);
-                error!("{}", &message);
-                return Transition::next(self, DownloadingBackoff { package: package.clone() });
-            }
+        // Without the repository CRD no repository can be found, which would only be reported
+        // as a package missing from all repositories
+        if let Err(e) = pod_state.repositories.check_available().await {
+            let message = format!("{}, packages cannot be downloaded until it is registered again, e.g. by restarting the agent with KRUSTLET_REGISTER_CRDS=true", e);
+            error!("{}", &message);
+            return Transition::next(self, SetupFailed { message });
+        }
+        let repos = match pod_state.repositories.find_repositories(&package).await {
+            Ok(repos) => repos,
             Err(e) => {
                 // An error occurred when looking for a repository providing this package
                 let message = format!("Error occurred trying to find package {}: {:?}", &package, e);
                 error!("{}", &message);
                 return Transition::next(self, DownloadingBackoff { package: package.clone() });
             }
+        };
+        if repos.is_empty() {
+            // No repository was found that provides this package
+            let message = format!("Cannot find package {} in any repository, aborting ..", &package);
+            error!("{}", &message);
+            return Transition::next(self, DownloadingBackoff { package: package.clone() });
+        }
+        let mirrors = repos.len();
+        for (attempt, mut repo) in repos.into_iter().enumerate() {
+            // We found a repository providing the package, proceed with download
+            // The repository has already downloaded its metadata it this time, as that
+            // was used to check whether it provides the package
+            info!("Starting download of package {} from repository {} ({}/{})", &package, &repo, attempt + 1, mirrors);
+            let download_directory = pod_state.download_directory.clone();
+            let cancelled = Box::pin(Downloading::pod_deleted(
+                pod_state.client.clone(),
+                Arc::clone(&pod_state.pod_changed),
+                _pod.namespace().to_string(),
+                _pod.name().to_string(),
+            ));
+            let download_result = repo.download_package(&package, download_directory.clone(), cancelled).await;
+            match download_result {
+                Ok(()) => {
+                    info!("Successfully downloaded package {} to {:?}", package, download_directory.clone());
+                    pod_state.package_download_backoff_strategy.reset();
+                    return Transition::next(self, Installing {
+                        download_directory: pod_state.download_directory.clone(),
+                        parcel_directory: pod_state.parcel_directory.clone(),
+                        package: package.clone(),
+                    });
+                }
+                Err(e @ PackageDownloadCancelled { .. }) => {
+                    return Transition::next(self, Terminated { message: e.to_string(), failed: false });
+                }
+                Err(e) => {
+                    // The next mirror may still serve the package
+                    warn!("Download of package {} from repository {} failed: {}", package, repo, e);
+                }
+            }
         }
+        warn!("Download of package {} failed from all {} repositories providing it", package, mirrors);
+        Transition::next(self, DownloadingBackoff { package: package.clone() })
     }
 
     async fn json_status(
@@ -3,12 +3,13 @@ use kubelet::state::prelude::*;
 use crate::PodState;
 use crate::states::install_package::Installing;
 use crate::states::download_package::Downloading;
+use crate::states::terminated::Terminated;
 use kubelet::backoff::BackoffStrategy;
 use crate::repository::package::Package;
 use log::{debug, info, error};
 
 #[derive(Debug, TransitionTo)]
-#[transition_to(Downloading)]
+#[transition_to(Downloading, Terminated)]
 /// The Pod failed to run.
 // If we manually implement, we can allow for arguments.
 pub struct DownloadingBackoff {
@@ -18,6 +19,13 @@ pub struct DownloadingBackoff {
 #[async_trait::async_trait]
 impl State<PodState> for DownloadingBackoff {
     async fn next(self: Box<Self>, pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
+        if pod_state.package_download_backoff_strategy.retries_exhausted() {
+            let message = format!("Giving up on downloading package {} after {} retries",
+                                  self.package,
+                                  pod_state.package_download_backoff_strategy.max_retries().unwrap_or_default());
+            error!("{}", message);
+            return Transition::next(self, Terminated { message, failed: true });
+        }
         info!("Backing of before retrying download of package {}", self.package);
         pod_state.package_download_backoff_strategy.wait().await;
         Transition::next(self, Downloading)
@@ -30,4 +38,8 @@ impl State<PodState> for DownloadingBackoff {
     ) -> anyhow::Result<serde_json::Value> {
         make_status(Phase::Pending, &"status:running")
     }
+
+    fn is_waiting(&self) -> bool {
+        true
+    }
 }
@@ -1,12 +1,17 @@
+use k8s_openapi::api::core::v1::{ContainerState as KubeContainerState, ContainerStateTerminated as KubeContainerStateTerminated, ContainerStateWaiting as KubeContainerStateWaiting, ContainerStatus as KubeContainerStatus};
+use kubelet::backoff::BackoffStrategy;
 use kubelet::state::prelude::*;
 
 use crate::PodState;
+use crate::eviction::EVICTION_REASON;
+use crate::states::running::OOM_KILLED_REASON;
 use crate::states::starting::Starting;
-use log::{trace, debug, error, info, warn};
-use crate::states::install_package::Installing;
+use crate::states::terminated::Terminated;
+use log::{debug, info, warn};
+use nix::sys::signal::Signal;
 
 #[derive(Default, Debug, TransitionTo)]
-#[transition_to(Starting, Installing)]
+#[transition_to(Starting, Terminated)]
 /// The Pod failed to run.
 // If we manually implement, we can allow for arguments.
 pub struct Failed {
@@ -27,27 +32,85 @@ impl Failed {
 #[async_trait::async_trait]
 impl State<PodState> for Failed {
     async fn next(self: Box<Self>, pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
-        info!("Process entered failed state");
-        if self.restart_enabled(_pod) {
-            debug!("Restart poliy is set to restart, starting...");
-            return Transition::next(self, Starting {});
-        } else {
+        info!("Process for pod {} failed: {}", _pod.name(), self.message);
+        if pod_state.evictions.contains(&pod_state.pod_key) {
+            info!("Pod {} was evicted, not restarting its process", _pod.name());
+            let message = String::from(EVICTION_REASON);
+            return Transition::next(self, Terminated { message, failed: true });
+        }
+        if !self.restart_enabled(_pod) {
             debug!("Restart is disabled for process.");
+            let message = self.message.clone();
+            return Transition::next(self, Terminated { message, failed: true });
+        }
+
+        if pod_state.errors >= pod_state.max_restarts {
+            warn!(
+                "Process for pod {} failed {} times, giving up",
+                _pod.name(),
+                pod_state.errors + 1
+            );
+            let message = format!(
+                "{}, giving up after {} restarts",
+                self.message, pod_state.errors
+            );
+            return Transition::next(self, Terminated { message, failed: true });
         }
-        //tokio::time::delay_for(std::time::Duration::from_secs(2)).await;
-       // T//ransition::next(self, Installing{
-        //    download_directory: pod_state.download_directory.clone(),
-         //   parcel_directory: pod_state.parcel_directory.clone(),
-         //   package: pod_state.package.clone()
-        //})
-        Transition::Complete(Ok(()))
+
+        pod_state.errors += 1;
+        pod_state.restart_count += 1;
+        info!(
+            "Restarting process for pod {} ({} of {})",
+            _pod.name(),
+            pod_state.errors,
+            pod_state.max_restarts
+        );
+        pod_state.restart_backoff_strategy.wait().await;
+        Transition::next(self, Starting {})
     }
 
     async fn json_status(
         &self,
-        _pod_state: &mut PodState,
-        _pod: &Pod,
+        pod_state: &mut PodState,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Failed, &self.message)
+        // A process which was killed for exceeding its memory limit is reported as such, like
+        // the Kubernetes kubelet does, with the exit status of SIGKILL
+        let (reason, last_state) = if pod_state.oom_killed {
+            let terminated = KubeContainerStateTerminated {
+                exit_code: 128 + Signal::SIGKILL as i32,
+                signal: Some(Signal::SIGKILL as i32),
+                reason: Some(String::from(OOM_KILLED_REASON)),
+                message: Some(self.message.clone()),
+                ..Default::default()
+            };
+            (OOM_KILLED_REASON, Some(KubeContainerState { terminated: Some(terminated), ..Default::default() }))
+        } else {
+            ("Error", None)
+        };
+        let container_statuses = pod
+            .containers()
+            .iter()
+            .map(|container| KubeContainerStatus {
+                name: container.name().to_string(),
+                ready: false,
+                restart_count: pod_state.restart_count,
+                started: Some(false),
+                state: Some(KubeContainerState {
+                    waiting: Some(KubeContainerStateWaiting {
+                        reason: Some(String::from(reason)),
+                        message: Some(self.message.clone()),
+                    }),
+                    ..Default::default()
+                }),
+                last_state: last_state.clone(),
+                ..Default::default()
+            })
+            .collect();
+        Ok(make_status_with_containers(Phase::Failed, &self.message, container_statuses, vec![]))
+    }
+
+    fn is_waiting(&self) -> bool {
+        true
     }
 }
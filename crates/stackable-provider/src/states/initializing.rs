@@ -0,0 +1,272 @@
+use crate::error::StackableError;
+use crate::error::StackableError::RuntimeError;
+use crate::states::create_config::CreatingConfig;
+use crate::states::starting::{ProcessSpec, Starting};
+use crate::states::terminated::Terminated;
+use crate::PodState;
+use chrono::Utc;
+use kubelet::container::Container;
+use kubelet::pod::Pod;
+use kubelet::state::prelude::*;
+use kubelet::state::{State, Transition};
+use log::{debug, error, info};
+use std::path::Path;
+use std::process::{Child, ExitStatus};
+use std::time::{Duration, Instant};
+
+/// Runs the init containers of the pod one after another before the process of the pod is
+/// started. Init containers run commands from the package of the pod and have to exit
+/// successfully, otherwise the pod fails.
+///
+/// Init containers only run once, restarts of the process after a failure start it right away.
+/// They are not run again either for a process which is re-adopted after a restart of the
+/// krustlet.
+///
+/// An init container which is still running when the `activeDeadlineSeconds` of the pod have
+/// passed, or when the state is abandoned, e.g. because the pod was deleted, is killed.
+#[derive(Default, Debug, TransitionTo)]
+#[transition_to(Starting, Terminated)]
+pub struct Initializing;
+
+/// How often it is checked whether an init container has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A running init container, which is killed and reaped when it is dropped before it exited.
+struct InitProcess(Child);
+
+impl Drop for InitProcess {
+    fn drop(&mut self) {
+        if let Ok(None) = self.0.try_wait() {
+            debug!("Killing init container process {}", self.0.id());
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+}
+
+impl Initializing {
+    /// Returns how long init containers may still run until the `activeDeadlineSeconds` of the
+    /// pod, counted from when the pod was started, have passed. Without a deadline, they may
+    /// run as long as they need.
+    fn remaining_time(pod: &Pod) -> Option<Duration> {
+        let kube_pod = pod.as_kube_pod();
+        let deadline = kube_pod.spec.as_ref()?.active_deadline_seconds?;
+        let started = kube_pod
+            .status
+            .as_ref()
+            .and_then(|status| status.start_time.as_ref())
+            .map_or_else(Utc::now, |time| time.0);
+        let elapsed = Utc::now().signed_duration_since(started).num_seconds();
+        Some(Duration::from_secs(
+            deadline.saturating_sub(elapsed).max(0) as u64
+        ))
+    }
+
+    /// Builds the process of the init container in the same way as the process of the pod is
+    /// built, so that it is run as the same user from the same package.
+    async fn process_spec(
+        pod_state: &PodState,
+        pod: &Pod,
+        container: &Container,
+    ) -> Result<ProcessSpec, StackableError> {
+        let package_directory = pod_state
+            .parcel_directory
+            .join(pod_state.package.get_directory_name());
+        let (binary, args) = Starting::resolve_command(
+            &package_directory,
+            container.command(),
+            container.args(),
+            &pod_state.package.get_default_entrypoint(),
+        )?;
+        let (uid, gid) = Starting::requested_ids(pod, container)?;
+        Starting::check_ids_permitted(uid, gid)?;
+        let working_directory = Starting::working_directory(pod, &package_directory)?;
+        let hosts_file = Starting::prepare_hosts_file(pod_state, pod)?;
+
+        let template_data = CreatingConfig::create_render_data(pod_state);
+        let mut os_args = vec![];
+        for arg in args {
+            let rendered = CreatingConfig::render_config_template(template_data.clone(), arg)
+                .map_err(|e| RuntimeError {
+                    msg: format!("Failed to render process arguments: {}", e),
+                })?;
+            os_args.push(rendered);
+        }
+
+        Ok(ProcessSpec {
+            binary,
+            args: os_args,
+            env: kubelet::provider::env_vars(container, pod, &pod_state.client).await,
+            working_directory,
+            uid,
+            gid,
+            umask: pod_state.umask,
+            hosts_file,
+            // Init containers are not constrained
+            cgroup: None,
+        })
+    }
+
+    /// Runs the process to completion, appending its output to the log file, and returns how it
+    /// exited. The process is killed if it does not exit within the timeout, or if the returned
+    /// future is dropped before.
+    async fn run(
+        spec: &ProcessSpec,
+        log_file: &Path,
+        timeout: Option<Duration>,
+    ) -> Result<ExitStatus, StackableError> {
+        let (stdout, stderr) = Starting::open_log_file(log_file)?;
+        let started = Instant::now();
+        let mut process = InitProcess(
+            Starting::build_command(spec)
+                .stdout(stdout)
+                .stderr(stderr)
+                .spawn()?,
+        );
+        loop {
+            if let Some(status) = process.0.try_wait()? {
+                return Ok(status);
+            }
+            if let Some(timeout) = timeout {
+                if started.elapsed() >= timeout {
+                    return Err(RuntimeError {
+                        msg: format!(
+                            "did not finish within the active deadline of the pod, {} seconds were left",
+                            timeout.as_secs()
+                        ),
+                    });
+                }
+            }
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Runs the init container and succeeds if it exited with status 0.
+    async fn run_container(
+        pod_state: &PodState,
+        pod: &Pod,
+        container: &Container,
+    ) -> Result<(), StackableError> {
+        let spec = Initializing::process_spec(pod_state, pod, container).await?;
+        debug!(
+            "Running init container {} with command {:?} and arguments {:?}",
+            container.name(),
+            spec.binary,
+            spec.args
+        );
+        let status = Initializing::run(
+            &spec,
+            &pod_state.log_file,
+            Initializing::remaining_time(pod),
+        )
+        .await?;
+        if !status.success() {
+            return Err(RuntimeError {
+                msg: match status.code() {
+                    Some(code) => format!("exited with status {}", code),
+                    None => String::from("was terminated by a signal"),
+                },
+            });
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl State<PodState> for Initializing {
+    async fn next(self: Box<Self>, pod_state: &mut PodState, pod: &Pod) -> Transition<PodState> {
+        let adopting = pod_state
+            .process_records
+            .load(&pod_state.pod_key)
+            .map_or(false, |record| record.is_running());
+        if adopting {
+            debug!(
+                "Process of pod {} is still running, skipping its init containers",
+                pod.name()
+            );
+            return Transition::next(self, Starting);
+        }
+        for container in pod.init_containers() {
+            info!(
+                "Running init container {} of pod {}",
+                container.name(),
+                pod.name()
+            );
+            if let Err(e) = Initializing::run_container(pod_state, pod, &container).await {
+                let message = format!("Init container {} failed: {}", container.name(), e);
+                error!("{}", message);
+                return Transition::next(
+                    self,
+                    Terminated {
+                        message,
+                        failed: true,
+                    },
+                );
+            }
+        }
+        Transition::next(self, Starting)
+    }
+
+    async fn json_status(
+        &self,
+        _pod_state: &mut PodState,
+        _pod: &Pod,
+    ) -> anyhow::Result<serde_json::Value> {
+        make_status(Phase::Pending, "Initializing")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn test_init_process_runs_to_completion() {
+        let log_directory = tempfile::tempdir().unwrap();
+        let log_file = log_directory.path().join("init.log");
+        let spec = ProcessSpec {
+            binary: PathBuf::from("/bin/sh"),
+            args: vec![
+                String::from("-c"),
+                String::from("sleep 1; echo done; exit 3"),
+            ],
+            env: HashMap::new(),
+            working_directory: std::env::temp_dir(),
+            uid: None,
+            gid: None,
+            umask: crate::DEFAULT_UMASK,
+            hosts_file: None,
+            cgroup: None,
+        };
+
+        let status = Initializing::run(&spec, &log_file, None).await.unwrap();
+
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(std::fs::read_to_string(&log_file).unwrap(), "done\n");
+    }
+
+    #[tokio::test]
+    async fn test_init_process_is_killed_after_timeout() {
+        let log_directory = tempfile::tempdir().unwrap();
+        let log_file = log_directory.path().join("init.log");
+        let spec = ProcessSpec {
+            binary: PathBuf::from("/bin/sh"),
+            args: vec![String::from("-c"), String::from("sleep 30")],
+            env: HashMap::new(),
+            working_directory: std::env::temp_dir(),
+            uid: None,
+            gid: None,
+            umask: crate::DEFAULT_UMASK,
+            hosts_file: None,
+            cgroup: None,
+        };
+
+        let started = Instant::now();
+        let result = Initializing::run(&spec, &log_file, Some(Duration::from_millis(500))).await;
+
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
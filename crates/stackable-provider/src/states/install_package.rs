@@ -6,15 +6,17 @@ use crate::states::running::Running;
 use crate::states::failed::Failed;
 use crate::states::create_config::CreatingConfig;
 use crate::states::setup_failed::SetupFailed;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use kube::api::Meta;
 use k8s_openapi::api::core::v1::PodSpec;
 use crate::repository::package::Package;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use crate::error::StackableError;
+use crate::error::StackableError::PackageArchiveError;
+use std::fs;
 use std::fs::File;
 use flate2::read::GzDecoder;
-use tar::Archive;
+use tar::{Archive, EntryType};
 
 #[derive(Debug, TransitionTo)]
 #[transition_to(CreatingConfig, SetupFailed)]
@@ -37,21 +39,102 @@ impl Installing {
         self.parcel_directory.join(package.get_directory_name())
     }
 
-    fn install_package<T: Into<Package>>(&self, package: T) -> Result<(), StackableError> {
+    fn install_package<T: Into<Package>>(&self, package: T, max_package_size: u64) -> Result<(), StackableError> {
         let package: Package = package.into();
         // To be on the safe side, check if the package is actually there
 
         let archive_path = self.download_directory.join(package.get_file_name());
-        let tar_gz = File::open(&archive_path)?;
-        let tar = GzDecoder::new(tar_gz);
-        let mut archive = Archive::new(tar);
-
         let target_directory = self.get_target_directory(package.clone());
 
+        Installing::validate_archive(&archive_path, max_package_size)?;
+
+        // Unpack into a temporary directory first and only move it to the final location once
+        // everything was unpacked, so that a failed installation never leaves a partially
+        // installed package behind that package_installed would consider present
+        let temp_directory = self.parcel_directory.join(format!(".{}.installing", package.get_directory_name()));
+        if temp_directory.exists() {
+            debug!("Removing leftovers of a previous installation attempt in {:?}", temp_directory);
+            fs::remove_dir_all(&temp_directory)?;
+        }
+
         println!("Installing package: {:?} from {:?} into {:?}", package, archive_path, target_directory);
-        archive.unpack(self.parcel_directory.join(package.get_directory_name()))?;
+        let unpack_result = Installing::open_archive(&archive_path)
+            .and_then(|mut archive| Ok(archive.unpack(&temp_directory)?))
+            .and_then(|_| Ok(fs::rename(&temp_directory, &target_directory)?));
+
+        if unpack_result.is_err() && temp_directory.exists() {
+            if let Err(e) = fs::remove_dir_all(&temp_directory) {
+                warn!("Failed to clean up temporary directory {:?}: {}", temp_directory, e);
+            }
+        }
+        unpack_result
+    }
+
+    fn open_archive(archive_path: &Path) -> Result<Archive<GzDecoder<File>>, StackableError> {
+        let tar_gz = File::open(archive_path)?;
+        Ok(Archive::new(GzDecoder::new(tar_gz)))
+    }
+
+    /// Checks all entries of the archive before anything is unpacked.
+    ///
+    /// Entries (and targets of links) that would end up outside of the target directory are
+    /// rejected, as is an archive whose content exceeds `max_package_size` bytes once unpacked.
+    fn validate_archive(archive_path: &Path, max_package_size: u64) -> Result<(), StackableError> {
+        let mut archive = Installing::open_archive(archive_path)?;
+        let mut unpacked_size: u64 = 0;
+
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let path = entry.path()?.into_owned();
+            if !Installing::stays_in_target_directory(&path) {
+                return Err(PackageArchiveError {
+                    msg: format!("entry {:?} points outside of the target directory", path),
+                });
+            }
+
+            if let Some(link_name) = entry.link_name()? {
+                // Symlinks are resolved relative to the directory they are in, hard links
+                // relative to the root of the archive
+                let link_target = match entry.header().entry_type() {
+                    EntryType::Symlink => path.parent().unwrap_or_else(|| Path::new("")).join(link_name),
+                    _ => link_name.into_owned(),
+                };
+                if !Installing::stays_in_target_directory(&link_target) {
+                    return Err(PackageArchiveError {
+                        msg: format!("link {:?} points outside of the target directory", path),
+                    });
+                }
+            }
+
+            unpacked_size = unpacked_size.saturating_add(entry.header().size()?);
+            if unpacked_size > max_package_size {
+                return Err(PackageArchiveError {
+                    msg: format!("unpacked content exceeds the maximum size of {} bytes", max_package_size),
+                });
+            }
+        }
         Ok(())
     }
+
+    /// Returns true if the given path, taken relative to the target directory, does not leave
+    /// it. Only the path itself is considered, no symlinks on disk are resolved.
+    fn stays_in_target_directory(path: &Path) -> bool {
+        let mut depth: usize = 0;
+        for component in path.components() {
+            match component {
+                Component::Prefix(_) | Component::RootDir => return false,
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if depth == 0 {
+                        return false;
+                    }
+                    depth -= 1;
+                }
+                Component::Normal(_) => depth += 1,
+            }
+        }
+        true
+    }
 }
 
 #[async_trait::async_trait]
@@ -63,7 +146,11 @@ impl State<PodState> for Installing {
             return Transition::next(self, CreatingConfig{ target_directory: None });
         } else {
             info!("Installing package {}", package);
-            self.install_package(package.clone());
+            if let Err(e) = self.install_package(package.clone(), pod_state.max_package_size) {
+                let message = format!("Failed to install package {}: {}", package, e);
+                error!("{}", message);
+                return Transition::next(self, SetupFailed { message });
+            }
         }
 
 
@@ -78,4 +165,42 @@ impl State<PodState> for Installing {
     ) -> anyhow::Result<serde_json::Value> {
         make_status(Phase::Pending, &"status:initializing")
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    #[test]
+    fn test_valid_archive_is_accepted() {
+        assert!(Installing::validate_archive(&fixture("valid-package.tar.gz"), 1024).is_ok());
+    }
+
+    #[test]
+    fn test_archive_exceeding_max_size_is_rejected() {
+        assert!(Installing::validate_archive(&fixture("valid-package.tar.gz"), 10).is_err());
+    }
+
+    #[test]
+    fn test_malicious_archives_are_rejected() {
+        for name in &[
+            "parent-directory-traversal.tar.gz",
+            "absolute-path.tar.gz",
+            "symlink-traversal.tar.gz",
+        ] {
+            let result = Installing::validate_archive(&fixture(name), 1024 * 1024);
+            assert!(matches!(result, Err(PackageArchiveError { .. })), "{} was not rejected", name);
+        }
+    }
+
+    #[test]
+    fn test_stays_in_target_directory() {
+        assert!(Installing::stays_in_target_directory(Path::new("bin/product")));
+        assert!(Installing::stays_in_target_directory(Path::new("./bin/../lib/x.so")));
+        assert!(!Installing::stays_in_target_directory(Path::new("bin/../../etc/passwd")));
+        assert!(!Installing::stays_in_target_directory(Path::new("/etc/passwd")));
+    }
+}
@@ -5,43 +5,123 @@ use crate::PodState;
 use crate::states::failed::Failed;
 use crate::states::stopping::Stopping;
 use crate::states::install_package::Installing;
+use kubelet::backoff::BackoffStrategy;
 use kubelet::container::ContainerKey;
 use log::{debug, info, warn, error};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::timeout;
-use std::process::Child;
+use tokio::time::{timeout, Instant};
 use crate::error::StackableError;
+use crate::probe::{make_ready_status, ContainerProbe, ProbeKind};
+use crate::config_watch;
+use crate::states::create_config::CreatingConfig;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::Api;
+use kubelet::pod::patch_status;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::{ContainerState as KubeContainerState, ContainerStateRunning as KubeContainerStateRunning, ContainerStatus as KubeContainerStatus};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 
-#[derive(Debug, TransitionTo)]
+/// The reason reported for processes which were killed for exceeding their memory limit, as the
+/// Kubernetes kubelet does.
+pub const OOM_KILLED_REASON: &str = "OOMKilled";
+
+/// How long a process has to run before earlier failures are forgotten, so that the next failure
+/// is retried with the shortest backoff again and does not count towards the maximum restarts.
+const SUSTAINED_RUN_DURATION: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Default, Debug, TransitionTo)]
 #[transition_to(Stopping, Failed, Running, Installing)]
-pub struct Running {
-    pub process_handle: Option<Child>,
-}
+pub struct Running;
 
 impl Running {
-    fn take_handle(&mut self) -> Child {
-        debug!("testing");
-        let mut handle = std::mem::replace(&mut self.process_handle, None);
-        handle.unwrap()
+    /// Returns the statuses of the containers of the pod while its process is running.
+    fn container_statuses(pod: &Pod, pod_state: &PodState, ready: bool, started_at: DateTime<Utc>) -> Vec<KubeContainerStatus> {
+        pod.containers()
+            .iter()
+            .map(|container| KubeContainerStatus {
+                name: container.name().to_string(),
+                ready,
+                restart_count: pod_state.restart_count,
+                started: Some(true),
+                state: Some(KubeContainerState {
+                    running: Some(KubeContainerStateRunning { started_at: Some(Time(started_at)) }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Returns whether processes in the cgroup of the pod were killed for exceeding its memory
+    /// limit since its process was started.
+    fn oom_killed(pod_state: &PodState) -> bool {
+        match (pod_state.oom_kills, pod_state.cgroups.oom_kills(&pod_state.pod_key)) {
+            (Some(before), Some(now)) => now > before,
+            _ => false,
+        }
+    }
+
+    /// Stops the process of the pod after one of its probes failed for good.
+    async fn stop_unhealthy_process(pod_state: &mut PodState, pod: &Pod, message: &str) {
+        warn!("Stopping process for pod {}: {}", pod.name(), message);
+        if let Some(mut process) = pod_state.process_handle.take() {
+            if let Err(e) = Stopping::stop_process(&mut process, pod, pod_state.process_spec.as_ref()).await {
+                error!("Failed to stop process for pod {}: {}", pod.name(), e);
+            }
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl State<PodState> for Running {
-    async fn next(mut self: Box<Self>, pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
+    async fn next(self: Box<Self>, pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
 
         debug!("waiting");
 //        &self.wait();
 //        warn!("process ended!");
 //        Transition::next(self, Failed{ message: "process ended".to_string() })
         let mut changed = Arc::clone(&pod_state.pod_changed);
-        //let mut handle = &self.take_handle();
-        let mut handle = std::mem::replace(&mut self.process_handle, None).unwrap();
         while let Ok(_) = timeout(Duration::from_millis(100), changed.notified()).await {
             debug!("drained a waiting notification");
         }
         debug!("done draining");
+        let started = Instant::now();
+        let started_at = Utc::now();
+
+        let containers = _pod.containers();
+        // Commands can be executed next to the process for as long as it is watched here
+        let _exec_registration = match (&pod_state.process_spec, containers.first()) {
+            (Some(spec), Some(container)) => Some(pod_state.exec_targets.register(pod_state.pod_key.clone(), container.name(), spec)),
+            _ => None,
+        };
+        // Pods with unsupported probes were already rejected before their process was started
+        let container_probe = |kind| {
+            containers.first().and_then(|c| ContainerProbe::from_container(c, kind).ok().flatten())
+        };
+        let mut startup_probe = container_probe(ProbeKind::Startup);
+        let mut liveness_probe = container_probe(ProbeKind::Liveness);
+        let mut readiness_probe = container_probe(ProbeKind::Readiness);
+
+        // Without a readiness probe the pod is ready as soon as its process runs, otherwise it
+        // only becomes ready once the probe succeeds
+        let api: Api<KubePod> = Api::namespaced(pod_state.client.clone(), _pod.namespace());
+        let ready = readiness_probe.is_none();
+        let statuses = Running::container_statuses(_pod, pod_state, ready, started_at);
+        patch_status(&api, _pod.name(), make_ready_status(ready, "", statuses)).await;
+
+        // Without the annotation nobody waits for config changes
+        let watched_config_maps = if config_watch::restart_on_config_change(_pod) {
+            CreatingConfig::get_config_maps(_pod)
+        } else {
+            vec![]
+        };
+        let config_changed = config_watch::changed(
+            pod_state.client.clone(),
+            _pod.namespace(),
+            watched_config_maps,
+        );
+        tokio::pin!(config_changed);
 
         loop {
             println!("running");
@@ -50,18 +130,72 @@ impl State<PodState> for Running {
                     debug!("pod changed");
                     break;
                 },
+                _ = &mut config_changed => {
+                    info!("Restarting pod {} to apply its changed configuration", _pod.name());
+                    return Transition::next(self, Stopping { reconfigure: true });
+                },
                 _ = tokio::time::delay_for(std::time::Duration::from_secs(1))  => {
                     debug!("timer expired");
                 }
             }
-            match handle.try_wait() {
-                Ok(None) => debug!("Still running"),
+            // The process handle is kept in the pod state, so that it can still be reached to
+            // stop the process if this state is aborted because the pod was deleted
+            match pod_state.process_handle.as_mut().map(|handle| handle.has_exited()) {
+                Some(Ok(false)) => {
+                    debug!("Still running");
+                    if pod_state.errors > 0 && started.elapsed() >= SUSTAINED_RUN_DURATION {
+                        pod_state.errors = 0;
+                        pod_state.restart_backoff_strategy.reset();
+                    }
+                }
                 _ => {
                     error!("died");
+                    if Running::oom_killed(pod_state) {
+                        pod_state.oom_killed = true;
+                        let message = format!("{}: process exceeded its memory limit", OOM_KILLED_REASON);
+                        return Transition::next(self, Failed { message });
+                    }
                     return Transition::next(self, Failed { message: "process died".to_string() })
                 }
 
             }
+            // Liveness and readiness are only checked once the process finished starting up
+            if let Some(probe) = startup_probe.as_mut() {
+                match probe.run_if_due().await {
+                    Some(Ok(())) => {
+                        info!("Process for pod {} started up", _pod.name());
+                        startup_probe = None;
+                    }
+                    Some(Err(message)) => {
+                        Running::stop_unhealthy_process(pod_state, _pod, &message).await;
+                        return Transition::next(self, Failed { message });
+                    }
+                    None => {}
+                }
+                continue;
+            }
+            if let Some(probe) = liveness_probe.as_mut() {
+                if let Some(Err(message)) = probe.run_if_due().await {
+                    Running::stop_unhealthy_process(pod_state, _pod, &message).await;
+                    return Transition::next(self, Failed { message });
+                }
+            }
+            // A failing readiness probe only takes the pod out of service, the process is kept
+            if let Some(probe) = readiness_probe.as_mut() {
+                match probe.run_if_due().await {
+                    Some(Ok(())) => {
+                        info!("Pod {} is ready", _pod.name());
+                        let statuses = Running::container_statuses(_pod, pod_state, true, started_at);
+                        patch_status(&api, _pod.name(), make_ready_status(true, "", statuses)).await;
+                    }
+                    Some(Err(message)) => {
+                        warn!("Pod {} is not ready: {}", _pod.name(), message);
+                        let statuses = Running::container_statuses(_pod, pod_state, false, started_at);
+                        patch_status(&api, _pod.name(), make_ready_status(false, &message, statuses)).await;
+                    }
+                    None => {}
+                }
+            }
         }
         Transition::next(self, Installing{
             download_directory: pod_state.download_directory.clone(),
@@ -72,9 +206,11 @@ impl State<PodState> for Running {
 
     async fn json_status(
         &self,
-        _pod_state: &mut PodState,
-        _pod: &Pod,
+        pod_state: &mut PodState,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Running, &"status:running")
+        // Readiness is reported once the state has checked for a readiness probe
+        let container_statuses = Running::container_statuses(pod, pod_state, false, Utc::now());
+        Ok(make_status_with_containers(Phase::Running, "status:running", container_statuses, vec![]))
     }
 }
\ No newline at end of file
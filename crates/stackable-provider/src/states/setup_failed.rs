@@ -28,4 +28,8 @@ impl State<PodState> for SetupFailed {
     ) -> anyhow::Result<serde_json::Value> {
         make_status(Phase::Pending, &self.message)
     }
+
+    fn is_waiting(&self) -> bool {
+        true
+    }
 }
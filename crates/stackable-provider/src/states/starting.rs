@@ -1,107 +1,651 @@
+use crate::cgroup::{self, ResourceLimits};
+use crate::error::StackableError;
+use crate::error::StackableError::{PodValidationError, RuntimeError};
+use crate::eviction::EVICTION_REASON;
+use crate::fail_fatal;
+use crate::host_aliases;
+use crate::lifecycle::{LifecycleHook, POST_START_TIMEOUT};
+use crate::probe;
+use crate::process::{self, ProcessHandle, ProcessRecord};
 use crate::states::create_config::CreatingConfig;
 use crate::states::failed::Failed;
 use crate::states::running::Running;
 use crate::PodState;
-use kubelet::pod::Pod;
+use kubelet::container::Container;
+use kubelet::pod::{Pod, PodKey};
 use kubelet::state::prelude::*;
 use kubelet::state::{State, Transition};
 use log::{debug, error, info, trace, warn};
-use std::ffi::OsStr;
+use nix::sys::signal::Signal;
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{getegid, geteuid};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path, PathBuf};
 use std::process::{Command, Stdio};
 use tokio::time::Duration;
 
+/// Annotation which overrides the working directory of the process. Relative paths are resolved
+/// against the package directory, which is the default working directory.
+pub const WORKING_DIRECTORY_ANNOTATION: &str = "stackable.de/working-directory";
+
 #[derive(Default, Debug, TransitionTo)]
 #[transition_to(Running, Failed)]
 pub struct Starting;
 
+/// Everything needed to launch the process of a pod.
+#[derive(Clone, Debug)]
+pub(crate) struct ProcessSpec {
+    pub(crate) binary: PathBuf,
+    pub(crate) args: Vec<String>,
+    pub(crate) env: HashMap<String, String>,
+    pub(crate) working_directory: PathBuf,
+    pub(crate) uid: Option<u32>,
+    pub(crate) gid: Option<u32>,
+    pub(crate) umask: u32,
+    /// Replaces `/etc/hosts` for the process, see [`host_aliases`]
+    pub(crate) hosts_file: Option<PathBuf>,
+    /// The cgroup the process joins, see [`cgroup`]
+    pub(crate) cgroup: Option<PathBuf>,
+}
+
+impl Starting {
+    /// Returns the host ports the container declares. The process runs directly on the node, so
+    /// a container port is a host port unless a different `hostPort` is given.
+    fn host_ports(container: &Container) -> Result<Vec<u16>, StackableError> {
+        let mut ports = vec![];
+        for port in container.ports().iter().flatten() {
+            let number = port.host_port.unwrap_or(port.container_port);
+            match u16::try_from(number) {
+                Ok(number) if number > 0 => ports.push(number),
+                _ => {
+                    return Err(PodValidationError {
+                        msg: format!("Port {} is out of range", number),
+                    })
+                }
+            }
+        }
+        Ok(ports)
+    }
+
+    /// Records the started process, so that it is re-adopted instead of started again after a
+    /// restart of the krustlet.
+    fn record_process(pod_state: &PodState, pid: u32, ports: Vec<u16>) {
+        let start_time = match process::start_time_of(pid) {
+            Some(start_time) => start_time,
+            None => {
+                warn!("Unable to determine when process {} started, it will not be re-adopted after a restart of the krustlet", pid);
+                return;
+            }
+        };
+        let record = ProcessRecord {
+            namespace: pod_state.pod_key.namespace(),
+            name: pod_state.pod_key.name(),
+            pid,
+            start_time,
+            package: pod_state.package.clone(),
+            ports,
+        };
+        if let Err(e) = pod_state.process_records.save(&record) {
+            warn!(
+                "Failed to record process {}, it will not be re-adopted after a restart of the krustlet: {}",
+                pid, e
+            );
+        }
+    }
+
+    /// Runs the `postStart` hook of the container, if it has one, next to the started process.
+    async fn run_post_start_hook(container: &Container, spec: &ProcessSpec) -> Result<(), String> {
+        match LifecycleHook::post_start(container).map_err(|e| e.to_string())? {
+            Some(hook) => {
+                info!("Running postStart hook of container {}", container.name());
+                hook.run(Some(spec), POST_START_TIMEOUT).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Reserves the given ports for the pod, unless one of them is already reserved by another
+    /// pod, in which case nothing is reserved.
+    fn reserve_ports(
+        port_map: &mut BTreeMap<u16, PodKey>,
+        ports: &[u16],
+        pod_key: &PodKey,
+    ) -> Result<(), StackableError> {
+        for port in ports {
+            if let Some(owner) = port_map.get(port).filter(|owner| *owner != pod_key) {
+                return Err(RuntimeError {
+                    msg: format!(
+                        "Port {} is already in use by pod {} in namespace {}",
+                        port,
+                        owner.name(),
+                        owner.namespace()
+                    ),
+                });
+            }
+        }
+        for port in ports {
+            port_map.insert(*port, pod_key.clone());
+        }
+        Ok(())
+    }
+
+    /// Opens the log file for the process in append mode and returns two handles to it, which
+    /// can be used as stdout and stderr of the process.
+    pub(crate) fn open_log_file(log_file: &Path) -> Result<(Stdio, Stdio), std::io::Error> {
+        let stdout = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file)?;
+        let stderr = stdout.try_clone()?;
+        Ok((Stdio::from(stdout), Stdio::from(stderr)))
+    }
+
+    /// Determines the binary to start and its arguments from `command` and `args` of the
+    /// container, following the Kubernetes semantics: `command` replaces the entrypoint of the
+    /// package and `args` are appended to it. If no command is given, the default entrypoint of
+    /// the package is started with `args`.
+    ///
+    /// The binary is resolved relative to the package directory and must not point anywhere
+    /// outside of it, so that pods cannot be used to run arbitrary binaries on the host.
+    pub(crate) fn resolve_command(
+        package_directory: &Path,
+        command: &Option<Vec<String>>,
+        args: &Option<Vec<String>>,
+        default_entrypoint: &str,
+    ) -> Result<(PathBuf, Vec<String>), StackableError> {
+        let mut command_line = match command {
+            Some(command) if !command.is_empty() => command.clone(),
+            _ => {
+                debug!(
+                    "No command specified, using default entrypoint {}",
+                    default_entrypoint
+                );
+                vec![default_entrypoint.to_string()]
+            }
+        };
+        if let Some(args) = args {
+            command_line.extend(args.iter().cloned());
+        }
+        let binary = command_line.remove(0);
+
+        let canonical_package_directory = package_directory.canonicalize()?;
+        let binary_path = package_directory
+            .join(&binary)
+            .canonicalize()
+            .map_err(|e| PodValidationError {
+                msg: format!("Unable to resolve command {}: {}", binary, e),
+            })?;
+        if !binary_path.starts_with(&canonical_package_directory) {
+            return Err(PodValidationError {
+                msg: format!(
+                    "Command {} resolves to {:?} which is outside of the package directory {:?}",
+                    binary, binary_path, canonical_package_directory
+                ),
+            });
+        }
+        Ok((binary_path, command_line))
+    }
+
+    /// Determines the working directory of the process, which is the package directory unless
+    /// the pod overrides it with the [`WORKING_DIRECTORY_ANNOTATION`]. The annotation has to be
+    /// a relative path without `..` which does not resolve to a directory outside of the package
+    /// directory via symlinks either.
+    pub(crate) fn working_directory(
+        pod: &Pod,
+        package_directory: &Path,
+    ) -> Result<PathBuf, StackableError> {
+        let working_directory = match pod.get_annotation(WORKING_DIRECTORY_ANNOTATION) {
+            Some(directory) => {
+                let relative = Path::new(directory)
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+                if !relative {
+                    return Err(PodValidationError {
+                        msg: format!(
+                            "Working directory {:?} must be a path relative to the package directory without '..'",
+                            directory
+                        ),
+                    });
+                }
+                package_directory.join(directory)
+            }
+            None => package_directory.to_path_buf(),
+        };
+        if !working_directory.is_dir() {
+            return Err(PodValidationError {
+                msg: format!(
+                    "Working directory {:?} does not exist or is not a directory",
+                    working_directory
+                ),
+            });
+        }
+        // A symlink inside of the package must not lead outside of it either
+        let canonical_package_directory = package_directory.canonicalize()?;
+        let canonical_working_directory = working_directory.canonicalize()?;
+        if !canonical_working_directory.starts_with(&canonical_package_directory) {
+            return Err(PodValidationError {
+                msg: format!(
+                    "Working directory {:?} resolves to {:?} which is outside of the package directory {:?}",
+                    working_directory, canonical_working_directory, canonical_package_directory
+                ),
+            });
+        }
+        Ok(working_directory)
+    }
+
+    /// Determines the user and group the process is to be run as from `runAsUser` and
+    /// `runAsGroup` of the security context of the container, falling back to the security
+    /// context of the pod. `None` means that the respective id of the krustlet is kept.
+    pub(crate) fn requested_ids(
+        pod: &Pod,
+        container: &Container,
+    ) -> Result<(Option<u32>, Option<u32>), StackableError> {
+        let pod_context = pod
+            .as_kube_pod()
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.security_context.as_ref());
+        let container_context = container.security_context();
+
+        let user = container_context
+            .and_then(|context| context.run_as_user)
+            .or_else(|| pod_context.and_then(|context| context.run_as_user));
+        let group = container_context
+            .and_then(|context| context.run_as_group)
+            .or_else(|| pod_context.and_then(|context| context.run_as_group));
+
+        let to_id = |id: Option<i64>, kind: &str| {
+            id.map(|id| {
+                u32::try_from(id).map_err(|_| PodValidationError {
+                    msg: format!("{} is not a valid {} id", id, kind),
+                })
+            })
+            .transpose()
+        };
+        Ok((to_id(user, "user")?, to_id(group, "group")?))
+    }
+
+    /// Checks that the krustlet is able to start a process as the given user and group, which
+    /// requires it to run as root unless the ids are its own anyway.
+    pub(crate) fn check_ids_permitted(
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<(), StackableError> {
+        let euid = geteuid().as_raw();
+        let egid = getegid().as_raw();
+        if euid == 0 {
+            return Ok(());
+        }
+        if let Some(uid) = uid.filter(|uid| *uid != euid) {
+            return Err(PodValidationError {
+                msg: format!(
+                    "The process cannot be run as user {} because the krustlet is not running as root",
+                    uid
+                ),
+            });
+        }
+        if let Some(gid) = gid.filter(|gid| *gid != egid) {
+            return Err(PodValidationError {
+                msg: format!(
+                    "The process cannot be run as group {} because the krustlet is not running as root",
+                    gid
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Switches to the given user and group and drops all supplementary groups, like the
+    /// command does when it is given a user or group. Meant to be called between fork and exec.
+    fn drop_privileges(uid: Option<u32>, gid: Option<u32>) -> std::io::Result<()> {
+        if uid.is_none() && gid.is_none() {
+            return Ok(());
+        }
+        // Safety: setgroups, setgid and setuid are async-signal-safe and do not allocate
+        unsafe {
+            if nix::libc::setgroups(0, std::ptr::null()) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if let Some(gid) = gid {
+                if nix::libc::setgid(gid) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(uid) = uid {
+                if nix::libc::setuid(uid) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns where the hosts file of the pod is written if it has host aliases.
+    pub(crate) fn hosts_file_path(pod_state: &PodState) -> PathBuf {
+        pod_state.config_directory.join("_hosts").join(format!(
+            "{}-{}",
+            pod_state.pod_key.namespace(),
+            pod_state.pod_key.name()
+        ))
+    }
+
+    /// Writes the hosts file with the host aliases of the pod and returns its path, or `None`
+    /// if the pod has no host aliases.
+    pub(crate) fn prepare_hosts_file(
+        pod_state: &PodState,
+        pod: &Pod,
+    ) -> Result<Option<PathBuf>, StackableError> {
+        let aliases = host_aliases::host_aliases(pod);
+        if aliases.is_empty() {
+            return Ok(None);
+        }
+        if !geteuid().is_root() {
+            return Err(PodValidationError {
+                msg: String::from(
+                    "Host aliases are only supported if the krustlet is running as root",
+                ),
+            });
+        }
+        let path = Starting::hosts_file_path(pod_state);
+        host_aliases::write_hosts_file(&path, &aliases)?;
+        Ok(Some(path))
+    }
+
+    /// Creates the cgroup which constrains the resources of the process, if enforcing resource
+    /// constraints is enabled and the container requests or limits CPU or limits memory. If the
+    /// cgroup cannot be created, the process runs unconstrained.
+    pub(crate) fn prepare_cgroup(
+        pod_state: &PodState,
+        container: &Container,
+    ) -> Result<Option<PathBuf>, StackableError> {
+        if !pod_state.cgroups.enabled() {
+            return Ok(None);
+        }
+        let limits = match ResourceLimits::from_container(container)? {
+            Some(limits) => limits,
+            None => return Ok(None),
+        };
+        match pod_state.cgroups.create(&pod_state.pod_key, &limits) {
+            Ok(cgroup) => Ok(Some(cgroup)),
+            Err(e) => {
+                warn!(
+                    "Unable to create cgroup for pod {}, its process runs without resource constraints: {}",
+                    pod_state.pod_key.name(),
+                    e
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Creates the command that launches the process.
+    ///
+    /// The environment of the krustlet is inherited, the variables from the pod spec are applied
+    /// on top of it, so the pod can override inherited values like `PATH`.
+    ///
+    /// If a user or group is given, the process drops its privileges to them before the binary
+    /// is executed. If a cgroup is given, the process joins it before, so that it never runs
+    /// outside of it.
+    pub(crate) fn build_command(spec: &ProcessSpec) -> Command {
+        let mut command = Command::new(&spec.binary);
+        command
+            .args(&spec.args)
+            .envs(&spec.env)
+            .current_dir(&spec.working_directory);
+        if spec.hosts_file.is_none() && spec.cgroup.is_none() {
+            if let Some(gid) = spec.gid {
+                command.gid(gid);
+            }
+            if let Some(uid) = spec.uid {
+                command.uid(uid);
+            }
+        } else {
+            // Converted up front, as nothing may be allocated between fork and exec
+            let to_c_string = |path: &Path| CString::new(path.as_os_str().as_bytes());
+            let hosts_file = spec.hosts_file.as_deref().map(to_c_string);
+            let cgroup_procs = spec
+                .cgroup
+                .as_deref()
+                .map(|cgroup| to_c_string(&cgroup::procs_file(cgroup)));
+            let (uid, gid) = (spec.uid, spec.gid);
+            // Safety: only async-signal-safe functions are called, which do not allocate.
+            // The namespace can only be entered and the cgroup only be joined as root, so the
+            // privileges are dropped afterwards here instead of by the command, which would drop
+            // them before.
+            unsafe {
+                command.pre_exec(move || {
+                    let invalid = |_: &_| std::io::Error::from(std::io::ErrorKind::InvalidInput);
+                    if let Some(cgroup_procs) = &cgroup_procs {
+                        cgroup::join(cgroup_procs.as_ref().map_err(invalid)?)?;
+                    }
+                    if let Some(hosts_file) = &hosts_file {
+                        host_aliases::enter_namespace(hosts_file.as_ref().map_err(invalid)?)?;
+                    }
+                    Starting::drop_privileges(uid, gid)
+                });
+            }
+        }
+        let mask = Mode::from_bits_truncate(spec.umask);
+        // Safety: umask is async-signal-safe and does not allocate, so it may be called between
+        // fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                umask(mask);
+                Ok(())
+            });
+        }
+        command
+    }
+}
+
 #[async_trait::async_trait]
 impl State<PodState> for Starting {
     async fn next(self: Box<Self>, pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
+        if pod_state.evictions.contains(&pod_state.pod_key) {
+            return Transition::next(self, Failed { message: String::from(EVICTION_REASON) });
+        }
         let container = _pod.containers()[0].clone();
         let template_data = CreatingConfig::create_render_data(&pod_state);
-        if let Some(mut command) = container.command().clone() {
-            debug!("Reversing {:?}", &command);
-            command.reverse();
-            debug!("Processing {:?}", &command);
-            if let Some(binary) = command.pop() {
-                let binary = pod_state
-                    .parcel_directory
-                    .join(pod_state.package.clone().get_directory_name())
-                    .join(binary);
-
-                let binary = OsStr::new(&binary);
-                command.reverse();
-
-                let os_args: Vec<String> = command
-                    .iter()
-                    .map(|s| {
-                        CreatingConfig::render_config_template(
-                            template_data.clone(),
-                            String::from(s),
-                        )
-                        .unwrap()
-                    })
-                    .collect();
+        let package_directory = pod_state
+            .parcel_directory
+            .join(pod_state.package.get_directory_name());
 
-                debug!(
-                    "Starting command: {:?} with arguments {:?}",
-                    binary, os_args
+        let (binary, args) = match Starting::resolve_command(
+            &package_directory,
+            container.command(),
+            container.args(),
+            &pod_state.package.get_default_entrypoint(),
+        ) {
+            Ok(command) => command,
+            Err(e) => fail_fatal!(e),
+        };
+
+        let (uid, gid) = match Starting::requested_ids(_pod, &container) {
+            Ok(ids) => ids,
+            Err(e) => fail_fatal!(e),
+        };
+        if let Err(e) = Starting::check_ids_permitted(uid, gid) {
+            fail_fatal!(e);
+        }
+        if let Err(e) = probe::validate_probes(&container) {
+            fail_fatal!(e);
+        }
+        let working_directory = match Starting::working_directory(_pod, &package_directory) {
+            Ok(directory) => directory,
+            Err(e) => fail_fatal!(e),
+        };
+        let hosts_file = match Starting::prepare_hosts_file(pod_state, _pod) {
+            Ok(hosts_file) => hosts_file,
+            Err(e) => fail_fatal!(e),
+        };
+        let cgroup = match Starting::prepare_cgroup(pod_state, &container) {
+            Ok(cgroup) => cgroup,
+            Err(e) => fail_fatal!(e),
+        };
+
+        let mut os_args = vec![];
+        for arg in args {
+            match CreatingConfig::render_config_template(template_data.clone(), arg) {
+                Ok(rendered) => os_args.push(rendered),
+                Err(e) => {
+                    let error_message = format!("Failed to render process arguments: {}", e);
+                    error!("{}", error_message);
+                    return Transition::next(
+                        self,
+                        Failed {
+                            message: error_message,
+                        },
+                    );
+                }
+            }
+        }
+        let reservation = match Starting::host_ports(&container) {
+            Ok(ports) => {
+                let mut port_map = pod_state.port_map.lock().await;
+                Starting::reserve_ports(&mut port_map, &ports, &pod_state.pod_key).map(|_| ports)
+            }
+            Err(e) => Err(e),
+        };
+        let ports = match reservation {
+            Ok(ports) => ports,
+            Err(e) => {
+                error!("Failed to reserve ports for pod {}: {}", _pod.name(), e);
+                return Transition::next(
+                    self,
+                    Failed {
+                        message: e.to_string(),
+                    },
                 );
-                let start_result = Command::new(binary)
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
-                    .args(&os_args)
-                    .spawn();
-
-                match start_result {
-                    Ok(mut child) => {
-                        info!(
-                            "Successfully executed command \"{:?}\" with args {:?}",
-                            binary, &os_args
-                        );
-                        debug!("Waiting if startup fails..");
-                        for i in 1..10 {
-                            tokio::time::delay_for(Duration::from_secs(1)).await;
-                            if let Ok(None) = child.try_wait() {
-                                trace!("Process still alive after {} seconds ..", i);
-                            } else {
-                                error!("Process died after {} seconds during startup!", i);
-                                return Transition::next(
-                                    self,
-                                    Failed {
-                                        message: "process failed during startup".to_string(),
-                                    },
-                                );
-                            }
-                        }
-                        //pod_state.process_handle = Some(child);
-                        return Transition::next(
-                            self,
-                            Running {
-                                process_handle: Some(child),
-                            },
-                        );
-                    }
-                    Err(error) => {
-                        let error_message = format!("Failed to start process with error {}", error);
-                        error!("{}", error_message);
+            }
+        };
+
+        let (stdout, stderr) = match Starting::open_log_file(&pod_state.log_file) {
+            Ok(log_handles) => log_handles,
+            Err(error) => {
+                let error_message = format!(
+                    "Failed to open log file {:?} with error {}",
+                    &pod_state.log_file, error
+                );
+                error!("{}", error_message);
+                return Transition::next(
+                    self,
+                    Failed {
+                        message: error_message,
+                    },
+                );
+            }
+        };
+
+        let spec = ProcessSpec {
+            binary,
+            args: os_args,
+            env: kubelet::provider::env_vars(&container, _pod, &pod_state.client).await,
+            working_directory,
+            uid,
+            gid,
+            umask: pod_state.umask,
+            hosts_file,
+            cgroup,
+        };
+        // Only OOM kills from here on concern the process which is about to run
+        pod_state.oom_kills = pod_state.cgroups.oom_kills(&pod_state.pod_key);
+        pod_state.oom_killed = false;
+        if let Some(record) = pod_state.process_records.load(&pod_state.pod_key) {
+            if record.package == pod_state.package && record.is_running() {
+                info!(
+                    "Adopting process {} of pod {}, which was started before the krustlet restarted",
+                    record.pid,
+                    _pod.name()
+                );
+                pod_state.process_handle = Some(record.adopt());
+                pod_state.process_spec = Some(spec);
+                return Transition::next(self, Running);
+            }
+            info!(
+                "Process {} of pod {} is gone since the krustlet restarted, starting it again",
+                record.pid,
+                _pod.name()
+            );
+            pod_state.process_records.remove(&pod_state.pod_key);
+        }
+        debug!(
+            "Starting command: {:?} with arguments {:?} and environment variables {:?} in {:?} as user {:?} and group {:?} with umask {:o}, logging to {:?}",
+            spec.binary,
+            spec.args,
+            spec.env.keys(),
+            spec.working_directory,
+            spec.uid,
+            spec.gid,
+            spec.umask,
+            &pod_state.log_file
+        );
+        let start_result = Starting::build_command(&spec)
+            .stdout(stdout)
+            .stderr(stderr)
+            .spawn();
+
+        match start_result {
+            Ok(mut child) => {
+                info!(
+                    "Successfully executed command \"{:?}\" with args {:?}",
+                    spec.binary, spec.args
+                );
+                debug!("Waiting if startup fails..");
+                for i in 1..10 {
+                    tokio::time::delay_for(Duration::from_secs(1)).await;
+                    if let Ok(None) = child.try_wait() {
+                        trace!("Process still alive after {} seconds ..", i);
+                    } else {
+                        error!("Process died after {} seconds during startup!", i);
                         return Transition::next(
                             self,
                             Failed {
-                                message: error_message,
+                                message: "process failed during startup".to_string(),
                             },
                         );
                     }
                 }
+                // The process is recorded before the hook runs, so that it is stopped when the
+                // pod is deleted meanwhile, and re-adopted when the krustlet restarts meanwhile.
+                let pid = child.id();
+                Starting::record_process(pod_state, pid, ports);
+                pod_state.process_handle = Some(ProcessHandle::Started(child));
+                let hook_result = Starting::run_post_start_hook(&container, &spec).await;
+                pod_state.process_spec = Some(spec);
+                if let Err(e) = hook_result {
+                    let message = format!("postStart hook failed: {}", e);
+                    error!(
+                        "Killing process {} of pod {}, its {}",
+                        pid,
+                        _pod.name(),
+                        message
+                    );
+                    if let Some(mut process) = pod_state.process_handle.take() {
+                        let _ = process.signal(Signal::SIGKILL);
+                        // Reap the process, so that it does not linger as a zombie
+                        let _ = process.reap().await;
+                    }
+                    pod_state.process_records.remove(&pod_state.pod_key);
+                    return Transition::next(self, Failed { message });
+                }
+                Transition::next(self, Running)
+            }
+            Err(error) => {
+                let error_message = format!("Failed to start process with error {}", error);
+                error!("{}", error_message);
+                Transition::next(
+                    self,
+                    Failed {
+                        message: error_message,
+                    },
+                )
             }
         }
-        error!("No command found, not starting anything..");
-        return Transition::next(
-            self,
-            Failed {
-                message: "no command object present, failing process".to_string(),
-            },
-        );
     }
 
     async fn json_status(
@@ -112,3 +656,272 @@ impl State<PodState> for Starting {
         make_status(Phase::Pending, &"status:running")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pod_env_is_passed_to_process() {
+        let mut env = HashMap::new();
+        env.insert(
+            String::from("STACKABLE_TEST_VARIABLE"),
+            String::from("some value"),
+        );
+        env.insert(String::from("PATH"), String::from("/stackable/bin"));
+
+        let output = Starting::build_command(&process_spec("/usr/bin/env", &[], env))
+            .output()
+            .expect("failed to run env");
+        let output = String::from_utf8(output.stdout).unwrap();
+
+        assert!(output
+            .lines()
+            .any(|l| l == "STACKABLE_TEST_VARIABLE=some value"));
+        assert!(output.lines().any(|l| l == "PATH=/stackable/bin"));
+    }
+
+    fn process_spec(binary: &str, args: &[&str], env: HashMap<String, String>) -> ProcessSpec {
+        ProcessSpec {
+            binary: PathBuf::from(binary),
+            args: args.iter().map(|arg| String::from(*arg)).collect(),
+            env,
+            working_directory: std::env::temp_dir(),
+            uid: None,
+            gid: None,
+            umask: crate::DEFAULT_UMASK,
+            hosts_file: None,
+            cgroup: None,
+        }
+    }
+
+    #[test]
+    fn test_process_runs_in_working_directory_with_umask() {
+        let working_directory = tempfile::tempdir().unwrap();
+        let mut spec = process_spec("/bin/sh", &["-c", "pwd; umask"], HashMap::new());
+        spec.working_directory = working_directory.path().canonicalize().unwrap();
+        spec.umask = 0o027;
+
+        let output = Starting::build_command(&spec)
+            .output()
+            .expect("failed to run sh");
+        let output = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(lines[0], spec.working_directory.to_str().unwrap());
+        assert_eq!(lines[1], "0027");
+    }
+
+    fn create_annotated_pod(annotations: serde_json::Value) -> Pod {
+        let pod: k8s_openapi::api::core::v1::Pod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "test", "annotations": annotations },
+            "spec": { "containers": [{ "name": "test" }] }
+        }))
+        .unwrap();
+        Pod::from(pod)
+    }
+
+    #[test]
+    fn test_working_directory_defaults_to_package_directory() {
+        let package_directory = create_package_directory();
+        let pod = create_annotated_pod(serde_json::json!({}));
+
+        assert_eq!(
+            Starting::working_directory(&pod, package_directory.path()).unwrap(),
+            package_directory.path()
+        );
+    }
+
+    #[test]
+    fn test_working_directory_annotation_is_relative_to_package_directory() {
+        let package_directory = create_package_directory();
+        let pod = create_annotated_pod(serde_json::json!({ WORKING_DIRECTORY_ANNOTATION: "bin" }));
+
+        assert_eq!(
+            Starting::working_directory(&pod, package_directory.path()).unwrap(),
+            package_directory.path().join("bin")
+        );
+
+        let pod =
+            create_annotated_pod(serde_json::json!({ WORKING_DIRECTORY_ANNOTATION: "missing" }));
+        assert!(Starting::working_directory(&pod, package_directory.path()).is_err());
+    }
+
+    #[test]
+    fn test_working_directory_annotation_must_stay_in_package_directory() {
+        let package_directory = create_package_directory();
+
+        for directory in &["/tmp", "..", "bin/../..", "./../bin"] {
+            let pod = create_annotated_pod(
+                serde_json::json!({ WORKING_DIRECTORY_ANNOTATION: directory }),
+            );
+            assert!(
+                Starting::working_directory(&pod, package_directory.path()).is_err(),
+                "{} was accepted",
+                directory
+            );
+        }
+    }
+
+    #[test]
+    fn test_working_directory_annotation_must_not_follow_symlinks_out_of_package_directory() {
+        let package_directory = create_package_directory();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), package_directory.path().join("escape"))
+            .unwrap();
+        std::os::unix::fs::symlink(
+            package_directory.path().join("bin"),
+            package_directory.path().join("binaries"),
+        )
+        .unwrap();
+
+        let pod =
+            create_annotated_pod(serde_json::json!({ WORKING_DIRECTORY_ANNOTATION: "escape" }));
+        assert!(Starting::working_directory(&pod, package_directory.path()).is_err());
+
+        let pod =
+            create_annotated_pod(serde_json::json!({ WORKING_DIRECTORY_ANNOTATION: "binaries" }));
+        assert_eq!(
+            Starting::working_directory(&pod, package_directory.path()).unwrap(),
+            package_directory.path().join("binaries")
+        );
+    }
+
+    fn create_pod(pod_context: serde_json::Value, container_context: serde_json::Value) -> Pod {
+        let pod: k8s_openapi::api::core::v1::Pod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "test" },
+            "spec": {
+                "securityContext": pod_context,
+                "containers": [{ "name": "test", "securityContext": container_context }]
+            }
+        }))
+        .unwrap();
+        Pod::from(pod)
+    }
+
+    #[test]
+    fn test_requested_ids_prefer_container_context() {
+        let pod = create_pod(
+            serde_json::json!({ "runAsUser": 1000, "runAsGroup": 1000 }),
+            serde_json::json!({ "runAsUser": 2000 }),
+        );
+        let container = pod.containers()[0].clone();
+
+        let ids = Starting::requested_ids(&pod, &container).unwrap();
+
+        assert_eq!(ids, (Some(2000), Some(1000)));
+    }
+
+    #[test]
+    fn test_requested_ids_default_to_none() {
+        let pod = create_pod(serde_json::json!({}), serde_json::json!({}));
+        let container = pod.containers()[0].clone();
+
+        assert_eq!(
+            Starting::requested_ids(&pod, &container).unwrap(),
+            (None, None)
+        );
+    }
+
+    #[test]
+    fn test_requested_ids_reject_negative_ids() {
+        let pod = create_pod(
+            serde_json::json!({ "runAsUser": -1 }),
+            serde_json::json!({}),
+        );
+        let container = pod.containers()[0].clone();
+
+        assert!(Starting::requested_ids(&pod, &container).is_err());
+    }
+
+    #[test]
+    fn test_own_ids_are_always_permitted() {
+        let uid = geteuid().as_raw();
+        let gid = getegid().as_raw();
+
+        assert!(Starting::check_ids_permitted(Some(uid), Some(gid)).is_ok());
+        assert!(Starting::check_ids_permitted(None, None).is_ok());
+    }
+
+    fn create_package_directory() -> tempfile::TempDir {
+        let package_directory = tempfile::tempdir().unwrap();
+        std::fs::create_dir(package_directory.path().join("bin")).unwrap();
+        std::fs::write(package_directory.path().join("bin/product"), "").unwrap();
+        std::fs::write(package_directory.path().join("start.sh"), "").unwrap();
+        package_directory
+    }
+
+    #[test]
+    fn test_resolve_command_falls_back_to_default_entrypoint() {
+        let package_directory = create_package_directory();
+        let args = Some(vec![String::from("--verbose")]);
+
+        let (binary, args) =
+            Starting::resolve_command(package_directory.path(), &None, &args, "bin/product")
+                .unwrap();
+
+        assert_eq!(
+            binary,
+            package_directory
+                .path()
+                .canonicalize()
+                .unwrap()
+                .join("bin/product")
+        );
+        assert_eq!(args, vec![String::from("--verbose")]);
+    }
+
+    #[test]
+    fn test_resolve_command_appends_args_to_command() {
+        let package_directory = create_package_directory();
+        let command = Some(vec![String::from("start.sh"), String::from("run")]);
+        let args = Some(vec![String::from("--verbose")]);
+
+        let (binary, args) =
+            Starting::resolve_command(package_directory.path(), &command, &args, "bin/product")
+                .unwrap();
+
+        assert_eq!(
+            binary,
+            package_directory
+                .path()
+                .canonicalize()
+                .unwrap()
+                .join("start.sh")
+        );
+        assert_eq!(args, vec![String::from("run"), String::from("--verbose")]);
+    }
+
+    #[test]
+    fn test_resolve_command_rejects_binaries_outside_of_package() {
+        let package_directory = create_package_directory();
+
+        for command in &["/bin/sh", "../../../../../../bin/sh", "bin/../../start.sh"] {
+            let command = Some(vec![String::from(*command)]);
+            assert!(Starting::resolve_command(
+                package_directory.path(),
+                &command,
+                &None,
+                "bin/product"
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_reserve_ports_rejects_ports_of_other_pods() {
+        let kafka = PodKey::new("default", "kafka");
+        let zookeeper = PodKey::new("default", "zookeeper");
+        let mut port_map = BTreeMap::new();
+        port_map.insert(2181, zookeeper.clone());
+
+        assert!(Starting::reserve_ports(&mut port_map, &[9092, 2181], &kafka).is_err());
+        assert!(!port_map.contains_key(&9092));
+
+        Starting::reserve_ports(&mut port_map, &[9092], &kafka).unwrap();
+        // Reserving again, e.g. when the process is restarted, succeeds
+        Starting::reserve_ports(&mut port_map, &[9092], &kafka).unwrap();
+        assert_eq!(port_map.get(&9092), Some(&kafka));
+        assert_eq!(port_map.get(&2181), Some(&zookeeper));
+    }
+}
@@ -5,10 +5,14 @@ use crate::PodState;
 use crate::states::failed::Failed;
 use crate::states::stopping::Stopping;
 use crate::states::starting::Starting;
+use crate::states::create_config::CreatingConfig;
 
 #[derive(Default, Debug, TransitionTo)]
-#[transition_to(Starting)]
-pub struct Stopped;
+#[transition_to(Starting, CreatingConfig)]
+pub struct Stopped {
+    /// Whether the configuration of the pod is created again before the process is restarted.
+    pub reconfigure: bool,
+}
 
 
 #[async_trait::async_trait]
@@ -18,6 +22,9 @@ impl State<PodState> for Stopped {
             tokio::time::delay_for(std::time::Duration::from_secs(2)).await;
             println!("stopped");
         }
+        if self.reconfigure {
+            return Transition::next(self, CreatingConfig { target_directory: None });
+        }
         Transition::next(self, Starting)
     }
 
@@ -28,4 +35,8 @@ impl State<PodState> for Stopped {
     ) -> anyhow::Result<serde_json::Value> {
         make_status(Phase::Pending, &"status:running")
     }
+
+    fn is_waiting(&self) -> bool {
+        true
+    }
 }
\ No newline at end of file
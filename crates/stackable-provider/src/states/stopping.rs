@@ -2,19 +2,156 @@ use kubelet::state::{State, Transition};
 use kubelet::pod::Pod;
 use kubelet::state::prelude::*;
 use crate::PodState;
+use crate::error::StackableError;
 use crate::states::failed::Failed;
 use crate::states::stopped::Stopped;
+use log::{debug, info, warn};
+use crate::process::ProcessHandle;
+use crate::lifecycle::LifecycleHook;
+use crate::states::starting::ProcessSpec;
+use nix::sys::signal::Signal;
+use std::time::{Duration, Instant};
+
+/// How often the process is polled while waiting for it to exit after SIGTERM.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The time the process gets after SIGTERM at least, even if its `preStop` hook used up the
+/// grace period, as in Kubernetes.
+const MIN_GRACE_PERIOD_AFTER_PRE_STOP: Duration = Duration::from_secs(2);
 
 #[derive(Default, Debug, TransitionTo)]
 #[transition_to(Stopped, Failed)]
-pub struct Stopping;
+pub struct Stopping {
+    /// Whether the configuration of the pod is created again before the process is restarted.
+    pub reconfigure: bool,
+}
+
+/// Describes how a process ended when it was asked to stop.
+#[derive(Debug, PartialEq)]
+pub enum StopResult {
+    /// The process had already exited before it was signalled.
+    AlreadyExited,
+    /// The process exited within the grace period after receiving SIGTERM.
+    Graceful,
+    /// The process did not exit within the grace period and was sent SIGKILL.
+    Killed,
+}
+
+impl StopResult {
+    /// A human readable description, suitable for the pod status message.
+    pub fn message(&self) -> &'static str {
+        match self {
+            StopResult::AlreadyExited => "process had already exited",
+            StopResult::Graceful => "process exited gracefully",
+            StopResult::Killed => "process did not exit within the grace period and was killed",
+        }
+    }
+}
 
+impl Stopping {
+    /// Runs the `preStop` hook of the pod, if it has one, then sends SIGTERM to the process and
+    /// waits for it to exit. The hook and the process share the grace period of the pod. If the
+    /// process is still running after that, SIGKILL is sent.
+    ///
+    /// A hook which fails or does not finish in time is only logged. `exec` hooks are run like
+    /// the process described by `spec`.
+    pub async fn stop_process(
+        process: &mut ProcessHandle,
+        pod: &Pod,
+        spec: Option<&ProcessSpec>,
+    ) -> Result<StopResult, StackableError> {
+        let pid = process.id();
+        if process.has_exited()? {
+            debug!("Process {} has already exited", pid);
+            return Ok(StopResult::AlreadyExited);
+        }
+
+        let mut grace_period = pod.termination_grace_period();
+        // Stackable pods run a single process, which belongs to the first container
+        let hook = match pod.containers().first().map(LifecycleHook::pre_stop) {
+            Some(Ok(hook)) => hook,
+            Some(Err(e)) => {
+                warn!("Skipping preStop hook for pod {}: {}", pod.name(), e);
+                None
+            }
+            None => None,
+        };
+        if let Some(hook) = hook {
+            info!(
+                "Running preStop hook for pod {}, waiting up to {:?} for it to finish",
+                pod.name(),
+                grace_period
+            );
+            let started = Instant::now();
+            if let Err(e) = hook.run(spec, grace_period).await {
+                warn!(
+                    "preStop hook for pod {} failed, stopping the process anyway: {}",
+                    pod.name(),
+                    e
+                );
+            }
+            grace_period = grace_period
+                .checked_sub(started.elapsed())
+                .unwrap_or_default()
+                .max(MIN_GRACE_PERIOD_AFTER_PRE_STOP);
+        }
+
+        info!(
+            "Sending SIGTERM to process {}, waiting up to {:?} for it to exit",
+            pid, grace_period
+        );
+        process.signal(Signal::SIGTERM)?;
+
+        let deadline = Instant::now() + grace_period;
+        while Instant::now() < deadline {
+            if process.has_exited()? {
+                info!("Process {} exited gracefully", pid);
+                return Ok(StopResult::Graceful);
+            }
+            tokio::time::delay_for(STOP_POLL_INTERVAL).await;
+        }
+
+        warn!(
+            "Process {} did not exit within {:?}, sending SIGKILL",
+            pid, grace_period
+        );
+        process.signal(Signal::SIGKILL)?;
+        // Reap the process, so that it does not linger as a zombie
+        process.reap().await?;
+        Ok(StopResult::Killed)
+    }
+}
 
 #[async_trait::async_trait]
 impl State<PodState> for Stopping {
-    async fn next(self: Box<Self>, pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
-        println!("stopping");
-        Transition::next(self, Stopped)
+    async fn next(self: Box<Self>, pod_state: &mut PodState, pod: &Pod) -> Transition<PodState> {
+        if let Some(mut process) = pod_state.process_handle.take() {
+            match Stopping::stop_process(&mut process, pod, pod_state.process_spec.as_ref()).await {
+                Ok(result) => {
+                    info!(
+                        "Stopped process for pod {}: {}",
+                        pod.name(),
+                        result.message()
+                    );
+                    pod_state.process_records.remove(&pod_state.pod_key);
+                }
+                Err(e) => {
+                    return Transition::next(
+                        self,
+                        Failed {
+                            message: format!("Failed to stop process: {}", e),
+                        },
+                    );
+                }
+            }
+        } else {
+            debug!(
+                "No process running for pod {}, nothing to stop",
+                pod.name()
+            );
+        }
+        let reconfigure = self.reconfigure;
+        Transition::next(self, Stopped { reconfigure })
     }
 
     async fn json_status(
@@ -24,4 +161,4 @@ impl State<PodState> for Stopping {
     ) -> anyhow::Result<serde_json::Value> {
         make_status(Phase::Pending, &"status:running")
     }
-}
\ No newline at end of file
+}
@@ -1,19 +1,102 @@
 use kubelet::state::prelude::*;
+use kubelet::pod::patch_status;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::Api;
+use log::{debug, error, info, warn};
+use std::sync::Arc;
 
 use crate::PodState;
-use crate::states::install_package::Installing;
+use crate::parcel_gc;
+use crate::states::create_service::CreatingService;
+use crate::states::starting::Starting;
+use crate::states::stopping::Stopping;
 
 #[derive(Default, Debug)]
-/// The Pod failed to run.
-// If we manually implement, we can allow for arguments.
+/// The pod was deleted or its process failed for good. This state is terminal, it stops the
+/// process of the pod and releases everything that was acquired for it.
 pub struct Terminated {
     pub message: String,
+    /// Whether the pod is reported as failed instead of succeeded.
+    pub failed: bool,
+}
+
+impl Terminated {
+    fn phase(&self) -> Phase {
+        if self.failed {
+            Phase::Failed
+        } else {
+            Phase::Succeeded
+        }
+    }
+
+    /// Removes the hosts file written for the host aliases of the pod, if there is one.
+    async fn remove_hosts_file(pod_state: &PodState) {
+        let path = Starting::hosts_file_path(pod_state);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => debug!("Removed hosts file {:?}", path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove hosts file {:?}: {}", path, e),
+        }
+    }
+
+    /// Removes the cgroup of the pod, if it has one, now that its process is gone.
+    fn remove_cgroup(pod_state: &PodState) {
+        if let Err(e) = pod_state.cgroups.remove(&pod_state.pod_key) {
+            warn!("Failed to remove cgroup of pod {}: {}", pod_state.pod_key.name(), e);
+        }
+    }
+
+    /// Marks the package of the pod as unused and removes its parcel right away if the parcel
+    /// garbage collection grace period allows it. Otherwise it is left to the periodic
+    /// collection.
+    async fn release_package(pod_state: &PodState) {
+        pod_state.package_usage.lock().unwrap().release(&pod_state.package, &pod_state.pod_key);
+        parcel_gc::collect_in_background(
+            Arc::clone(&pod_state.package_usage),
+            pod_state.parcel_directory.clone(),
+            pod_state.download_directory.clone(),
+        )
+        .await;
+    }
 }
 
 #[async_trait::async_trait]
 impl State<PodState> for Terminated {
-    async fn next(self: Box<Self>, pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
-        println!("terminated");
+    async fn next(self: Box<Self>, pod_state: &mut PodState, pod: &Pod) -> Transition<PodState> {
+        let message = match pod_state.process_handle.take() {
+            Some(mut process) => {
+                match Stopping::stop_process(&mut process, pod, pod_state.process_spec.as_ref()).await {
+                    Ok(result) => result.message().to_string(),
+                    Err(e) => {
+                        error!("Failed to stop process for pod {}: {}", pod.name(), e);
+                        format!("failed to stop process: {}", e)
+                    }
+                }
+            }
+            None => {
+                debug!("No process running for pod {}", pod.name());
+                String::from("no process was running")
+            }
+        };
+        let message = if self.failed {
+            self.message.clone()
+        } else {
+            message
+        };
+        info!("Pod {} terminated: {}", pod.name(), message);
+
+        pod_state.process_records.remove(&pod_state.pod_key);
+        Terminated::remove_hosts_file(pod_state).await;
+        Terminated::remove_cgroup(pod_state);
+        Terminated::release_package(pod_state).await;
+        CreatingService::delete_service(&pod_state.client, pod).await;
+
+        // The status for this state has already been sent before the process was stopped, so
+        // the outcome of stopping it needs to be patched in explicitly
+        let api: Api<KubePod> = Api::namespaced(pod_state.client.clone(), pod.namespace());
+        if let Ok(patch) = make_status(self.phase(), &message) {
+            patch_status(&api, pod.name(), patch).await;
+        }
         Transition::Complete(Ok(()))
     }
 
@@ -22,6 +105,6 @@ impl State<PodState> for Terminated {
         _pod_state: &mut PodState,
         _pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Succeeded, &self.message)
+        make_status(self.phase(), &self.message)
     }
 }
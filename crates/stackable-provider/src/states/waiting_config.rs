@@ -32,4 +32,8 @@ impl State<PodState> for WaitingConfigMap {
     ) -> anyhow::Result<serde_json::Value> {
         make_status(Phase::Pending, &"status:running")
     }
+
+    fn is_waiting(&self) -> bool {
+        true
+    }
 }
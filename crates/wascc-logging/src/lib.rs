@@ -38,7 +38,9 @@ use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::error::Error;
 use std::fs::{File, OpenOptions};
-use std::sync::RwLock;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
 
 use simplelog::{Config, LevelFilter, WriteLogger};
 
@@ -47,6 +49,15 @@ capability_provider!(LoggingProvider, LoggingProvider::new);
 
 pub const LOG_PATH_KEY: &str = "LOG_PATH";
 
+/// Configuration key selecting the [`LogFormat`] of an actor, defaults to plain text.
+pub const LOG_FORMAT_KEY: &str = "LOG_FORMAT";
+
+/// Configuration key for the name of the pod an actor belongs to, added to JSON log lines.
+pub const LOG_POD_NAME_KEY: &str = "LOG_POD_NAME";
+
+/// Configuration key for the namespace of the pod an actor belongs to, added to JSON log lines.
+pub const LOG_POD_NAMESPACE_KEY: &str = "LOG_POD_NAMESPACE";
+
 /// Origin of messages coming from wascc host
 const SYSTEM_ACTOR: &str = "system";
 
@@ -63,11 +74,59 @@ enum LogLevel {
     TRACE,
 }
 
+/// The format log lines of an actor are written in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Plain text lines, as written by `simplelog`.
+    Plain,
+    /// One JSON object per line, carrying the pod name, namespace, actor key and a timestamp
+    /// next to the message.
+    Json,
+}
+
+impl LogFormat {
+    /// The value of this format for the [`LOG_FORMAT_KEY`] configuration key.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogFormat::Plain => "plain",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Plain
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plain" => Ok(LogFormat::Plain),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}", s)),
+        }
+    }
+}
+
+/// The log output of a single actor.
+enum ActorLogger {
+    Plain(Box<WriteLogger<File>>),
+    Json {
+        output: Mutex<File>,
+        pod_name: String,
+        pod_namespace: String,
+    },
+}
+
 /// LoggingProvider provides an implementation of the wascc:logging capability
 /// that keeps separate log output for each actor.
 pub struct LoggingProvider {
     dispatcher: RwLock<Box<dyn Dispatcher>>,
-    output_map: RwLock<HashMap<String, Box<WriteLogger<File>>>>,
+    output_map: RwLock<HashMap<String, ActorLogger>>,
 }
 
 impl Default for LoggingProvider {
@@ -93,8 +152,32 @@ impl LoggingProvider {
             .get(LOG_PATH_KEY)
             .ok_or("log file path was unspecified")?;
 
-        let file = OpenOptions::new().write(true).open(path)?;
-        let logger = WriteLogger::new(LevelFilter::Trace, Config::default(), file);
+        let format = match config.values.get(LOG_FORMAT_KEY) {
+            Some(format) => format.parse::<LogFormat>()?,
+            None => LogFormat::default(),
+        };
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        let logger = match format {
+            LogFormat::Plain => ActorLogger::Plain(WriteLogger::new(
+                LevelFilter::Trace,
+                Config::default(),
+                file,
+            )),
+            LogFormat::Json => ActorLogger::Json {
+                output: Mutex::new(file),
+                pod_name: config
+                    .values
+                    .get(LOG_POD_NAME_KEY)
+                    .cloned()
+                    .unwrap_or_default(),
+                pod_namespace: config
+                    .values
+                    .get(LOG_POD_NAMESPACE_KEY)
+                    .cloned()
+                    .unwrap_or_default(),
+            },
+        };
         let mut output_map = self.output_map.write().unwrap();
         output_map.insert(config.module, logger);
         Ok(vec![])
@@ -160,12 +243,29 @@ impl CapabilityProvider for LoggingProvider {
                 let logger = output_map
                     .get(actor)
                     .ok_or(format!("Unable to find logger for actor {}", actor))?;
-                logger.log(
-                    &log::Record::builder()
-                        .args(format_args!("[{}] {}", actor, log_msg.body))
-                        .level(level)
-                        .build(),
-                );
+                match logger {
+                    ActorLogger::Plain(logger) => logger.log(
+                        &log::Record::builder()
+                            .args(format_args!("[{}] {}", actor, log_msg.body))
+                            .level(level)
+                            .build(),
+                    ),
+                    ActorLogger::Json {
+                        output,
+                        pod_name,
+                        pod_namespace,
+                    } => {
+                        let line = json_log_line(
+                            &chrono::Utc::now().to_rfc3339(),
+                            level,
+                            pod_namespace,
+                            pod_name,
+                            actor,
+                            &log_msg.body,
+                        );
+                        writeln!(output.lock().unwrap(), "{}", line)?;
+                    }
+                }
                 Ok(vec![])
             }
             _ => Err(format!("Unknown operation: {}", op).into()),
@@ -173,6 +273,26 @@ impl CapabilityProvider for LoggingProvider {
     }
 }
 
+/// Renders a log message of an actor as a single line JSON object.
+fn json_log_line(
+    timestamp: &str,
+    level: log::Level,
+    namespace: &str,
+    pod: &str,
+    actor: &str,
+    message: &str,
+) -> String {
+    serde_json::json!({
+        "timestamp": timestamp,
+        "level": level.to_string(),
+        "namespace": namespace,
+        "pod": pod,
+        "actor": actor,
+        "message": message,
+    })
+    .to_string()
+}
+
 impl TryFrom<u32> for LogLevel {
     type Error = ();
     fn try_from(value: u32) -> Result<Self, Self::Error> {
@@ -188,3 +308,34 @@ impl TryFrom<u32> for LogLevel {
         Ok(level)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("Plain".parse::<LogFormat>().unwrap(), LogFormat::Plain);
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_json_log_line() {
+        let line = json_log_line(
+            "2020-09-01T12:00:00+00:00",
+            log::Level::Info,
+            "default",
+            "greet",
+            "MB4OLD",
+            "hello\nworld",
+        );
+        assert!(!line.contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["namespace"], "default");
+        assert_eq!(value["pod"], "greet");
+        assert_eq!(value["actor"], "MB4OLD");
+        assert_eq!(value["message"], "hello\nworld");
+    }
+}
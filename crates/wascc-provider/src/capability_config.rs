@@ -0,0 +1,126 @@
+//! Configuration of the capabilities which operators manage as `CapabilityProvider` objects.
+//!
+//! Every object names a capability and the configuration actors bound to it get by default,
+//! e.g. the URL of the NATS server of the Messaging capability:
+//!
+//! ```yaml
+//! apiVersion: wascc.dev/v1
+//! kind: CapabilityProvider
+//! metadata:
+//!   name: messaging
+//! spec:
+//!   capability: "wascc:messaging"
+//!   config:
+//!     URL: nats://nats.default.svc:4222
+//! ```
+//!
+//! The objects are read whenever actors are started, so changes apply to the actors started
+//! afterwards. The environment of the provider and of the container of an actor override the
+//! configuration of the objects, and so do the settings the provider makes for each actor,
+//! e.g. the assigned port.
+use crate::EnvVars;
+use kube::api::{Api, ListParams};
+use kube_derive::CustomResource;
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The default configuration of a capability.
+#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug)]
+#[kube(kind = "CapabilityProvider", group = "wascc.dev", version = "v1")]
+pub struct CapabilityProviderSpec {
+    /// The ID of the capability, e.g. `wascc:messaging`
+    pub capability: String,
+    /// The configuration actors bound to the capability get
+    #[serde(default)]
+    pub config: HashMap<String, String>,
+}
+
+/// The configuration of all capabilities from the `CapabilityProvider` objects.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CapabilityDefaults {
+    config: HashMap<String, EnvVars>,
+}
+
+impl CapabilityDefaults {
+    /// Reads the configuration from the API server. If it cannot be read, actors are started
+    /// without it.
+    pub(crate) async fn load(client: &kube::Client) -> Self {
+        let api: Api<CapabilityProvider> = Api::all(client.clone());
+        match api.list(&ListParams::default()).await {
+            Ok(list) => CapabilityDefaults::from_providers(list.items),
+            Err(e) => {
+                warn!(
+                    "Unable to read the configuration of the capabilities, starting actors without it: {:?}",
+                    e
+                );
+                CapabilityDefaults::default()
+            }
+        }
+    }
+
+    /// Collects the configuration of the objects. Objects for the same capability are merged
+    /// in the order of their names, the later ones win on conflicts.
+    fn from_providers(mut providers: Vec<CapabilityProvider>) -> Self {
+        providers.sort_by(|a, b| a.metadata.name.cmp(&b.metadata.name));
+        let mut config: HashMap<String, EnvVars> = HashMap::new();
+        for provider in providers {
+            config
+                .entry(provider.spec.capability)
+                .or_default()
+                .extend(provider.spec.config);
+        }
+        CapabilityDefaults { config }
+    }
+
+    /// Returns the configuration of the capability, overridden by `env`.
+    pub(crate) fn env(&self, capability: &str, env: &EnvVars) -> EnvVars {
+        let mut merged = self.config.get(capability).cloned().unwrap_or_default();
+        merged.extend(env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        merged
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn provider(name: &str, capability: &str, config: &[(&str, &str)]) -> CapabilityProvider {
+        CapabilityProvider::new(
+            name,
+            CapabilityProviderSpec {
+                capability: capability.to_owned(),
+                config: config
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            },
+        )
+    }
+
+    #[test]
+    fn test_env_overrides_capability_config() {
+        let defaults = CapabilityDefaults::from_providers(vec![
+            provider(
+                "messaging-override",
+                "wascc:messaging",
+                &[("URL", "nats://nats.prod:4222")],
+            ),
+            provider(
+                "messaging",
+                "wascc:messaging",
+                &[
+                    ("URL", "nats://localhost:4222"),
+                    ("CLIENT_NAME", "krustlet"),
+                ],
+            ),
+        ]);
+        let mut env = EnvVars::new();
+        env.insert("CLIENT_NAME".to_owned(), "greeter".to_owned());
+
+        let messaging = defaults.env("wascc:messaging", &env);
+        assert_eq!(messaging.get("URL").unwrap(), "nats://nats.prod:4222");
+        assert_eq!(messaging.get("CLIENT_NAME").unwrap(), "greeter");
+        assert_eq!(defaults.env("wascc:logging", &env), env);
+    }
+}
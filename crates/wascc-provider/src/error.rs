@@ -0,0 +1,176 @@
+//! The errors of the wascc provider.
+//!
+//! The provider reports them to the kubelet as [`anyhow::Error`]s, from which they can be
+//! downcast to a [`WasccError`] again to tell the failures apart.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use thiserror::Error;
+
+/// A failure of the wascc provider while it starts or runs actors.
+#[derive(Error, Debug)]
+pub enum WasccError {
+    /// Reading or writing a file failed
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+    /// The port map file could not be parsed
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// A blocking task of the provider panicked or was cancelled
+    #[error(transparent)]
+    Task(#[from] tokio::task::JoinError),
+    /// A directory of the provider could not be created
+    #[error("Unable to create the {purpose} directory {}: {source}, {hint}", .path.display())]
+    DirectoryNotCreated {
+        /// What the directory is used for
+        purpose: String,
+        /// The directory
+        path: PathBuf,
+        /// Why it could not be created
+        source: std::io::Error,
+        /// How to fix it
+        hint: &'static str,
+    },
+    /// A directory of the provider exists, but files cannot be written to it
+    #[error("The {purpose} directory {} is not writable: {source}, {hint}", .path.display())]
+    DirectoryNotWritable {
+        /// What the directory is used for
+        purpose: String,
+        /// The directory
+        path: PathBuf,
+        /// Why writing to it failed
+        source: std::io::Error,
+        /// How to fix it
+        hint: &'static str,
+    },
+    /// A CRD the provider requires is not registered with the API server
+    #[error("A required CRD has not been registered: {missing_crds:?}")]
+    CrdMissing {
+        /// The names of the missing CRDs
+        missing_crds: Vec<String>,
+    },
+    /// Loading the built-in capabilities did not finish in time
+    #[error("Loading the built-in capabilities did not finish within {timeout:?}, one of them seems to hang while it is instantiated")]
+    CapabilityLoadTimeout {
+        /// How long loading them was waited for
+        timeout: Duration,
+    },
+    /// None of the built-in capabilities could be loaded
+    #[error("None of the capabilities could be loaded: {reasons:?}")]
+    NoCapabilityLoaded {
+        /// Why each of the capabilities could not be loaded
+        reasons: HashMap<String, String>,
+    },
+    /// A capability could not be instantiated or added to the host
+    #[error("Failed to load {capability} capability: {message}")]
+    CapabilityNotLoaded {
+        /// The capability ID
+        capability: String,
+        /// What went wrong
+        message: String,
+    },
+    /// An actor needs a capability which is not available on the node
+    #[error("Actor {actor} requires the {capability} capability, which is not available on this node: {reason}")]
+    CapabilityUnavailable {
+        /// The public key of the actor
+        actor: String,
+        /// The capability ID
+        capability: String,
+        /// Why the capability is not available
+        reason: String,
+    },
+    /// The module is no valid actor
+    #[error("Error loading WASM: {message}")]
+    InvalidActor {
+        /// What is wrong with the module
+        message: String,
+    },
+    /// The module is no valid WASM module
+    #[error(transparent)]
+    InvalidModule(#[from] wasmparser::BinaryReaderError),
+    /// The actor needs more memory than its container may use
+    #[error("Actor requires {required} bytes of memory at instantiation, which exceeds its memory limit of {limit} bytes")]
+    MemoryLimitExceeded {
+        /// The memory the actor needs at instantiation, in bytes
+        required: u64,
+        /// The memory limit of the container, in bytes
+        limit: u64,
+    },
+    /// The host refused to add the actor
+    #[error("Error adding actor: {message}")]
+    ActorNotAdded {
+        /// Why the host refused it
+        message: String,
+    },
+    /// The actor could not be bound to a capability
+    #[error("Error configuring {capability} capability for module: {message}")]
+    BindingFailed {
+        /// The capability ID
+        capability: String,
+        /// What went wrong
+        message: String,
+    },
+    /// The log file of the actor could not be opened
+    #[error("Unable to open log file {}: {source}", .path.display())]
+    LogFileNotOpened {
+        /// The log file
+        path: PathBuf,
+        /// Why it could not be opened
+        source: std::io::Error,
+    },
+    /// A container asks for a port which is not valid
+    #[error("Host port {port} is invalid")]
+    InvalidPort {
+        /// The port the container asked for
+        port: i32,
+    },
+    /// The host port a container asks for is assigned to another pod
+    #[error("Port {port} is currently in use")]
+    PortInUse {
+        /// The port the container asked for
+        port: u16,
+    },
+    /// The annotation which pins containers to host ports is malformed
+    #[error("Invalid wascc.dev/host-port annotation '{value}': {message}")]
+    InvalidHostPortAnnotation {
+        /// The value of the annotation
+        value: String,
+        /// What is wrong with it
+        message: String,
+    },
+    /// The host port a container is pinned to by annotation is assigned to another pod
+    #[error("Host port {port} pinned by the wascc.dev/host-port annotation is already assigned to pod {owner}")]
+    HostPortConflict {
+        /// The pinned port
+        port: u16,
+        /// The namespace and name of the pod the port is assigned to
+        owner: String,
+    },
+    /// There is no free port left to assign to an actor
+    #[error("all ports are currently in use")]
+    PortsExhausted,
+    /// The node already runs as many pods with actors as it may
+    #[error("OutOfpods: the node already runs the maximum of {max_actors} pods with actors")]
+    NodeCapacityExceeded {
+        /// How many pods may run actors on the node at once
+        max_actors: usize,
+    },
+    /// A reloaded actor could not be started and the previous one could not be restored, so
+    /// the container runs no actor anymore
+    #[error("Unable to start the reloaded actor of container {container_name}: {message}; the previous actor could not be restored: {restore_message}")]
+    ReloadFailed {
+        /// The name of the container
+        container_name: String,
+        /// Why the reloaded actor could not be started
+        message: String,
+        /// Why the previous actor could not be restored
+        restore_message: String,
+    },
+    /// Pulling the images of a pod failed more often than it may be retried
+    #[error("Giving up on pulling the images of the pod after {retries} retries")]
+    ImagePullRetriesExhausted {
+        /// How often pulling was retried
+        retries: usize,
+    },
+}
@@ -20,7 +20,7 @@
 //!     let kubeconfig = kube::Config::infer().await.unwrap();
 //!
 //!     // Instantiate the provider type
-//!     let provider = WasccProvider::new(store, &kubelet_config, kubeconfig.clone()).await.unwrap();
+//!     let provider = WasccProvider::new(store, &kubelet_config, kubeconfig.clone(), vec![]).await.unwrap();
 //!
 //!     // Instantiate the Kubelet
 //!     let kubelet = Kubelet::new(provider, kubeconfig, kubelet_config).await.unwrap();
@@ -32,33 +32,68 @@
 #![deny(missing_docs)]
 
 use async_trait::async_trait;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::api::{Api, PatchParams};
+use kube::error::ErrorResponse;
 use kubelet::backoff::ExponentialBackoffStrategy;
-use kubelet::container::Handle as ContainerHandle;
+use kubelet::container::{Handle as ContainerHandle, PullPolicy};
 use kubelet::handle::StopHandler;
 use kubelet::node::Builder;
-use kubelet::pod::{Handle, Pod, PodKey};
+use kubelet::pod::{Handle, Phase, Pod, PodKey};
 use kubelet::provider::Provider;
 use kubelet::provider::ProviderError;
 use kubelet::store::Store;
 
 use kubelet::volume::Ref;
-use log::{debug, info};
+use log::{debug, error, info, warn};
+use oci_distribution::Reference;
 use tempfile::NamedTempFile;
 use tokio::sync::{RwLock, Notify};
 use wascc_fs::FileSystemProvider;
-use wascc_host::{Actor, Host, NativeCapability};
+use wascc_host::{Actor, Host, NativeCapability, WasiParams};
 use wascc_httpsrv::HttpServerProvider;
-use wascc_logging::{LoggingProvider, LOG_PATH_KEY};
+use wascc_logging::{
+    LoggingProvider, LOG_FORMAT_KEY, LOG_PATH_KEY, LOG_POD_NAMESPACE_KEY, LOG_POD_NAME_KEY,
+};
+
+pub use error::WasccError;
+pub use log_cleanup::DEFAULT_LOG_SWEEP_INTERVAL;
+pub use wascc_logging::LogFormat;
+pub use watchdog::{DEFAULT_WATCHDOG_FAILURE_THRESHOLD, DEFAULT_WATCHDOG_INTERVAL};
 
 extern crate rand;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::Mutex as TokioMutex;
 
+mod capability_config;
+mod error;
+mod lifecycle;
+mod log_cleanup;
+mod messaging;
+mod orphans;
+mod port_map;
+mod preemption;
+mod prewarm;
+mod read_only_fs;
+mod shared_host;
 mod states;
+mod termination;
+mod watchdog;
+use capability_config::CapabilityDefaults;
+use messaging::PodMessagingProvider;
+use preemption::Preemptibles;
+use read_only_fs::ReadOnlyFileSystemProvider;
+use shared_host::SharedHost;
 use states::registered::Registered;
 use states::terminated::Terminated;
+use termination::Termination;
+use watchdog::WatchdogSettings;
 
 /// The architecture that the pod targets.
 const TARGET_WASM32_WASCC: &str = "wasm32-wascc";
@@ -69,9 +104,15 @@ const FS_CAPABILITY: &str = "wascc:blobstore";
 /// The name of the HTTP capability.
 const HTTP_CAPABILITY: &str = "wascc:http_server";
 
+/// The name of the Extras capability, providing GUIDs, random numbers and sequences.
+const EXTRAS_CAPABILITY: &str = "wascc:extras";
+
 /// The name of the Logging capability.
 const LOG_CAPABILITY: &str = "wascc:logging";
 
+/// The name of the Messaging capability, which the actors of a pod use to message each other.
+const MESSAGING_CAPABILITY: &str = "wascc:messaging";
+
 /// The root directory of waSCC logs.
 const LOG_DIR_NAME: &str = "wascc-logs";
 
@@ -81,6 +122,85 @@ const FS_CONFIG_ROOTDIR: &str = "ROOT";
 /// The root directory of waSCC volumes.
 const VOLUME_DIR: &str = "volumes";
 
+/// The root directory of the termination messages of containers.
+const TERMINATION_DIR_NAME: &str = "wascc-termination";
+
+/// The reason reported for pods which were evicted forcibly, as the Kubernetes kubelet does.
+const EVICTION_REASON: &str = "Evicted";
+
+/// The termination message of actors which were stopped because their pod was evicted.
+const EVICTED_MESSAGE: &str = "Actor stopped because the pod was evicted";
+
+/// The file in the data directory the assigned ports are persisted to.
+const PORT_MAP_FILE_NAME: &str = "wascc-port-map.json";
+
+/// The prefix of the pod annotations that select the binding name of a capability.
+const BINDING_ANNOTATION_PREFIX: &str = "wascc.dev/binding.";
+
+/// Pod annotation whose value can be changed, e.g. with `kubectl annotate --overwrite`, to
+/// reload the actors of a running pod from their images.
+pub const RELOAD_ANNOTATION: &str = "wascc.dev/reload";
+
+/// The address HTTP actors listen on. The bundled HTTP server capability always listens on all
+/// IPv4 interfaces, it cannot be configured to listen on another address.
+pub(crate) const HTTP_BIND_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+/// Pod annotation which makes the actors of the pod log into files at a predictable location,
+/// e.g. for log shippers, instead of temporary files. The value is a path relative to the log
+/// directory, in which `{namespace}`, `{pod}` and `{container}` are replaced, such as
+/// `{namespace}/{pod}/{container}.log`. The files are appended to and kept after the pod is gone.
+pub const LOG_FILE_ANNOTATION: &str = "wascc.dev/log-file";
+
+/// Pod annotation the provider sets to the ports assigned to the HTTP actors of the pod, comma
+/// separated in the order of their containers, so that services or operators can discover them.
+pub const HTTP_PORT_ANNOTATION: &str = "wascc.dev/http-port";
+
+/// Pod annotation which pins the actors of the pod to well-known host ports instead of ports
+/// assigned from the dynamic range, e.g. `8443`. Pods with several containers list the port of
+/// each pinned container as comma separated `<container>=<port>` pairs. A pinned port takes
+/// precedence over the ports of the container, and the pod fails if it is taken by another pod.
+pub const HOST_PORT_ANNOTATION: &str = "wascc.dev/host-port";
+
+/// Experimental pod annotation listing portable capability providers to load for the actors of
+/// the pod next to the built-in native capabilities, as comma separated
+/// `<capability ID>=<OCI reference>` pairs.
+///
+/// Portable capabilities are WASI modules, which can do very little until WASI has matured.
+/// Pods without this annotation only get the native capabilities.
+pub const PORTABLE_CAPABILITIES_ANNOTATION: &str = "wascc.dev/experimental-portable-capabilities";
+
+/// The CRDs which have to be registered with the API server for the provider to start. Their
+/// definitions are in the `crds` directory of this crate.
+pub const CRDS: &[&str] = &["capabilityproviders.wascc.dev"];
+
+/// The reason pods are failed with if they select another architecture.
+const UNSUPPORTED_ARCHITECTURE_REASON: &str = "UnsupportedArchitecture";
+
+/// The capabilities the provider can configure for an actor.
+const SUPPORTED_CAPABILITIES: &[&str] = &[
+    EXTRAS_CAPABILITY,
+    FS_CAPABILITY,
+    HTTP_CAPABILITY,
+    LOG_CAPABILITY,
+    MESSAGING_CAPABILITY,
+];
+
+/// The size of a page of WASM linear memory in bytes.
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// How long to wait for an actor with the HTTP capability to listen on its port
+/// before it is reported as running but not ready, unless configured otherwise
+/// via [`WasccProvider::with_http_readiness_timeout`].
+pub const DEFAULT_HTTP_READINESS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long loading the built-in capabilities may take by default before the provider gives up.
+pub const DEFAULT_CAPABILITY_LOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// By how much the delays between attempts to pull the images of a pod are randomly
+/// lengthened, so that pods which failed at the same time do not all retry at once, unless
+/// configured otherwise via [`WasccProvider::with_image_pull_backoff`].
+pub const DEFAULT_IMAGE_PULL_BACKOFF_JITTER: f64 = 0.2;
+
 /// Kubernetes' view of environment variables is an unordered map of string to string.
 type EnvVars = std::collections::HashMap<String, String>;
 
@@ -88,39 +208,40 @@ type EnvVars = std::collections::HashMap<String, String>;
 pub struct ActorHandle {
     /// The public key of the wascc Actor that will be stopped
     pub key: String,
-    host: Arc<Mutex<Host>>,
+    host: SharedHost,
     volumes: Vec<VolumeBinding>,
     capabilities: Vec<String>,
+    /// The port assigned to the actor
+    port: u16,
 }
 
 #[async_trait::async_trait]
 impl StopHandler for ActorHandle {
     async fn stop(&mut self) -> anyhow::Result<()> {
         debug!("stopping wascc instance {}", self.key);
-        let host = self.host.clone();
         let key = self.key.clone();
         let volumes: Vec<VolumeBinding> = self.volumes.drain(0..).collect();
         let capabilities = self.capabilities.clone();
-        tokio::task::spawn_blocking(move || {
-            let lock = host.lock().unwrap();
-            lock.remove_actor(&key)
-                .map_err(|e| anyhow::anyhow!("unable to remove actor: {:?}", e))?;
-
-            if capabilities.contains(&FS_CAPABILITY.to_owned()) {
-                for volume in volumes.into_iter() {
-                    lock.remove_native_capability(FS_CAPABILITY, Some(volume.name.clone()))
-                        .map_err(|e| {
-                            anyhow::anyhow!(
-                                "unable to remove volume {:?} capability: {:?}",
-                                volume.name,
-                                e
-                            )
-                        })?;
+        self.host
+            .with_host(move |lock| -> anyhow::Result<()> {
+                lock.remove_actor(&key)
+                    .map_err(|e| anyhow::anyhow!("unable to remove actor: {:?}", e))?;
+
+                if capabilities.contains(&FS_CAPABILITY.to_owned()) {
+                    for volume in volumes.into_iter() {
+                        lock.remove_native_capability(FS_CAPABILITY, Some(volume.name.clone()))
+                            .map_err(|e| {
+                                anyhow::anyhow!(
+                                    "unable to remove volume {:?} capability: {:?}",
+                                    volume.name,
+                                    e
+                                )
+                            })?;
+                    }
                 }
-            }
-            Ok(())
-        })
-        .await?
+                Ok(())
+            })
+            .await?
     }
 
     async fn wait(&mut self) -> anyhow::Result<()> {
@@ -146,80 +267,854 @@ struct SharedPodState {
     store: Arc<dyn Store + Sync + Send>,
     volume_path: PathBuf,
     log_path: PathBuf,
-    host: Arc<Mutex<Host>>,
+    termination_path: PathBuf,
+    host: SharedHost,
     port_map: Arc<TokioMutex<BTreeMap<u16, PodKey>>>,
+    port_map_path: PathBuf,
+    http_readiness_timeout: Duration,
+    log_format: LogFormat,
+    log_sweep_interval: Arc<Mutex<Duration>>,
+    /// The settings of the host watchdog, which only runs once it is configured
+    watchdog_settings: Arc<Mutex<Option<WatchdogSettings>>>,
+    /// Built-in capabilities which could not be loaded, with the reason why
+    unavailable_capabilities: Arc<HashMap<String, String>>,
+    /// Environment variables every actor gets, unless its pod sets them itself
+    default_env: EnvVars,
+    /// How many pods may run actors on the node at once, unlimited if unset
+    max_actors: Option<usize>,
+    /// The pods which hold one of the `max_actors` slots, from when they passed the capacity
+    /// check until they stop or fail
+    actor_slots: Arc<Mutex<BTreeSet<PodKey>>>,
+    /// Whether pods which exceed `max_actors` preempt running pods with a lower priority
+    preemption: bool,
+    preemptibles: Preemptibles,
+    /// The backoff every pod starts with for retrying to pull its images
+    image_pull_backoff: ExponentialBackoffStrategy,
+    /// Held for reading while actors are added to the host, and for writing while orphaned
+    /// actors are removed from it
+    host_changes: Arc<RwLock<()>>,
+    /// The images whose modules were pulled when the provider started, with their size in bytes
+    prewarmed_images: Arc<BTreeMap<String, usize>>,
 }
 
 impl WasccProvider {
     /// Returns a new wasCC provider configured to use the proper data directory
     /// (including creating it if necessary)
+    ///
+    /// Actor modules are fetched from `store`, which can be composed of several stores with
+    /// [`ComposableStore`](kubelet::store::composite::ComposableStore), e.g. a local cache in
+    /// front of the registry.
+    ///
+    /// The modules of the images in `prewarm` are pulled into the store before the provider is
+    /// returned, so that the first pods which run them start faster. They are pulled
+    /// concurrently and without credentials, and images which cannot be pulled are only
+    /// logged. The pulled images are reported in the status of the node.
     pub async fn new(
         store: Arc<dyn Store + Sync + Send>,
         config: &kubelet::config::Config,
         kubeconfig: kube::Config,
-    ) -> anyhow::Result<Self> {
+        prewarm: Vec<String>,
+    ) -> Result<Self, WasccError> {
+        Self::new_with_capability_load_timeout(
+            store,
+            config,
+            kubeconfig,
+            prewarm,
+            DEFAULT_CAPABILITY_LOAD_TIMEOUT,
+        )
+        .await
+    }
+
+    /// Returns a new wasCC provider like [`WasccProvider::new`], which fails if loading the
+    /// built-in capabilities takes longer than `capability_load_timeout`.
+    ///
+    /// A capability which hangs while it is instantiated cannot be interrupted, so it keeps a
+    /// blocking thread busy, but the kubelet reports the problem instead of never starting.
+    pub async fn new_with_capability_load_timeout(
+        store: Arc<dyn Store + Sync + Send>,
+        config: &kubelet::config::Config,
+        kubeconfig: kube::Config,
+        prewarm: Vec<String>,
+        capability_load_timeout: Duration,
+    ) -> Result<Self, WasccError> {
         let client = kube::Client::new(kubeconfig);
-        let host = Arc::new(Mutex::new(Host::new()));
+        let missing_crds = check_crds(&client).await;
+        if !missing_crds.is_empty() {
+            return Err(WasccError::CrdMissing { missing_crds });
+        }
+        let host = SharedHost::new(Host::new());
         let log_path = config.data_dir.join(LOG_DIR_NAME);
         let volume_path = config.data_dir.join(VOLUME_DIR);
-        let port_map = Arc::new(TokioMutex::new(BTreeMap::<u16, PodKey>::new()));
-        tokio::fs::create_dir_all(&log_path).await?;
-        tokio::fs::create_dir_all(&volume_path).await?;
-
-        // wascc has native and portable capabilities.
-        //
-        // Native capabilities are either dynamic libraries (.so, .dylib, .dll)
-        // or statically linked Rust libaries. If the native capabilty is a dynamic
-        // library it must be loaded and configured through [`NativeCapability::from_file`].
-        // If it is a statically linked libary it can be configured through
-        // [`NativeCapability::from_instance`].
-        //
-        // Portable capabilities are WASM modules.  Portable capabilities
-        // don't fully work, and won't until the WASI spec has matured.
-        //
-        // Here we are using the native capabilties as statically linked libraries that will
-        // be compiled into the wascc-provider binary.
-        let cloned_host = host.clone();
-        tokio::task::spawn_blocking(move || {
-            info!("Loading HTTP capability");
-            let http_provider = HttpServerProvider::new();
-            let data = NativeCapability::from_instance(http_provider, None)
-                .map_err(|e| anyhow::anyhow!("Failed to instantiate HTTP capability: {}", e))?;
-
-            cloned_host
-                .lock()
-                .unwrap()
-                .add_native_capability(data)
-                .map_err(|e| anyhow::anyhow!("Failed to add HTTP capability: {}", e))?;
-
-            info!("Loading log capability");
-            let logging_provider = LoggingProvider::new();
-            let logging_capability = NativeCapability::from_instance(logging_provider, None)
-                .map_err(|e| anyhow::anyhow!("Failed to instantiate log capability: {}", e))?;
-            cloned_host
-                .lock()
-                .unwrap()
-                .add_native_capability(logging_capability)
-                .map_err(|e| anyhow::anyhow!("Failed to add log capability: {}", e))
-        })
-        .await??;
-        Ok(Self {
+        let termination_path = config.data_dir.join(TERMINATION_DIR_NAME);
+        prepare_directory(&log_path, "actor log").await?;
+        prepare_directory(&volume_path, "volume").await?;
+        prepare_directory(&termination_path, "termination message").await?;
+
+        // Actors may have survived a restart of the kubelet, so their ports must not be handed
+        // out again. Ports of pods which are gone in the meantime are released.
+        let port_map_path = config.data_dir.join(PORT_MAP_FILE_NAME);
+        let mut ports = port_map::load(&port_map_path).await?;
+        if let Err(e) = port_map::reconcile(&client, &config.node_name, &mut ports).await {
+            warn!(
+                "Unable to reconcile assigned ports with the API server, keeping all of them: {:?}",
+                e
+            );
+        }
+        port_map::persist(&port_map_path, &ports).await;
+        let port_map = Arc::new(TokioMutex::new(ports));
+
+        // Actors run inside of the kubelet, so none of them is running yet and every log file
+        // left in the log directory is a leftover of an unclean shutdown
+        log_cleanup::remove_orphans(&log_path, &Default::default(), Duration::from_secs(0)).await;
+        let handles: Arc<RwLock<BTreeMap<PodKey, Handle<ActorHandle, LogHandleFactory>>>> =
+            Default::default();
+        let log_sweep_interval = Arc::new(Mutex::new(DEFAULT_LOG_SWEEP_INTERVAL));
+        tokio::spawn(log_cleanup::run(
+            Arc::clone(&handles),
+            log_path.clone(),
+            Arc::clone(&log_sweep_interval),
+        ));
+
+        let cloned_host = host.current();
+        let load_capabilities =
+            tokio::task::spawn_blocking(move || load_native_capabilities(&cloned_host));
+        // The modules are pulled while the capabilities are loading
+        let prewarming = tokio::spawn(prewarm::prewarm(Arc::clone(&store), prewarm));
+        let unavailable_capabilities =
+            match tokio::time::timeout(capability_load_timeout, load_capabilities).await {
+                Ok(result) => result??,
+                Err(_) => {
+                    return Err(WasccError::CapabilityLoadTimeout {
+                        timeout: capability_load_timeout,
+                    })
+                }
+            };
+        let prewarmed_images = prewarming.await.unwrap_or_else(|e| {
+            warn!("Prewarming actor modules failed: {}", e);
+            BTreeMap::new()
+        });
+        let provider = Self {
             shared: SharedPodState {
                 client,
-                handles: Default::default(),
+                handles,
                 store,
                 volume_path,
                 log_path,
+                termination_path,
                 host,
                 port_map,
+                port_map_path,
+                http_readiness_timeout: DEFAULT_HTTP_READINESS_TIMEOUT,
+                log_format: LogFormat::default(),
+                log_sweep_interval,
+                watchdog_settings: Default::default(),
+                unavailable_capabilities: Arc::new(unavailable_capabilities),
+                default_env: EnvVars::new(),
+                max_actors: None,
+                actor_slots: Default::default(),
+                preemption: false,
+                preemptibles: Preemptibles::default(),
+                image_pull_backoff: ExponentialBackoffStrategy::default()
+                    .with_jitter(DEFAULT_IMAGE_PULL_BACKOFF_JITTER),
+                host_changes: Default::default(),
+                prewarmed_images: Arc::new(prewarmed_images),
+            },
+        };
+        Ok(provider)
+    }
+
+    /// Sets how long to wait for actors with the HTTP capability to listen on their
+    /// assigned port before reporting them as ready. A zero timeout disables the check.
+    pub fn with_http_readiness_timeout(mut self, timeout: Duration) -> Self {
+        self.shared.http_readiness_timeout = timeout;
+        self
+    }
+
+    /// Returns the capabilities the actors of the pod are bound to, sorted and without
+    /// duplicates. These are the capabilities in the claims of the actors, as an actor does not
+    /// start if one of them is not available. Unknown pods have no capabilities.
+    pub async fn pod_capabilities(&self, pod_key: &PodKey) -> Vec<String> {
+        let handles = self.shared.handles.read().await;
+        let handle = match handles.get(pod_key) {
+            Some(handle) => handle,
+            None => return Vec::new(),
+        };
+        let capabilities: BTreeSet<String> = handle
+            .map_container_handles(|actor| actor.capabilities.clone())
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        capabilities.into_iter().collect()
+    }
+
+    /// Returns the images whose modules were pulled when the provider started, see
+    /// [`WasccProvider::new`].
+    pub fn prewarmed_images(&self) -> Vec<String> {
+        self.shared.prewarmed_images.keys().cloned().collect()
+    }
+
+    /// Sets the format of the actor logs. With [`LogFormat::Json`] every line is a JSON object
+    /// which carries the pod name, namespace, actor key and a timestamp next to the message.
+    pub fn with_log_format(mut self, log_format: LogFormat) -> Self {
+        self.shared.log_format = log_format;
+        self
+    }
+
+    /// Sets environment variables which are passed to every actor, e.g. the name of the cluster
+    /// or node. Variables which the container of an actor sets itself take precedence.
+    pub fn with_default_env(mut self, default_env: HashMap<String, String>) -> Self {
+        self.shared.default_env = default_env;
+        self
+    }
+
+    /// Limits how many pods may run actors on the node at once, so that the actors cannot
+    /// exhaust the memory of the node. Further pods fail with a
+    /// [`WasccError::NodeCapacityExceeded`] instead of being started, so that their controllers
+    /// can recreate them on another node. The limit is advertised as the `pods` capacity of the
+    /// node, which keeps the scheduler from placing more pods on it in the first place.
+    pub fn with_max_actors(mut self, max_actors: usize) -> Self {
+        self.shared.max_actors = Some(max_actors);
+        self
+    }
+
+    /// Sets whether pods which do not fit into [`WasccProvider::with_max_actors`] preempt the
+    /// running pod with the lowest priority, if it is lower than their own. The preempted pod
+    /// terminates with the reason `Preempting`. Disabled by default, in which case these pods
+    /// fail.
+    pub fn with_preemption(mut self, enabled: bool) -> Self {
+        self.shared.preemption = enabled;
+        self
+    }
+
+    /// Sets how long to wait between attempts to pull the images of a pod. Once the retries of
+    /// the strategy are exhausted, the pod fails with a [`WasccError::ImagePullRetriesExhausted`]
+    /// instead of trying again. Defaults to the Kubernetes backoff with a jitter of
+    /// [`DEFAULT_IMAGE_PULL_BACKOFF_JITTER`] and unlimited retries.
+    pub fn with_image_pull_backoff(mut self, backoff: ExponentialBackoffStrategy) -> Self {
+        self.shared.image_pull_backoff = backoff;
+        self
+    }
+
+    /// Sets how often the log directory is swept for log files which no longer belong to any
+    /// pod. The new interval takes effect after the currently scheduled sweep.
+    pub fn with_log_sweep_interval(self, interval: Duration) -> Self {
+        *self.shared.log_sweep_interval.lock().unwrap() = interval;
+        self
+    }
+
+    /// Starts the watchdog, which checks every `interval` whether the waSCC host still
+    /// responds, and replaces the host and restarts all actors in the new one after
+    /// `failure_threshold` failed checks in a row. If the watchdog runs already, the new
+    /// settings take effect after the currently scheduled check.
+    ///
+    /// The watchdog is disabled unless this is called, as the replaced host cannot be shut
+    /// down. [`DEFAULT_WATCHDOG_INTERVAL`] and [`DEFAULT_WATCHDOG_FAILURE_THRESHOLD`] are
+    /// reasonable settings.
+    pub fn with_host_watchdog(self, interval: Duration, failure_threshold: usize) -> Self {
+        let settings = WatchdogSettings {
+            interval,
+            failure_threshold: failure_threshold.max(1),
+        };
+        let running = self
+            .shared
+            .watchdog_settings
+            .lock()
+            .unwrap()
+            .replace(settings)
+            .is_some();
+        if !running {
+            tokio::spawn(watchdog::run(self.shared.clone()));
+        }
+        self
+    }
+
+    /// Replaces the actor of the given container with the current version of its module,
+    /// without recreating the pod. The image is pulled again, regardless of the pull policy,
+    /// and the new actor keeps the port and volumes of the one it replaces.
+    ///
+    /// This happens automatically whenever the [`RELOAD_ANNOTATION`] of a running pod changes.
+    pub async fn reload_actor(&self, key: &PodKey, container_name: &str) -> anyhow::Result<()> {
+        self.shared.reload_actor(key, container_name).await
+    }
+
+    /// Checks whether the pod could be run by this provider without actually running it.
+    ///
+    /// This pulls the modules of all containers, verifies that they are validly signed actors
+    /// which only use capabilities the provider supports and fit into their memory limit, and
+    /// that the host ports the pod asks for are available. Nothing is added to the waSCC host,
+    /// so this can be used to catch bad manifests early, e.g. from a webhook or CI.
+    pub async fn validate_pod(&self, pod: &Pod) -> anyhow::Result<()> {
+        let containers = pod.all_containers();
+        for container in containers.iter() {
+            if container.image()?.is_none() {
+                return Err(anyhow::anyhow!(
+                    "Container {} does not specify an image",
+                    container.name()
+                ));
+            }
+        }
+
+        {
+            let port_map = self.shared.port_map.lock().await;
+            check_host_ports(pod, &port_map)?;
+        }
+
+        let auth_resolver =
+            kubelet::secret::RegistryAuthResolver::new(self.shared.client.clone(), pod);
+        let modules = self
+            .shared
+            .store
+            .fetch_pod_modules(pod, &auth_resolver)
+            .await?;
+        let portable_capabilities = portable_capability_bindings(pod)?;
+        for container in containers.iter() {
+            let data = modules.get(container.name()).ok_or_else(|| {
+                anyhow::anyhow!("No module was fetched for container {}", container.name())
+            })?;
+            validate_actor(data, container.memory_limit()?, &portable_capabilities).map_err(
+                |e| anyhow::anyhow!("Container {} is not a valid actor: {}", container.name(), e),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl SharedPodState {
+    /// Releases the ports assigned to the pod and returns whether it had any.
+    async fn release_ports(&self, key: &PodKey) -> bool {
+        let mut lock = self.port_map.lock().await;
+        let ports_to_remove: Vec<u16> = lock
+            .iter()
+            .filter_map(|(k, v)| if v == key { Some(*k) } else { None })
+            .collect();
+        debug!(
+            "Pod {} in namespace {} releasing ports {:?}.",
+            key.name(),
+            key.namespace(),
+            &ports_to_remove
+        );
+        let released_ports = !ports_to_remove.is_empty();
+        for port in ports_to_remove {
+            lock.remove(&port);
+        }
+        port_map::persist(&self.port_map_path, &lock).await;
+        released_ports
+    }
+
+    /// Reserves one of the `max_actors` slots for the pod, unless the other pods which hold one
+    /// leave no room for it. Reserving a slot again is a no-op, so that pods which are retried
+    /// keep theirs.
+    fn reserve_actor_slot(&self, key: &PodKey) -> Result<(), WasccError> {
+        let mut actor_slots = self.actor_slots.lock().unwrap();
+        let others = actor_slots.iter().filter(|slot| *slot != key).count();
+        states::registered::check_actor_capacity(others, self.max_actors)?;
+        actor_slots.insert(key.clone());
+        Ok(())
+    }
+
+    /// Releases the `max_actors` slot of the pod, if it holds one.
+    fn release_actor_slot(&self, key: &PodKey) {
+        self.actor_slots.lock().unwrap().remove(key);
+    }
+
+    /// Removes the actors of the pod, releases its ports and removes its handle, without
+    /// waiting for its state machine, and reports the pod as failed with [`EVICTION_REASON`]
+    /// if it still exists.
+    async fn evict_pod(&self, key: &PodKey) -> anyhow::Result<()> {
+        info!(
+            "Forcibly evicting pod {} in namespace {}",
+            key.name(),
+            key.namespace()
+        );
+        self.fail_pod(key, EVICTION_REASON, EVICTED_MESSAGE).await
+    }
+
+    /// Removes the actors of the pod, releases its ports and removes its handle, without
+    /// waiting for its state machine, and reports the pod as failed with the reason, and its
+    /// containers as terminated with the message, if it still exists.
+    async fn fail_pod(&self, key: &PodKey, reason: &str, message: &str) -> anyhow::Result<()> {
+        self.preemptibles.remove(key);
+        let mut handle = self.handles.write().await.remove(key);
+        if let Some(handle) = handle.as_mut() {
+            if let Err(e) = handle.stop().await {
+                warn!(
+                    "Failed to stop actors of failed pod {}, continuing anyway: {:?}",
+                    key.name(),
+                    e
+                );
+            }
+        }
+        let released_ports = self.release_ports(key).await;
+        self.release_actor_slot(key);
+        self.preemptibles.stopped(key);
+
+        let pod = match handle.as_ref() {
+            Some(handle) => handle.pod().clone(),
+            None => {
+                let api: Api<KubePod> = Api::namespaced(self.client.clone(), &key.namespace());
+                match api.get(&key.name()).await {
+                    Ok(pod) => Pod::from(pod),
+                    Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {
+                        info!("Failed pod {} is gone already", key.name());
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        };
+        if released_ports {
+            patch_http_port_annotation(&self.client, key, &[]).await;
+        }
+        let mut container_statuses = Vec::new();
+        for container in pod.containers() {
+            let log_path = match handle.as_ref() {
+                Some(handle) => handle
+                    .map_container_handle_factory(container.name(), |factory| {
+                        factory.path().to_path_buf()
+                    })
+                    .await
+                    .ok(),
+                None => None,
+            };
+            let termination = Termination {
+                reason: message,
+                failed: true,
+                log_path: log_path.as_deref(),
+            };
+            container_statuses.push(
+                termination::terminated_status(
+                    &self.termination_path,
+                    key,
+                    &container,
+                    &termination,
+                    0,
+                )
+                .await,
+            );
+        }
+        termination::report(
+            &self.client,
+            &pod,
+            Phase::Failed,
+            reason,
+            container_statuses,
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Replaces the actor of the given container with the current version of its module, which
+    /// is pulled again. The new actor keeps the port and volumes of the one it replaces.
+    ///
+    /// Both actors cannot run side by side, as they share the public key and the port, so the
+    /// current actor is stopped first. If the new one fails to start, the current module is
+    /// started again from the local store. If that fails as well, [`WasccError::ReloadFailed`]
+    /// is returned, as the container is left without an actor.
+    async fn reload_actor(&self, key: &PodKey, container_name: &str) -> anyhow::Result<()> {
+        let _host_changes = self.host_changes.read().await;
+        // The handles are not locked while the image is pulled, which may take long, so that
+        // other pods can be added and removed meanwhile
+        let current = {
+            let handles = self.handles.read().await;
+            let handle = handles.get(key).ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: key.name(),
+            })?;
+            CurrentActor::of(handle, container_name).await?
+        };
+        // The current module has to be taken from the store before the new one replaces it.
+        let previous = match self
+            .prepare_actor(key, &current, container_name, PullPolicy::Never)
+            .await
+        {
+            Ok(previous) => Some(previous),
+            Err(e) => {
+                warn!(
+                    "The current module of container {} in pod {} is not stored locally, it cannot be restored if the reloaded one fails to start: {:?}",
+                    container_name,
+                    key.name(),
+                    e
+                );
+                None
+            }
+        };
+        let (data, config) = self
+            .prepare_actor(key, &current, container_name, PullPolicy::Always)
+            .await?;
+
+        info!(
+            "Reloading actor of container {} in pod {}",
+            container_name,
+            key.name()
+        );
+        // The pod may have been removed while the image was pulled
+        let handles = self.handles.write().await;
+        let handle = handles.get(key).ok_or_else(|| ProviderError::PodNotFound {
+            pod_name: key.name(),
+        })?;
+        handle.stop_container(container_name).await?;
+        let e = match self
+            .replace_actor(handle, container_name, data, config)
+            .await
+        {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        warn!(
+            "Failed to start the reloaded actor of container {} in pod {}, restoring the previous one: {:?}",
+            container_name,
+            key.name(),
+            e
+        );
+        let restore_message = match previous {
+            Some((data, config)) => match self
+                .replace_actor(handle, container_name, data, config)
+                .await
+            {
+                Ok(()) => return Err(e),
+                Err(restore_error) => restore_error.to_string(),
             },
+            None => "the module is not stored locally".to_owned(),
+        };
+        Err(WasccError::ReloadFailed {
+            container_name: container_name.to_owned(),
+            message: e.to_string(),
+            restore_message,
+        }
+        .into())
+    }
+
+    /// Pulls the module of the given container with the pull policy, and returns it with the
+    /// configuration of a new actor for the container, which keeps the port and volumes of the
+    /// current one.
+    async fn prepare_actor(
+        &self,
+        key: &PodKey,
+        current: &CurrentActor,
+        container_name: &str,
+        pull_policy: PullPolicy,
+    ) -> anyhow::Result<(Vec<u8>, ActorConfig)> {
+        let pod = &current.pod;
+        let container = pod
+            .containers()
+            .into_iter()
+            .find(|container| container.name() == container_name)
+            .ok_or_else(|| ProviderError::ContainerNotFound {
+                pod_name: key.name(),
+                container_name: container_name.to_owned(),
+            })?;
+        let capability_defaults = CapabilityDefaults::load(&self.client).await;
+        let log_file = configured_log_file(pod, container_name, &self.log_path)?;
+
+        let reference = container
+            .image()?
+            .ok_or_else(|| anyhow::anyhow!("Container {} has no image", container_name))?;
+        let auth = kubelet::secret::RegistryAuthResolver::new(self.client.clone(), pod)
+            .resolve_registry_auth(&reference)
+            .await?;
+        let data = self.store.get(&reference, pull_policy, &auth).await?;
+        debug!(
+            "Pulled module of container {} in pod {} from {}",
+            container_name,
+            key.name(),
+            reference
+        );
+
+        let config = ActorConfig {
+            env: <WasccProvider as Provider>::env_vars(&container, pod, &self.client).await,
+            volumes: current.volumes.clone(),
+            port_assigned: current.port,
+            memory_limit: container.memory_limit()?,
+            bindings: capability_bindings(pod),
+            log_config: log_config(pod, self.log_format),
+            unavailable_capabilities: Arc::clone(&self.unavailable_capabilities),
+            portable_capabilities: portable_capability_bindings(pod)?,
+            default_env: self.default_env.clone(),
+            capability_defaults,
+            log_file,
+            messaging_prefix: messaging_prefix(pod),
+        };
+        Ok((data, config))
+    }
+
+    /// Runs a prepared actor in the host and makes it the actor of the given container. The
+    /// current actor of the container has to be stopped before, unless it runs in another host.
+    async fn replace_actor(
+        &self,
+        handle: &Handle<ActorHandle, LogHandleFactory>,
+        container_name: &str,
+        data: Vec<u8>,
+        config: ActorConfig,
+    ) -> anyhow::Result<()> {
+        let host = self.host.clone();
+        let log_path = self.log_path.clone();
+        let (actor, _) =
+            tokio::task::spawn_blocking(move || wascc_run(host, data, config, &log_path)).await??;
+        handle.replace_container(container_name, actor).await
+    }
+}
+
+/// What a new actor of a container takes over from the current one.
+struct CurrentActor {
+    pod: Pod,
+    volumes: Vec<VolumeBinding>,
+    port: u16,
+}
+
+impl CurrentActor {
+    /// Copies what a new actor takes over from the actor of the container, so that the handle
+    /// does not need to stay locked while the new actor is prepared.
+    async fn of(
+        handle: &Handle<ActorHandle, LogHandleFactory>,
+        container_name: &str,
+    ) -> anyhow::Result<Self> {
+        let (volumes, port) = handle
+            .map_container_handle(container_name, |actor| (actor.volumes.clone(), actor.port))
+            .await?;
+        Ok(CurrentActor {
+            pod: handle.pod().clone(),
+            volumes,
+            port,
         })
     }
 }
 
+/// Loads the built-in capabilities into the host and returns the ones which could not be
+/// loaded, with the reason why.
+fn load_native_capabilities(
+    host: &Arc<Mutex<Host>>,
+) -> Result<HashMap<String, String>, WasccError> {
+    // wascc has native and portable capabilities.
+    //
+    // Native capabilities are either dynamic libraries (.so, .dylib, .dll)
+    // or statically linked Rust libaries. If the native capabilty is a dynamic
+    // library it must be loaded and configured through [`NativeCapability::from_file`].
+    // If it is a statically linked libary it can be configured through
+    // [`NativeCapability::from_instance`].
+    //
+    // Portable capabilities are WASM modules.  Portable capabilities
+    // don't fully work, and won't until the WASI spec has matured. Pods can
+    // experiment with them through [`PORTABLE_CAPABILITIES_ANNOTATION`].
+    //
+    // Here we are using the native capabilties as statically linked libraries that will
+    // be compiled into the wascc-provider binary.
+    //
+    // The Extras capability is not loaded here, as every waSCC host comes with it already
+    // and refuses to load a second instance under the default binding.
+    //
+    // Each capability is loaded on its own, so that a failure to load one of them only
+    // affects the actors that need it.
+    let loaders: [(&str, fn() -> wascc_host::Result<NativeCapability>); 3] = [
+        (HTTP_CAPABILITY, || {
+            NativeCapability::from_instance(HttpServerProvider::new(), None)
+        }),
+        (LOG_CAPABILITY, || {
+            NativeCapability::from_instance(LoggingProvider::new(), None)
+        }),
+        (MESSAGING_CAPABILITY, || {
+            NativeCapability::from_instance(PodMessagingProvider::new(), None)
+        }),
+    ];
+    let mut unavailable = HashMap::new();
+    for (capability, instantiate) in loaders.iter() {
+        info!("Loading {} capability", capability);
+        let result = instantiate()
+            .map_err(|e| format!("failed to instantiate capability: {}", e))
+            .and_then(|instance| {
+                host.lock()
+                    .unwrap()
+                    .add_native_capability(instance)
+                    .map_err(|e| format!("failed to add capability: {}", e))
+            });
+        if let Err(e) = result {
+            error!("Unable to load {} capability: {}", capability, e);
+            unavailable.insert(capability.to_string(), e);
+        }
+    }
+    if unavailable.len() == loaders.len() {
+        return Err(WasccError::NoCapabilityLoaded {
+            reasons: unavailable,
+        });
+    }
+    Ok(unavailable)
+}
+
+/// Returns the CRDs in [`CRDS`] which are not registered with the API server.
+async fn check_crds(client: &kube::Client) -> Vec<String> {
+    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
+    let mut missing_crds = vec![];
+    for crd in CRDS {
+        debug!("Checking if CRD \"{}\" is registered", crd);
+        if let Err(e) = crds.get(crd).await {
+            error!("Missing required CRD \"{}\": {:?}", crd, e);
+            missing_crds.push(crd.to_string());
+        }
+    }
+    missing_crds
+}
+
+/// The file written to check that a directory is writable.
+const WRITE_PROBE_FILE_NAME: &str = ".write-probe";
+
+/// Creates the directory if necessary and checks that files can be written to it, so that a
+/// misconfigured data directory is reported at startup instead of when the first pod runs.
+async fn prepare_directory(path: &Path, purpose: &str) -> Result<(), WasccError> {
+    let hint = |e: &std::io::Error| match e.kind() {
+        std::io::ErrorKind::PermissionDenied => {
+            "make sure the user running the kubelet owns it or may write to it"
+        }
+        _ => "make sure the file system is mounted writable and the path is not a file",
+    };
+    tokio::fs::create_dir_all(path)
+        .await
+        .map_err(|e| WasccError::DirectoryNotCreated {
+            purpose: purpose.to_owned(),
+            path: path.to_owned(),
+            hint: hint(&e),
+            source: e,
+        })?;
+    let probe = path.join(WRITE_PROBE_FILE_NAME);
+    let written = match tokio::fs::write(&probe, b"").await {
+        Ok(()) => tokio::fs::remove_file(&probe).await,
+        Err(e) => Err(e),
+    };
+    written.map_err(|e| WasccError::DirectoryNotWritable {
+        purpose: purpose.to_owned(),
+        path: path.to_owned(),
+        hint: hint(&e),
+        source: e,
+    })
+}
+
+/// Checks that the module is a signed actor, which is currently valid and only uses capabilities
+/// supported by the provider or by the given portable capability providers.
+fn validate_actor(
+    data: &[u8],
+    memory_limit: Option<u64>,
+    portable_capabilities: &HashMap<String, String>,
+) -> anyhow::Result<()> {
+    let token = wascap::wasm::extract_claims(data)
+        .map_err(|e| anyhow::anyhow!("unable to read the claims of the module: {}", e))?
+        .ok_or_else(|| anyhow::anyhow!("the module is not signed"))?;
+    let validation = wascap::jwt::validate_token::<wascap::jwt::Actor>(&token.jwt)
+        .map_err(|e| anyhow::anyhow!("unable to validate the claims of the module: {}", e))?;
+    if !validation.signature_valid {
+        return Err(anyhow::anyhow!("the signature of the module is invalid"));
+    }
+    if validation.expired {
+        return Err(anyhow::anyhow!(
+            "the claims of the module expired {}",
+            validation.expires_human
+        ));
+    }
+    if validation.cannot_use_yet {
+        return Err(anyhow::anyhow!(
+            "the module cannot be used before {}",
+            validation.not_before_human
+        ));
+    }
+
+    let capabilities = token
+        .claims
+        .metadata
+        .and_then(|metadata| metadata.caps)
+        .unwrap_or_default();
+    let unsupported: Vec<&String> = capabilities
+        .iter()
+        .filter(|capability| {
+            !SUPPORTED_CAPABILITIES.contains(&capability.as_str())
+                && !portable_capabilities.contains_key(*capability)
+        })
+        .collect();
+    if !unsupported.is_empty() {
+        return Err(anyhow::anyhow!(
+            "the module uses unsupported capabilities {:?}",
+            unsupported
+        ));
+    }
+
+    if let Some(limit) = memory_limit {
+        check_memory_limit(data, limit)?;
+    }
+    Ok(())
+}
+
+/// Checks that none of the host ports requested by the pod are taken, either by other pods or
+/// by the pod itself.
+fn check_host_ports(pod: &Pod, port_map: &BTreeMap<u16, PodKey>) -> anyhow::Result<()> {
+    let pinned_ports = pinned_host_ports(pod)?;
+    let mut requested = BTreeSet::new();
+    for container in pod.containers() {
+        let host_ports: Vec<i32> = match pinned_ports.get(container.name()) {
+            Some(port) => vec![i32::from(*port)],
+            None => container
+                .ports()
+                .iter()
+                .flatten()
+                .filter_map(|port| port.host_port)
+                .collect(),
+        };
+        for host_port in host_ports {
+            let host_port = u16::try_from(host_port)
+                .map_err(|_| anyhow::anyhow!("Host port {} is invalid", host_port))?;
+            if port_map.contains_key(&host_port) || !requested.insert(host_port) {
+                return Err(anyhow::anyhow!("Port {} is currently in use", host_port));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the host ports the [`HOST_PORT_ANNOTATION`] of the pod pins its containers to, keyed
+/// by container name.
+fn pinned_host_ports(pod: &Pod) -> Result<HashMap<String, u16>, WasccError> {
+    let value = match pod.get_annotation(HOST_PORT_ANNOTATION) {
+        Some(value) => value,
+        None => return Ok(HashMap::new()),
+    };
+    let invalid = |message: String| WasccError::InvalidHostPortAnnotation {
+        value: value.to_owned(),
+        message,
+    };
+    let containers = pod.containers();
+    let mut ports = HashMap::new();
+    for entry in value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (container, port) = match entry.find('=') {
+            Some(index) => (entry[..index].trim(), entry[index + 1..].trim()),
+            None => match containers.as_slice() {
+                [container] => (container.name(), entry),
+                _ => {
+                    return Err(invalid(String::from(
+                        "the pod has several containers, so ports have to be given as <container>=<port>",
+                    )))
+                }
+            },
+        };
+        if !containers.iter().any(|c| c.name() == container) {
+            return Err(invalid(format!("the pod has no container {}", container)));
+        }
+        let port = match port.parse::<u16>() {
+            Ok(port) if port > 0 => port,
+            _ => return Err(invalid(format!("{} is no valid port", port))),
+        };
+        if ports.values().any(|pinned| *pinned == port) {
+            return Err(invalid(format!("port {} is pinned more than once", port)));
+        }
+        ports.insert(container.to_owned(), port);
+    }
+    Ok(ports)
+}
+
 struct ModuleRunContext {
     modules: HashMap<String, Vec<u8>>,
     volumes: HashMap<String, Ref>,
+    /// Modules of the portable capability providers, keyed by capability ID
+    portable_capabilities: HashMap<String, Vec<u8>>,
 }
 
 /// State that is shared between pod state handlers.
@@ -227,8 +1122,24 @@ pub struct PodState {
     key: PodKey,
     run_context: ModuleRunContext,
     errors: usize,
+    /// When the pod failed the last time
+    last_failure: Option<std::time::Instant>,
+    /// How often the pod was restarted after a failure
+    restart_count: i32,
+    /// Containers whose HTTP port did not come up within the readiness timeout, with the address
+    /// and port they are expected to listen on
+    unready_containers: BTreeMap<String, (IpAddr, u16)>,
     image_pull_backoff_strategy: ExponentialBackoffStrategy,
     crash_loop_backoff_strategy: ExponentialBackoffStrategy,
+    /// The value of the reload annotation the actors were last loaded for
+    reload_generation: Option<String>,
+    pod_changed: Arc<Notify>,
+    /// Portable capabilities which were loaded into the host for this pod
+    portable_capabilities: BTreeSet<String>,
+    /// The priority of the pod, which decides whether it may preempt running pods
+    priority: i32,
+    /// Notified once the running pod is preempted by a pod with a higher priority
+    preempt: Arc<Notify>,
     shared: SharedPodState,
 }
 
@@ -236,26 +1147,49 @@ pub struct PodState {
 #[async_trait]
 impl kubelet::state::AsyncDrop for PodState {
     async fn async_drop(self) {
-        {
-            let mut lock = self.shared.port_map.lock().await;
-            let ports_to_remove: Vec<u16> = lock
-                .iter()
-                .filter_map(|(k, v)| if v == &self.key { Some(*k) } else { None })
-                .collect();
-            debug!(
-                "Pod {} in namespace {} releasing ports {:?}.",
-                &self.key.name(),
-                &self.key.namespace(),
-                &ports_to_remove
-            );
-            for port in ports_to_remove {
-                lock.remove(&port);
-            }
+        if self.shared.release_ports(&self.key).await {
+            patch_http_port_annotation(&self.shared.client, &self.key, &[]).await;
         }
+        self.shared.preemptibles.remove(&self.key);
         {
             let mut handles = self.shared.handles.write().await;
             handles.remove(&self.key);
         }
+        self.shared.release_actor_slot(&self.key);
+        let termination_path = termination::pod_directory(&self.shared.termination_path, &self.key);
+        if let Err(e) = tokio::fs::remove_dir_all(&termination_path).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!(
+                    "Failed to remove termination messages of pod {}: {}",
+                    self.key.name(),
+                    e
+                );
+            }
+        }
+        if !self.portable_capabilities.is_empty() {
+            let binding = portable_binding(&self.key);
+            let pod_name = self.key.name();
+            let capabilities = self.portable_capabilities;
+            let removal = self.shared.host.with_host(move |host| {
+                for capability in capabilities.iter() {
+                    // Despite its name, this removes portable capabilities as well
+                    if let Err(e) = host.remove_native_capability(capability, Some(binding.clone()))
+                    {
+                        warn!(
+                            "Failed to remove portable {} capability of pod {}: {}",
+                            capability, pod_name, e
+                        );
+                    }
+                }
+            });
+            if let Err(e) = removal.await {
+                warn!(
+                    "Failed to remove portable capabilities of pod {}: {}",
+                    self.key.name(),
+                    e
+                );
+            }
+        }
     }
 }
 
@@ -271,25 +1205,88 @@ impl Provider for WasccProvider {
         builder.set_architecture("wasm-wasi");
         builder.add_taint("NoSchedule", "kubernetes.io/arch", Self::ARCH);
         builder.add_taint("NoExecute", "kubernetes.io/arch", Self::ARCH);
+        if let Some(max_actors) = self.shared.max_actors {
+            builder.add_capacity("pods", &max_actors.to_string());
+            builder.add_allocatable("pods", &max_actors.to_string());
+        }
+        for (image, size) in self.shared.prewarmed_images.iter() {
+            builder.add_image(vec![image.clone()], Some(*size as i64));
+        }
+        Ok(())
+    }
+
+    /// Removes actors, ports and capabilities which do not belong to any pod anymore. This waits
+    /// for pods which are starting, so it runs in the background instead of holding up the pod
+    /// watch.
+    async fn reconcile(&self, pods: &[Pod]) -> anyhow::Result<()> {
+        let shared = self.shared.clone();
+        let pods = pods.to_vec();
+        tokio::spawn(async move {
+            if let Err(e) = orphans::remove_orphans(&shared, &pods).await {
+                warn!("Unable to remove orphaned actors: {:?}", e);
+            }
+        });
         Ok(())
     }
 
     async fn initialize_pod_state(&self, pod: &Pod, pod_changed: Arc<Notify>) -> anyhow::Result<Self::PodState> {
+        // Pods which tolerate the taints of the node, but select another architecture, would
+        // only fail with confusing errors about their modules not being actors
+        if !pod.runs_on_architecture(Self::ARCH) {
+            let message = format!(
+                "Pod {} selects an architecture other than {}, which this node runs",
+                pod.name(),
+                Self::ARCH
+            );
+            kubelet::pod::reject_pod(
+                &self.shared.client,
+                pod,
+                UNSUPPORTED_ARCHITECTURE_REASON,
+                &message,
+            )
+            .await;
+            return Err(anyhow::anyhow!(message));
+        }
         let run_context = ModuleRunContext {
             modules: Default::default(),
             volumes: Default::default(),
+            portable_capabilities: Default::default(),
         };
         let key = PodKey::from(pod);
         Ok(PodState {
             key,
             run_context,
             errors: 0,
-            image_pull_backoff_strategy: ExponentialBackoffStrategy::default(),
+            last_failure: None,
+            restart_count: 0,
+            unready_containers: BTreeMap::new(),
+            image_pull_backoff_strategy: self.shared.image_pull_backoff.clone(),
             crash_loop_backoff_strategy: ExponentialBackoffStrategy::default(),
+            reload_generation: pod.get_annotation(RELOAD_ANNOTATION).map(String::from),
+            pod_changed,
+            portable_capabilities: BTreeSet::new(),
+            priority: preemption::priority(pod),
+            preempt: Arc::new(Notify::new()),
             shared: self.shared.clone(),
         })
     }
 
+    async fn capabilities(&self, namespace: String, pod: String) -> anyhow::Result<Vec<String>> {
+        let key = PodKey::new(&namespace, &pod);
+        if !self.shared.handles.read().await.contains_key(&key) {
+            return Err(ProviderError::PodNotFound { pod_name: pod }.into());
+        }
+        Ok(self.pod_capabilities(&key).await)
+    }
+
+    /// Removes the actors of the pod, releases its ports and removes its handle right away, and
+    /// reports the pod as failed with the reason `Evicted`. Actors which are still being started
+    /// when the pod is evicted are not removed, so such a pod has to be evicted again once they
+    /// were started.
+    async fn evict_pod(&self, pod_key: &PodKey) -> anyhow::Result<()> {
+        self.shared.evict_pod(pod_key).await
+    }
+
     async fn logs(
         &self,
         namespace: String,
@@ -307,9 +1304,12 @@ impl Provider for WasccProvider {
     }
 }
 
+#[derive(Clone)]
 struct VolumeBinding {
     name: String,
     host_path: PathBuf,
+    /// Whether the actor may only read from the volume
+    read_only: bool,
 }
 
 /// Capability describes a waSCC capability.
@@ -317,120 +1317,903 @@ struct VolumeBinding {
 /// Capabilities are made available to actors through a two-part processthread:
 /// - They must be registered
 /// - For each actor, the capability must be configured
+///
+/// The HTTP and logging capabilities are bound with the default binding unless the pod has an
+/// annotation `wascc.dev/binding.<capability>` naming the binding, e.g.
+/// `wascc.dev/binding.wascc.http_server: web`. As Kubernetes does not allow colons in
+/// annotation keys, the colon in the capability ID is written as a dot. The Filesystem
+/// capability is always bound with the name of the volume.
 struct Capability {
     name: &'static str,
     binding: Option<String>,
     env: EnvVars,
 }
 
-/// Holds our tempfile handle.
+/// Returns the configuration of the File System capability for each of the volumes. Every volume
+/// gets an instance of its own, which is bound under the name of the volume and has the host
+/// path of the volume as its root directory.
+fn volume_capabilities(volumes: &[VolumeBinding], env: &EnvVars) -> Vec<Capability> {
+    volumes
+        .iter()
+        .map(|vol| {
+            let mut fsenv = env.clone();
+            fsenv.insert(
+                FS_CONFIG_ROOTDIR.to_owned(),
+                vol.host_path.as_path().to_str().unwrap().to_owned(),
+            );
+            Capability {
+                name: FS_CAPABILITY,
+                binding: Some(vol.name.clone()),
+                env: fsenv,
+            }
+        })
+        .collect()
+}
+
+/// The file an actor logs into.
+enum LogFile {
+    /// A temporary file, which is removed together with the handle
+    Temporary(NamedTempFile),
+    /// A file set with the [`LOG_FILE_ANNOTATION`], which is kept
+    Configured(PathBuf),
+}
+
+impl LogFile {
+    /// Opens the file at the given path for the actor to log into, creating it and its
+    /// directory if necessary.
+    fn configured(path: PathBuf) -> Result<Self, WasccError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if let Err(source) = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+        {
+            return Err(WasccError::LogFileNotOpened { path, source });
+        }
+        Ok(LogFile::Configured(path))
+    }
+
+    /// The path of the file the actor logs into.
+    fn path(&self) -> &Path {
+        match self {
+            LogFile::Temporary(temp) => temp.path(),
+            LogFile::Configured(path) => path,
+        }
+    }
+}
+
+/// Holds our log file handle.
 struct LogHandleFactory {
-    temp: NamedTempFile,
+    file: LogFile,
+}
+
+impl LogHandleFactory {
+    /// The path of the file the actor logs into.
+    fn path(&self) -> &Path {
+        self.file.path()
+    }
 }
 
 impl kubelet::log::HandleFactory<tokio::fs::File> for LogHandleFactory {
     /// Creates `tokio::fs::File` on demand for log reading.
     fn new_handle(&self) -> tokio::fs::File {
-        tokio::fs::File::from_std(self.temp.reopen().unwrap())
+        let file = match &self.file {
+            LogFile::Temporary(temp) => temp.reopen(),
+            LogFile::Configured(path) => std::fs::File::open(path),
+        };
+        tokio::fs::File::from_std(file.unwrap())
     }
 }
 
+/// Checks the memory the module declares against the memory limit of its container.
+///
+/// The waSCC host creates the WASM engine itself and offers no way to cap the linear memory of
+/// an actor, so a module that needs more memory than allowed right at instantiation is rejected
+/// here. Modules which may grow beyond the limit later on are only warned about.
+fn check_memory_limit(data: &[u8], limit: u64) -> Result<(), WasccError> {
+    let mut initial_bytes: u64 = 0;
+    let mut maximum_bytes: Option<u64> = Some(0);
+    let mut add_memory = |memory: wasmparser::MemoryType| {
+        initial_bytes += u64::from(memory.limits.initial) * WASM_PAGE_SIZE;
+        maximum_bytes = match (maximum_bytes, memory.limits.maximum) {
+            (Some(total), Some(pages)) => Some(total + u64::from(pages) * WASM_PAGE_SIZE),
+            _ => None,
+        };
+    };
+    for payload in wasmparser::Parser::new(0).parse_all(data) {
+        match payload? {
+            wasmparser::Payload::MemorySection(reader) => {
+                for memory in reader {
+                    add_memory(memory?);
+                }
+            }
+            wasmparser::Payload::ImportSection(reader) => {
+                for import in reader {
+                    if let wasmparser::ImportSectionEntryType::Memory(memory) = import?.ty {
+                        add_memory(memory);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if initial_bytes > limit {
+        return Err(WasccError::MemoryLimitExceeded {
+            required: initial_bytes,
+            limit,
+        });
+    }
+    match maximum_bytes {
+        Some(maximum) if maximum <= limit => (),
+        _ => warn!(
+            "Actor may grow its memory beyond its limit of {} bytes, which the waSCC host cannot prevent",
+            limit
+        ),
+    }
+    Ok(())
+}
+
+/// Returns the binding names the pod's annotations select for the HTTP and logging
+/// capabilities, keyed by capability ID.
+fn capability_bindings(pod: &Pod) -> HashMap<String, String> {
+    [HTTP_CAPABILITY, LOG_CAPABILITY]
+        .iter()
+        .filter_map(|capability| {
+            let key = format!(
+                "{}{}",
+                BINDING_ANNOTATION_PREFIX,
+                capability.replace(':', ".")
+            );
+            pod.get_annotation(&key)
+                .map(|binding| (capability.to_string(), binding.to_owned()))
+        })
+        .collect()
+}
+
+/// Sets the [`HTTP_PORT_ANNOTATION`] of the pod to the given ports, or removes it if there are
+/// none.
+async fn patch_http_port_annotation(client: &kube::Client, key: &PodKey, ports: &[u16]) {
+    let value = if ports.is_empty() {
+        serde_json::Value::Null
+    } else {
+        let ports: Vec<String> = ports.iter().map(u16::to_string).collect();
+        serde_json::Value::from(ports.join(","))
+    };
+    let patch = serde_json::json!({
+        "metadata": {
+            "annotations": { HTTP_PORT_ANNOTATION: value }
+        }
+    });
+    let data = serde_json::to_vec(&patch).expect("Annotation patch should always be serializable");
+    let api: Api<KubePod> = Api::namespaced(client.clone(), &key.namespace());
+    match api.patch(&key.name(), &PatchParams::default(), data).await {
+        Ok(_) => debug!("Set HTTP ports of pod {} to {:?}", key.name(), ports),
+        // The pod may already be gone when its ports are released
+        Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {}
+        Err(e) => warn!(
+            "Failed to annotate pod {} with its HTTP ports: {}",
+            key.name(),
+            e
+        ),
+    }
+}
+
+/// Returns the OCI references of the portable capability providers the pod asks for through the
+/// [`PORTABLE_CAPABILITIES_ANNOTATION`], keyed by capability ID.
+fn portable_capability_references(pod: &Pod) -> anyhow::Result<HashMap<String, Reference>> {
+    let annotation = match pod.get_annotation(PORTABLE_CAPABILITIES_ANNOTATION) {
+        Some(annotation) => annotation,
+        None => return Ok(HashMap::new()),
+    };
+    annotation
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| -> anyhow::Result<(String, Reference)> {
+            let mut parts = entry.splitn(2, '=').map(str::trim);
+            match (parts.next(), parts.next()) {
+                (Some(capability), Some(reference)) if !capability.is_empty() => {
+                    Ok((capability.to_owned(), Reference::try_from(reference)?))
+                }
+                _ => Err(anyhow::anyhow!(
+                    "Invalid portable capability '{}', expected <capability ID>=<OCI reference>",
+                    entry
+                )),
+            }
+        })
+        .collect()
+}
+
+/// The prefix of the binding names of portable capability providers.
+const PORTABLE_BINDING_PREFIX: &str = "portable-";
+
+/// The binding name the portable capability providers of the pod are loaded under. Every pod
+/// gets instances of its own, so that they can be removed together with the pod.
+fn portable_binding(key: &PodKey) -> String {
+    format!(
+        "{}{}-{}",
+        PORTABLE_BINDING_PREFIX,
+        key.namespace(),
+        key.name()
+    )
+}
+
+/// Returns the binding names of the portable capability providers of the pod, keyed by
+/// capability ID.
+fn portable_capability_bindings(pod: &Pod) -> anyhow::Result<HashMap<String, String>> {
+    let binding = portable_binding(&PodKey::from(pod));
+    Ok(portable_capability_references(pod)?
+        .into_iter()
+        .map(|(capability, _)| (capability, binding.clone()))
+        .collect())
+}
+
+/// Loads a portable capability provider into the host under the given binding name.
+fn add_portable_capability(
+    host: &Host,
+    capability: &str,
+    binding: &str,
+    data: &[u8],
+) -> Result<(), WasccError> {
+    let module = Actor::from_slice(data).map_err(|e| WasccError::CapabilityNotLoaded {
+        capability: capability.to_owned(),
+        message: format!("the portable capability is invalid: {}", e),
+    })?;
+    warn!(
+        "Loading experimental portable {} capability for binding '{}'",
+        capability, binding
+    );
+    host.add_capability(module, Some(binding), WasiParams::default())
+        .map_err(|e| WasccError::CapabilityNotLoaded {
+            capability: capability.to_owned(),
+            message: format!("failed to add the portable capability: {}", e),
+        })
+}
+
+/// Loads an instance of the capability under the given binding name, unless the host already
+/// has one. Named instances stay loaded, so they can be shared by all actors using that binding.
+fn ensure_named_capability<F>(
+    host: &Arc<Mutex<Host>>,
+    capability: &str,
+    binding: &str,
+    instantiate: F,
+) -> Result<(), WasccError>
+where
+    F: FnOnce() -> wascc_host::Result<NativeCapability>,
+{
+    let lock = host.lock().unwrap();
+    if lock
+        .capabilities()
+        .contains_key(&(binding.to_owned(), capability.to_owned()))
+    {
+        return Ok(());
+    }
+    info!(
+        "Loading {} capability for binding '{}'",
+        capability, binding
+    );
+    let instance = instantiate().map_err(|e| WasccError::CapabilityNotLoaded {
+        capability: capability.to_owned(),
+        message: format!("failed to instantiate capability: {}", e),
+    })?;
+    lock.add_native_capability(instance)
+        .map_err(|e| WasccError::CapabilityNotLoaded {
+            capability: capability.to_owned(),
+            message: format!("failed to add capability: {}", e),
+        })
+}
+
+/// Describes how an actor is to be run, derived from its container and pod.
+struct ActorConfig {
+    env: EnvVars,
+    volumes: Vec<VolumeBinding>,
+    port_assigned: u16,
+    memory_limit: Option<u64>,
+    /// Binding names of capabilities, keyed by capability ID
+    bindings: HashMap<String, String>,
+    /// Additional configuration of the logging capability
+    log_config: HashMap<String, String>,
+    /// Built-in capabilities which could not be loaded, with the reason why
+    unavailable_capabilities: Arc<HashMap<String, String>>,
+    /// Binding names of the experimental portable capabilities, keyed by capability ID
+    portable_capabilities: HashMap<String, String>,
+    /// Environment variables which are overridden by `env`
+    default_env: EnvVars,
+    /// The configuration of the capabilities, which is overridden by the environment
+    capability_defaults: CapabilityDefaults,
+    /// The file the actor logs into, unless it gets a temporary one
+    log_file: Option<PathBuf>,
+    /// The subject prefix which scopes the messages of the actor to its pod
+    messaging_prefix: String,
+}
+
+/// Returns the subject prefix of the pod for the Messaging capability. All actors of the pod are
+/// bound with it, which lets them message each other, but no actor of another pod.
+fn messaging_prefix(pod: &Pod) -> String {
+    format!("{}.{}", pod.namespace(), pod.name())
+}
+
+/// Returns the file the actor of the container logs into if the pod sets one with the
+/// [`LOG_FILE_ANNOTATION`]. The file has to be inside of the log directory.
+fn configured_log_file(
+    pod: &Pod,
+    container_name: &str,
+    log_path: &Path,
+) -> anyhow::Result<Option<PathBuf>> {
+    let pattern = match pod.get_annotation(LOG_FILE_ANNOTATION) {
+        Some(pattern) => pattern,
+        None => return Ok(None),
+    };
+    let relative_path = pattern
+        .replace("{namespace}", pod.namespace())
+        .replace("{pod}", pod.name())
+        .replace("{container}", container_name);
+    let mut log_file = log_path.to_path_buf();
+    for component in Path::new(&relative_path).components() {
+        match component {
+            std::path::Component::Normal(name) => log_file.push(name),
+            std::path::Component::CurDir => {}
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Log file '{}' in annotation {} must be a relative path inside of the log directory",
+                    pattern,
+                    LOG_FILE_ANNOTATION
+                ))
+            }
+        }
+    }
+    if log_file == log_path {
+        return Err(anyhow::anyhow!(
+            "Log file '{}' in annotation {} names no file",
+            pattern,
+            LOG_FILE_ANNOTATION
+        ));
+    }
+    Ok(Some(log_file))
+}
+
+/// Returns the environment of an actor, which consists of the default environment of the
+/// provider and the environment of its container. On conflicts, the container wins.
+fn merge_env(default_env: EnvVars, env: EnvVars) -> EnvVars {
+    let mut merged = default_env;
+    merged.extend(env);
+    merged
+}
+
+/// Returns the configuration of the logging capability for an actor of the given pod.
+fn log_config(pod: &Pod, log_format: LogFormat) -> HashMap<String, String> {
+    let mut config = HashMap::new();
+    config.insert(LOG_FORMAT_KEY.to_owned(), log_format.as_str().to_owned());
+    config.insert(LOG_POD_NAME_KEY.to_owned(), pod.name().to_owned());
+    config.insert(LOG_POD_NAMESPACE_KEY.to_owned(), pod.namespace().to_owned());
+    config
+}
+
 /// Run the given WASM data as a waSCC actor with the given public key.
 ///
 /// The provided capabilities will be configured for this actor, but the capabilities
 /// must first be loaded into the host by some other process, such as register_native_capabilities().
+///
+/// Next to the handle, the port the actor serves HTTP on is returned if it uses the
+/// HTTP capability.
+///
+/// The host is locked for every change, so this must run on a blocking thread.
 fn wascc_run(
-    host: Arc<Mutex<Host>>,
+    shared_host: SharedHost,
     data: Vec<u8>,
-    env: EnvVars,
-    volumes: Vec<VolumeBinding>,
+    config: ActorConfig,
     log_path: &Path,
-    port_assigned: u16,
-) -> anyhow::Result<ContainerHandle<ActorHandle, LogHandleFactory>> {
+) -> Result<(ContainerHandle<ActorHandle, LogHandleFactory>, Option<u16>), WasccError> {
+    let ActorConfig {
+        env,
+        volumes,
+        port_assigned,
+        memory_limit,
+        bindings,
+        log_config,
+        unavailable_capabilities,
+        portable_capabilities,
+        default_env,
+        capability_defaults,
+        log_file,
+        messaging_prefix,
+    } = config;
+    let host = shared_host.current();
+    let mut env = merge_env(default_env, env);
+    // Set after merging, so that the pod cannot pick the prefix of another pod
+    env.insert(messaging::SUBJECT_PREFIX_KEY.to_owned(), messaging_prefix);
     let mut capabilities: Vec<Capability> = Vec::new();
     info!("sending actor to wascc host");
-    let log_output = NamedTempFile::new_in(&log_path)?;
+    let log_output = match log_file {
+        Some(path) => LogFile::configured(path)?,
+        None => LogFile::Temporary(NamedTempFile::new_in(&log_path)?),
+    };
+
+    if let Some(limit) = memory_limit {
+        check_memory_limit(&data, limit)?;
+    }
 
-    let load =
-        Actor::from_slice(&data).map_err(|e| anyhow::anyhow!("Error loading WASM: {}", e))?;
+    let load = Actor::from_slice(&data).map_err(|e| WasccError::InvalidActor {
+        message: e.to_string(),
+    })?;
     let pk = load.public_key();
 
     let actor_caps = load.capabilities();
 
+    // Named instances are loaded on demand, so only the default instances may be missing
+    for capability in actor_caps.iter() {
+        if let Some(reason) = unavailable_capabilities.get(capability) {
+            if !bindings.contains_key(capability) && !portable_capabilities.contains_key(capability)
+            {
+                return Err(WasccError::CapabilityUnavailable {
+                    actor: pk,
+                    capability: capability.clone(),
+                    reason: reason.clone(),
+                });
+            }
+        }
+    }
+
+    if actor_caps.contains(&EXTRAS_CAPABILITY.to_owned()) {
+        // The host binds actors to its built in Extras capability by itself when they are
+        // added and there is no configuration to pass, so it needs no entry in `capabilities`
+        debug!("Actor {} uses the Extras capability", pk);
+    }
+
     if actor_caps.contains(&LOG_CAPABILITY.to_owned()) {
-        let mut logenv = env.clone();
+        let mut logenv = capability_defaults.env(LOG_CAPABILITY, &env);
+        logenv.extend(log_config);
         logenv.insert(
             LOG_PATH_KEY.to_string(),
             log_output.path().to_str().unwrap().to_owned(),
         );
+        let binding = bindings.get(LOG_CAPABILITY).cloned();
+        if let Some(binding) = binding.as_ref() {
+            ensure_named_capability(&host, LOG_CAPABILITY, binding, || {
+                NativeCapability::from_instance(LoggingProvider::new(), Some(binding.clone()))
+            })?;
+        }
         capabilities.push(Capability {
             name: LOG_CAPABILITY,
-            binding: None,
+            binding,
             env: logenv,
         });
     }
 
-    if actor_caps.contains(&HTTP_CAPABILITY.to_owned()) {
-        let mut httpenv = env.clone();
+    let http_port = if actor_caps.contains(&HTTP_CAPABILITY.to_owned()) {
+        Some(port_assigned)
+    } else {
+        None
+    };
+    if http_port.is_some() {
+        let mut httpenv = capability_defaults.env(HTTP_CAPABILITY, &env);
         httpenv.insert("PORT".to_string(), port_assigned.to_string());
+        let binding = bindings.get(HTTP_CAPABILITY).cloned();
+        if let Some(binding) = binding.as_ref() {
+            ensure_named_capability(&host, HTTP_CAPABILITY, binding, || {
+                NativeCapability::from_instance(HttpServerProvider::new(), Some(binding.clone()))
+            })?;
+        }
         capabilities.push(Capability {
             name: HTTP_CAPABILITY,
-            binding: None,
+            binding,
             env: httpenv,
         });
     }
 
+    if actor_caps.contains(&MESSAGING_CAPABILITY.to_owned()) {
+        // A single instance serves the actors of all pods and keeps the pods apart by the
+        // subject prefix in the configuration
+        capabilities.push(Capability {
+            name: MESSAGING_CAPABILITY,
+            binding: None,
+            env: capability_defaults.env(MESSAGING_CAPABILITY, &env),
+        });
+    }
+
     if actor_caps.contains(&FS_CAPABILITY.to_owned()) {
-        for vol in &volumes {
+        let fsenv = capability_defaults.env(FS_CAPABILITY, &env);
+        for (vol, capability) in volumes.iter().zip(volume_capabilities(&volumes, &fsenv)) {
             info!(
                 "Loading File System capability for volume name: '{}' host_path: '{}'",
                 vol.name,
                 vol.host_path.display()
             );
-            let mut fsenv = env.clone();
-            fsenv.insert(
-                FS_CONFIG_ROOTDIR.to_owned(),
-                vol.host_path.as_path().to_str().unwrap().to_owned(),
-            );
-            let fs_provider = FileSystemProvider::new();
-            let fs_capability =
-                NativeCapability::from_instance(fs_provider, Some(vol.name.clone())).map_err(
-                    |e| anyhow::anyhow!("Failed to instantiate File System capability: {}", e),
-                )?;
+            let binding = Some(vol.name.clone());
+            let fs_capability = if vol.read_only {
+                NativeCapability::from_instance(ReadOnlyFileSystemProvider::new(), binding)
+            } else {
+                NativeCapability::from_instance(FileSystemProvider::new(), binding)
+            }
+            .map_err(|e| WasccError::CapabilityNotLoaded {
+                capability: FS_CAPABILITY.to_owned(),
+                message: format!("failed to instantiate capability: {}", e),
+            })?;
             host.lock()
                 .unwrap()
                 .add_native_capability(fs_capability)
-                .map_err(|e| anyhow::anyhow!("Failed to add File System capability: {}", e))?;
-            capabilities.push(Capability {
-                name: FS_CAPABILITY,
-                binding: Some(vol.name.clone()),
-                env: fsenv,
-            });
+                .map_err(|e| WasccError::CapabilityNotLoaded {
+                    capability: FS_CAPABILITY.to_owned(),
+                    message: format!("failed to add capability: {}", e),
+                })?;
+            capabilities.push(capability);
         }
     }
 
     host.lock()
         .unwrap()
         .add_actor(load)
-        .map_err(|e| anyhow::anyhow!("Error adding actor: {}", e))?;
+        .map_err(|e| WasccError::ActorNotAdded {
+            message: e.to_string(),
+        })?;
     capabilities.iter().try_for_each(|cap| {
         info!("configuring capability {}", cap.name);
         host.lock()
             .unwrap()
             .set_binding(&pk, cap.name, cap.binding.clone(), cap.env.clone())
-            .map_err(|e| anyhow::anyhow!("Error configuring capabilities for module: {}", e))
+            .map_err(|e| WasccError::BindingFailed {
+                capability: cap.name.to_owned(),
+                message: e.to_string(),
+            })
     })?;
+    for (capability, binding) in portable_capabilities.iter() {
+        if !actor_caps.contains(capability) {
+            continue;
+        }
+        info!("configuring portable capability {}", capability);
+        host.lock()
+            .unwrap()
+            .set_binding(
+                &pk,
+                capability,
+                Some(binding.clone()),
+                capability_defaults.env(capability, &env),
+            )
+            .map_err(|e| WasccError::BindingFailed {
+                capability: capability.clone(),
+                message: e.to_string(),
+            })?;
+    }
 
-    let log_handle_factory = LogHandleFactory { temp: log_output };
+    let log_handle_factory = LogHandleFactory { file: log_output };
 
     info!("wascc actor executing");
-    Ok(ContainerHandle::new(
-        ActorHandle {
-            host,
-            key: pk,
-            volumes,
-            capabilities: actor_caps,
-        },
-        log_handle_factory,
+    Ok((
+        ContainerHandle::new(
+            ActorHandle {
+                host: shared_host,
+                key: pk,
+                volumes,
+                capabilities: actor_caps,
+                port: port_assigned,
+            },
+            log_handle_factory,
+        ),
+        http_port,
     ))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Assembles a module which only consists of a memory section with the given limits.
+    fn module_with_memory(initial: u8, maximum: Option<u8>) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        match maximum {
+            Some(maximum) => module.extend(&[0x05, 0x04, 0x01, 0x01, initial, maximum]),
+            None => module.extend(&[0x05, 0x03, 0x01, 0x00, initial]),
+        }
+        module
+    }
+
+    #[test]
+    fn test_memory_within_limit() {
+        let module = module_with_memory(2, Some(4));
+        assert!(check_memory_limit(&module, 4 * WASM_PAGE_SIZE).is_ok());
+        // Growing beyond the limit is only warned about
+        assert!(check_memory_limit(&module, 2 * WASM_PAGE_SIZE).is_ok());
+        assert!(check_memory_limit(&module_with_memory(2, None), 2 * WASM_PAGE_SIZE).is_ok());
+    }
+
+    #[test]
+    fn test_capability_bindings_from_annotations() {
+        let pod = Pod::from(
+            serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                "metadata": {
+                    "name": "test",
+                    "annotations": { "wascc.dev/binding.wascc.http_server": "web" }
+                },
+                "spec": { "containers": [] }
+            }))
+            .unwrap(),
+        );
+        let bindings = capability_bindings(&pod);
+        assert_eq!(bindings.get(HTTP_CAPABILITY), Some(&"web".to_owned()));
+        assert!(bindings.get(LOG_CAPABILITY).is_none());
+    }
+
+    #[test]
+    fn test_portable_capabilities_from_annotation() {
+        let pod = Pod::from(
+            serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                "metadata": {
+                    "name": "test",
+                    "namespace": "default",
+                    "annotations": {
+                        "wascc.dev/experimental-portable-capabilities":
+                            "example:kv=webassembly.azurecr.io/kv:v1, example:queue=webassembly.azurecr.io/queue:v1"
+                    }
+                },
+                "spec": { "containers": [] }
+            }))
+            .unwrap(),
+        );
+        let references = portable_capability_references(&pod).unwrap();
+        assert_eq!(references.len(), 2);
+        assert_eq!(
+            references
+                .get("example:kv")
+                .map(|reference| reference.repository()),
+            Some("kv")
+        );
+        let bindings = portable_capability_bindings(&pod).unwrap();
+        assert_eq!(
+            bindings.get("example:queue"),
+            Some(&"portable-default-test".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_portable_capabilities_without_annotation() {
+        let pod = Pod::from(
+            serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                "metadata": { "name": "test" },
+                "spec": { "containers": [] }
+            }))
+            .unwrap(),
+        );
+        assert!(portable_capability_references(&pod).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pod_env_overrides_default_env() {
+        let mut default_env = EnvVars::new();
+        default_env.insert("CLUSTER_NAME".to_owned(), "production".to_owned());
+        default_env.insert("NODE_NAME".to_owned(), "node-1".to_owned());
+        let mut env = EnvVars::new();
+        env.insert("CLUSTER_NAME".to_owned(), "staging".to_owned());
+        env.insert("PORT".to_owned(), "8080".to_owned());
+
+        let merged = merge_env(default_env, env);
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged.get("CLUSTER_NAME"), Some(&"staging".to_owned()));
+        assert_eq!(merged.get("NODE_NAME"), Some(&"node-1".to_owned()));
+        assert_eq!(merged.get("PORT"), Some(&"8080".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_prepare_directory() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let path = data_dir.path().join("nested").join(LOG_DIR_NAME);
+        prepare_directory(&path, "actor log").await.unwrap();
+        assert!(path.is_dir());
+        assert!(!path.join(WRITE_PROBE_FILE_NAME).exists());
+
+        // A directory cannot be created below a file
+        let file = data_dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let err = prepare_directory(&file.join("logs"), "actor log")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("actor log directory"));
+        assert!(matches!(err, WasccError::DirectoryNotCreated { .. }));
+    }
+
+    #[test]
+    fn test_volumes_get_their_own_root() {
+        let volumes = vec![
+            VolumeBinding {
+                name: "data".to_owned(),
+                host_path: PathBuf::from("/volumes/data"),
+                read_only: false,
+            },
+            VolumeBinding {
+                name: "cache".to_owned(),
+                host_path: PathBuf::from("/volumes/cache"),
+                read_only: true,
+            },
+        ];
+        let mut env = EnvVars::new();
+        env.insert("GREETING".to_owned(), "hello".to_owned());
+
+        let capabilities = volume_capabilities(&volumes, &env);
+        assert_eq!(capabilities.len(), 2);
+        for (capability, (binding, root)) in capabilities
+            .iter()
+            .zip(&[("data", "/volumes/data"), ("cache", "/volumes/cache")])
+        {
+            assert_eq!(capability.name, FS_CAPABILITY);
+            assert_eq!(capability.binding.as_deref(), Some(*binding));
+            assert_eq!(
+                capability.env.get(FS_CONFIG_ROOTDIR),
+                Some(&root.to_string())
+            );
+            assert_eq!(capability.env.get("GREETING"), Some(&"hello".to_owned()));
+        }
+    }
+
+    #[test]
+    fn test_configured_log_file() {
+        let pod_with_log_file = |log_file: &str| {
+            Pod::from(
+                serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                    "metadata": {
+                        "name": "web",
+                        "namespace": "shop",
+                        "annotations": { "wascc.dev/log-file": log_file }
+                    },
+                    "spec": { "containers": [] }
+                }))
+                .unwrap(),
+            )
+        };
+        let log_path = Path::new("/var/log/wascc");
+
+        assert_eq!(
+            configured_log_file(
+                &pod_with_log_file("{namespace}/{pod}/{container}.log"),
+                "greeter",
+                log_path
+            )
+            .unwrap(),
+            Some(PathBuf::from("/var/log/wascc/shop/web/greeter.log"))
+        );
+        assert!(
+            configured_log_file(&pod_with_log_file("../{pod}.log"), "greeter", log_path).is_err()
+        );
+        assert!(
+            configured_log_file(&pod_with_log_file("/tmp/{pod}.log"), "greeter", log_path).is_err()
+        );
+        assert!(configured_log_file(&pod_with_log_file("."), "greeter", log_path).is_err());
+    }
+
+    #[test]
+    fn test_pod_architecture_from_selector_and_affinity() {
+        let pod_with_spec = |spec: serde_json::Value| {
+            Pod::from(
+                serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                    "metadata": { "name": "test" },
+                    "spec": spec
+                }))
+                .unwrap(),
+            )
+        };
+        let affinity = |operator: &str, values: serde_json::Value| {
+            serde_json::json!({
+                "containers": [],
+                "affinity": { "nodeAffinity": { "requiredDuringSchedulingIgnoredDuringExecution": {
+                    "nodeSelectorTerms": [{ "matchExpressions": [{
+                        "key": "kubernetes.io/arch", "operator": operator, "values": values
+                    }] }]
+                } } }
+            })
+        };
+
+        let pod = pod_with_spec(serde_json::json!({ "containers": [] }));
+        assert!(pod.runs_on_architecture(TARGET_WASM32_WASCC));
+        let pod = pod_with_spec(serde_json::json!({
+            "containers": [],
+            "nodeSelector": { "kubernetes.io/arch": TARGET_WASM32_WASCC }
+        }));
+        assert!(pod.runs_on_architecture(TARGET_WASM32_WASCC));
+        let pod = pod_with_spec(serde_json::json!({
+            "containers": [],
+            "nodeSelector": { "beta.kubernetes.io/arch": "amd64" }
+        }));
+        assert!(!pod.runs_on_architecture(TARGET_WASM32_WASCC));
+
+        let pod = pod_with_spec(affinity("In", serde_json::json!(["amd64", "wasm32-wascc"])));
+        assert!(pod.runs_on_architecture(TARGET_WASM32_WASCC));
+        let pod = pod_with_spec(affinity("In", serde_json::json!(["amd64"])));
+        assert!(!pod.runs_on_architecture(TARGET_WASM32_WASCC));
+        let pod = pod_with_spec(affinity("NotIn", serde_json::json!(["wasm32-wascc"])));
+        assert!(!pod.runs_on_architecture(TARGET_WASM32_WASCC));
+    }
+
+    #[test]
+    fn test_unsigned_module_is_rejected() {
+        let err = validate_actor(&module_with_memory(1, None), None, &HashMap::new()).unwrap_err();
+        assert!(err.to_string().contains("not signed"));
+    }
+
+    #[test]
+    fn test_host_port_conflicts() {
+        let pod = Pod::from(
+            serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                "metadata": { "name": "test", "namespace": "default" },
+                "spec": {
+                    "containers": [
+                        { "name": "a", "ports": [{ "containerPort": 80, "hostPort": 30080 }] },
+                        { "name": "b", "ports": [{ "containerPort": 80 }] }
+                    ]
+                }
+            }))
+            .unwrap(),
+        );
+        let mut port_map = BTreeMap::new();
+        assert!(check_host_ports(&pod, &port_map).is_ok());
+
+        port_map.insert(30080, PodKey::new("default", "other"));
+        assert!(check_host_ports(&pod, &port_map).is_err());
+    }
+
+    fn pod_with_host_port_annotation(value: &str, containers: &[&str]) -> Pod {
+        let containers: Vec<_> = containers
+            .iter()
+            .map(|name| serde_json::json!({ "name": name }))
+            .collect();
+        Pod::from(
+            serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                "metadata": {
+                    "name": "test",
+                    "namespace": "default",
+                    "annotations": { HOST_PORT_ANNOTATION: value }
+                },
+                "spec": { "containers": containers }
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_pinned_host_ports_from_annotation() {
+        let pod = pod_with_host_port_annotation("8443", &["web"]);
+        let ports = pinned_host_ports(&pod).unwrap();
+        assert_eq!(ports.get("web"), Some(&8443));
+        let mut port_map = BTreeMap::new();
+        assert!(check_host_ports(&pod, &port_map).is_ok());
+        port_map.insert(8443, PodKey::new("default", "other"));
+        assert!(check_host_ports(&pod, &port_map).is_err());
+
+        let pod = pod_with_host_port_annotation("web=8443, admin=9443", &["web", "admin"]);
+        let ports = pinned_host_ports(&pod).unwrap();
+        assert_eq!(ports.get("web"), Some(&8443));
+        assert_eq!(ports.get("admin"), Some(&9443));
+
+        for (value, containers) in &[
+            ("8443", &["web", "admin"][..]),
+            ("api=8443", &["web"][..]),
+            ("web=https", &["web"][..]),
+            ("web=0", &["web"][..]),
+            ("web=8443,admin=8443", &["web", "admin"][..]),
+        ] {
+            let pod = pod_with_host_port_annotation(value, containers);
+            assert!(
+                matches!(
+                    pinned_host_ports(&pod),
+                    Err(WasccError::InvalidHostPortAnnotation { .. })
+                ),
+                "{} was accepted",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_initial_memory_exceeds_limit() {
+        let module = module_with_memory(3, None);
+        let err = check_memory_limit(&module, 2 * WASM_PAGE_SIZE).unwrap_err();
+        assert!(err.to_string().contains("exceeds its memory limit"));
+        match err {
+            WasccError::MemoryLimitExceeded { required, limit } => {
+                assert_eq!(required, 3 * WASM_PAGE_SIZE);
+                assert_eq!(limit, 2 * WASM_PAGE_SIZE);
+            }
+            other => panic!("expected the memory limit to be exceeded, got {:?}", other),
+        }
+    }
+}
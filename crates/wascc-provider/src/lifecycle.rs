@@ -0,0 +1,281 @@
+//! Execution of the lifecycle hooks of containers, i.e. `postStart` hooks after their actors
+//! started and `preStop` hooks before they are stopped.
+//!
+//! Actors have no process to run commands in, so only `httpGet` hooks are supported. They are
+//! sent to the port assigned to the actor, regardless of the port the hook names, as that is the
+//! port the actor listens on.
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use k8s_openapi::api::core::v1::{HTTPGetAction, Handler};
+use kubelet::container::Container;
+use kubelet::pod::{Handle, Pod};
+use log::{info, warn};
+
+use crate::states::starting::wait_for_port;
+use crate::{ActorHandle, LogHandleFactory, HTTP_BIND_ADDRESS};
+
+/// How long a `postStart` hook may take, including the time the actor needs to listen on its
+/// port. Kubernetes does not limit it, but the state machine of the pod waits for the hook, so
+/// that a hook which hangs would keep the pod from being stopped.
+pub(crate) const POST_START_TIMEOUT: Duration = Duration::from_secs(120);
+
+fn post_start(container: &Container) -> Option<&Handler> {
+    container.lifecycle()?.post_start.as_ref()
+}
+
+fn pre_stop(container: &Container) -> Option<&Handler> {
+    container.lifecycle()?.pre_stop.as_ref()
+}
+
+/// Runs the `postStart` hook of the container, if it has one, as soon as its actor listens on
+/// the port of the bind address.
+pub(crate) async fn run_post_start_hook(
+    container: &Container,
+    bind_address: IpAddr,
+    port: u16,
+) -> Result<(), String> {
+    let handler = match post_start(container) {
+        Some(handler) => handler,
+        None => return Ok(()),
+    };
+    let deadline = Instant::now() + POST_START_TIMEOUT;
+    if !wait_for_port(bind_address, port, POST_START_TIMEOUT).await {
+        return Err(format!(
+            "the actor did not listen on port {} within {:?}",
+            port, POST_START_TIMEOUT
+        ));
+    }
+    info!("Running postStart hook of container {}", container.name());
+    run_hook(
+        handler,
+        bind_address,
+        port,
+        deadline.saturating_duration_since(Instant::now()),
+    )
+    .await
+}
+
+/// Returns the ports assigned to the actors of the pod by container name, so that the `preStop`
+/// hooks can be run without holding on to the handle of the pod.
+pub(crate) async fn actor_ports(
+    pod: &Pod,
+    handle: &Handle<ActorHandle, LogHandleFactory>,
+) -> HashMap<String, u16> {
+    let mut ports = HashMap::new();
+    for container in pod.containers() {
+        if let Ok(port) = handle
+            .map_container_handle(container.name(), |actor| actor.port)
+            .await
+        {
+            ports.insert(container.name().to_owned(), port);
+        }
+    }
+    ports
+}
+
+/// Runs the `preStop` hooks of the containers of the pod one after the other, until the grace
+/// period of the pod is used up. A hook which fails or does not finish in time is only logged,
+/// the actor is stopped anyway.
+pub(crate) async fn run_pre_stop_hooks(pod: &Pod, ports: &HashMap<String, u16>) {
+    let containers: Vec<Container> = pod
+        .containers()
+        .into_iter()
+        .filter(|container| pre_stop(container).is_some())
+        .collect();
+    if containers.is_empty() {
+        return;
+    }
+
+    let deadline = Instant::now() + pod.termination_grace_period();
+    for container in containers.iter() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let result = match (pre_stop(container), ports.get(container.name())) {
+            (Some(handler), Some(port)) => {
+                run_hook(handler, HTTP_BIND_ADDRESS, *port, remaining).await
+            }
+            (_, None) => Err("the actor is unknown".to_owned()),
+            (None, _) => Ok(()),
+        };
+        match result {
+            Ok(()) => info!(
+                "preStop hook of container {} in pod {} succeeded",
+                container.name(),
+                pod.name()
+            ),
+            Err(e) => warn!(
+                "preStop hook of container {} in pod {} failed, stopping the actor anyway: {}",
+                container.name(),
+                pod.name(),
+                e
+            ),
+        }
+    }
+}
+
+/// Runs the hook against the actor, which listens on the port of the bind address.
+async fn run_hook(
+    handler: &Handler,
+    bind_address: IpAddr,
+    port: u16,
+    timeout: Duration,
+) -> Result<(), String> {
+    match (&handler.http_get, &handler.exec) {
+        (Some(http_get), _) => {
+            let url = hook_url(http_get, bind_address, port)?;
+            send(http_get, &url, timeout).await
+        }
+        (None, Some(_)) => Err("exec hooks are not supported for actors".to_owned()),
+        (None, None) => Err("only httpGet hooks are supported for actors".to_owned()),
+    }
+}
+
+/// Returns the URL the hook is sent to. Actors which listen on all interfaces are reached via
+/// the loopback interface, unless the hook names a host.
+fn hook_url(http_get: &HTTPGetAction, bind_address: IpAddr, port: u16) -> Result<String, String> {
+    if let Some(scheme) = http_get.scheme.as_deref() {
+        if !scheme.eq_ignore_ascii_case("HTTP") {
+            return Err(format!("scheme {} is not supported, only HTTP is", scheme));
+        }
+    }
+    let address = match http_get.host.as_deref().filter(|host| !host.is_empty()) {
+        Some(host) => match host.parse::<IpAddr>() {
+            Ok(address) => SocketAddr::new(address, port).to_string(),
+            Err(_) => format!("{}:{}", host, port),
+        },
+        None => SocketAddr::new(
+            match bind_address {
+                IpAddr::V4(address) if address.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(address) if address.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                address => address,
+            },
+            port,
+        )
+        .to_string(),
+    };
+    let path = http_get.path.as_deref().unwrap_or("/");
+    let separator = if path.starts_with('/') { "" } else { "/" };
+    Ok(format!("http://{}{}{}", address, separator, path))
+}
+
+/// Sends the GET request of the hook, which has to return a status below 400 within the
+/// timeout.
+async fn send(http_get: &HTTPGetAction, url: &str, timeout: Duration) -> Result<(), String> {
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(|e| format!("unable to create HTTP client: {}", e))?;
+    let mut request = client.get(url);
+    for header in http_get.http_headers.iter().flatten() {
+        request = request.header(header.name.as_str(), header.value.as_str());
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("GET {} failed: {}", url, e))?;
+    if response.status().as_u16() < 400 {
+        Ok(())
+    } else {
+        Err(format!("GET {} returned {}", url, response.status()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn http_get(path: &str) -> HTTPGetAction {
+        serde_json::from_value(serde_json::json!({ "path": path, "port": 8080 })).unwrap()
+    }
+
+    fn container(lifecycle: serde_json::Value) -> Container {
+        Container::new(
+            &serde_json::from_value(serde_json::json!({
+                "name": "greeter",
+                "lifecycle": lifecycle,
+            }))
+            .unwrap(),
+        )
+    }
+
+    /// Answers a single request with the status line and returns the request it got.
+    /// Connections which send nothing, e.g. those checking whether the port is open, are skipped.
+    fn serve_once(listener: TcpListener, status: &'static str) -> std::thread::JoinHandle<String> {
+        std::thread::spawn(move || loop {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let read = stream.read(&mut request).unwrap_or(0);
+            if read == 0 {
+                continue;
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            return String::from_utf8_lossy(&request[..read]).into_owned();
+        })
+    }
+
+    #[test]
+    fn test_hooks_are_sent_to_the_assigned_port() {
+        let unspecified = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        assert_eq!(
+            hook_url(&http_get("/shutdown"), unspecified, 30001).unwrap(),
+            "http://127.0.0.1:30001/shutdown"
+        );
+        assert_eq!(
+            hook_url(&http_get("drain"), "10.0.0.1".parse().unwrap(), 30001).unwrap(),
+            "http://10.0.0.1:30001/drain"
+        );
+        let mut https = http_get("/");
+        https.scheme = Some("HTTPS".to_owned());
+        assert!(hook_url(&https, unspecified, 30001).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hook_has_to_succeed() {
+        let timeout = Duration::from_secs(5);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/shutdown", listener.local_addr().unwrap());
+        let server = serve_once(listener, "200 OK");
+        assert_eq!(send(&http_get("/shutdown"), &url, timeout).await, Ok(()));
+        assert!(server.join().unwrap().starts_with("GET /shutdown "));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/shutdown", listener.local_addr().unwrap());
+        let server = serve_once(listener, "503 Service Unavailable");
+        assert!(send(&http_get("/shutdown"), &url, timeout).await.is_err());
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_post_start_hook_is_sent_once_the_actor_listens() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = serve_once(listener, "200 OK");
+        let http_get = container(serde_json::json!({
+            "postStart": { "httpGet": { "path": "/warmup", "port": 8080 } },
+        }));
+        assert_eq!(
+            run_post_start_hook(&http_get, IpAddr::V4(Ipv4Addr::UNSPECIFIED), port).await,
+            Ok(())
+        );
+        assert!(server.join().unwrap().starts_with("GET /warmup "));
+
+        let exec = container(serde_json::json!({
+            "postStart": { "exec": { "command": ["/bin/true"] } },
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(
+            run_post_start_hook(&exec, IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Removal of actor log files which are no longer used by any pod.
+//!
+//! Every container logs into a temporary file in the log directory, which is removed when its
+//! handle is dropped. If the kubelet does not shut down cleanly, these files are left behind.
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use kubelet::pod::{Handle, PodKey};
+use log::{info, warn};
+use tokio::sync::RwLock;
+
+use crate::{ActorHandle, LogHandleFactory};
+
+/// How often the log directory is swept for orphaned log files, unless configured otherwise
+/// via [`crate::WasccProvider::with_log_sweep_interval`].
+pub const DEFAULT_LOG_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The prefix of the names of the temporary files actors log into.
+const LOG_FILE_PREFIX: &str = ".tmp";
+
+/// How long a log file has to be left untouched before a periodic sweep removes it. A pod only
+/// adds its handles once all of its containers were started, so the log files of a starting pod
+/// are not yet known when the sweep runs.
+const ORPHAN_MIN_AGE: Duration = Duration::from_secs(10 * 60);
+
+type HandleMap = RwLock<BTreeMap<PodKey, Handle<ActorHandle, LogHandleFactory>>>;
+
+/// Returns the log files of all pods that currently have handles.
+async fn active_log_files(handles: &HandleMap) -> HashSet<PathBuf> {
+    let handles = handles.read().await;
+    let mut active = HashSet::new();
+    for handle in handles.values() {
+        active.extend(
+            handle
+                .map_handle_factories(|factory| factory.path().to_path_buf())
+                .await,
+        );
+    }
+    active
+}
+
+/// Removes all log files in `log_path` which are not in `active` and have not been modified
+/// for at least `min_age`.
+pub(crate) async fn remove_orphans(log_path: &Path, active: &HashSet<PathBuf>, min_age: Duration) {
+    let mut entries = match tokio::fs::read_dir(log_path).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!(
+                "Unable to read log directory {}: {:?}",
+                log_path.display(),
+                e
+            );
+            return;
+        }
+    };
+    loop {
+        let entry = match entries.next_entry().await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return,
+            Err(e) => {
+                warn!(
+                    "Unable to read log directory {}: {:?}",
+                    log_path.display(),
+                    e
+                );
+                return;
+            }
+        };
+        let path = entry.path();
+        let is_log_file = entry
+            .file_name()
+            .to_str()
+            .map_or(false, |name| name.starts_with(LOG_FILE_PREFIX));
+        if !is_log_file || active.contains(&path) || !is_older_than(&entry, min_age).await {
+            continue;
+        }
+        info!("Removing orphaned log file {}", path.display());
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            warn!("Unable to remove log file {}: {:?}", path.display(), e);
+        }
+    }
+}
+
+async fn is_older_than(entry: &tokio::fs::DirEntry, min_age: Duration) -> bool {
+    let modified = match entry.metadata().await.and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return false,
+    };
+    SystemTime::now()
+        .duration_since(modified)
+        .map_or(false, |age| age >= min_age)
+}
+
+/// Periodically removes the log files which do not belong to any pod with a handle.
+pub(crate) async fn run(
+    handles: Arc<HandleMap>,
+    log_path: PathBuf,
+    interval: Arc<Mutex<Duration>>,
+) {
+    loop {
+        let delay = *interval.lock().unwrap();
+        tokio::time::delay_for(delay).await;
+        let active = active_log_files(&handles).await;
+        remove_orphans(&log_path, &active, ORPHAN_MIN_AGE).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_remove_orphans_keeps_active_and_foreign_files() {
+        let log_path = tempfile::tempdir().unwrap();
+        let orphan = log_path.path().join(".tmpOrphan");
+        let active = log_path.path().join(".tmpActive");
+        let foreign = log_path.path().join("other.log");
+        for path in &[&orphan, &active, &foreign] {
+            std::fs::write(path, "").unwrap();
+        }
+        let active_files: HashSet<PathBuf> = vec![active.clone()].into_iter().collect();
+
+        remove_orphans(log_path.path(), &active_files, Duration::from_secs(0)).await;
+
+        assert!(!orphan.exists());
+        assert!(active.exists());
+        assert!(foreign.exists());
+    }
+
+    #[tokio::test]
+    async fn test_remove_orphans_spares_recent_files() {
+        let log_path = tempfile::tempdir().unwrap();
+        let recent = log_path.path().join(".tmpRecent");
+        std::fs::write(&recent, "").unwrap();
+
+        remove_orphans(log_path.path(), &HashSet::new(), ORPHAN_MIN_AGE).await;
+
+        assert!(recent.exists());
+    }
+}
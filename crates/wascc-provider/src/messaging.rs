@@ -0,0 +1,498 @@
+//! A Messaging capability which lets the actors of a pod message each other.
+//!
+//! Actors bound to it are not connected to an external message broker such as NATS. Instead the
+//! provider delivers the messages an actor publishes to the actors which subscribed to their
+//! subject. Every binding carries the subject prefix of the pod of the actor in
+//! [`SUBJECT_PREFIX_KEY`] and the subjects the actor subscribes to in [`SUBSCRIPTION_KEY`].
+//!
+//! # Isolation
+//!
+//! Pods are isolated by the provider itself: messages, requests and replies are only delivered
+//! to actors whose prefix equals the prefix of the sending actor. The prefix of a subject is not
+//! used to look up the pod, so an actor cannot reach the actors of another pod by publishing to
+//! a subject starting with their prefix, and nothing is sent beyond the host. Actors may use
+//! subjects with or without the prefix of their own pod, it is removed before subjects are
+//! matched.
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use log::warn;
+use wascc_codec::capabilities::{
+    CapabilityDescriptor, CapabilityProvider, Dispatcher, NullDispatcher, OperationDirection,
+    OP_GET_CAPABILITY_DESCRIPTOR,
+};
+use wascc_codec::core::{CapabilityConfiguration, OP_BIND_ACTOR, OP_REMOVE_ACTOR};
+use wascc_codec::messaging::{
+    BrokerMessage, RequestMessage, OP_DELIVER_MESSAGE, OP_PERFORM_REQUEST, OP_PUBLISH_MESSAGE,
+};
+use wascc_codec::{deserialize, serialize};
+
+/// The configuration key holding the subject prefix of the pod an actor belongs to.
+pub(crate) const SUBJECT_PREFIX_KEY: &str = "MESSAGING_SUBJECT_PREFIX";
+
+/// The configuration key holding the comma separated subjects an actor subscribes to. Subjects
+/// may contain the NATS wildcards `*`, matching a single token, and `>`, matching the rest.
+pub(crate) const SUBSCRIPTION_KEY: &str = "SUBSCRIPTION";
+
+/// Origin of messages coming from wascc host
+const SYSTEM_ACTOR: &str = "system";
+
+/// The prefix of the subjects the replies to requests are published to.
+const INBOX_PREFIX: &str = "_INBOX.";
+
+/// How long a request waits for a reply if it sets no timeout.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many threads deliver messages to actors at the same time.
+const DELIVERY_WORKERS: usize = 4;
+
+/// How many messages may wait for delivery before further messages are dropped.
+const DELIVERY_QUEUE_SIZE: usize = 1024;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A message waiting to be delivered to an actor.
+struct Delivery {
+    actor: String,
+    message: Vec<u8>,
+}
+
+/// An actor bound to the capability.
+struct Subscriber {
+    prefix: String,
+    subscriptions: Vec<String>,
+}
+
+/// The Messaging capability shared by the actors of all pods.
+pub(crate) struct PodMessagingProvider {
+    dispatcher: Arc<RwLock<Box<dyn Dispatcher>>>,
+    subscribers: RwLock<HashMap<String, Subscriber>>,
+    /// Requests waiting for a reply, keyed by the prefix of the pod and the reply subject
+    pending_replies: Mutex<HashMap<(String, String), Sender<Vec<u8>>>>,
+    next_inbox: AtomicU64,
+    /// Messages waiting for one of the delivery workers
+    deliveries: Mutex<SyncSender<Delivery>>,
+}
+
+impl PodMessagingProvider {
+    pub(crate) fn new() -> Self {
+        let dispatcher: Arc<RwLock<Box<dyn Dispatcher>>> =
+            Arc::new(RwLock::new(Box::new(NullDispatcher::new())));
+        let (deliveries, receiver) = sync_channel(DELIVERY_QUEUE_SIZE);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for worker in 0..DELIVERY_WORKERS {
+            let dispatcher = Arc::clone(&dispatcher);
+            let receiver = Arc::clone(&receiver);
+            let spawned = std::thread::Builder::new()
+                .name(format!("pod-messaging-{}", worker))
+                .spawn(move || deliver_messages(&dispatcher, &receiver));
+            if let Err(e) = spawned {
+                warn!("Unable to start messaging delivery worker: {}", e);
+            }
+        }
+        PodMessagingProvider {
+            dispatcher,
+            subscribers: RwLock::new(HashMap::new()),
+            pending_replies: Mutex::new(HashMap::new()),
+            next_inbox: AtomicU64::new(0),
+            deliveries: Mutex::new(deliveries),
+        }
+    }
+
+    fn bind(
+        &self,
+        config: CapabilityConfiguration,
+    ) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+        let prefix = config
+            .values
+            .get(SUBJECT_PREFIX_KEY)
+            .ok_or("subject prefix of the pod was unspecified")?
+            .clone();
+        let subscriptions = config
+            .values
+            .get(SUBSCRIPTION_KEY)
+            .map(|subjects| {
+                subjects
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|subject| !subject.is_empty())
+                    .map(|subject| relative_subject(&prefix, subject).to_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.subscribers.write().unwrap().insert(
+            config.module,
+            Subscriber {
+                prefix,
+                subscriptions,
+            },
+        );
+        Ok(vec![])
+    }
+
+    fn prefix_of(&self, actor: &str) -> Result<String, Box<dyn Error + Sync + Send>> {
+        self.subscribers
+            .read()
+            .unwrap()
+            .get(actor)
+            .map(|subscriber| subscriber.prefix.clone())
+            .ok_or_else(|| {
+                format!("Actor {} is not bound to the messaging capability", actor).into()
+            })
+    }
+
+    /// Returns the actors of the pod which subscribed to the subject, except for the given one.
+    fn recipients(&self, prefix: &str, subject: &str, except: Option<&str>) -> Vec<String> {
+        self.subscribers
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(actor, subscriber)| {
+                Some(actor.as_str()) != except
+                    && subscriber.prefix == prefix
+                    && subscriber
+                        .subscriptions
+                        .iter()
+                        .any(|pattern| subject_matches(pattern, subject))
+            })
+            .map(|(actor, _)| actor.clone())
+            .collect()
+    }
+
+    /// Queues the message for one of the delivery workers rather than delivering it on the
+    /// thread of the sender, as the actor may be busy handling a message of its own or even
+    /// waiting for the sender. Messages which do not fit into the queue are dropped, as NATS
+    /// does with the messages of slow consumers.
+    fn deliver(&self, actor: String, message: Vec<u8>) {
+        let queued = self
+            .deliveries
+            .lock()
+            .unwrap()
+            .try_send(Delivery { actor, message });
+        match queued {
+            Ok(()) => (),
+            Err(TrySendError::Full(delivery)) => warn!(
+                "Dropping message to actor {}, {} messages are waiting for delivery already",
+                delivery.actor, DELIVERY_QUEUE_SIZE
+            ),
+            Err(TrySendError::Disconnected(delivery)) => warn!(
+                "Dropping message to actor {}, no delivery worker is running",
+                delivery.actor
+            ),
+        }
+    }
+
+    fn publish(
+        &self,
+        actor: &str,
+        message: BrokerMessage,
+    ) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+        let prefix = self.prefix_of(actor)?;
+        let subject = relative_subject(&prefix, &message.subject).to_owned();
+        if subject.starts_with(INBOX_PREFIX) {
+            // The requester may have given up waiting already
+            if let Some(reply) = self
+                .pending_replies
+                .lock()
+                .unwrap()
+                .remove(&(prefix, subject))
+            {
+                let _ = reply.send(message.body);
+            }
+            return Ok(vec![]);
+        }
+        let recipients = self.recipients(&prefix, &subject, None);
+        let message = serialize(BrokerMessage {
+            reply_to: relative_subject(&prefix, &message.reply_to).to_owned(),
+            subject,
+            body: message.body,
+        })?;
+        for recipient in recipients {
+            self.deliver(recipient, message.clone());
+        }
+        Ok(vec![])
+    }
+
+    /// Delivers the request to the subscribed actors of the pod and returns the first reply.
+    /// The requesting actor itself is left out, as it cannot handle a message while it waits.
+    fn request(
+        &self,
+        actor: &str,
+        request: RequestMessage,
+    ) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+        let prefix = self.prefix_of(actor)?;
+        let subject = relative_subject(&prefix, &request.subject).to_owned();
+        let recipients = self.recipients(&prefix, &subject, Some(actor));
+        if recipients.is_empty() {
+            return Err(format!("No actor of the pod subscribed to {}", subject).into());
+        }
+
+        let reply_to = format!(
+            "{}{}",
+            INBOX_PREFIX,
+            self.next_inbox.fetch_add(1, Ordering::Relaxed)
+        );
+        let (sender, receiver) = channel();
+        self.pending_replies
+            .lock()
+            .unwrap()
+            .insert((prefix.clone(), reply_to.clone()), sender);
+        let message = serialize(BrokerMessage {
+            subject: subject.clone(),
+            reply_to: reply_to.clone(),
+            body: request.body,
+        })?;
+        for recipient in recipients {
+            self.deliver(recipient, message.clone());
+        }
+
+        let timeout = if request.timeout_ms > 0 {
+            Duration::from_millis(request.timeout_ms as u64)
+        } else {
+            DEFAULT_REQUEST_TIMEOUT
+        };
+        let reply = receiver.recv_timeout(timeout);
+        self.pending_replies
+            .lock()
+            .unwrap()
+            .remove(&(prefix, reply_to));
+        reply.map_err(|_| format!("No reply to request on {} within {:?}", subject, timeout).into())
+    }
+
+    fn get_descriptor(&self) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+        Ok(serialize(
+            CapabilityDescriptor::builder()
+                .id(crate::MESSAGING_CAPABILITY)
+                .name("krustlet Pod Messaging Provider")
+                .long_description("A waSCC messaging capability provider for the actors of a pod")
+                .version(VERSION)
+                .revision(1)
+                .with_operation(
+                    OP_PUBLISH_MESSAGE,
+                    OperationDirection::ToProvider,
+                    "Publish a message to the actors of the pod",
+                )
+                .with_operation(
+                    OP_PERFORM_REQUEST,
+                    OperationDirection::ToProvider,
+                    "Send a request to the actors of the pod and wait for a reply",
+                )
+                .with_operation(
+                    OP_DELIVER_MESSAGE,
+                    OperationDirection::ToActor,
+                    "Deliver a message to a subscribed actor",
+                )
+                .build(),
+        )?)
+    }
+}
+
+impl CapabilityProvider for PodMessagingProvider {
+    fn configure_dispatch(
+        &self,
+        dispatcher: Box<dyn Dispatcher>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        *self.dispatcher.write().unwrap() = dispatcher;
+        Ok(())
+    }
+
+    fn handle_call(
+        &self,
+        actor: &str,
+        op: &str,
+        msg: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        match (op, actor) {
+            (OP_BIND_ACTOR, SYSTEM_ACTOR) => self.bind(deserialize(msg)?),
+            (OP_REMOVE_ACTOR, SYSTEM_ACTOR) => {
+                let config = deserialize::<CapabilityConfiguration>(msg)?;
+                self.subscribers.write().unwrap().remove(&config.module);
+                Ok(vec![])
+            }
+            (OP_GET_CAPABILITY_DESCRIPTOR, SYSTEM_ACTOR) => self.get_descriptor(),
+            (OP_PUBLISH_MESSAGE, _) => self.publish(actor, deserialize(msg)?),
+            (OP_PERFORM_REQUEST, _) => self.request(actor, deserialize(msg)?),
+            _ => Err(format!("Unsupported operation {}", op).into()),
+        }
+    }
+}
+
+/// Delivers queued messages until the provider is dropped.
+fn deliver_messages(
+    dispatcher: &RwLock<Box<dyn Dispatcher>>,
+    receiver: &Mutex<Receiver<Delivery>>,
+) {
+    loop {
+        // The lock is released before delivering, so that the other workers can go on meanwhile
+        let delivery = match receiver.lock().unwrap().recv() {
+            Ok(delivery) => delivery,
+            Err(_) => return,
+        };
+        if let Err(e) = dispatcher.read().unwrap().dispatch(
+            &delivery.actor,
+            OP_DELIVER_MESSAGE,
+            &delivery.message,
+        ) {
+            warn!(
+                "Unable to deliver message to actor {}: {}",
+                delivery.actor, e
+            );
+        }
+    }
+}
+
+/// Removes the prefix of the pod from the subject, if it starts with it.
+fn relative_subject<'a>(prefix: &str, subject: &'a str) -> &'a str {
+    subject
+        .strip_prefix(prefix)
+        .and_then(|rest| rest.strip_prefix('.'))
+        .unwrap_or(subject)
+}
+
+/// Matches the subject against a subscription in the way NATS does.
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut subject_tokens = subject.split('.');
+    for token in pattern.split('.') {
+        match (token, subject_tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (token, Some(subject_token)) if token == subject_token => {}
+            _ => return false,
+        }
+    }
+    subject_tokens.next().is_none()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Passes every delivered message on to the test.
+    struct RecordingDispatcher {
+        delivered: Mutex<Sender<(String, BrokerMessage)>>,
+    }
+
+    impl Dispatcher for RecordingDispatcher {
+        fn dispatch(
+            &self,
+            actor: &str,
+            _op: &str,
+            msg: &[u8],
+        ) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+            let message = deserialize(msg)?;
+            self.delivered
+                .lock()
+                .unwrap()
+                .send((actor.to_owned(), message))?;
+            Ok(vec![])
+        }
+    }
+
+    fn provider() -> (Arc<PodMessagingProvider>, Receiver<(String, BrokerMessage)>) {
+        let provider = PodMessagingProvider::new();
+        let (sender, receiver) = channel();
+        provider
+            .configure_dispatch(Box::new(RecordingDispatcher {
+                delivered: Mutex::new(sender),
+            }))
+            .unwrap();
+        (Arc::new(provider), receiver)
+    }
+
+    fn bind(provider: &PodMessagingProvider, actor: &str, prefix: &str, subscription: &str) {
+        let mut values = HashMap::new();
+        values.insert(SUBJECT_PREFIX_KEY.to_owned(), prefix.to_owned());
+        values.insert(SUBSCRIPTION_KEY.to_owned(), subscription.to_owned());
+        let config = CapabilityConfiguration {
+            module: actor.to_owned(),
+            values,
+        };
+        provider
+            .handle_call(SYSTEM_ACTOR, OP_BIND_ACTOR, &serialize(config).unwrap())
+            .unwrap();
+    }
+
+    fn publish(provider: &PodMessagingProvider, actor: &str, subject: &str, body: &[u8]) {
+        let message = BrokerMessage {
+            subject: subject.to_owned(),
+            reply_to: String::new(),
+            body: body.to_vec(),
+        };
+        provider
+            .handle_call(actor, OP_PUBLISH_MESSAGE, &serialize(message).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_subject_matches() {
+        assert!(subject_matches("orders.new", "orders.new"));
+        assert!(subject_matches("orders.*", "orders.new"));
+        assert!(subject_matches("orders.>", "orders.new.eu"));
+        assert!(!subject_matches("orders.*", "orders.new.eu"));
+        assert!(!subject_matches("orders.>", "orders"));
+        assert!(!subject_matches("orders.new", "orders"));
+    }
+
+    #[test]
+    fn test_messages_stay_within_the_pod() {
+        let (provider, delivered) = provider();
+        bind(&provider, "subscriber", "default.pod-a", "orders.*");
+        bind(&provider, "publisher", "default.pod-a", "");
+        bind(
+            &provider,
+            "other-pod",
+            "default.pod-b",
+            "orders.*, default.pod-a.orders.*",
+        );
+
+        publish(&provider, "publisher", "orders.new", b"first");
+        publish(
+            &provider,
+            "publisher",
+            "default.pod-a.orders.new",
+            b"second",
+        );
+
+        let mut bodies = vec![];
+        for _ in 0..2 {
+            let (actor, message) = delivered.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(actor, "subscriber");
+            assert_eq!(message.subject, "orders.new");
+            bodies.push(message.body);
+        }
+        bodies.sort();
+        assert_eq!(bodies, vec![b"first".to_vec(), b"second".to_vec()]);
+        assert!(delivered.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn test_request_returns_reply() {
+        let (provider, delivered) = provider();
+        bind(&provider, "requester", "default.pod-a", "");
+        bind(&provider, "responder", "default.pod-a", "greetings");
+
+        let requesting = Arc::clone(&provider);
+        let request = std::thread::spawn(move || {
+            let request = RequestMessage {
+                subject: "greetings".to_owned(),
+                body: b"hello".to_vec(),
+                timeout_ms: 5000,
+            };
+            requesting.handle_call(
+                "requester",
+                OP_PERFORM_REQUEST,
+                &serialize(request).unwrap(),
+            )
+        });
+        let (actor, message) = delivered.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(actor, "responder");
+        assert_eq!(message.body, b"hello");
+        publish(&provider, "responder", &message.reply_to, b"world");
+
+        assert_eq!(request.join().unwrap().unwrap(), b"world");
+    }
+}
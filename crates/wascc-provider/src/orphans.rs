@@ -0,0 +1,134 @@
+//! Removal of actors which run in the waSCC host without belonging to a pod.
+//!
+//! The actors of a pod are only stopped through its handles, which a pod only gets once all of
+//! its actors were started. Actors of pods which failed to start, or which were missed while the
+//! pod watch was down, would otherwise keep running along with their ports and capabilities.
+use std::collections::HashSet;
+use std::panic::{self, AssertUnwindSafe};
+
+use kubelet::pod::{Pod, PodKey};
+use log::{info, warn};
+
+use crate::{port_map, portable_binding, SharedPodState, FS_CAPABILITY, PORTABLE_BINDING_PREFIX};
+
+/// Removes every actor from the host which does not belong to a pod handle, together with the
+/// volume capabilities no handle uses. The ports and portable capabilities of pods which are
+/// neither in `pods` nor have a handle are released as well.
+pub(crate) async fn remove_orphans(shared: &SharedPodState, pods: &[Pod]) -> anyhow::Result<()> {
+    // Pods add their actors to the host before they add their handles, so none of them may
+    // start while the host is compared with the handles
+    let _host_changes = shared.host_changes.write().await;
+
+    let mut pod_keys: HashSet<PodKey> = pods.iter().map(PodKey::from).collect();
+    let mut actors = HashSet::new();
+    let mut volumes = HashSet::new();
+    {
+        let handles = shared.handles.read().await;
+        for (key, handle) in handles.iter() {
+            pod_keys.insert(key.clone());
+            let container_handles = handle
+                .map_container_handles(|actor| {
+                    let volumes: Vec<String> =
+                        actor.volumes.iter().map(|v| v.name.clone()).collect();
+                    (actor.key.clone(), volumes)
+                })
+                .await;
+            for (actor, actor_volumes) in container_handles {
+                actors.insert(actor);
+                volumes.extend(actor_volumes);
+            }
+        }
+    }
+    let portable_bindings: HashSet<String> = pod_keys.iter().map(portable_binding).collect();
+
+    shared
+        .host
+        .with_host(move |host| {
+            for (actor, _) in host.actors() {
+                if actors.contains(&actor) {
+                    continue;
+                }
+                info!("Removing orphaned actor {}", actor);
+                // The host panics instead of returning an error if the actor is stopping already,
+                // which is caught so that the host stays usable
+                match panic::catch_unwind(AssertUnwindSafe(|| host.remove_actor(&actor))) {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("Unable to remove orphaned actor {}: {}", actor, e),
+                    Err(_) => warn!("Orphaned actor {} is stopping already", actor),
+                }
+            }
+            let capabilities = host.capabilities().into_iter().map(|(key, _)| key);
+            for (binding, capability) in
+                orphaned_capabilities(capabilities, &volumes, &portable_bindings)
+            {
+                info!(
+                    "Removing orphaned capability {} bound as {}",
+                    capability, binding
+                );
+                if let Err(e) = host.remove_native_capability(&capability, Some(binding.clone())) {
+                    warn!(
+                        "Unable to remove orphaned capability {} bound as {}: {}",
+                        capability, binding, e
+                    );
+                }
+            }
+        })
+        .await?;
+
+    let mut ports = shared.port_map.lock().await;
+    let assigned = ports.len();
+    port_map::retain_existing(&mut ports, &pod_keys);
+    if ports.len() != assigned {
+        port_map::persist(&shared.port_map_path, &ports).await;
+    }
+    Ok(())
+}
+
+/// Returns the `(binding, capability ID)` pairs of the capability instances which belong to a
+/// volume or to the portable capabilities of a pod, but are not in use anymore.
+fn orphaned_capabilities(
+    capabilities: impl Iterator<Item = (String, String)>,
+    volumes: &HashSet<String>,
+    portable_bindings: &HashSet<String>,
+) -> Vec<(String, String)> {
+    let mut orphaned: Vec<(String, String)> = capabilities
+        .filter(|(binding, capability)| {
+            if capability == FS_CAPABILITY {
+                !volumes.contains(binding)
+            } else {
+                binding.starts_with(PORTABLE_BINDING_PREFIX) && !portable_bindings.contains(binding)
+            }
+        })
+        .collect();
+    orphaned.sort();
+    orphaned
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_orphaned_capabilities() {
+        let capabilities = vec![
+            ("default".to_owned(), "wascc:http_server".to_owned()),
+            ("data".to_owned(), FS_CAPABILITY.to_owned()),
+            ("gone".to_owned(), FS_CAPABILITY.to_owned()),
+            ("portable-default-foo".to_owned(), "example:kv".to_owned()),
+            ("portable-default-gone".to_owned(), "example:kv".to_owned()),
+        ];
+        let volumes: HashSet<String> = vec!["data".to_owned()].into_iter().collect();
+        let portable_bindings: HashSet<String> =
+            vec![portable_binding(&PodKey::new("default", "foo"))]
+                .into_iter()
+                .collect();
+
+        assert_eq!(
+            orphaned_capabilities(capabilities.into_iter(), &volumes, &portable_bindings),
+            vec![
+                ("gone".to_owned(), FS_CAPABILITY.to_owned()),
+                ("portable-default-gone".to_owned(), "example:kv".to_owned()),
+            ]
+        );
+    }
+}
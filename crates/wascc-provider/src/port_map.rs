@@ -0,0 +1,121 @@
+//! Persistence of the ports assigned to pods, so that they survive a restart of the kubelet.
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, ListParams};
+use kubelet::pod::PodKey;
+use log::{info, warn};
+
+use crate::WasccError;
+
+/// An assigned port as it is stored in the port map file.
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct PortEntry {
+    port: u16,
+    namespace: String,
+    name: String,
+}
+
+/// Reads the port map from the given file. A missing file results in an empty map.
+pub(crate) async fn load(path: &Path) -> Result<BTreeMap<u16, PodKey>, WasccError> {
+    let content = match tokio::fs::read(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let entries: Vec<PortEntry> = serde_json::from_slice(&content)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| (entry.port, PodKey::new(entry.namespace, entry.name)))
+        .collect())
+}
+
+/// Writes the port map to the given file, replacing it atomically.
+pub(crate) async fn save(path: &Path, ports: &BTreeMap<u16, PodKey>) -> anyhow::Result<()> {
+    let entries: Vec<PortEntry> = ports
+        .iter()
+        .map(|(port, key)| PortEntry {
+            port: *port,
+            namespace: key.namespace(),
+            name: key.name(),
+        })
+        .collect();
+    let temp_path = path.with_extension("tmp");
+    tokio::fs::write(&temp_path, serde_json::to_vec(&entries)?).await?;
+    tokio::fs::rename(&temp_path, path).await?;
+    Ok(())
+}
+
+/// Saves the port map, logging instead of failing if that is not possible, as the map in memory
+/// stays correct anyway.
+pub(crate) async fn persist(path: &Path, ports: &BTreeMap<u16, PodKey>) {
+    if let Err(e) = save(path, ports).await {
+        warn!("Unable to persist port map to {}: {:?}", path.display(), e);
+    }
+}
+
+/// Drops the ports of all pods which are no longer scheduled to this node according to the API
+/// server.
+pub(crate) async fn reconcile(
+    client: &kube::Client,
+    node_name: &str,
+    ports: &mut BTreeMap<u16, PodKey>,
+) -> anyhow::Result<()> {
+    let api: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams {
+        field_selector: Some(format!("spec.nodeName={}", node_name)),
+        ..Default::default()
+    };
+    let existing: HashSet<PodKey> = api.list(&params).await?.iter().map(PodKey::from).collect();
+    retain_existing(ports, &existing);
+    Ok(())
+}
+
+/// Drops the ports of all pods which are not in `existing`.
+pub(crate) fn retain_existing(ports: &mut BTreeMap<u16, PodKey>, existing: &HashSet<PodKey>) {
+    ports.retain(|port, key| {
+        let exists = existing.contains(key);
+        if !exists {
+            info!(
+                "Releasing port {} of pod {} in namespace {}, which no longer exists",
+                port,
+                key.name(),
+                key.namespace()
+            );
+        }
+        exists
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ports.json");
+        assert!(load(&path).await.unwrap().is_empty());
+
+        let mut ports = BTreeMap::new();
+        ports.insert(30000, PodKey::new("default", "foo"));
+        ports.insert(30001, PodKey::new("other", "bar"));
+        save(&path, &ports).await.unwrap();
+
+        assert_eq!(load(&path).await.unwrap(), ports);
+    }
+
+    #[test]
+    fn test_retain_existing() {
+        let mut ports = BTreeMap::new();
+        ports.insert(30000, PodKey::new("default", "foo"));
+        ports.insert(30001, PodKey::new("default", "gone"));
+        let existing: HashSet<PodKey> = vec![PodKey::new("default", "foo")].into_iter().collect();
+
+        retain_existing(&mut ports, &existing);
+
+        assert_eq!(ports.len(), 1);
+        assert!(ports.contains_key(&30000));
+    }
+}
@@ -0,0 +1,170 @@
+//! Preemption of running pods with a low priority in favour of pods with a higher one, once
+//! the node runs as many pods with actors as it may.
+//!
+//! Pods become preemptible once their actors are running. A preempted pod is told so through
+//! its [`Notify`], upon which it stops its actors and terminates with [`PREEMPTION_REASON`].
+//! Once it has given up its handle and ports, it signals the pod which preempted it through
+//! [`Preemptibles::stopped`].
+use kubelet::pod::{Pod, PodKey};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// The reason reported for preempted pods, as the Kubernetes kubelet does.
+pub(crate) const PREEMPTION_REASON: &str = "Preempting";
+
+/// The priorities of the built-in priority classes.
+const SYSTEM_PRIORITIES: &[(&str, i32)] = &[
+    ("system-node-critical", 2_000_001_000),
+    ("system-cluster-critical", 2_000_000_000),
+];
+
+/// Returns the priority of the pod.
+///
+/// The API server resolves the `priorityClassName` of a pod into its `priority`, so the class
+/// name is only looked at for the built-in classes, in case it did not. Pods without either
+/// have the priority 0, as in Kubernetes.
+pub(crate) fn priority(pod: &Pod) -> i32 {
+    let spec = match pod.as_kube_pod().spec.as_ref() {
+        Some(spec) => spec,
+        None => return 0,
+    };
+    if let Some(priority) = spec.priority {
+        return priority;
+    }
+    spec.priority_class_name
+        .as_deref()
+        .and_then(|class| {
+            SYSTEM_PRIORITIES
+                .iter()
+                .find(|(name, _)| *name == class)
+                .map(|(_, priority)| *priority)
+        })
+        .unwrap_or(0)
+}
+
+struct Preemptible {
+    priority: i32,
+    preempt: Arc<Notify>,
+}
+
+/// The running pods which may be preempted.
+#[derive(Clone, Default)]
+pub(crate) struct Preemptibles {
+    pods: Arc<Mutex<BTreeMap<PodKey, Preemptible>>>,
+    /// The preempted pods which have not stopped yet, with the signal their preemptor waits for
+    stopping: Arc<Mutex<BTreeMap<PodKey, Arc<Notify>>>>,
+}
+
+impl Preemptibles {
+    /// Makes the pod preemptible by pods with a higher priority. `preempt` is notified once
+    /// the pod has to make room.
+    pub(crate) fn insert(&self, key: PodKey, priority: i32, preempt: Arc<Notify>) {
+        self.pods
+            .lock()
+            .unwrap()
+            .insert(key, Preemptible { priority, preempt });
+    }
+
+    /// Removes the pod, e.g. because it was deleted.
+    pub(crate) fn remove(&self, key: &PodKey) {
+        self.pods.lock().unwrap().remove(key);
+    }
+
+    /// Preempts the pod with the lowest priority if it is lower than `priority`, and returns
+    /// it with the signal which is notified once it has stopped. Every pod is only preempted
+    /// once, so that pods which wait for room at the same time do not pick the same one.
+    pub(crate) fn preempt_lowest(&self, priority: i32) -> Option<(PodKey, Arc<Notify>)> {
+        let mut pods = self.pods.lock().unwrap();
+        let lowest = pods
+            .iter()
+            .filter(|(_, pod)| pod.priority < priority)
+            .min_by_key(|(_, pod)| pod.priority)
+            .map(|(key, _)| key.clone())?;
+        let preempted = pods.remove(&lowest)?;
+        let stopped = Arc::new(Notify::new());
+        self.stopping
+            .lock()
+            .unwrap()
+            .insert(lowest.clone(), Arc::clone(&stopped));
+        preempted.preempt.notify();
+        Some((lowest, stopped))
+    }
+
+    /// Tells the pod which preempted the given one that it has stopped its actors and released
+    /// its handle and ports. Does nothing if the pod was not preempted.
+    pub(crate) fn stopped(&self, key: &PodKey) {
+        if let Some(stopped) = self.stopping.lock().unwrap().remove(key) {
+            stopped.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn pod_with_spec(spec: serde_json::Value) -> Pod {
+        let kube_pod: KubePod = serde_json::from_value(json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "greet" },
+            "spec": spec,
+        }))
+        .unwrap();
+        Pod::from(kube_pod)
+    }
+
+    #[test]
+    fn test_pod_priority() {
+        let containers = json!([{ "name": "greet" }]);
+        assert_eq!(
+            priority(&pod_with_spec(json!({ "containers": containers }))),
+            0
+        );
+        assert_eq!(
+            priority(&pod_with_spec(json!({
+                "containers": containers,
+                "priorityClassName": "high",
+                "priority": 1000,
+            }))),
+            1000
+        );
+        assert_eq!(
+            priority(&pod_with_spec(json!({
+                "containers": containers,
+                "priorityClassName": "system-node-critical",
+            }))),
+            2_000_001_000
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lowest_priority_pod_is_preempted_once() {
+        let preemptibles = Preemptibles::default();
+        let low = Arc::new(Notify::new());
+        let medium = Arc::new(Notify::new());
+        preemptibles.insert(PodKey::new("default", "low"), -10, Arc::clone(&low));
+        preemptibles.insert(PodKey::new("default", "medium"), 100, Arc::clone(&medium));
+
+        assert!(preemptibles.preempt_lowest(-10).is_none());
+        let (preempted, stopped) = preemptibles.preempt_lowest(1000).unwrap();
+        assert_eq!(preempted, PodKey::new("default", "low"));
+        tokio::time::timeout(Duration::from_secs(1), low.notified())
+            .await
+            .expect("the preempted pod was not notified");
+        assert_eq!(
+            preemptibles.preempt_lowest(1000).map(|(key, _)| key),
+            Some(PodKey::new("default", "medium"))
+        );
+        assert!(preemptibles.preempt_lowest(1000).is_none());
+
+        preemptibles.stopped(&preempted);
+        tokio::time::timeout(Duration::from_secs(1), stopped.notified())
+            .await
+            .expect("the preempting pod was not told that the preempted one stopped");
+    }
+}
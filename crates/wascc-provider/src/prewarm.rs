@@ -0,0 +1,121 @@
+//! Pulling of actor modules when the provider starts, so that the first pods which run them do
+//! not wait for their registry.
+//!
+//! Modules are pulled into the store of the provider, e.g. its local cache, with the
+//! `IfNotPresent` policy and without credentials. Failures are only logged, as the modules are
+//! pulled again by the pods which need them anyway.
+use kubelet::container::PullPolicy;
+use kubelet::store::Store;
+use log::{info, warn};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// Pulls the modules of all references concurrently and returns the references which were
+/// pulled, with the size of their modules in bytes.
+pub(crate) async fn prewarm(
+    store: Arc<dyn Store + Sync + Send>,
+    references: Vec<String>,
+) -> BTreeMap<String, usize> {
+    if references.is_empty() {
+        return BTreeMap::new();
+    }
+    info!("Prewarming {} actor modules", references.len());
+    let pulls: Vec<_> = references
+        .into_iter()
+        .map(|reference| {
+            let store = Arc::clone(&store);
+            tokio::spawn(async move {
+                let size = pull(store.as_ref(), &reference).await;
+                (reference, size)
+            })
+        })
+        .collect();
+
+    let mut warmed = BTreeMap::new();
+    for pull in pulls {
+        match pull.await {
+            Ok((reference, Some(size))) => {
+                warmed.insert(reference, size);
+            }
+            Ok((_, None)) => (),
+            Err(e) => warn!("Prewarming an actor module panicked: {}", e),
+        }
+    }
+    info!("Prewarmed {} actor modules", warmed.len());
+    warmed
+}
+
+async fn pull(store: &(dyn Store + Sync + Send), reference: &str) -> Option<usize> {
+    let image_ref = match Reference::try_from(reference) {
+        Ok(image_ref) => image_ref,
+        Err(e) => {
+            warn!(
+                "Not prewarming invalid image reference {}: {}",
+                reference, e
+            );
+            return None;
+        }
+    };
+    match store
+        .get(
+            &image_ref,
+            PullPolicy::IfNotPresent,
+            &RegistryAuth::Anonymous,
+        )
+        .await
+    {
+        Ok(module) => {
+            info!("Prewarmed module of {} ({} bytes)", reference, module.len());
+            Some(module.len())
+        }
+        Err(e) => {
+            warn!("Unable to prewarm module of {}: {:?}", reference, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_trait::async_trait;
+
+    struct FakeStore;
+
+    #[async_trait]
+    impl Store for FakeStore {
+        async fn get(
+            &self,
+            image_ref: &Reference,
+            _pull_policy: PullPolicy,
+            _auth: &RegistryAuth,
+        ) -> anyhow::Result<Vec<u8>> {
+            match image_ref.repository() {
+                "greet" => Ok(vec![0; 4]),
+                _ => Err(anyhow::anyhow!("no such image")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_pulled_modules_are_warmed() {
+        let warmed = prewarm(
+            Arc::new(FakeStore),
+            vec![
+                String::from("webassembly.azurecr.io/greet:v1"),
+                String::from("webassembly.azurecr.io/missing:v1"),
+                String::new(),
+            ],
+        )
+        .await;
+
+        let expected: BTreeMap<String, usize> =
+            vec![(String::from("webassembly.azurecr.io/greet:v1"), 4)]
+                .into_iter()
+                .collect();
+        assert_eq!(warmed, expected);
+    }
+}
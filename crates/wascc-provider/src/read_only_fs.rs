@@ -0,0 +1,95 @@
+//! A File System capability for volumes which are mounted read-only.
+//!
+//! The File System capability itself has no notion of read-only roots, so it is wrapped and
+//! every operation which would modify the volume is rejected before it reaches the capability.
+use std::error::Error;
+
+use wascc_codec::blobstore::{
+    OP_CREATE_CONTAINER, OP_REMOVE_CONTAINER, OP_REMOVE_OBJECT, OP_START_UPLOAD, OP_UPLOAD_CHUNK,
+};
+use wascc_codec::capabilities::{CapabilityProvider, Dispatcher};
+use wascc_fs::FileSystemProvider;
+
+/// The operations of the blob store capability which modify the file system.
+const WRITE_OPERATIONS: &[&str] = &[
+    OP_CREATE_CONTAINER,
+    OP_REMOVE_CONTAINER,
+    OP_REMOVE_OBJECT,
+    OP_START_UPLOAD,
+    OP_UPLOAD_CHUNK,
+];
+
+/// A File System capability which only allows actors to read.
+pub(crate) struct ReadOnlyFileSystemProvider {
+    inner: FileSystemProvider,
+}
+
+impl ReadOnlyFileSystemProvider {
+    pub(crate) fn new() -> Self {
+        ReadOnlyFileSystemProvider {
+            inner: FileSystemProvider::new(),
+        }
+    }
+}
+
+impl CapabilityProvider for ReadOnlyFileSystemProvider {
+    fn configure_dispatch(
+        &self,
+        dispatcher: Box<dyn Dispatcher>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.inner.configure_dispatch(dispatcher)
+    }
+
+    fn handle_call(
+        &self,
+        actor: &str,
+        op: &str,
+        msg: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        if WRITE_OPERATIONS.contains(&op) {
+            return Err(format!("{} is not allowed on a read-only volume", op).into());
+        }
+        self.inner.handle_call(actor, op, msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use wascc_codec::blobstore::{Container, OP_LIST_OBJECTS};
+    use wascc_codec::core::{CapabilityConfiguration, OP_BIND_ACTOR};
+    use wascc_codec::serialize;
+
+    #[test]
+    fn test_writes_are_rejected() {
+        let root = tempfile::tempdir().unwrap();
+        let provider = ReadOnlyFileSystemProvider::new();
+        let mut values = HashMap::new();
+        values.insert(
+            crate::FS_CONFIG_ROOTDIR.to_owned(),
+            root.path().to_str().unwrap().to_owned(),
+        );
+        let config = CapabilityConfiguration {
+            module: "actor".to_owned(),
+            values,
+        };
+        provider
+            .handle_call("system", OP_BIND_ACTOR, &serialize(config).unwrap())
+            .unwrap();
+
+        let container = serialize(Container {
+            id: "data".to_owned(),
+        })
+        .unwrap();
+        assert!(provider
+            .handle_call("actor", OP_CREATE_CONTAINER, &container)
+            .is_err());
+        assert!(!root.path().join("data").exists());
+
+        std::fs::create_dir(root.path().join("data")).unwrap();
+        assert!(provider
+            .handle_call("actor", OP_LIST_OBJECTS, &container)
+            .is_ok());
+    }
+}
@@ -0,0 +1,89 @@
+//! Access to the waSCC host which all actors run in.
+//!
+//! Every operation on the host locks it, and some of them, e.g. adding an actor or a
+//! capability, take long or hang. The host is therefore only ever locked on blocking threads, so
+//! that waiting for it never stalls the async runtime and with it every other pod on the node.
+use std::sync::{Arc, Mutex, RwLock};
+
+use wascc_host::Host;
+
+/// The waSCC host actors currently run in. Clones share the host, so that every actor handle
+/// stops its actor in the host which replaced the one it was started in.
+#[derive(Clone)]
+pub(crate) struct SharedHost {
+    current: Arc<RwLock<Arc<Mutex<Host>>>>,
+}
+
+impl SharedHost {
+    pub(crate) fn new(host: Host) -> Self {
+        SharedHost {
+            current: Arc::new(RwLock::new(Arc::new(Mutex::new(host)))),
+        }
+    }
+
+    /// Returns the host actors currently run in. It must only be locked on blocking threads,
+    /// e.g. in [`SharedHost::with_host`].
+    pub(crate) fn current(&self) -> Arc<Mutex<Host>> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    /// Replaces the host actors run in, e.g. when the current one stopped responding.
+    pub(crate) fn replace(&self, host: Arc<Mutex<Host>>) {
+        *self.current.write().unwrap() = host;
+    }
+
+    /// Locks the current host on a blocking thread and calls `f` with it.
+    pub(crate) async fn with_host<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&Host) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let host = self.current();
+        Ok(tokio::task::spawn_blocking(move || f(&host.lock().unwrap())).await?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_locked_host_does_not_stall_the_runtime() {
+        let shared = SharedHost::new(Host::new());
+        let host = shared.current();
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel();
+        let holder = std::thread::spawn(move || {
+            let _host = host.lock().unwrap();
+            locked_tx.send(()).unwrap();
+            // The test releases the host, unless the runtime is stalled
+            release_rx.recv_timeout(Duration::from_secs(10)).is_ok()
+        });
+        locked_rx.recv().unwrap();
+
+        // The test runtime has a single thread, which a lock taken on it would block until the
+        // host is released, so that no other task could make progress meanwhile
+        let actors =
+            tokio::spawn(async move { shared.with_host(|host| host.actors().len()).await });
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticking = Arc::clone(&ticks);
+        tokio::spawn(async move {
+            loop {
+                ticking.fetch_add(1, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+            }
+        });
+        while ticks.load(Ordering::SeqCst) < 100 {
+            tokio::task::yield_now().await;
+        }
+        let _ = release_tx.send(());
+
+        assert!(
+            holder.join().unwrap(),
+            "the runtime made no progress while the host was locked"
+        );
+        assert_eq!(actors.await.unwrap().unwrap(), 0);
+    }
+}
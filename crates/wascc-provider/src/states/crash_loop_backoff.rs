@@ -1,26 +1,60 @@
 use crate::PodState;
-use kubelet::backoff::BackoffStrategy;
+use k8s_openapi::api::core::v1::ContainerState as KubeContainerState;
+use k8s_openapi::api::core::v1::ContainerStateWaiting as KubeContainerStateWaiting;
+use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
 use kubelet::state::prelude::*;
+use std::time::Duration;
 
 use super::registered::Registered;
 
 /// Pod has failed multiple times.
 #[derive(Default, Debug, TransitionTo)]
 #[transition_to(Registered)]
-pub struct CrashLoopBackoff;
+pub struct CrashLoopBackoff {
+    /// How long to wait before the pod is restarted
+    pub delay: Duration,
+}
 
 #[async_trait::async_trait]
 impl State<PodState> for CrashLoopBackoff {
-    async fn next(self: Box<Self>, pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
-        pod_state.crash_loop_backoff_strategy.wait().await;
+    async fn next(self: Box<Self>, _pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
+        tokio::time::delay_for(self.delay).await;
         Transition::next(self, Registered)
     }
 
     async fn json_status(
         &self,
-        _pod_state: &mut PodState,
-        _pod: &Pod,
+        pod_state: &mut PodState,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Pending, "CrashLoopBackoff")
+        let message = format!("back-off {}s restarting failed pod", self.delay.as_secs());
+        let container_statuses: Vec<KubeContainerStatus> = pod
+            .containers()
+            .iter()
+            .map(|container| KubeContainerStatus {
+                name: container.name().to_string(),
+                ready: false,
+                started: Some(false),
+                restart_count: pod_state.restart_count,
+                state: Some(KubeContainerState {
+                    waiting: Some(KubeContainerStateWaiting {
+                        reason: Some("CrashLoopBackOff".to_string()),
+                        message: Some(message.clone()),
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .collect();
+        Ok(make_status_with_containers(
+            Phase::Pending,
+            "CrashLoopBackOff",
+            container_statuses,
+            vec![],
+        ))
+    }
+
+    fn is_waiting(&self) -> bool {
+        true
     }
 }
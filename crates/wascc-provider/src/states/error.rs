@@ -1,9 +1,18 @@
+use kubelet::backoff::BackoffStrategy;
 use kubelet::state::prelude::*;
+use std::time::{Duration, Instant};
 
 use super::crash_loop_backoff::CrashLoopBackoff;
 use super::registered::Registered;
 use crate::PodState;
 
+/// How many failures in a row are retried right away before the pod is considered to be
+/// crash looping.
+const MAX_IMMEDIATE_RETRIES: usize = 3;
+
+/// Failures which are further apart than this are not counted as crash looping.
+const CRASH_LOOP_WINDOW: Duration = Duration::from_secs(5 * 60);
+
 #[derive(Default, Debug, TransitionTo)]
 #[transition_to(Registered, CrashLoopBackoff)]
 /// The Pod failed to run.
@@ -15,12 +24,24 @@ pub struct Error {
 #[async_trait::async_trait]
 impl State<PodState> for Error {
     async fn next(self: Box<Self>, pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
+        let now = Instant::now();
+        if let Some(last_failure) = pod_state.last_failure {
+            if now.duration_since(last_failure) > CRASH_LOOP_WINDOW {
+                pod_state.errors = 0;
+            }
+        }
+        pod_state.last_failure = Some(now);
+        // The pod checks for room again when it is registered anew
+        pod_state.shared.release_actor_slot(&pod_state.key);
         pod_state.errors += 1;
-        if pod_state.errors > 3 {
+        pod_state.restart_count += 1;
+
+        if pod_state.errors > MAX_IMMEDIATE_RETRIES {
             pod_state.errors = 0;
-            Transition::next(self, CrashLoopBackoff)
+            let delay = pod_state.crash_loop_backoff_strategy.next_duration();
+            Transition::next(self, CrashLoopBackoff { delay })
         } else {
-            tokio::time::delay_for(std::time::Duration::from_secs(5)).await;
+            tokio::time::delay_for(Duration::from_secs(5)).await;
             Transition::next(self, Registered)
         }
     }
@@ -32,4 +53,8 @@ impl State<PodState> for Error {
     ) -> anyhow::Result<serde_json::Value> {
         make_status(Phase::Pending, &self.message)
     }
+
+    fn is_waiting(&self) -> bool {
+        true
+    }
 }
@@ -1,8 +1,13 @@
+use std::collections::HashMap;
+
 use kubelet::backoff::BackoffStrategy;
+use kubelet::container::PullPolicy;
+use kubelet::secret::RegistryAuthResolver;
 use kubelet::state::prelude::*;
+use kubelet::store::DigestMismatchError;
 use log::error;
 
-use crate::PodState;
+use crate::{fail_fatal, PodState};
 
 use super::image_pull_backoff::ImagePullBackoff;
 use super::volume_mount::VolumeMount;
@@ -12,11 +17,30 @@ use super::volume_mount::VolumeMount;
 #[transition_to(VolumeMount, ImagePullBackoff)]
 pub struct ImagePull;
 
+/// Pulls the modules of the experimental portable capability providers the pod asks for, keyed
+/// by capability ID.
+async fn pull_portable_capabilities(
+    pod_state: &PodState,
+    pod: &Pod,
+    auth_resolver: &RegistryAuthResolver,
+) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+    let mut modules = HashMap::new();
+    for (capability, reference) in crate::portable_capability_references(pod)? {
+        let auth = auth_resolver.resolve_registry_auth(&reference).await?;
+        let data = pod_state
+            .shared
+            .store
+            .get(&reference, PullPolicy::IfNotPresent, &auth)
+            .await?;
+        modules.insert(capability, data);
+    }
+    Ok(modules)
+}
+
 #[async_trait::async_trait]
 impl State<PodState> for ImagePull {
     async fn next(self: Box<Self>, pod_state: &mut PodState, pod: &Pod) -> Transition<PodState> {
-        let auth_resolver =
-            kubelet::secret::RegistryAuthResolver::new(pod_state.shared.client.clone(), &pod);
+        let auth_resolver = RegistryAuthResolver::new(pod_state.shared.client.clone(), &pod);
         pod_state.run_context.modules = match pod_state
             .shared
             .store
@@ -24,11 +48,24 @@ impl State<PodState> for ImagePull {
             .await
         {
             Ok(modules) => modules,
+            // Pulling again would only yield the same image, so there is no point in retrying
+            Err(e) if e.downcast_ref::<DigestMismatchError>().is_some() => {
+                pod_state.shared.release_actor_slot(&pod_state.key);
+                fail_fatal!(e)
+            }
             Err(e) => {
                 error!("{:?}", e);
                 return Transition::next(self, ImagePullBackoff);
             }
         };
+        pod_state.run_context.portable_capabilities =
+            match pull_portable_capabilities(pod_state, pod, &auth_resolver).await {
+                Ok(modules) => modules,
+                Err(e) => {
+                    error!("Unable to pull portable capabilities: {:?}", e);
+                    return Transition::next(self, ImagePullBackoff);
+                }
+            };
         pod_state.image_pull_backoff_strategy.reset();
         Transition::next(self, VolumeMount)
     }
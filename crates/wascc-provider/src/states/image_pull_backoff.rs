@@ -1,5 +1,5 @@
 use super::image_pull::ImagePull;
-use crate::PodState;
+use crate::{fail_fatal, PodState, WasccError};
 use kubelet::backoff::BackoffStrategy;
 use kubelet::state::prelude::*;
 
@@ -11,7 +11,15 @@ pub struct ImagePullBackoff;
 #[async_trait::async_trait]
 impl State<PodState> for ImagePullBackoff {
     async fn next(self: Box<Self>, pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
-        pod_state.image_pull_backoff_strategy.wait().await;
+        let backoff = &mut pod_state.image_pull_backoff_strategy;
+        if backoff.retries_exhausted() {
+            let e = WasccError::ImagePullRetriesExhausted {
+                retries: backoff.max_retries().unwrap_or_default(),
+            };
+            pod_state.shared.release_actor_slot(&pod_state.key);
+            fail_fatal!(e);
+        }
+        backoff.wait().await;
         Transition::next(self, ImagePull)
     }
 
@@ -22,4 +30,8 @@ impl State<PodState> for ImagePullBackoff {
     ) -> anyhow::Result<serde_json::Value> {
         make_status(Phase::Pending, "ImagePullBackoff")
     }
+
+    fn is_waiting(&self) -> bool {
+        true
+    }
 }
@@ -1,12 +1,53 @@
 use log::info;
+use std::time::Duration;
 
-use crate::PodState;
+use crate::{PodState, WasccError};
 use kubelet::container::Container;
 use kubelet::state::prelude::*;
 
 use super::error::Error;
 use super::image_pull::ImagePull;
-use crate::transition_to_error;
+use crate::{fail_fatal, transition_to_error};
+
+/// How long to wait for a preempted pod to stop its actors.
+const PREEMPTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fails if `running` pods which hold a slot, not counting the pod itself, leave no room for the
+/// pod.
+pub(crate) fn check_actor_capacity(
+    running: usize,
+    max_actors: Option<usize>,
+) -> Result<(), WasccError> {
+    match max_actors {
+        Some(max_actors) if running >= max_actors => {
+            Err(WasccError::NodeCapacityExceeded { max_actors })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Preempts the running pod with the lowest priority, if it is lower than the priority of the
+/// pod, and waits until it has stopped its actors and released its handle and ports. Returns
+/// whether there is room for the pod now.
+async fn make_room(pod_state: &PodState, pod: &Pod) -> bool {
+    let (preempted, stopped) = match pod_state
+        .shared
+        .preemptibles
+        .preempt_lowest(pod_state.priority)
+    {
+        Some(preempted) => preempted,
+        None => return false,
+    };
+    info!(
+        "Pod {} preempts pod {} in namespace {} to make room",
+        pod.name(),
+        preempted.name(),
+        preempted.namespace()
+    );
+    tokio::time::timeout(PREEMPTION_TIMEOUT, stopped.notified())
+        .await
+        .is_ok()
+}
 
 fn validate_pod_runnable(pod: &Pod) -> anyhow::Result<()> {
     if !pod.init_containers().is_empty() {
@@ -51,12 +92,24 @@ pub struct Registered;
 
 #[async_trait::async_trait]
 impl State<PodState> for Registered {
-    async fn next(self: Box<Self>, _pod_state: &mut PodState, pod: &Pod) -> Transition<PodState> {
+    async fn next(self: Box<Self>, pod_state: &mut PodState, pod: &Pod) -> Transition<PodState> {
         info!("Pod added: {}.", pod.name());
         match validate_pod_runnable(&pod) {
             Ok(_) => (),
             Err(e) => transition_to_error!(self, e),
         }
+        // The pod fails instead of waiting for room, so that its controller can recreate it on
+        // another node. The slot is reserved right away, so that pods which are registered at
+        // the same time cannot all pass the check.
+        if let Err(e) = pod_state.shared.reserve_actor_slot(&pod_state.key) {
+            if !pod_state.shared.preemption || !make_room(pod_state, pod).await {
+                fail_fatal!(e);
+            }
+            // Another pod may have taken the room in the meantime
+            if let Err(e) = pod_state.shared.reserve_actor_slot(&pod_state.key) {
+                fail_fatal!(e);
+            }
+        }
         Transition::next(self, ImagePull)
     }
 
@@ -161,4 +214,14 @@ mod test {
             "validation error did not give name of bad container"
         );
     }
+
+    #[test]
+    fn cannot_run_pod_beyond_max_actors() {
+        check_actor_capacity(5, None).unwrap();
+        check_actor_capacity(1, Some(2)).unwrap();
+        match check_actor_capacity(2, Some(2)) {
+            Err(WasccError::NodeCapacityExceeded { max_actors }) => assert_eq!(max_actors, 2),
+            other => panic!("expected the capacity to be exceeded, got {:?}", other),
+        }
+    }
 }
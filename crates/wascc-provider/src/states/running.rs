@@ -1,55 +1,175 @@
-use crate::PodState;
+use super::starting::wait_for_port;
+use super::terminated::Terminated;
+use crate::{fail_fatal, PodState, WasccError, RELOAD_ANNOTATION};
 use chrono::Utc;
 use k8s_openapi::api::core::v1::ContainerState as KubeContainerState;
 use k8s_openapi::api::core::v1::ContainerStateRunning as KubeContainerStateRunning;
 use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
+use k8s_openapi::api::core::v1::Pod as KubePod;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time as KubeTime;
+use kube::Api;
+use kubelet::backoff::BackoffStrategy;
+use kubelet::pod::patch_status;
 use kubelet::state::prelude::*;
+use log::{error, info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a pod has to run before earlier failures are forgotten, so that the next failure
+/// starts with the shortest backoff again. This matches the Kubernetes kubelet.
+const SUSTAINED_RUN_DURATION: Duration = Duration::from_secs(10 * 60);
+
+/// How often containers which did not listen on their HTTP port yet are checked again.
+const UNREADY_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 /// The Kubelet is running the Pod.
-#[derive(Default, Debug)]
+#[derive(Default, Debug, TransitionTo)]
+#[transition_to(Terminated)]
 pub struct Running;
 
+impl Running {
+    /// Reloads all actors of the pod if its reload annotation changed.
+    ///
+    /// The pod handed to the state is the one the state was entered with, so the current one is
+    /// fetched from the API server. Failed reloads are logged, unless they leave a container
+    /// without an actor, which is returned as an error.
+    async fn reload_if_requested(pod_state: &mut PodState, pod: &Pod) -> anyhow::Result<()> {
+        let api: Api<KubePod> = Api::namespaced(pod_state.shared.client.clone(), pod.namespace());
+        let current = match api.get(pod.name()).await {
+            Ok(current) => Pod::from(current),
+            Err(e) => {
+                warn!(
+                    "Unable to fetch pod {} to check for reloads: {}",
+                    pod.name(),
+                    e
+                );
+                return Ok(());
+            }
+        };
+        let generation = current.get_annotation(RELOAD_ANNOTATION).map(String::from);
+        if generation == pod_state.reload_generation {
+            return Ok(());
+        }
+        pod_state.reload_generation = generation;
+        info!("Reloading actors of pod {}", pod.name());
+        for container in pod.containers() {
+            if let Err(e) = pod_state
+                .shared
+                .reload_actor(&pod_state.key, container.name())
+                .await
+            {
+                if let Some(WasccError::ReloadFailed { .. }) = e.downcast_ref::<WasccError>() {
+                    return Err(e);
+                }
+                error!(
+                    "Failed to reload actor of container {} in pod {}: {:?}",
+                    container.name(),
+                    pod.name(),
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks whether the containers which did not listen on their HTTP port yet do so now, and
+    /// reports the pod status again if any of them became ready.
+    async fn recheck_unready_containers(pod_state: &mut PodState, pod: &Pod) {
+        let mut listening = Vec::new();
+        for (name, (address, port)) in pod_state.unready_containers.iter() {
+            if wait_for_port(*address, *port, Duration::from_secs(0)).await {
+                listening.push(name.clone());
+            }
+        }
+        if listening.is_empty() {
+            return;
+        }
+        for name in listening {
+            info!(
+                "Container {} of pod {} listens on its port now, reporting it as ready",
+                name,
+                pod.name()
+            );
+            pod_state.unready_containers.remove(&name);
+        }
+        let api: Api<KubePod> = Api::namespaced(pod_state.shared.client.clone(), pod.namespace());
+        patch_status(&api, pod.name(), running_status(pod_state, pod)).await;
+    }
+}
+
+/// Returns the status of the running pod, in which containers are ready once they listen on their
+/// HTTP port.
+fn running_status(pod_state: &PodState, pod: &Pod) -> serde_json::Value {
+    let ts = Utc::now();
+    let container_statuses: Vec<KubeContainerStatus> = pod
+        .containers()
+        .iter()
+        .map(|container| {
+            let state = KubeContainerState {
+                running: Some(KubeContainerStateRunning {
+                    started_at: Some(KubeTime(ts)),
+                }),
+                ..Default::default()
+            };
+            KubeContainerStatus {
+                name: container.name().to_string(),
+                ready: !pod_state.unready_containers.contains_key(container.name()),
+                restart_count: pod_state.restart_count,
+                started: Some(true),
+                state: Some(state),
+                ..Default::default()
+            }
+        })
+        .collect();
+    make_status_with_containers(Phase::Running, "Running", container_statuses, vec![])
+}
+
 #[async_trait::async_trait]
 impl State<PodState> for Running {
-    async fn next(self: Box<Self>, _pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
-        // Wascc has no notion of exiting so we just sleep.
-        // I _think_ that periodically awaiting will allow the task to be interrupted.
+    async fn next(self: Box<Self>, pod_state: &mut PodState, pod: &Pod) -> Transition<PodState> {
+        let sustained_run = tokio::time::delay_for(SUSTAINED_RUN_DURATION);
+        tokio::pin!(sustained_run);
+        let mut errors_forgotten = false;
+        let pod_changed = Arc::clone(&pod_state.pod_changed);
+        let preempt = Arc::clone(&pod_state.preempt);
+        pod_state.shared.preemptibles.insert(
+            pod_state.key.clone(),
+            pod_state.priority,
+            Arc::clone(&preempt),
+        );
+
+        // Wascc has no notion of exiting so we just wait for changes to the pod, or for it to
+        // make room for a pod with a higher priority.
         loop {
-            tokio::time::delay_for(std::time::Duration::from_secs(10)).await;
+            tokio::select! {
+                _ = preempt.notified() => {
+                    info!("Pod {} was preempted", pod.name());
+                    return Transition::next(self, Terminated { preempted: true });
+                }
+                _ = &mut sustained_run, if !errors_forgotten => {
+                    pod_state.errors = 0;
+                    pod_state.crash_loop_backoff_strategy.reset();
+                    errors_forgotten = true;
+                }
+                _ = pod_changed.notified() => {
+                    if let Err(e) = Running::reload_if_requested(pod_state, pod).await {
+                        pod_state.shared.release_actor_slot(&pod_state.key);
+                        fail_fatal!(e);
+                    }
+                }
+                _ = tokio::time::delay_for(UNREADY_RECHECK_INTERVAL),
+                    if !pod_state.unready_containers.is_empty() => {
+                    Running::recheck_unready_containers(pod_state, pod).await;
+                }
+            }
         }
     }
 
     async fn json_status(
         &self,
-        _pod_state: &mut PodState,
+        pod_state: &mut PodState,
         pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        let ts = Utc::now();
-        let container_statuses: Vec<KubeContainerStatus> = pod
-            .containers()
-            .iter()
-            .map(|container| {
-                let state = KubeContainerState {
-                    running: Some(KubeContainerStateRunning {
-                        started_at: Some(KubeTime(ts)),
-                    }),
-                    ..Default::default()
-                };
-                KubeContainerStatus {
-                    name: container.name().to_string(),
-                    ready: true,
-                    started: Some(true),
-                    state: Some(state),
-                    ..Default::default()
-                }
-            })
-            .collect();
-        Ok(make_status_with_containers(
-            Phase::Running,
-            "Running",
-            container_statuses,
-            vec![],
-        ))
+        Ok(running_status(pod_state, pod))
     }
 }
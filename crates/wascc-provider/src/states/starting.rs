@@ -1,45 +1,48 @@
 use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr};
 use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use tokio::net::TcpStream;
 use tokio::sync::Mutex;
+use tokio::time::Instant;
 
 use kubelet::container::{Container, ContainerKey, Handle as ContainerHandle};
 use kubelet::pod::{Handle, PodKey};
 use kubelet::provider::Provider;
 use kubelet::state::prelude::*;
 
+use crate::capability_config::CapabilityDefaults;
+use crate::lifecycle;
+use crate::port_map;
 use crate::rand::Rng;
+use crate::termination::{self, Termination};
 use crate::PodState;
 use crate::VolumeBinding;
 use crate::{
-    fail_fatal, transition_to_error, wascc_run, ActorHandle, LogHandleFactory, WasccProvider,
+    add_portable_capability, fail_fatal, patch_http_port_annotation, transition_to_error,
+    wascc_run, ActorConfig, ActorHandle, LogHandleFactory, WasccError, WasccProvider,
+    HTTP_BIND_ADDRESS,
 };
 
 use super::error::Error;
 use super::running::Running;
 
-#[derive(Debug)]
-struct PortAllocationError;
+/// The reason reported for pods whose `postStart` hook failed, as the Kubernetes kubelet does.
+const FAILED_POST_START_HOOK_REASON: &str = "FailedPostStartHook";
 
-impl std::fmt::Display for PortAllocationError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "all ports are currently in use")
-    }
-}
-
-impl std::error::Error for PortAllocationError {
-    fn description(&self) -> &str {
-        "all ports are currently in use"
-    }
-}
+/// How often the port of an HTTP actor is probed while waiting for it to listen.
+const HTTP_READINESS_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 async fn find_available_port(
     port_map: &Arc<Mutex<BTreeMap<u16, PodKey>>>,
+    port_map_path: &Path,
     pod: &Pod,
-) -> Result<u16, PortAllocationError> {
+) -> Result<u16, WasccError> {
     let pod_key = PodKey::from(pod);
     let mut empty_port: BTreeSet<u16> = BTreeSet::new();
     let mut lock = port_map.lock().await;
@@ -47,49 +50,154 @@ async fn find_available_port(
         let generated_port: u16 = rand::thread_rng().gen_range(30000, 32768);
         if !lock.contains_key(&generated_port) {
             lock.insert(generated_port, pod_key);
+            port_map::persist(port_map_path, &lock).await;
             return Ok(generated_port);
         }
         empty_port.insert(generated_port);
     }
-    Err(PortAllocationError)
+    Err(WasccError::PortsExhausted)
 }
 
+/// Assigns the container its host port: the port it is pinned to by annotation, otherwise the
+/// host port of the container or one from the dynamic range.
 async fn assign_container_port(
     port_map: Arc<Mutex<BTreeMap<u16, PodKey>>>,
+    port_map_path: &Path,
     pod: &Pod,
     container: &Container,
-) -> anyhow::Result<u16> {
+    pinned_port: Option<u16>,
+) -> Result<u16, WasccError> {
+    if let Some(pinned_port) = pinned_port {
+        let pod_key = PodKey::from(pod);
+        let mut lock = port_map.lock().await;
+        // The port may still be assigned to this very pod from before a restart
+        let owner = lock
+            .get(&pinned_port)
+            .filter(|owner| *owner != &pod_key)
+            .cloned();
+        if let Some(owner) = owner {
+            error!(
+                "Failed to assign pinned hostport {} to pod {}, because pod {} has it",
+                pinned_port,
+                pod.name(),
+                owner.name()
+            );
+            return Err(WasccError::HostPortConflict {
+                port: pinned_port,
+                owner: format!("{}/{}", owner.namespace(), owner.name()),
+            });
+        }
+        lock.insert(pinned_port, pod_key);
+        port_map::persist(port_map_path, &lock).await;
+        return Ok(pinned_port);
+    }
     let mut port_assigned: u16 = 0;
     if let Some(container_vec) = container.ports().as_ref() {
         for c_port in container_vec.iter() {
             let container_port = c_port.container_port;
             if let Some(host_port) = c_port.host_port {
-                let host_port: u16 = u16::try_from(host_port)?;
+                let host_port = u16::try_from(host_port)
+                    .map_err(|_| WasccError::InvalidPort { port: host_port })?;
+                let pod_key = PodKey::from(pod);
                 let mut lock = port_map.lock().await;
-                if !lock.contains_key(&host_port) {
+                // The port may still be assigned to this very pod from before a restart
+                if lock.get(&host_port).map_or(true, |owner| owner == &pod_key) {
                     port_assigned = host_port;
-                    lock.insert(port_assigned, PodKey::from(pod));
+                    lock.insert(port_assigned, pod_key);
+                    port_map::persist(port_map_path, &lock).await;
                 } else {
                     error!(
                         "Failed to assign hostport {}, because it's taken",
                         &host_port
                     );
-                    return Err(anyhow::anyhow!("Port {} is currently in use", &host_port));
+                    return Err(WasccError::PortInUse { port: host_port });
                 }
             } else if container_port >= 0 && container_port <= 65536 {
-                port_assigned = find_available_port(&port_map, pod).await?;
+                port_assigned = find_available_port(&port_map, port_map_path, pod).await?;
             }
         }
     }
     Ok(port_assigned)
 }
 
+/// Waits until something accepts connections on the given port of a local address, returns
+/// false if that did not happen within `timeout`. If the address is unspecified, the loopback
+/// address is checked.
+pub(crate) async fn wait_for_port(address: IpAddr, port: u16, timeout: Duration) -> bool {
+    let address = if address.is_unspecified() {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    } else {
+        address
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if TcpStream::connect((address, port)).await.is_ok() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::delay_for(HTTP_READINESS_POLL_INTERVAL).await;
+    }
+}
+
+/// Reports the pod as failed, because the container could not be started for the given reason.
+async fn report_start_failure(
+    pod_state: &PodState,
+    pod: &Pod,
+    container: &Container,
+    reason: &str,
+    pod_reason: &str,
+) {
+    let termination = Termination {
+        reason,
+        failed: true,
+        log_path: None,
+    };
+    let status = termination::terminated_status(
+        &pod_state.shared.termination_path,
+        &pod_state.key,
+        container,
+        &termination,
+        pod_state.restart_count,
+    )
+    .await;
+    termination::report(
+        &pod_state.shared.client,
+        pod,
+        Phase::Failed,
+        pod_reason,
+        vec![status],
+    )
+    .await;
+}
+
+/// Returns the directory of the volume a mount refers to, which is the `subPath` of the mount
+/// within the volume if it has one. The `subPath` must not lead outside of the volume.
+fn volume_path(volume_root: &Path, sub_path: Option<&str>) -> anyhow::Result<PathBuf> {
+    let sub_path = match sub_path {
+        Some(sub_path) if !sub_path.is_empty() => Path::new(sub_path),
+        _ => return Ok(volume_root.to_path_buf()),
+    };
+    if !sub_path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(anyhow::anyhow!(
+            "subPath {} must be a relative path within the volume",
+            sub_path.display()
+        ));
+    }
+    Ok(volume_root.join(sub_path))
+}
+
 async fn start_container(
     pod_state: &mut PodState,
     container: &Container,
     pod: &Pod,
     port_assigned: u16,
-) -> anyhow::Result<ContainerHandle<ActorHandle, LogHandleFactory>> {
+    capability_defaults: &CapabilityDefaults,
+) -> anyhow::Result<(ContainerHandle<ActorHandle, LogHandleFactory>, Option<u16>)> {
     let env =
         <WasccProvider as Provider>::env_vars(&container, &pod, &pod_state.shared.client).await;
     let volume_bindings: Vec<VolumeBinding> =
@@ -109,13 +217,18 @@ async fn start_container(
                     // been validated by the k8s API
                     Ok(VolumeBinding {
                         name: vm.name.clone(),
-                        host_path: vol.deref().clone(),
+                        host_path: volume_path(vol.deref(), vm.sub_path.as_deref())?,
+                        read_only: vm.read_only.unwrap_or(false),
                     })
                 })
                 .collect::<anyhow::Result<_>>()?
         } else {
             vec![]
         };
+    // The subdirectory a mount refers to may not have been created yet
+    for binding in volume_bindings.iter() {
+        tokio::fs::create_dir_all(&binding.host_path).await?;
+    }
 
     debug!("Starting container {} on thread", container.name());
 
@@ -130,12 +243,44 @@ async fn start_container(
                 container.name()
             )
         })?;
+    let config = ActorConfig {
+        env,
+        volumes: volume_bindings,
+        port_assigned,
+        memory_limit: container.memory_limit()?,
+        bindings: crate::capability_bindings(pod),
+        log_config: crate::log_config(pod, pod_state.shared.log_format),
+        unavailable_capabilities: Arc::clone(&pod_state.shared.unavailable_capabilities),
+        portable_capabilities: crate::portable_capability_bindings(pod)?,
+        default_env: pod_state.shared.default_env.clone(),
+        capability_defaults: capability_defaults.clone(),
+        log_file: crate::configured_log_file(pod, container.name(), &pod_state.shared.log_path)?,
+        messaging_prefix: crate::messaging_prefix(pod),
+    };
     let lp = pod_state.shared.log_path.clone();
     let host = pod_state.shared.host.clone();
-    tokio::task::spawn_blocking(move || {
-        wascc_run(host, module_data, env, volume_bindings, &lp, port_assigned)
-    })
-    .await?
+    Ok(tokio::task::spawn_blocking(move || wascc_run(host, module_data, config, &lp)).await??)
+}
+
+/// Loads the portable capability providers pulled for the pod into the host. Providers which are
+/// still loaded from before a restart of the pod are kept.
+async fn load_portable_capabilities(pod_state: &mut PodState) -> anyhow::Result<()> {
+    let binding = crate::portable_binding(&pod_state.key);
+    let modules = std::mem::take(&mut pod_state.run_context.portable_capabilities);
+    for (capability, data) in modules {
+        if pod_state.portable_capabilities.contains(&capability) {
+            continue;
+        }
+        let binding = binding.clone();
+        let name = capability.clone();
+        pod_state
+            .shared
+            .host
+            .with_host(move |host| add_portable_capability(host, &name, &binding, &data))
+            .await??;
+        pod_state.portable_capabilities.insert(capability);
+    }
+    Ok(())
 }
 
 /// The Kubelet is starting the Pod.
@@ -147,13 +292,35 @@ pub struct Starting;
 impl State<PodState> for Starting {
     async fn next(self: Box<Self>, pod_state: &mut PodState, pod: &Pod) -> Transition<PodState> {
         info!("Starting containers for pod {:?}", pod.name());
+        let host_changes = Arc::clone(&pod_state.shared.host_changes);
+        let _host_changes = host_changes.read().await;
+
+        if let Err(e) = load_portable_capabilities(pod_state).await {
+            pod_state.shared.release_actor_slot(&pod_state.key);
+            fail_fatal!(e);
+        }
+
+        let capability_defaults = CapabilityDefaults::load(&pod_state.shared.client).await;
+
+        let pinned_ports = match crate::pinned_host_ports(pod) {
+            Ok(pinned_ports) => pinned_ports,
+            Err(e) => {
+                pod_state.shared.release_actor_slot(&pod_state.key);
+                fail_fatal!(e)
+            }
+        };
 
         let mut container_handles = HashMap::new();
+        let mut http_ports = Vec::new();
+        let mut readiness_checks = Vec::new();
+        pod_state.unready_containers.clear();
         for container in pod.containers() {
             let port_assigned = match assign_container_port(
                 Arc::clone(&pod_state.shared.port_map),
+                &pod_state.shared.port_map_path,
                 &pod,
                 &container,
+                pinned_ports.get(container.name()).copied(),
             )
             .await
             {
@@ -166,11 +333,56 @@ impl State<PodState> for Starting {
                 port_assigned
             );
 
-            let container_handle =
-                match start_container(pod_state, &container, &pod, port_assigned).await {
-                    Ok(handle) => handle,
-                    Err(e) => fail_fatal!(e),
-                };
+            let (mut container_handle, http_port) = match start_container(
+                pod_state,
+                &container,
+                &pod,
+                port_assigned,
+                &capability_defaults,
+            )
+            .await
+            {
+                Ok(started) => started,
+                Err(e) => {
+                    report_start_failure(pod_state, pod, &container, &e.to_string(), "Error").await;
+                    pod_state.shared.release_actor_slot(&pod_state.key);
+                    fail_fatal!(e)
+                }
+            };
+            if let Some(port) = http_port {
+                http_ports.push(port);
+                readiness_checks.push((container.name().to_string(), port));
+            }
+            if let Err(e) =
+                lifecycle::run_post_start_hook(&container, HTTP_BIND_ADDRESS, port_assigned).await
+            {
+                let reason = format!("postStart hook failed: {}", e);
+                error!(
+                    "Stopping the actors of pod {}, the {} of container {}",
+                    pod.name(),
+                    reason,
+                    container.name()
+                );
+                let started = container_handles
+                    .values_mut()
+                    .chain(std::iter::once(&mut container_handle));
+                for handle in started {
+                    if let Err(e) = handle.stop().await {
+                        warn!("Unable to stop actor of pod {}: {:?}", pod.name(), e);
+                    }
+                }
+                report_start_failure(
+                    pod_state,
+                    pod,
+                    &container,
+                    &reason,
+                    FAILED_POST_START_HOOK_REASON,
+                )
+                .await;
+                let e = anyhow::anyhow!(reason);
+                pod_state.shared.release_actor_slot(&pod_state.key);
+                fail_fatal!(e)
+            }
             container_handles.insert(
                 ContainerKey::App(container.name().to_string()),
                 container_handle,
@@ -186,6 +398,45 @@ impl State<PodState> for Starting {
 
         info!("All containers started for pod {:?}.", pod.name());
 
+        let readiness_timeout = pod_state.shared.http_readiness_timeout;
+        if readiness_timeout > Duration::from_secs(0) {
+            // The actors start listening independently of each other, so they are waited for
+            // at the same time
+            let waits: Vec<_> = readiness_checks
+                .into_iter()
+                .map(|(name, port)| {
+                    debug!(
+                        "Waiting up to {:?} for container {} to listen on port {}",
+                        readiness_timeout, name, port
+                    );
+                    tokio::spawn(async move {
+                        let listening =
+                            wait_for_port(HTTP_BIND_ADDRESS, port, readiness_timeout).await;
+                        (name, port, listening)
+                    })
+                })
+                .collect();
+            for wait in waits {
+                match wait.await {
+                    Ok((_, _, true)) => (),
+                    Ok((name, port, false)) => {
+                        warn!(
+                            "Container {} is not listening on port {} after {:?}, reporting it as not ready until it does",
+                            name, port, readiness_timeout
+                        );
+                        pod_state
+                            .unready_containers
+                            .insert(name, (HTTP_BIND_ADDRESS, port));
+                    }
+                    Err(e) => warn!("Waiting for a container to listen failed: {}", e),
+                }
+            }
+        }
+
+        if !http_ports.is_empty() {
+            patch_http_port_annotation(&pod_state.shared.client, &pod_state.key, &http_ports).await;
+        }
+
         Transition::next(self, Running)
     }
 
@@ -197,3 +448,116 @@ impl State<PodState> for Starting {
         make_status(Phase::Pending, "Starting")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pod(name: &str) -> Pod {
+        Pod::from(
+            serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                "metadata": { "name": name, "namespace": "default" },
+                "spec": {
+                    "containers": [{ "name": "web", "ports": [{ "containerPort": 8080 }] }]
+                }
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_pinned_port_is_reserved() {
+        let directory = tempfile::tempdir().unwrap();
+        let port_map_path = directory.path().join("port-map.json");
+        let port_map = Arc::new(Mutex::new(BTreeMap::new()));
+        let pod = pod("web");
+        let container = pod.containers().remove(0);
+
+        let port = assign_container_port(
+            Arc::clone(&port_map),
+            &port_map_path,
+            &pod,
+            &container,
+            Some(8443),
+        )
+        .await
+        .unwrap();
+        assert_eq!(port, 8443);
+        assert_eq!(
+            port_map.lock().await.get(&8443),
+            Some(&PodKey::new("default", "web"))
+        );
+        // The pod keeps its port when it is started again
+        assert!(assign_container_port(
+            Arc::clone(&port_map),
+            &port_map_path,
+            &pod,
+            &container,
+            Some(8443),
+        )
+        .await
+        .is_ok());
+        // Without a pinned port, one from the dynamic range is assigned
+        let port = assign_container_port(port_map, &port_map_path, &pod, &container, None)
+            .await
+            .unwrap();
+        assert!(port >= 30000 && port < 32768);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_port_conflict() {
+        let directory = tempfile::tempdir().unwrap();
+        let port_map_path = directory.path().join("port-map.json");
+        let mut ports = BTreeMap::new();
+        ports.insert(8443, PodKey::new("default", "other"));
+        let port_map = Arc::new(Mutex::new(ports));
+        let pod = pod("web");
+        let container = pod.containers().remove(0);
+
+        match assign_container_port(port_map, &port_map_path, &pod, &container, Some(8443)).await {
+            Err(WasccError::HostPortConflict { port, owner }) => {
+                assert_eq!(port, 8443);
+                assert_eq!(owner, "default/other");
+            }
+            other => panic!("Expected a host port conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_volume_path_with_sub_path() {
+        let root = Path::new("/volumes/data");
+        assert_eq!(volume_path(root, None).unwrap(), root);
+        assert_eq!(volume_path(root, Some("")).unwrap(), root);
+        assert_eq!(
+            volume_path(root, Some("logs/actor")).unwrap(),
+            Path::new("/volumes/data/logs/actor")
+        );
+        assert!(volume_path(root, Some("../other")).is_err());
+        assert!(volume_path(root, Some("logs/../../other")).is_err());
+        assert!(volume_path(root, Some("/etc")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_port_detects_listener() {
+        let mut listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let _ = listener.accept().await;
+        });
+        assert!(wait_for_port(Ipv4Addr::UNSPECIFIED.into(), port, Duration::from_secs(5)).await);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_port_times_out() {
+        // Bind and drop a listener to get a port nobody listens on
+        let port = {
+            let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        assert!(!wait_for_port(Ipv4Addr::LOCALHOST.into(), port, Duration::from_millis(300)).await);
+    }
+}
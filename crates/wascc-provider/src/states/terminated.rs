@@ -1,20 +1,110 @@
-use crate::PodState;
+use crate::lifecycle;
+use crate::preemption::PREEMPTION_REASON;
+use crate::termination::{self, Termination};
+use crate::{patch_http_port_annotation, PodState};
 use kubelet::state::prelude::*;
 
-/// Pod was deleted.
+/// The termination message of actors which were stopped because their pod was deleted.
+const STOPPED_REASON: &str = "Actor stopped because the pod was deleted";
+
+/// The termination message of actors which were stopped to make room for a pod with a higher
+/// priority.
+const PREEMPTED_REASON: &str = "Actor stopped to make room for a pod with a higher priority";
+
+/// Pod was deleted, or preempted by a pod with a higher priority.
 #[derive(Default, Debug)]
-pub struct Terminated;
+pub struct Terminated {
+    pub(crate) preempted: bool,
+}
+
+/// Removes the handle of a preempted pod and releases its ports, and tells the pod which
+/// preempted it that there is room now. Deleted pods do this when they are dropped, but
+/// preempted pods stay around until they are deleted.
+async fn release_preempted(pod_state: &PodState) {
+    pod_state
+        .shared
+        .handles
+        .write()
+        .await
+        .remove(&pod_state.key);
+    if pod_state.shared.release_ports(&pod_state.key).await {
+        patch_http_port_annotation(&pod_state.shared.client, &pod_state.key, &[]).await;
+    }
+    pod_state.shared.release_actor_slot(&pod_state.key);
+    pod_state.shared.preemptibles.stopped(&pod_state.key);
+}
 
 #[async_trait::async_trait]
 impl State<PodState> for Terminated {
-    async fn next(self: Box<Self>, pod_state: &mut PodState, _pod: &Pod) -> Transition<PodState> {
+    async fn next(self: Box<Self>, pod_state: &mut PodState, pod: &Pod) -> Transition<PodState> {
+        // The hooks may take up the whole grace period, so they must not hold the lock on the
+        // handles of all pods.
+        let ports = match pod_state.shared.handles.read().await.get(&pod_state.key) {
+            Some(handle) => Some(lifecycle::actor_ports(pod, handle).await),
+            None => None,
+        };
+        if let Some(ports) = ports {
+            lifecycle::run_pre_stop_hooks(pod, &ports).await;
+        }
+
         let mut lock = pod_state.shared.handles.write().await;
         if let Some(handle) = lock.get_mut(&pod_state.key) {
             let stop_result = handle.stop().await;
+            let error = stop_result.as_ref().err().map(|e| e.to_string());
+            let mut container_statuses = Vec::new();
+            for container in pod.containers() {
+                let log_path = handle
+                    .map_container_handle_factory(container.name(), |factory| {
+                        factory.path().to_path_buf()
+                    })
+                    .await
+                    .ok();
+                let stopped_reason = if self.preempted {
+                    PREEMPTED_REASON
+                } else {
+                    STOPPED_REASON
+                };
+                let termination = Termination {
+                    reason: error.as_deref().unwrap_or(stopped_reason),
+                    failed: error.is_some() || self.preempted,
+                    log_path: log_path.as_deref(),
+                };
+                container_statuses.push(
+                    termination::terminated_status(
+                        &pod_state.shared.termination_path,
+                        &pod_state.key,
+                        &container,
+                        &termination,
+                        pod_state.restart_count,
+                    )
+                    .await,
+                );
+            }
+            let (phase, reason) = match error {
+                Some(_) => (Phase::Failed, "Error"),
+                None if self.preempted => (Phase::Failed, PREEMPTION_REASON),
+                None => (Phase::Succeeded, "Terminated"),
+            };
+            termination::report(
+                &pod_state.shared.client,
+                pod,
+                phase,
+                reason,
+                container_statuses,
+            )
+            .await;
             if let Err(e) = stop_result {
+                if self.preempted {
+                    drop(lock);
+                    release_preempted(pod_state).await;
+                }
                 return Transition::Complete(Err(e));
             }
         }
+        if self.preempted {
+            drop(lock);
+            release_preempted(pod_state).await;
+        }
         Transition::Complete(Ok(()))
     }
 
@@ -23,6 +113,10 @@ impl State<PodState> for Terminated {
         _pod_state: &mut PodState,
         _pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Succeeded, "Terminated")
+        if self.preempted {
+            make_status(Phase::Failed, PREEMPTION_REASON)
+        } else {
+            make_status(Phase::Succeeded, "Terminated")
+        }
     }
 }
@@ -0,0 +1,286 @@
+//! Termination messages of containers, which Kubernetes reports in the terminated state of a
+//! container.
+//!
+//! Actors cannot write to files outside of their volumes, so the provider writes the reason an
+//! actor stopped or failed to the termination message path of its container itself and reads it
+//! back from there. Every container gets a directory of its own below the termination directory
+//! of the provider, which its termination message path is resolved in.
+use std::path::{Component, Path, PathBuf};
+
+use chrono::Utc;
+use k8s_openapi::api::core::v1::ContainerState as KubeContainerState;
+use k8s_openapi::api::core::v1::ContainerStateTerminated as KubeContainerStateTerminated;
+use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time as KubeTime;
+use kube::Api;
+use kubelet::container::Container;
+use kubelet::pod::{make_status_with_containers, patch_status, Phase, Pod, PodKey};
+use log::warn;
+
+/// The termination message path of containers which do not set one, as in Kubernetes.
+const DEFAULT_TERMINATION_MESSAGE_PATH: &str = "/dev/termination-log";
+
+/// The termination message policy which uses the end of the log of a failed container as its
+/// termination message.
+const FALLBACK_TO_LOGS_ON_ERROR: &str = "FallbackToLogsOnError";
+
+/// The maximum length of a termination message in bytes, as in Kubernetes.
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// How many lines at the end of the log are used as termination message if the policy falls
+/// back to the log, as in Kubernetes.
+const FALLBACK_LOG_LINES: usize = 80;
+
+/// The maximum length of a termination message taken from the log in bytes, as in Kubernetes.
+const MAX_FALLBACK_LOG_LENGTH: usize = 2048;
+
+/// Why a container terminated.
+pub(crate) struct Termination<'a> {
+    /// A short reason, which is written to the termination message path
+    pub(crate) reason: &'a str,
+    /// Whether the container failed, as opposed to being stopped
+    pub(crate) failed: bool,
+    /// The log of the container, if it is still around
+    pub(crate) log_path: Option<&'a Path>,
+}
+
+/// Returns the directory the termination messages of the containers of the pod are kept in.
+pub(crate) fn pod_directory(root: &Path, key: &PodKey) -> PathBuf {
+    root.join(key.namespace()).join(key.name())
+}
+
+/// Returns the file the termination message of the container is written to. The termination
+/// message path has to be absolute and must not leave the directory of the container.
+fn message_path(root: &Path, key: &PodKey, container: &Container) -> anyhow::Result<PathBuf> {
+    let path = Path::new(
+        container
+            .termination_message_path()
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_TERMINATION_MESSAGE_PATH),
+    );
+    let mut message_path = pod_directory(root, key).join(container.name());
+    let mut components = path.components();
+    if components.next() != Some(Component::RootDir) {
+        return Err(anyhow::anyhow!(
+            "Termination message path {} of container {} is not absolute",
+            path.display(),
+            container.name()
+        ));
+    }
+    for component in components {
+        match component {
+            Component::Normal(name) => message_path.push(name),
+            Component::CurDir => {}
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Termination message path {} of container {} must not contain '..'",
+                    path.display(),
+                    container.name()
+                ))
+            }
+        }
+    }
+    Ok(message_path)
+}
+
+/// Returns the end of the log, limited to the last lines and bytes Kubernetes uses.
+fn log_tail(log: &str) -> String {
+    let start = log
+        .trim_end()
+        .rmatch_indices('\n')
+        .nth(FALLBACK_LOG_LINES - 1)
+        .map(|(index, _)| index + 1)
+        .unwrap_or(0);
+    let tail = log[start..].trim_end();
+    let mut cut = tail.len().saturating_sub(MAX_FALLBACK_LOG_LENGTH);
+    while !tail.is_char_boundary(cut) {
+        cut += 1;
+    }
+    tail[cut..].to_owned()
+}
+
+/// Truncates the message to the maximum length of a termination message.
+fn truncate(mut message: String) -> String {
+    let mut end = message.len().min(MAX_MESSAGE_LENGTH);
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    message.truncate(end);
+    message
+}
+
+/// Writes the termination message to the termination message path of the container and reads
+/// it back. The message is the reason of the termination, unless the container failed, its
+/// policy is `FallbackToLogsOnError` and it logged something, in which case it is the end of the
+/// log. Actors have no way to write a message themselves, so the log is the only place they
+/// can explain a failure.
+pub(crate) async fn record(
+    root: &Path,
+    key: &PodKey,
+    container: &Container,
+    termination: &Termination<'_>,
+) -> anyhow::Result<String> {
+    let mut message = termination.reason.to_owned();
+    let fall_back_to_log = termination.failed
+        && container.termination_message_policy().map(String::as_str)
+            == Some(FALLBACK_TO_LOGS_ON_ERROR);
+    if let (true, Some(log_path)) = (fall_back_to_log, termination.log_path) {
+        let tail = log_tail(&String::from_utf8_lossy(&tokio::fs::read(log_path).await?));
+        if !tail.is_empty() {
+            message = tail;
+        }
+    }
+
+    let path = message_path(root, key, container)?;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(&path, message).await?;
+    let message = String::from_utf8_lossy(&tokio::fs::read(&path).await?).into_owned();
+    Ok(truncate(message))
+}
+
+/// Returns the status of the container in its terminated state, with the termination message
+/// recorded for the given termination.
+pub(crate) async fn terminated_status(
+    root: &Path,
+    key: &PodKey,
+    container: &Container,
+    termination: &Termination<'_>,
+    restart_count: i32,
+) -> KubeContainerStatus {
+    let message = match record(root, key, container, termination).await {
+        Ok(message) => message,
+        Err(e) => {
+            warn!(
+                "Unable to record termination message of container {} in pod {}: {:?}",
+                container.name(),
+                key.name(),
+                e
+            );
+            termination.reason.to_owned()
+        }
+    };
+    let (exit_code, reason) = if termination.failed {
+        (1, "Error")
+    } else {
+        (0, "Completed")
+    };
+    KubeContainerStatus {
+        name: container.name().to_string(),
+        ready: false,
+        started: Some(false),
+        restart_count,
+        state: Some(KubeContainerState {
+            terminated: Some(KubeContainerStateTerminated {
+                exit_code,
+                reason: Some(reason.to_owned()),
+                message: Some(message).filter(|message| !message.is_empty()),
+                finished_at: Some(KubeTime(Utc::now())),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Reports the terminated states of the containers of the pod. The status of a state is patched
+/// before the state runs, so the states which stop actors or see them fail report them
+/// themselves.
+pub(crate) async fn report(
+    client: &kube::Client,
+    pod: &Pod,
+    phase: Phase,
+    reason: &str,
+    container_statuses: Vec<KubeContainerStatus>,
+) {
+    let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    let status = make_status_with_containers(phase, reason, container_statuses, vec![]);
+    patch_status(&api, pod.name(), status).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn container(path: Option<&str>, policy: Option<&str>) -> Container {
+        Container::new(&k8s_openapi::api::core::v1::Container {
+            name: "actor".to_owned(),
+            termination_message_path: path.map(String::from),
+            termination_message_policy: policy.map(String::from),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_message_path() {
+        let root = Path::new("/termination");
+        let key = PodKey::new("default", "pod");
+        assert_eq!(
+            message_path(root, &key, &container(None, None)).unwrap(),
+            Path::new("/termination/default/pod/actor/dev/termination-log")
+        );
+        assert_eq!(
+            message_path(root, &key, &container(Some("/tmp/./reason"), None)).unwrap(),
+            Path::new("/termination/default/pod/actor/tmp/reason")
+        );
+        assert!(message_path(root, &key, &container(Some("reason"), None)).is_err());
+        assert!(message_path(root, &key, &container(Some("/../../reason"), None)).is_err());
+    }
+
+    #[test]
+    fn test_log_tail() {
+        let log: String = (1..=100).map(|line| format!("line {}\n", line)).collect();
+        let tail = log_tail(&log);
+        assert!(tail.starts_with("line 21\n"));
+        assert!(tail.ends_with("line 100"));
+
+        let long_line = "x".repeat(3000);
+        assert_eq!(log_tail(&long_line).len(), MAX_FALLBACK_LOG_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn test_record_falls_back_to_log_on_error() {
+        let root = tempfile::tempdir().unwrap();
+        let key = PodKey::new("default", "pod");
+        let log = root.path().join("log");
+        tokio::fs::write(&log, "starting\nout of memory\n")
+            .await
+            .unwrap();
+        let failed = Termination {
+            reason: "Actor failed",
+            failed: true,
+            log_path: Some(&log),
+        };
+        let stopped = Termination {
+            reason: "Pod was deleted",
+            failed: false,
+            log_path: Some(&log),
+        };
+
+        let fallback = container(None, Some(FALLBACK_TO_LOGS_ON_ERROR));
+        assert_eq!(
+            record(root.path(), &key, &fallback, &failed).await.unwrap(),
+            "starting\nout of memory"
+        );
+        assert_eq!(
+            record(root.path(), &key, &fallback, &stopped)
+                .await
+                .unwrap(),
+            "Pod was deleted"
+        );
+        let file = container(None, Some("File"));
+        assert_eq!(
+            record(root.path(), &key, &file, &failed).await.unwrap(),
+            "Actor failed"
+        );
+        assert_eq!(
+            tokio::fs::read_to_string(message_path(root.path(), &key, &file).unwrap())
+                .await
+                .unwrap(),
+            "Actor failed"
+        );
+    }
+}
@@ -0,0 +1,310 @@
+//! Recovery from a waSCC host which stopped responding.
+//!
+//! All actors of the node run in the same host, and every change to it locks the host. A
+//! capability or actor which hangs while the host is locked therefore stalls every pod on the
+//! node. The watchdog checks periodically whether the host can be locked and list its actors in
+//! time. After too many failed checks in a row, it replaces the host with a new one, loads the
+//! built-in capabilities and the portable capabilities of the pods into it, and runs the actors
+//! of all pods with a handle again, with the ports and volumes they had before.
+//!
+//! The old host cannot be shut down, as that needs the lock it is stuck with, so it is abandoned.
+//! Whatever still runs in it keeps running until the kubelet is restarted, e.g. its HTTP servers
+//! keep their ports. Actors whose port is still taken are not restarted, their pods fail with
+//! [`HOST_REPLACED_REASON`] instead. As abandoned hosts pile up, the watchdog only runs if it is
+//! enabled through [`crate::WasccProvider::with_host_watchdog`].
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+use kubelet::container::PullPolicy;
+use kubelet::pod::{Pod, PodKey};
+use log::{error, info, warn};
+use wascc_host::Host;
+
+use crate::{
+    add_portable_capability, load_native_capabilities, portable_binding,
+    portable_capability_references, CurrentActor, SharedPodState, WasccError,
+    DEFAULT_CAPABILITY_LOAD_TIMEOUT, HTTP_BIND_ADDRESS,
+};
+
+/// A reasonable interval for the watchdog to check whether the waSCC host responds, to pass to
+/// [`crate::WasccProvider::with_host_watchdog`].
+pub const DEFAULT_WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A reasonable number of failed checks in a row after which the waSCC host is replaced, to pass
+/// to [`crate::WasccProvider::with_host_watchdog`].
+pub const DEFAULT_WATCHDOG_FAILURE_THRESHOLD: usize = 3;
+
+/// The reason reported for pods whose actors could not be restarted in the new host, as the
+/// abandoned one still holds their port.
+const HOST_REPLACED_REASON: &str = "HostReplaced";
+
+/// The termination message of actors which could not be restarted in the new host.
+const PORT_TAKEN_MESSAGE: &str =
+    "Actor stopped because the waSCC host was replaced and the abandoned one still holds its port";
+
+/// How long the host may take to respond to a check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a check tries to lock the host until it times out.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The settings of the watchdog, which can be changed while it runs.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct WatchdogSettings {
+    pub(crate) interval: Duration,
+    pub(crate) failure_threshold: usize,
+}
+
+impl Default for WatchdogSettings {
+    fn default() -> Self {
+        WatchdogSettings {
+            interval: DEFAULT_WATCHDOG_INTERVAL,
+            failure_threshold: DEFAULT_WATCHDOG_FAILURE_THRESHOLD,
+        }
+    }
+}
+
+/// Locks the mutex, unless it is poisoned or stays locked for longer than the timeout.
+fn lock_within<T>(mutex: &Mutex<T>, timeout: Duration) -> Result<MutexGuard<T>, String> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match mutex.try_lock() {
+            Ok(guard) => return Ok(guard),
+            Err(TryLockError::Poisoned(_)) => {
+                return Err("a thread panicked while it used the host".to_owned())
+            }
+            Err(TryLockError::WouldBlock) if Instant::now() >= deadline => {
+                return Err(format!("the host stayed locked for {:?}", timeout))
+            }
+            Err(TryLockError::WouldBlock) => std::thread::sleep(LOCK_POLL_INTERVAL),
+        }
+    }
+}
+
+/// Checks whether the host lists its actors in time, and returns how many there are.
+async fn check(host: Arc<Mutex<Host>>) -> Result<usize, String> {
+    let check = tokio::task::spawn_blocking(move || {
+        lock_within(&host, CHECK_TIMEOUT).map(|host| host.actors().len())
+    });
+    // The lock is only waited for until the timeout, but listing the actors may hang as well
+    match tokio::time::timeout(CHECK_TIMEOUT * 2, check).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => Err(format!("the check panicked: {}", e)),
+        Err(_) => Err(format!(
+            "the host did not list its actors within {:?}",
+            CHECK_TIMEOUT * 2
+        )),
+    }
+}
+
+/// Periodically checks the host, and replaces it once it failed too many checks in a row.
+pub(crate) async fn run(shared: SharedPodState) {
+    let mut failures = 0;
+    loop {
+        let WatchdogSettings {
+            interval,
+            failure_threshold,
+        } = shared.watchdog_settings.lock().unwrap().unwrap_or_default();
+        tokio::time::delay_for(interval).await;
+        match check(shared.host.current()).await {
+            Ok(actors) => {
+                if failures > 0 {
+                    info!("waSCC host responds again, running {} actors", actors);
+                }
+                failures = 0;
+            }
+            Err(reason) => {
+                failures += 1;
+                warn!(
+                    "waSCC host failed health check {} of {}: {}",
+                    failures, failure_threshold, reason
+                );
+                if failures >= failure_threshold {
+                    failures = 0;
+                    error!("waSCC host is unresponsive, replacing it and restarting all actors");
+                    match recover(&shared).await {
+                        Ok((restarted, total)) => error!(
+                            "Replaced unresponsive waSCC host, restarted {} of {} actors",
+                            restarted, total
+                        ),
+                        Err(e) => error!(
+                            "Unable to replace unresponsive waSCC host, keeping it: {:?}",
+                            e
+                        ),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Replaces the host with a new one and runs the actors of all pods with a handle in it. Returns
+/// how many actors were restarted, and how many there were.
+async fn recover(shared: &SharedPodState) -> anyhow::Result<(usize, usize)> {
+    // Keeps pods from adding actors to the old host meanwhile. A pod which is stuck adding its
+    // actors to the old host never gives up its lock though, so it is not waited for long.
+    let _host_changes = match tokio::time::timeout(CHECK_TIMEOUT, shared.host_changes.write()).await
+    {
+        Ok(guard) => Some(guard),
+        Err(_) => {
+            warn!("Replacing the waSCC host while actors are being added to it");
+            None
+        }
+    };
+
+    let host = Arc::new(Mutex::new(Host::new()));
+    let loading = Arc::clone(&host);
+    let loading = tokio::task::spawn_blocking(move || load_native_capabilities(&loading));
+    let unavailable = tokio::time::timeout(DEFAULT_CAPABILITY_LOAD_TIMEOUT, loading)
+        .await
+        .map_err(|_| WasccError::CapabilityLoadTimeout {
+            timeout: DEFAULT_CAPABILITY_LOAD_TIMEOUT,
+        })???;
+    for (capability, reason) in unavailable.iter() {
+        if !shared.unavailable_capabilities.contains_key(capability) {
+            error!(
+                "The {} capability is no longer available, actors using it will fail: {}",
+                capability, reason
+            );
+        }
+    }
+    shared.host.replace(host);
+
+    let handles = shared.handles.read().await;
+    let mut restarted = 0;
+    let mut total = 0;
+    let mut failed_pods = Vec::new();
+    for (key, handle) in handles.iter() {
+        let pod = handle.pod();
+        if let Err(e) = load_portable_capabilities(shared, pod).await {
+            error!(
+                "Unable to load the portable capabilities of pod {} into the new waSCC host: {:?}",
+                key.name(),
+                e
+            );
+        }
+        for container in pod.containers() {
+            total += 1;
+            let container_name = container.name();
+            // The actor is not stopped before, as that would need the old host
+            let prepared = match CurrentActor::of(handle, container_name).await {
+                Ok(current) => {
+                    shared
+                        .prepare_actor(key, &current, container_name, PullPolicy::IfNotPresent)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            let result = match prepared {
+                Ok((_, config)) if !port_is_free(HTTP_BIND_ADDRESS, config.port_assigned) => {
+                    error!(
+                        "Port {} of container {} in pod {} is still held by the abandoned waSCC host, failing the pod",
+                        config.port_assigned,
+                        container_name,
+                        key.name()
+                    );
+                    failed_pods.push(key.clone());
+                    break;
+                }
+                Ok((data, config)) => {
+                    shared
+                        .replace_actor(handle, container_name, data, config)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => {
+                    info!(
+                        "Restarted actor of container {} in pod {} in the new waSCC host",
+                        container_name,
+                        key.name()
+                    );
+                    restarted += 1;
+                }
+                Err(e) => error!(
+                    "Unable to restart actor of container {} in pod {} in the new waSCC host: {:?}",
+                    container_name,
+                    key.name(),
+                    e
+                ),
+            }
+        }
+    }
+    drop(handles);
+
+    for key in failed_pods {
+        if let Err(e) = shared
+            .fail_pod(&key, HOST_REPLACED_REASON, PORT_TAKEN_MESSAGE)
+            .await
+        {
+            error!("Unable to fail pod {}: {:?}", key.name(), e);
+        }
+    }
+    Ok((restarted, total))
+}
+
+/// Returns whether the port can be bound on the address. Actors which do not serve HTTP never
+/// bind their port, so it is free for them as well.
+fn port_is_free(address: IpAddr, port: u16) -> bool {
+    match TcpListener::bind(SocketAddr::new(address, port)) {
+        Ok(_) => true,
+        Err(e) => e.kind() != ErrorKind::AddrInUse,
+    }
+}
+
+/// Loads the portable capability providers of the pod into the current host.
+async fn load_portable_capabilities(shared: &SharedPodState, pod: &Pod) -> anyhow::Result<()> {
+    let binding = portable_binding(&PodKey::from(pod));
+    for (capability, reference) in portable_capability_references(pod)? {
+        let auth = kubelet::secret::RegistryAuthResolver::new(shared.client.clone(), pod)
+            .resolve_registry_auth(&reference)
+            .await?;
+        let data = shared
+            .store
+            .get(&reference, PullPolicy::IfNotPresent, &auth)
+            .await?;
+        let binding = binding.clone();
+        shared
+            .host
+            .with_host(move |host| add_portable_capability(host, &capability, &binding, &data))
+            .await??;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_port_is_not_free_while_it_is_bound() {
+        let address = IpAddr::from([127, 0, 0, 1]);
+        let listener = TcpListener::bind(SocketAddr::new(address, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(!port_is_free(address, port));
+        drop(listener);
+        assert!(port_is_free(address, port));
+    }
+
+    #[test]
+    fn test_lock_within_fails_on_locked_or_poisoned_mutex() {
+        let mutex = Arc::new(Mutex::new(()));
+        assert!(lock_within(&mutex, Duration::from_millis(10)).is_ok());
+
+        let guard = mutex.lock().unwrap();
+        assert!(lock_within(&mutex, Duration::from_millis(10)).is_err());
+        drop(guard);
+
+        let poisoning = Arc::clone(&mutex);
+        std::thread::spawn(move || {
+            let _guard = poisoning.lock().unwrap();
+            panic!("poisoning the mutex");
+        })
+        .join()
+        .unwrap_err();
+        assert!(lock_within(&mutex, Duration::from_millis(10)).is_err());
+    }
+}
@@ -22,4 +22,8 @@ impl State<PodState> for CrashLoopBackoff {
     ) -> anyhow::Result<serde_json::Value> {
         make_status(Phase::Pending, "CrashLoopBackoff")
     }
+
+    fn is_waiting(&self) -> bool {
+        true
+    }
 }
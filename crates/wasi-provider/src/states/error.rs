@@ -32,4 +32,8 @@ impl State<PodState> for Error {
     ) -> anyhow::Result<serde_json::Value> {
         make_status(Phase::Pending, &self.message)
     }
+
+    fn is_waiting(&self) -> bool {
+        true
+    }
 }
@@ -22,4 +22,8 @@ impl State<PodState> for ImagePullBackoff {
     ) -> anyhow::Result<serde_json::Value> {
         make_status(Phase::Pending, "ImagePullBackoff")
     }
+
+    fn is_waiting(&self) -> bool {
+        true
+    }
 }
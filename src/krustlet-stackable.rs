@@ -1,13 +1,11 @@
-use kube::config::Config as KubeConfig;
-use kube::config::KubeConfigOptions;
 use kubelet::config::Config;
 use kubelet::store::composite::ComposableStore;
 use kubelet::store::oci::FileStore;
 use kubelet::Kubelet;
-use pnet::datalink;
+use pnet::datalink::{self, NetworkInterface};
 use pnet::ipnetwork::IpNetwork::V4;
-use stackable_provider::StackableProvider;
-use std::net::{IpAddr, Ipv4Addr};
+use stackable_provider::{StackableProvider, DEFAULT_CGROUP_PARENT};
+use std::net::IpAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -21,47 +19,118 @@ async fn main() -> anyhow::Result<()> {
     // Initialize the logger
     env_logger::init();
 
+    // Unless the node IP is configured, the node is registered with the address of the interface
+    // the processes of its pods are reachable on, which can be chosen explicitly if the host has
+    // several of them
+    if !config.node_ip_configured {
+        config.node_ip = get_default_ipaddress(config.node_interface.as_deref())?;
+    }
+
     //let kubeconfig = kubelet::bootstrap(&config, &config.bootstrap_file, notify_bootstrap).await?;
-    let kubeconfig = KubeConfig::from_kubeconfig(&KubeConfigOptions::default())
+    let kubeconfig = kubelet::load_kubeconfig(&config)
         .await
         .expect("Failed to create Kubernetes Client!");
 
-    let parcel_directory = PathBuf::from("/home/sliebau/IdeaProjects/krustlet/work/parcels");
-    let config_directory = PathBuf::from("/home/sliebau/IdeaProjects/krustlet/work/config");
+    // The data directory already has a config directory of its own for the kubelet
+    // certificates, so everything of the provider lives below a separate directory
+    let provider_directory = config.data_dir.join("stackable");
+    let parcel_directory = create_directory(
+        config.parcel_dir.clone(),
+        provider_directory.join("parcels"),
+        "--parcel-dir",
+    )?;
+    let config_directory = create_directory(
+        config.config_dir.clone(),
+        provider_directory.join("config"),
+        "--config-dir",
+    )?;
+    // Clusters which manage their CRDs themselves, e.g. via GitOps, keep the default of
+    // refusing to start while CRDs are missing
     let provider = StackableProvider::new(
         kube::Client::new(kubeconfig.clone()),
+        &config.node_name,
         parcel_directory,
         config_directory,
+        config.register_crds,
     )
     .await
-    .expect("Error initializing provider.");
+    .expect("Error initializing provider.")
+    .with_node_ip(config.node_ip);
+    let provider = match config.max_processes {
+        Some(max_processes) => provider.with_max_pods(max_processes.into()),
+        None => provider,
+    };
+    // Pods share the IP of the node, unless they get addresses of their own from a pool, which
+    // is experimental, as their processes still listen on the addresses of the node, and the
+    // pool has to be routed to the node and be local to it
+    let provider = match &config.pod_ip_pool {
+        Some(pool) => provider.with_pod_ip_pool(pool.parse()?),
+        None => provider,
+    };
+    // Resource constraints are opt-in, as creating cgroups requires the krustlet to run as root
+    // on a node which uses cgroups v2
+    let provider = if config.cgroups {
+        let parent = config
+            .cgroup_parent
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CGROUP_PARENT));
+        provider.with_cgroups(parent)
+    } else {
+        provider
+    };
 
     let kubelet = Kubelet::new(provider, kubeconfig, config).await?;
     kubelet.start().await
 }
 
-fn get_default_ipaddress() -> Option<IpAddr> {
-    let all_interfaces = datalink::interfaces();
+/// Returns the configured directory, or the default if none is configured, and creates it if
+/// it does not exist yet. The flag which configures the directory is named in the error.
+fn create_directory(
+    configured: Option<PathBuf>,
+    default: PathBuf,
+    flag: &str,
+) -> anyhow::Result<PathBuf> {
+    let directory = configured.unwrap_or(default);
+    std::fs::create_dir_all(&directory).map_err(|e| {
+        anyhow::anyhow!(
+            "Unable to create directory {} (set {} to use another one): {}",
+            directory.display(),
+            flag,
+            e
+        )
+    })?;
+    Ok(directory)
+}
 
-    let default_interface = all_interfaces
-        .iter()
-        .filter(|e| e.is_up() && !e.is_loopback() && e.ips.len() > 0)
-        .next();
+/// Returns the first IPv4 address of the named interface or, if no name is given, of the first
+/// interface which is up and not a loopback interface. Interfaces are considered in the order of
+/// their index, so that the same address is chosen on every start.
+fn get_default_ipaddress(interface_name: Option<&str>) -> anyhow::Result<IpAddr> {
+    let mut interfaces = datalink::interfaces();
+    interfaces.sort_by_key(|interface| interface.index);
 
-    match default_interface {
-        Some(interface) => {
-            println!("Found default interface with [{:?}].", interface.ips);
-            if let V4(test) = interface.ips[0] {
-                if let ip_v4_address = test.ip() {
-                    println!("found: {:?}", ip_v4_address);
-                } else {
-                    return None;
-                }
-            }
+    match interface_name {
+        Some(name) => {
+            let interface = interfaces
+                .iter()
+                .find(|interface| interface.name == name)
+                .ok_or_else(|| anyhow::anyhow!("Network interface {} does not exist", name))?;
+            ipv4_address(interface)
+                .ok_or_else(|| anyhow::anyhow!("Network interface {} has no IPv4 address", name))
         }
-        None => println!("Error while finding the default interface."),
-    };
-    return None;
+        None => interfaces
+            .iter()
+            .filter(|interface| interface.is_up() && !interface.is_loopback())
+            .find_map(ipv4_address)
+            .ok_or_else(|| anyhow::anyhow!("No network interface with an IPv4 address is up")),
+    }
+}
+
+fn ipv4_address(interface: &NetworkInterface) -> Option<IpAddr> {
+    interface.ips.iter().find_map(|network| match network {
+        V4(network) => Some(IpAddr::V4(network.ip())),
+        _ => None,
+    })
 }
 
 fn notify_bootstrap(message: String) {
@@ -1,6 +1,8 @@
 use kubelet::config::Config;
 use kubelet::store::composite::ComposableStore;
-use kubelet::store::oci::FileStore;
+use kubelet::store::fs::DirectoryCache;
+use kubelet::store::oci::{DigestCache, FileStore, DEFAULT_DIGEST_CACHE_SIZE};
+use kubelet::store::Store;
 use kubelet::Kubelet;
 use std::sync::Arc;
 use wascc_provider::WasccProvider;
@@ -18,21 +20,39 @@ async fn main() -> anyhow::Result<()> {
 
     let store = make_store(&config);
 
-    let provider = WasccProvider::new(store, &config, kubeconfig.clone()).await?;
+    let prewarm_images = config.prewarm_images.clone();
+    let provider = WasccProvider::new(store, &config, kubeconfig.clone(), prewarm_images).await?;
+    let provider = match config.max_actors {
+        Some(max_actors) => provider.with_max_actors(max_actors.into()),
+        None => provider,
+    };
     let kubelet = Kubelet::new(provider, kubeconfig, config).await?;
     kubelet.start().await
 }
 
-fn make_store(config: &Config) -> Arc<dyn kubelet::store::Store + Send + Sync> {
+/// Builds the store actor modules are fetched from: the registry, overridden by the local
+/// filesystem if local modules are allowed, behind a cache in the module cache directory if one
+/// is configured, e.g. a directory shared by several nodes.
+fn make_store(config: &Config) -> Arc<dyn Store + Send + Sync> {
     let client = oci_distribution::Client::from_source(config);
     let mut store_path = config.data_dir.join(".oci");
     store_path.push("modules");
-    let file_store = Arc::new(FileStore::new(client, &store_path));
-
-    if config.allow_local_modules {
+    // Modules are kept by the digest of their image as well, so that restarted pods do not
+    // pull them again as long as their image did not change
+    let digest_cache = DigestCache::new(
+        config.data_dir.join(".oci").join("digests"),
+        DEFAULT_DIGEST_CACHE_SIZE,
+    );
+    let file_store = Arc::new(FileStore::new(client, &store_path).with_digest_cache(digest_cache));
+
+    let store = if config.allow_local_modules {
         file_store.with_override(Arc::new(kubelet::store::fs::FileSystemStore {}))
     } else {
         file_store
+    };
+    match &config.module_cache_dir {
+        Some(cache_dir) => store.with_cache(Arc::new(DirectoryCache::new(cache_dir))),
+        None => store,
     }
 }
 
@@ -58,6 +58,12 @@ fn main() {
         }
     }
 
+    println!("Registering CRDs...");
+    if let Err(e) = register_crds() {
+        eprintln!("Registering CRDs failed: {}", e);
+        std::process::exit(EXIT_CODE_TESTS_FAILED);
+    }
+
     let test_result = run_tests(readiness);
 
     println!("All complete");
@@ -94,6 +100,21 @@ fn build_workspace() -> anyhow::Result<()> {
     }
 }
 
+fn register_crds() -> anyhow::Result<()> {
+    let apply_result = std::process::Command::new("kubectl")
+        .args(&["apply", "-f", "crates/wascc-provider/crds"])
+        .output()?;
+
+    if apply_result.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "{}",
+            String::from_utf8(apply_result.stderr).unwrap()
+        ))
+    }
+}
+
 fn prepare_for_bootstrap() -> BootstrapReadiness {
     let host_name = hostname::get()
         .expect("Can't get host name")